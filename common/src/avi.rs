@@ -0,0 +1,338 @@
+//! Motion JPEG in an AVI file, which is what the console's own camera app records and what
+//! every player opens.
+//!
+//! The headers go out first with guesses for everything only known at the end (frame count,
+//! sizes, the actual frame rate), [`AviWriter::finish`] appends the index and patches them. A file
+//! that never got finished still has all its frames, players just have to rebuild the index.
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+/// Everything before the first frame, see [`AviWriter::new`] for the layout.
+pub const HEADER_LEN: u64 = 224;
+
+// where the numbers patched in by `finish` live
+const RIFF_SIZE: u64 = 4;
+const AVIH_MICROS_PER_FRAME: u64 = 32;
+const AVIH_MAX_BYTES_PER_SEC: u64 = 36;
+const AVIH_TOTAL_FRAMES: u64 = 48;
+const AVIH_BUFFER_SIZE: u64 = 60;
+const STRH_SCALE: u64 = 128;
+const STRH_LENGTH: u64 = 140;
+const STRH_BUFFER_SIZE: u64 = 144;
+const MOVI_SIZE: u64 = 216;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Length of an index entry.
+const INDEX_ENTRY_LEN: u64 = 16;
+
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    // offset from the `movi` tag, where the index counts from, and length of every frame
+    index: Vec<(u32, u32)>,
+    // bytes after the `movi` tag so far
+    movi_len: u64,
+    largest: u32,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// Writes the headers for a `width` x `height` video at `fps` frames per second, which
+    /// [`AviWriter::finish`] replaces with the rate the frames actually came in at.
+    ///
+    /// One `RIFF AVI ` with an `hdrl` list (`avih`, then an `strl` list with `strh` and `strf`
+    /// for the one video stream), the `movi` list with a `00dc` chunk per frame and the `idx1`
+    /// index at the end.
+    pub fn new(mut out: W, width: u16, height: u16, fps: u32) -> io::Result<Self> {
+        let micros = 1_000_000 / fps.max(1);
+        let (width, height) = (width as u32, height as u32);
+
+        let mut h = Vec::with_capacity(HEADER_LEN as usize);
+        h.extend_from_slice(b"RIFF");
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h.extend_from_slice(b"AVI ");
+
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&192u32.to_le_bytes());
+        h.extend_from_slice(b"hdrl");
+
+        h.extend_from_slice(b"avih");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        for value in [micros, 0, 0, AVIF_HASINDEX, 0, 0, 1, 0, width, height, 0, 0, 0, 0] {
+            h.extend_from_slice(&value.to_le_bytes());
+        }
+
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&116u32.to_le_bytes());
+        h.extend_from_slice(b"strl");
+
+        h.extend_from_slice(b"strh");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        h.extend_from_slice(b"vids");
+        h.extend_from_slice(b"MJPG");
+        // flags, priority and language, initial frames, scale, rate, start, length, buffer size,
+        // quality (-1 is the default), sample size
+        for value in [0, 0, 0, micros, 1_000_000, 0, 0, 0, u32::MAX, 0] {
+            h.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [0, 0, width as u16, height as u16] {
+            h.extend_from_slice(&value.to_le_bytes());
+        }
+
+        // a BITMAPINFOHEADER
+        h.extend_from_slice(b"strf");
+        h.extend_from_slice(&40u32.to_le_bytes());
+        h.extend_from_slice(&40u32.to_le_bytes());
+        h.extend_from_slice(&width.to_le_bytes());
+        h.extend_from_slice(&height.to_le_bytes());
+        h.extend_from_slice(&1u16.to_le_bytes());
+        h.extend_from_slice(&24u16.to_le_bytes());
+        h.extend_from_slice(b"MJPG");
+        for value in [width * height * 3, 0, 0, 0, 0] {
+            h.extend_from_slice(&value.to_le_bytes());
+        }
+
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&4u32.to_le_bytes());
+        h.extend_from_slice(b"movi");
+        debug_assert_eq!(h.len() as u64, HEADER_LEN);
+
+        out.write_all(&h)?;
+        Ok(Self {
+            out,
+            index: Vec::new(),
+            movi_len: 4,
+            largest: 0,
+        })
+    }
+
+    /// Appends one JPEG.
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(jpeg.len()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        self.out.write_all(b"00dc")?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(jpeg)?;
+        // chunks start on even offsets
+        if len % 2 == 1 {
+            self.out.write_all(&[0])?;
+        }
+
+        self.index.push((self.movi_len as u32, len));
+        self.movi_len += 8 + (len as u64).next_multiple_of(2);
+        self.largest = self.largest.max(len);
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.index.len()
+    }
+
+    /// How long the file will be once finished.
+    pub fn len(&self) -> u64 {
+        HEADER_LEN - 4 + self.movi_len + 8 + self.index.len() as u64 * INDEX_ENTRY_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Writes the index and fixes up the headers, `duration` being how long the frames took to
+    /// come in.
+    pub fn finish(mut self, duration: Duration) -> io::Result<W> {
+        let frames = self.index.len() as u32;
+
+        let mut index = Vec::with_capacity(8 + self.index.len() * INDEX_ENTRY_LEN as usize);
+        index.extend_from_slice(b"idx1");
+        index.extend_from_slice(&(frames * INDEX_ENTRY_LEN as u32).to_le_bytes());
+        for &(offset, len) in &self.index {
+            index.extend_from_slice(b"00dc");
+            index.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&len.to_le_bytes());
+        }
+        self.out.write_all(&index)?;
+
+        let file_len = self.len();
+        self.patch(RIFF_SIZE, (file_len - 8) as u32)?;
+        self.patch(MOVI_SIZE, self.movi_len as u32)?;
+        self.patch(AVIH_TOTAL_FRAMES, frames)?;
+        self.patch(STRH_LENGTH, frames)?;
+        self.patch(AVIH_BUFFER_SIZE, self.largest + 8)?;
+        self.patch(STRH_BUFFER_SIZE, self.largest + 8)?;
+
+        if frames > 0 && !duration.is_zero() {
+            let micros = (duration.as_micros() / frames as u128).clamp(1, u32::MAX as u128) as u32;
+            let per_second = (self.movi_len as u128 * 1_000_000 / duration.as_micros()).min(u32::MAX as u128) as u32;
+            self.patch(AVIH_MICROS_PER_FRAME, micros)?;
+            self.patch(STRH_SCALE, micros)?;
+            self.patch(AVIH_MAX_BYTES_PER_SEC, per_second)?;
+        }
+
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn patch(&mut self, at: u64, value: u32) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(at))?;
+        self.out.write_all(&value.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A chunk and, for `RIFF` and `LIST`, everything in it.
+    #[derive(Debug)]
+    struct Chunk<'a> {
+        id: [u8; 4],
+        /// Offset of the data in the file.
+        at: usize,
+        data: &'a [u8],
+        /// The form or list type and the chunks after it.
+        list: Option<([u8; 4], Vec<Chunk<'a>>)>,
+    }
+
+    impl Chunk<'_> {
+        fn u32_at(&self, at: usize) -> u32 {
+            u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+        }
+
+        fn ids(&self) -> Vec<&[u8]> {
+            let (_, children) = self.list.as_ref().unwrap();
+            children.iter().map(|c| &c.id[..]).collect()
+        }
+
+        fn child(&self, id: &[u8]) -> &Self {
+            let (_, children) = self.list.as_ref().unwrap();
+            children.iter().find(|c| c.is(id)).unwrap()
+        }
+
+        /// Whether this is an `id` chunk or an `id` list.
+        fn is(&self, id: &[u8]) -> bool {
+            self.id == id || self.list.as_ref().is_some_and(|(t, _)| t == id)
+        }
+    }
+
+    /// Splits `data`, found at `at` in the file, into chunks. Sizes have to add up exactly.
+    fn chunks(data: &[u8], at: usize) -> Vec<Chunk<'_>> {
+        let mut found = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            assert!(data.len() - offset >= 8, "partial chunk header at {}", at + offset);
+            let id: [u8; 4] = data[offset..offset + 4].try_into().unwrap();
+            let len = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let start = offset + 8;
+            assert!(start + len <= data.len(), "{:?} at {} runs past its parent", id, at + offset);
+            assert_eq!((at + offset) % 2, 0, "{:?} starts on an odd offset", id);
+            let body = &data[start..start + len];
+            let list = (&id == b"RIFF" || &id == b"LIST").then(|| {
+                (body[..4].try_into().unwrap(), chunks(&body[4..], at + start + 4))
+            });
+            found.push(Chunk {
+                id,
+                at: at + start,
+                data: body,
+                list,
+            });
+            offset = start + len.next_multiple_of(2);
+        }
+        assert_eq!(offset, data.len(), "chunks overrun their parent");
+        found
+    }
+
+    fn record(frames: &[&[u8]], duration: Duration) -> Vec<u8> {
+        let mut avi = AviWriter::new(Cursor::new(Vec::new()), 640, 480, 30).unwrap();
+        for frame in frames {
+            avi.write_frame(frame).unwrap();
+        }
+        assert_eq!(avi.frames(), frames.len());
+        let len = avi.len();
+        let file = avi.finish(duration).unwrap().into_inner();
+        assert_eq!(file.len() as u64, len);
+        file
+    }
+
+    #[test]
+    fn riff_tree() {
+        let frames: [&[u8]; 3] = [&[0xff, 0xd8, 1, 0xff, 0xd9], &[0xff, 0xd8, 0xff, 0xd9], &[7; 9]];
+        let file = record(&frames, Duration::from_millis(300));
+
+        let mut top = chunks(&file, 0);
+        assert_eq!(top.len(), 1);
+        let riff = top.remove(0);
+        assert_eq!(riff.id, *b"RIFF");
+        assert_eq!(riff.data.len(), file.len() - 8);
+        assert_eq!(riff.list.as_ref().unwrap().0, *b"AVI ");
+        assert_eq!(riff.ids(), [&b"LIST"[..], b"LIST", b"idx1"]);
+
+        let hdrl = riff.child(b"hdrl");
+        assert_eq!(hdrl.ids(), [&b"avih"[..], b"LIST"]);
+        let avih = hdrl.child(b"avih");
+        assert_eq!(avih.data.len(), 56);
+        assert_eq!(avih.u32_at(0), 100_000, "µs per frame, from the duration");
+        assert_eq!(avih.u32_at(12), AVIF_HASINDEX);
+        assert_eq!(avih.u32_at(16), 3, "total frames");
+        assert_eq!(avih.u32_at(24), 1, "streams");
+        assert_eq!(avih.u32_at(28), 9 + 8, "buffer size");
+        assert_eq!((avih.u32_at(32), avih.u32_at(36)), (640, 480));
+
+        let strl = hdrl.child(b"strl");
+        assert_eq!(strl.ids(), [&b"strh"[..], b"strf"]);
+        let strh = strl.child(b"strh");
+        assert_eq!((&strh.data[..4], &strh.data[4..8]), (&b"vids"[..], &b"MJPG"[..]));
+        assert_eq!((strh.u32_at(20), strh.u32_at(24)), (100_000, 1_000_000), "scale and rate");
+        assert_eq!(strh.u32_at(32), 3, "length");
+        let strf = strl.child(b"strf");
+        assert_eq!((strf.u32_at(4), strf.u32_at(8)), (640, 480));
+        assert_eq!(&strf.data[16..20], b"MJPG");
+
+        let movi = riff.child(b"movi");
+        assert_eq!(movi.at + 4, HEADER_LEN as usize);
+        let (_, frame_chunks) = movi.list.as_ref().unwrap();
+        assert_eq!(frame_chunks.len(), 3);
+        for (chunk, frame) in frame_chunks.iter().zip(frames) {
+            assert_eq!(chunk.id, *b"00dc");
+            assert_eq!(chunk.data, frame);
+        }
+
+        // offsets count from the `movi` tag and point at the frame's chunk header
+        let idx1 = riff.child(b"idx1");
+        assert_eq!(idx1.data.len(), 3 * 16);
+        let movi_tag = movi.at;
+        for (entry, chunk) in idx1.data.chunks_exact(16).zip(frame_chunks) {
+            let field = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+            assert_eq!(&entry[..4], b"00dc");
+            assert_eq!(field(4), AVIIF_KEYFRAME);
+            assert_eq!(movi_tag + field(8) as usize, chunk.at - 8);
+            assert_eq!(field(12) as usize, chunk.data.len());
+        }
+    }
+
+    #[test]
+    fn without_frames() {
+        let file = record(&[], Duration::from_secs(1));
+        assert_eq!(file.len() as u64, HEADER_LEN + 8);
+        let riff = chunks(&file, 0).remove(0);
+        assert_eq!(riff.child(b"hdrl").child(b"avih").u32_at(16), 0);
+        // nothing to work the rate out from, so it's the one asked for
+        assert_eq!(riff.child(b"hdrl").child(b"avih").u32_at(0), 1_000_000 / 30);
+        assert!(riff.child(b"idx1").data.is_empty());
+    }
+
+    #[test]
+    fn rate_from_duration() {
+        let frames = [&[0u8; 100][..]; 10];
+        let file = record(&frames, Duration::from_secs(2));
+        let riff = chunks(&file, 0).remove(0);
+        let avih = riff.child(b"hdrl").child(b"avih");
+        assert_eq!(avih.u32_at(0), 200_000);
+        assert_eq!(avih.u32_at(4), 10 * 108 / 2 + 2, "bytes per second of the movi list");
+        assert_eq!(riff.child(b"hdrl").child(b"strl").child(b"strh").u32_at(20), 200_000);
+    }
+}
@@ -0,0 +1,877 @@
+//! Settings file kept on the SD card.
+//!
+//! Plain INI-ish text so it can be fixed by hand from a PC: `key = value` lines, `[section]`
+//! headers, `#` comments. Keys before the first section are global, `[keys]` maps actions to
+//! buttons (see [`Binding::parse`] for how they're written, several go comma separated) and every
+//! `[server]` section is one saved server, in the order they're shown. Unknown sections and keys
+//! are skipped so older builds can read files written by newer ones, and so are lines that don't
+//! parse (with a warning) so one typo doesn't lose everything else.
+//!
+//! ```text
+//! last_used = 192.168.1.10:5000
+//! queue_length = 3
+//! drop_policy = drop-oldest
+//! record_format = avi
+//! record_max_mb = 1024
+//! record_max_seconds = 600
+//! photo_format = jpeg
+//! photo_mpo = true
+//!
+//! [keys]
+//! exit = START, hold B
+//! settings = L+R
+//! rtsp =
+//!
+//! [server]
+//! name = Desktop
+//! host = 192.168.1.10
+//! port = 5000
+//! transport = udp
+//! favourite = true
+//! format = jpeg
+//! size = 640x480
+//! fps = 30
+//! camera = both-outer
+//! quality = 80
+//! stereo = side-by-side
+//! ```
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::handshake::{CameraId, FrameRate, Resolution, StreamConfig, Transport};
+use crate::input::{Action, Binding, KeyMap};
+use crate::protocol::{PixelFormat, DEFAULT_PORT};
+use crate::photo::PhotoFormat;
+use crate::queue::DropPolicy;
+use crate::record::{Container, Limits};
+use crate::stereo::StereoLayout;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Line {line}: {message}")]
+    Syntax { line: usize, message: String },
+}
+
+/// A server the console connected to before.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SavedServer {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub transport: Transport,
+    /// Favourites are listed before everything else.
+    pub favourite: bool,
+    /// Settings to ask for first, usually what the last session with this server used.
+    pub preferred: Option<StreamConfig>,
+}
+
+impl SavedServer {
+    /// Parses `host`, `host:port` or `[v6]:port`, the name defaults to the address.
+    pub fn from_address(address: &str, transport: Transport) -> Option<Self> {
+        let (host, port) = split_address(address.trim())?;
+        Some(Self {
+            name: address.trim().to_owned(),
+            host,
+            port,
+            transport,
+            favourite: false,
+            preferred: None,
+        })
+    }
+
+    /// `host:port`, what `TcpStream::connect` wants.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    fn same_place(&self, other: &SavedServer) -> bool {
+        self.host.eq_ignore_ascii_case(&other.host) && self.port == other.port
+    }
+}
+
+fn split_address(address: &str) -> Option<(String, u16)> {
+    if address.is_empty() {
+        return None;
+    }
+
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if rest.is_empty() => DEFAULT_PORT,
+            None => return None,
+        };
+        return Some((host.to_owned(), port));
+    }
+
+    match address.split_once(':') {
+        // more than one colon, a bare IPv6 address
+        Some((_, rest)) if rest.contains(':') => Some((address.to_owned(), DEFAULT_PORT)),
+        Some((host, port)) if !host.is_empty() => Some((host.to_owned(), port.parse().ok()?)),
+        Some(_) => None,
+        None => Some((address.to_owned(), DEFAULT_PORT)),
+    }
+}
+
+/// Frames the capture thread can get ahead of the network by, unless the file says otherwise.
+pub const DEFAULT_QUEUE_LENGTH: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Favourites first, then everything else, each group in the user's order.
+    pub servers: Vec<SavedServer>,
+    /// Address of the server connected to most recently.
+    pub last_used: Option<String>,
+    /// Frames waiting between the capture and network threads, see [`queue`](crate::queue).
+    pub queue_length: usize,
+    /// What goes when the queue is full.
+    pub drop_policy: DropPolicy,
+    /// What the buttons do.
+    pub keys: KeyMap,
+    /// What recordings to the SD card are saved as.
+    pub record_format: Container,
+    /// When recordings move on to the next file.
+    pub record_limits: Limits,
+    /// What stills are saved as.
+    pub photo_format: PhotoFormat,
+    /// Saves both outer cameras as one MPO instead of the 3D layout from the settings, for JPEG
+    /// stills.
+    pub photo_mpo: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            last_used: None,
+            queue_length: DEFAULT_QUEUE_LENGTH,
+            drop_policy: DropPolicy::default(),
+            keys: KeyMap::default(),
+            record_format: Container::default(),
+            record_limits: Limits::default(),
+            photo_format: PhotoFormat::default(),
+            photo_mpo: true,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file at `path`, a missing file is an empty config. Only fails if the file can't
+    /// be read, see [`Config::parse`] for what happens to lines that make no sense.
+    pub fn load(path: &Path) -> Result<(Self, Vec<ConfigError>), ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((Self::default(), Vec::new())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes to a temporary file first so a crash or a pulled SD card can't leave half a file.
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_string())?;
+        if fs::rename(&tmp, path).is_err() {
+            // the SD card's FAT driver won't rename over an existing file
+            let _ = fs::remove_file(path);
+            fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+
+    /// Parses the whole file, skipping lines that don't make sense and returning what was wrong
+    /// with them. A typo in one value shouldn't cost the user their saved servers and keys the
+    /// next time the file gets written.
+    pub fn parse(text: &str) -> (Self, Vec<ConfigError>) {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        let mut section: Option<String> = None;
+
+        for (i, raw) in text.lines().enumerate() {
+            let line = raw.trim();
+            let error = |message: String| ConfigError::Syntax {
+                line: i + 1,
+                message,
+            };
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[') {
+                let Some(name) = name.strip_suffix(']') else {
+                    errors.push(error("Unterminated section header".to_owned()));
+                    // whatever follows belongs to a section we don't know, not the one before
+                    section = Some(String::new());
+                    continue;
+                };
+                let name = name.trim();
+                if name == "server" {
+                    config.servers.push(SavedServer {
+                        name: String::new(),
+                        host: String::new(),
+                        port: DEFAULT_PORT,
+                        transport: Transport::Tcp,
+                        favourite: false,
+                        preferred: None,
+                    });
+                }
+                section = Some(name.to_owned());
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                errors.push(error(format!("Expected key = value, got {}", line)));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+
+            let set = match section.as_deref() {
+                None => set_global_key(&mut config, key, value),
+                Some("keys") => set_binding(&mut config.keys, key, value),
+                Some("server") => {
+                    let server = config.servers.last_mut().unwrap();
+                    set_server_key(server, key, value)
+                }
+                Some(_) => Ok(()),
+            };
+            if let Err(message) = set {
+                errors.push(error(message));
+            }
+        }
+
+        // a server without a host is useless, one without a name gets its address
+        config.servers.retain(|s| !s.host.is_empty());
+        for server in &mut config.servers {
+            if server.name.is_empty() {
+                server.name = server.address();
+            }
+        }
+        config.sort();
+
+        (config, errors)
+    }
+
+    /// Index of the last used server, if it's still saved.
+    pub fn last_used_index(&self) -> Option<usize> {
+        let last = self.last_used.as_deref()?;
+        self.servers.iter().position(|s| s.address() == last)
+    }
+
+    /// Records a successful connection: adds the server if it's new, otherwise updates the saved
+    /// one (keeping its name and place in the list). Returns its index.
+    pub fn remember(&mut self, server: SavedServer) -> usize {
+        self.last_used = Some(server.address());
+
+        match self.servers.iter().position(|s| s.same_place(&server)) {
+            Some(i) => {
+                let saved = &mut self.servers[i];
+                saved.transport = server.transport;
+                if server.preferred.is_some() {
+                    saved.preferred = server.preferred;
+                }
+                i
+            }
+            None => {
+                self.servers.push(server);
+                self.servers.len() - 1
+            }
+        }
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) {
+        let name = name.trim();
+        if let Some(server) = self.servers.get_mut(index) {
+            server.name = if name.is_empty() {
+                server.address()
+            } else {
+                name.to_owned()
+            };
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<SavedServer> {
+        (index < self.servers.len()).then(|| self.servers.remove(index))
+    }
+
+    /// Swaps with the entry above, favourites can't be moved below the rest and the other way
+    /// around. Returns the new index.
+    pub fn move_up(&mut self, index: usize) -> usize {
+        if index == 0 || index >= self.servers.len() {
+            return index;
+        }
+        if self.servers[index - 1].favourite != self.servers[index].favourite {
+            return index;
+        }
+        self.servers.swap(index - 1, index);
+        index - 1
+    }
+
+    /// See [`Config::move_up`].
+    pub fn move_down(&mut self, index: usize) -> usize {
+        if index + 1 >= self.servers.len() {
+            return index;
+        }
+        if self.servers[index + 1].favourite != self.servers[index].favourite {
+            return index;
+        }
+        self.servers.swap(index, index + 1);
+        index + 1
+    }
+
+    /// Favourites go to the end of the favourites, others to the top of the rest. Returns the new
+    /// index.
+    pub fn toggle_favourite(&mut self, index: usize) -> usize {
+        let server = match self.servers.get_mut(index) {
+            Some(server) => server,
+            None => return index,
+        };
+        server.favourite = !server.favourite;
+
+        let server = self.servers.remove(index);
+        let favourites = self.servers.iter().filter(|s| s.favourite).count();
+        self.servers.insert(favourites, server);
+        favourites
+    }
+
+    /// Restores the favourites-first order, keeping the order within each group.
+    fn sort(&mut self) {
+        self.servers.sort_by_key(|s| !s.favourite);
+    }
+}
+
+fn set_global_key(config: &mut Config, key: &str, value: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid {} {}", key, value);
+
+    match key {
+        "last_used" if !value.is_empty() => config.last_used = Some(value.to_owned()),
+        "queue_length" => {
+            config.queue_length = value
+                .parse()
+                .ok()
+                .filter(|&len| len > 0)
+                .ok_or_else(invalid)?
+        }
+        "drop_policy" => config.drop_policy = DropPolicy::from_name(value).ok_or_else(invalid)?,
+        "record_format" => config.record_format = Container::from_name(value).ok_or_else(invalid)?,
+        "record_max_mb" => {
+            let mb: u64 = value
+                .parse()
+                .ok()
+                .filter(|&mb| mb > 0)
+                .ok_or_else(invalid)?;
+            config.record_limits.max_bytes = mb << 20;
+        }
+        // 0 for no limit
+        "record_max_seconds" => {
+            let seconds: u64 = value.parse().map_err(|_| invalid())?;
+            config.record_limits.max_duration = (seconds > 0).then(|| Duration::from_secs(seconds));
+        }
+        "photo_format" => config.photo_format = PhotoFormat::from_name(value).ok_or_else(invalid)?,
+        "photo_mpo" => config.photo_mpo = parse_bool(value).ok_or_else(invalid)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn set_binding(keys: &mut KeyMap, key: &str, value: &str) -> Result<(), String> {
+    let Some(action) = Action::from_name(key) else {
+        return Ok(());
+    };
+    let bindings = value
+        .split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| Binding::parse(b).ok_or_else(|| format!("Invalid {} {}", key, b)))
+        .collect::<Result<Vec<_>, _>>()?;
+    keys.set(action, &bindings);
+    Ok(())
+}
+
+fn set_server_key(server: &mut SavedServer, key: &str, value: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid {} {}", key, value);
+    let preferred = || server.preferred.unwrap_or_default();
+
+    match key {
+        "name" => server.name = value.to_owned(),
+        "host" => server.host = value.to_owned(),
+        "port" => server.port = value.parse().map_err(|_| invalid())?,
+        "transport" => {
+            server.transport = match value {
+                "tcp" => Transport::Tcp,
+                "udp" => Transport::Udp,
+                _ => return Err(invalid()),
+            }
+        }
+        "favourite" => server.favourite = parse_bool(value).ok_or_else(invalid)?,
+        "format" => {
+            let format = format_from_name(value).ok_or_else(invalid)?;
+            server.preferred = Some(StreamConfig {
+                format,
+                ..preferred()
+            });
+        }
+        "size" => {
+            let (w, h) = value.split_once('x').ok_or_else(invalid)?;
+            let resolution = Resolution::new(
+                w.parse().map_err(|_| invalid())?,
+                h.parse().map_err(|_| invalid())?,
+            );
+            server.preferred = Some(StreamConfig {
+                resolution,
+                ..preferred()
+            });
+        }
+        "fps" => {
+            let frame_rate = frame_rate_from_name(value).ok_or_else(invalid)?;
+            server.preferred = Some(StreamConfig {
+                frame_rate,
+                ..preferred()
+            });
+        }
+        "camera" => {
+            let camera = camera_from_name(value).ok_or_else(invalid)?;
+            server.preferred = Some(StreamConfig {
+                camera,
+                ..preferred()
+            });
+        }
+        "quality" => {
+            let quality = value
+                .parse()
+                .ok()
+                .filter(|q| (1..=100).contains(q))
+                .ok_or_else(invalid)?;
+            server.preferred = Some(StreamConfig {
+                quality,
+                ..preferred()
+            });
+        }
+        "stereo" => {
+            let stereo = StereoLayout::from_name(value).ok_or_else(invalid)?;
+            server.preferred = Some(StreamConfig {
+                stereo,
+                ..preferred()
+            });
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# ctr-camera-rs settings")?;
+        if let Some(last) = &self.last_used {
+            writeln!(f, "last_used = {}", last)?;
+        }
+        writeln!(f, "queue_length = {}", self.queue_length)?;
+        writeln!(f, "drop_policy = {}", self.drop_policy.name())?;
+        writeln!(f, "record_format = {}", self.record_format.name())?;
+        writeln!(f, "record_max_mb = {}", self.record_limits.max_bytes >> 20)?;
+        writeln!(
+            f,
+            "record_max_seconds = {}",
+            self.record_limits.max_duration.map_or(0, |d| d.as_secs())
+        )?;
+        writeln!(f, "photo_format = {}", self.photo_format.name())?;
+        writeln!(f, "photo_mpo = {}", self.photo_mpo)?;
+
+        writeln!(f, "\n[keys]")?;
+        for action in Action::ALL {
+            let bindings: Vec<String> = self.keys.bindings(action).map(|b| b.to_string()).collect();
+            writeln!(f, "{} = {}", action.name(), bindings.join(", "))?;
+        }
+
+        for server in &self.servers {
+            let mut s = String::new();
+            let _ = writeln!(s, "\n[server]");
+            let _ = writeln!(s, "name = {}", server.name);
+            let _ = writeln!(s, "host = {}", server.host);
+            let _ = writeln!(s, "port = {}", server.port);
+            let _ = writeln!(
+                s,
+                "transport = {}",
+                match server.transport {
+                    Transport::Tcp => "tcp",
+                    Transport::Udp => "udp",
+                }
+            );
+            let _ = writeln!(s, "favourite = {}", server.favourite);
+            if let Some(preferred) = &server.preferred {
+                let _ = writeln!(s, "format = {}", format_name(preferred.format));
+                let _ = writeln!(
+                    s,
+                    "size = {}x{}",
+                    preferred.resolution.width, preferred.resolution.height
+                );
+                let _ = writeln!(s, "fps = {}", frame_rate_name(preferred.frame_rate));
+                let _ = writeln!(s, "camera = {}", camera_name(preferred.camera));
+                let _ = writeln!(s, "quality = {}", preferred.quality);
+                let _ = writeln!(s, "stereo = {}", preferred.stereo.name());
+            }
+            f.write_str(&s)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" | "yes" | "1" => Some(true),
+        "false" | "no" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn format_name(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Yuv422 => "yuv422",
+        PixelFormat::Rgb565 => "rgb565",
+        PixelFormat::Jpeg => "jpeg",
+    }
+}
+
+pub fn format_from_name(name: &str) -> Option<PixelFormat> {
+    [PixelFormat::Yuv422, PixelFormat::Rgb565, PixelFormat::Jpeg]
+        .into_iter()
+        .find(|&f| format_name(f) == name)
+}
+
+pub fn frame_rate_name(rate: FrameRate) -> &'static str {
+    match rate {
+        FrameRate::Fps15 => "15",
+        FrameRate::Fps15To5 => "15-5",
+        FrameRate::Fps15To2 => "15-2",
+        FrameRate::Fps10 => "10",
+        FrameRate::Fps8_5 => "8.5",
+        FrameRate::Fps5 => "5",
+        FrameRate::Fps20 => "20",
+        FrameRate::Fps20To5 => "20-5",
+        FrameRate::Fps30 => "30",
+        FrameRate::Fps30To5 => "30-5",
+        FrameRate::Fps15To10 => "15-10",
+        FrameRate::Fps20To10 => "20-10",
+        FrameRate::Fps30To10 => "30-10",
+    }
+}
+
+pub fn frame_rate_from_name(name: &str) -> Option<FrameRate> {
+    FrameRate::ALL
+        .into_iter()
+        .find(|&r| frame_rate_name(r) == name)
+}
+
+pub fn camera_name(camera: CameraId) -> &'static str {
+    match camera {
+        CameraId::OuterRight => "outer-right",
+        CameraId::Inner => "inner",
+        CameraId::OuterLeft => "outer-left",
+        CameraId::BothOuter => "both-outer",
+    }
+}
+
+pub fn camera_from_name(name: &str) -> Option<CameraId> {
+    CameraId::ALL
+        .into_iter()
+        .find(|&c| camera_name(c) == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Keys;
+
+    fn server(name: &str, address: &str, favourite: bool) -> SavedServer {
+        SavedServer {
+            name: name.to_owned(),
+            favourite,
+            ..SavedServer::from_address(address, Transport::Tcp).unwrap()
+        }
+    }
+
+    fn names(config: &Config) -> Vec<&str> {
+        config.servers.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    /// Favourites `a` and `b`, then `c`, `d` and `e`.
+    fn five() -> Config {
+        let mut config = Config::default();
+        for (i, name) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            let address = format!("10.0.0.{}:5000", i + 1);
+            config.servers.push(server(name, &address, i < 2));
+        }
+        config
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut keys = KeyMap::default();
+        keys.set(Action::Exit, &[Binding::press(Keys::START), Binding::hold(Keys::B)]);
+        keys.set(Action::Settings, &[Binding::press(Keys::L | Keys::R)]);
+        keys.set(Action::Rtsp, &[]);
+        let config = Config {
+            servers: vec![
+                SavedServer {
+                    transport: Transport::Udp,
+                    preferred: Some(StreamConfig {
+                        format: PixelFormat::Rgb565,
+                        frame_rate: FrameRate::Fps8_5,
+                        resolution: Resolution::new(400, 240),
+                        camera: CameraId::Inner,
+                        quality: 42,
+                        stereo: StereoLayout::TopBottom,
+                    }),
+                    ..server("Desktop", "192.168.1.10:5000", true)
+                },
+                server("fe80::1", "[fe80::1]:6000", false),
+            ],
+            last_used: Some("192.168.1.10:5000".to_owned()),
+            queue_length: 7,
+            drop_policy: DropPolicy::DropNewest,
+            keys,
+            record_format: Container::Y4m,
+            record_limits: Limits {
+                max_bytes: 5 << 20,
+                max_duration: None,
+            },
+            photo_format: PhotoFormat::Png,
+            photo_mpo: false,
+        };
+        let text = config.to_string();
+        let (parsed, errors) = Config::parse(&text);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(parsed, config);
+        assert_eq!(parsed.to_string(), text);
+        assert!(text.contains("exit = START, hold B\n"), "{}", text);
+        assert!(text.contains("rtsp = \n"), "{}", text);
+        assert_eq!(parsed.last_used_index(), Some(0));
+
+        let (empty, errors) = Config::parse("");
+        assert!(errors.is_empty());
+        assert_eq!(empty, Config::default());
+        assert_eq!(Config::parse(&Config::default().to_string()).0, Config::default());
+    }
+
+    #[test]
+    fn the_example() {
+        let text = include_str!("config.rs")
+            .lines()
+            .skip_while(|l| *l != "//! ```text")
+            .skip(1)
+            .take_while(|l| *l != "//! ```")
+            .map(|l| l.trim_start_matches("//!").trim())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (config, errors) = Config::parse(&text);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(names(&config), ["Desktop"]);
+        let desktop = &config.servers[0];
+        assert_eq!((desktop.transport, desktop.favourite), (Transport::Udp, true));
+        assert_eq!(desktop.preferred.unwrap().stereo, StereoLayout::SideBySide);
+        assert_eq!(config.keys.label(Action::Exit), "START/hold B");
+        assert_eq!(config.keys.label(Action::Rtsp), "-");
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let text = "\
+            queue_length = 0\n\
+            drop_policy = drop-everything\n\
+            photo_mpo = maybe\n\
+            record_max_mb = 64\n\
+            just some words\n\
+            [keys]\n\
+            exit = hold Q\n\
+            settings = L+R\n\
+            [server\n\
+            host = 10.9.9.9\n\
+            [server]\n\
+            name = Desktop\n\
+            host = 10.0.0.2\n\
+            port = 99999\n\
+            fps = fast\n\
+            quality = 101\n\
+            size = 640x\n\
+            camera = inner\n\
+            transport = carrier-pigeon\n\
+            [later]\n\
+            anything = goes\n";
+        let (config, errors) = Config::parse(text);
+        let lines: Vec<usize> = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Syntax { line, .. } => *line,
+                ConfigError::Io(e) => panic!("{}", e),
+            })
+            .collect();
+        assert_eq!(lines, [1, 2, 3, 5, 7, 9, 14, 15, 16, 17, 19]);
+        assert_eq!(errors[1].to_string(), "Line 2: Invalid drop_policy drop-everything");
+
+        // everything else is still there
+        let defaults = Config::default();
+        assert_eq!(config.queue_length, defaults.queue_length);
+        assert_eq!(config.drop_policy, defaults.drop_policy);
+        assert_eq!(config.record_limits.max_bytes, 64 << 20);
+        assert_eq!(config.keys.label(Action::Exit), defaults.keys.label(Action::Exit));
+        assert_eq!(config.keys.label(Action::Settings), "L+R");
+        // what follows a broken header doesn't end up in the server before it
+        assert_eq!(names(&config), ["Desktop"]);
+        let desktop = &config.servers[0];
+        assert_eq!((desktop.host.as_str(), desktop.port), ("10.0.0.2", DEFAULT_PORT));
+        assert_eq!(desktop.transport, Transport::Tcp);
+        // only the camera made sense
+        let camera = StreamConfig { camera: CameraId::Inner, ..StreamConfig::default() };
+        assert_eq!(desktop.preferred, Some(camera));
+    }
+
+    #[test]
+    fn servers_without_a_host() {
+        let (config, errors) = Config::parse("[server]\nname = Nowhere\n[server]\nhost = 10.0.0.3\n");
+        assert!(errors.is_empty());
+        assert_eq!(names(&config), ["10.0.0.3:5000"]);
+    }
+
+    #[test]
+    fn remember() {
+        let mut config = five();
+        let preferred = StreamConfig { quality: 50, ..StreamConfig::default() };
+
+        // the same place under another name keeps the saved name and position
+        let again = SavedServer {
+            transport: Transport::Udp,
+            preferred: Some(preferred),
+            ..server("whatever", "10.0.0.3:5000", false)
+        };
+        assert_eq!(config.remember(again), 2);
+        assert_eq!(config.servers[2].name, "c");
+        assert_eq!(config.servers[2].transport, Transport::Udp);
+        assert_eq!(config.servers[2].preferred, Some(preferred));
+        assert_eq!(config.last_used_index(), Some(2));
+
+        // without a preference the old one stays
+        assert_eq!(config.remember(server("c", "10.0.0.3:5000", false)), 2);
+        assert_eq!(config.servers[2].preferred, Some(preferred));
+
+        // new ones go at the end
+        assert_eq!(config.remember(server("f", "10.0.0.9:5000", false)), 5);
+        assert_eq!(names(&config), ["a", "b", "c", "d", "e", "f"]);
+        assert_eq!(config.last_used.as_deref(), Some("10.0.0.9:5000"));
+        assert_eq!(config.last_used_index(), Some(5));
+        config.remove(5);
+        assert_eq!(config.last_used_index(), None);
+    }
+
+    #[test]
+    fn rename() {
+        let mut config = five();
+        config.rename(0, "  Living room ");
+        assert_eq!(config.servers[0].name, "Living room");
+        // nothing gives the address back
+        config.rename(0, "   ");
+        assert_eq!(config.servers[0].name, "10.0.0.1:5000");
+        config.rename(9, "nope");
+        assert_eq!(names(&config)[1..], ["b", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn move_within_groups() {
+        let mut config = five();
+        assert_eq!(config.move_up(3), 2);
+        assert_eq!(names(&config), ["a", "b", "d", "c", "e"]);
+        assert_eq!(config.move_down(2), 3);
+        assert_eq!(names(&config), ["a", "b", "c", "d", "e"]);
+
+        // not past the favourites, or out of the list
+        assert_eq!(config.move_up(2), 2);
+        assert_eq!(config.move_down(1), 1);
+        assert_eq!(config.move_up(0), 0);
+        assert_eq!(config.move_down(4), 4);
+        assert_eq!(config.move_up(9), 9);
+        assert_eq!(config.move_down(9), 9);
+        assert_eq!(names(&config), ["a", "b", "c", "d", "e"]);
+
+        assert_eq!(config.move_up(1), 0);
+        assert_eq!(names(&config), ["b", "a", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn favourite() {
+        let mut config = five();
+        // to the end of the favourites
+        assert_eq!(config.toggle_favourite(4), 2);
+        assert_eq!(names(&config), ["a", "b", "e", "c", "d"]);
+        assert!(config.servers[2].favourite);
+        // and to the top of the rest
+        assert_eq!(config.toggle_favourite(0), 2);
+        assert_eq!(names(&config), ["b", "e", "a", "c", "d"]);
+        assert!(!config.servers[2].favourite);
+        assert_eq!(config.toggle_favourite(9), 9);
+
+        // the order survives a save
+        let (parsed, _) = Config::parse(&config.to_string());
+        assert_eq!(names(&parsed), ["b", "e", "a", "c", "d"]);
+        // and a hand-edited file gets favourites first
+        let text = "[server]\nhost = x\n[server]\nhost = y\nfavourite = yes\n";
+        assert_eq!(names(&Config::parse(text).0), ["y:5000", "x:5000"]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut config = five();
+        assert_eq!(config.remove(1).unwrap().name, "b");
+        assert!(config.remove(4).is_none());
+        assert_eq!(names(&config), ["a", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn addresses() {
+        let parse = |a| SavedServer::from_address(a, Transport::Tcp).map(|s| (s.host, s.port));
+        assert_eq!(parse(" 10.0.0.2 "), Some(("10.0.0.2".to_owned(), DEFAULT_PORT)));
+        assert_eq!(parse("desk:6000"), Some(("desk".to_owned(), 6000)));
+        assert_eq!(parse("[::1]:7000"), Some(("::1".to_owned(), 7000)));
+        assert_eq!(parse("[::1]"), Some(("::1".to_owned(), DEFAULT_PORT)));
+        assert_eq!(parse("fe80::2"), Some(("fe80::2".to_owned(), DEFAULT_PORT)));
+        for bad in ["", ":5000", "desk:port", "[::1]x", "desk:70000"] {
+            assert_eq!(parse(bad), None, "{}", bad);
+        }
+        let v6 = SavedServer::from_address("[::1]:7000", Transport::Tcp).unwrap();
+        assert_eq!(v6.address(), "[::1]:7000");
+    }
+
+    #[test]
+    fn load_and_save() {
+        let dir = std::env::temp_dir()
+            .join(format!("ctr-camera-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("3ds/config.ini");
+        let (missing, errors) = Config::load(&path).unwrap();
+        assert_eq!((missing, errors.len()), (Config::default(), 0));
+
+        let config = five();
+        config.save(&path).unwrap();
+        // again, over the existing file
+        config.save(&path).unwrap();
+        assert_eq!(Config::load(&path).unwrap().0, config);
+        assert!(!path.with_extension("tmp").exists());
+
+        // can't be read at all, which isn't the same as empty
+        fs::write(&path, b"\xff\xfe[server]\n").unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Io(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
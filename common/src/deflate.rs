@@ -0,0 +1,263 @@
+//! zlib streams for [`png`](crate::png), compressed with the fixed Huffman codes of RFC 1951.
+//!
+//! Greedy LZ77 over the usual 32 KiB window, one block for everything and no code tables to build
+//! or send. Bigger than what zlib itself makes, but it's one pass over the picture and simple
+//! enough to trust on the console.
+
+const WINDOW: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// Earlier matches to try per position, more is smaller and slower.
+const MAX_CHAIN: usize = 8;
+const NONE: u32 = u32::MAX;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Deflate packs bits starting from the least significant one.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self { out, acc: 0, bits: 0 }
+    }
+
+    fn put(&mut self, value: u32, bits: u32) {
+        self.acc |= value << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Huffman codes are the exception and go most significant bit first.
+    fn code(&mut self, code: u32, bits: u32) {
+        self.put(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, len: usize, distance: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| base as usize <= len) - 1;
+        self.symbol(257 + i as u16);
+        self.put((len - LENGTH_BASE[i] as usize) as u32, LENGTH_EXTRA[i] as u32);
+
+        let i = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+        self.code(i as u32, 5);
+        self.put((distance - DISTANCE_BASE[i] as usize) as u32, DISTANCE_EXTRA[i] as u32);
+    }
+
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.acc = 0;
+        self.bits = 0;
+    }
+}
+
+/// Positions seen so far, by the three bytes starting there.
+struct Matcher {
+    // latest position for every hash
+    head: Vec<u32>,
+    // the position before it with the same hash, indexed by position in the window
+    prev: Vec<u32>,
+}
+
+impl Matcher {
+    fn new() -> Self {
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW],
+        }
+    }
+
+    fn hash(data: &[u8], at: usize) -> usize {
+        let key = u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0]);
+        (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], at: usize) {
+        if at + MIN_MATCH <= data.len() {
+            let hash = Self::hash(data, at);
+            self.prev[at % WINDOW] = self.head[hash];
+            self.head[hash] = at as u32;
+        }
+    }
+
+    /// Length and distance of the longest earlier copy of what's at `at`, a length under
+    /// [`MIN_MATCH`] if there's none worth it.
+    fn longest(&self, data: &[u8], at: usize) -> (usize, usize) {
+        if at + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max = (data.len() - at).min(MAX_MATCH);
+        let (mut best, mut distance) = (0, 0);
+
+        let mut candidate = self.head[Self::hash(data, at)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE || at - candidate as usize > WINDOW {
+                break;
+            }
+            let from = candidate as usize;
+            let len = data[from..from + max]
+                .iter()
+                .zip(&data[at..at + max])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best {
+                (best, distance) = (len, at - from);
+                if len == max {
+                    break;
+                }
+            }
+
+            let next = self.prev[from % WINDOW];
+            // anything not older is a slot that got reused
+            if next == NONE || next >= candidate {
+                break;
+            }
+            candidate = next;
+        }
+        (best, distance)
+    }
+}
+
+/// Compresses `data` into a complete zlib stream, appended to `out`.
+pub fn zlib(data: &[u8], out: &mut Vec<u8>) {
+    // deflate with a 32 KiB window, no dictionary, fastest compression
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut bits = BitWriter::new(out);
+    // the one and last block, fixed codes
+    bits.put(1, 1);
+    bits.put(1, 2);
+
+    let mut matcher = Matcher::new();
+    let mut at = 0;
+    while at < data.len() {
+        let (len, distance) = matcher.longest(data, at);
+        if len >= MIN_MATCH {
+            bits.copy(len, distance);
+            for i in at..at + len {
+                matcher.insert(data, i);
+            }
+            at += len;
+        } else {
+            bits.symbol(data[at] as u16);
+            matcher.insert(data, at);
+            at += 1;
+        }
+    }
+    bits.symbol(256);
+    bits.flush();
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+}
+
+/// The checksum at the end of a zlib stream.
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    // the most bytes that can be summed before `b` could overflow
+    const CHUNK: usize = 5552;
+
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(CHUNK) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inflate(zlib: &[u8]) -> Vec<u8> {
+        miniz_oxide::inflate::decompress_to_vec_zlib(zlib).unwrap()
+    }
+
+    /// xorshift, which doesn't compress at all.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 12345u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut far = noise(40_000);
+        // a repeat further back than the window reaches
+        far.extend_from_within(..1000);
+        let inputs = [
+            vec![],
+            b"a".to_vec(),
+            b"abcabcabcabcabcabcabcabcabc".to_vec(),
+            // runs longer than the longest match
+            vec![0; 100_000],
+            noise(70_000),
+            far,
+        ];
+        for input in inputs {
+            let mut out = Vec::new();
+            zlib(&input, &mut out);
+            assert_eq!(inflate(&out), input, "{} bytes", input.len());
+        }
+    }
+
+    #[test]
+    fn compresses() {
+        let mut out = Vec::new();
+        zlib(&[0; 100_000], &mut out);
+        assert!(out.len() < 1000, "{}", out.len());
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // long enough for the sums to need reducing along the way
+        let data = noise(100_000);
+        let mut out = Vec::new();
+        zlib(&data, &mut out);
+        assert_eq!(out[out.len() - 4..], adler32(&data).to_be_bytes());
+    }
+}
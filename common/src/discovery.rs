@@ -0,0 +1,460 @@
+//! Finding receivers on the LAN, so nobody has to type an IP address on the software keyboard.
+//!
+//! The console broadcasts a probe to [`PORT`], every receiver listening there answers straight to
+//! the sender with an announcement saying where to connect. Both are single datagrams, integers
+//! little endian:
+//!
+//! Probe: magic `CTRD`, version `u8`, kind `u8` (0), nonce `u32`.
+//!
+//! Announcement: magic `CTRD`, version `u8`, kind `u8` (1), nonce `u32` copied from the probe,
+//! stream port `u16`, supported transports `u8` (bit 0 TCP, bit 1 UDP), name (`u8` length + UTF-8).
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::handshake::Transport;
+use crate::protocol::VERSION;
+
+pub const MAGIC: [u8; 4] = *b"CTRD";
+
+/// Port receivers listen for probes on.
+pub const PORT: u16 = 5001;
+
+const KIND_PROBE: u8 = 0;
+const KIND_ANNOUNCEMENT: u8 = 1;
+
+const PROBE_LEN: usize = 10;
+
+/// Fixed part of an announcement, before the name.
+const ANNOUNCEMENT_LEN: usize = 14;
+
+const MAX_NAME_LEN: usize = u8::MAX as usize;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DiscoveryError {
+    #[error("Datagram too short")]
+    TooShort,
+    #[error("Bad magic {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown message kind {0}")]
+    UnknownKind(u8),
+    #[error("Name is not valid UTF-8")]
+    BadName,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    pub nonce: u32,
+}
+
+impl Probe {
+    pub fn to_bytes(&self) -> [u8; PROBE_LEN] {
+        let mut b = [0u8; PROBE_LEN];
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4] = VERSION;
+        b[5] = KIND_PROBE;
+        b[6..10].copy_from_slice(&self.nonce.to_le_bytes());
+        b
+    }
+
+    pub fn parse(datagram: &[u8]) -> Result<Self, DiscoveryError> {
+        check_preamble(datagram, KIND_PROBE, PROBE_LEN)?;
+        Ok(Probe {
+            nonce: read_u32(&datagram[6..10]),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announcement {
+    pub nonce: u32,
+    /// Port the receiver accepts console connections on.
+    pub port: u16,
+    pub tcp: bool,
+    pub udp: bool,
+    /// Shown in the console's list, truncated to 255 bytes.
+    pub name: String,
+}
+
+impl Announcement {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let name = truncate(&self.name, MAX_NAME_LEN);
+
+        let mut b = Vec::with_capacity(ANNOUNCEMENT_LEN + name.len());
+        b.extend_from_slice(&MAGIC);
+        b.push(VERSION);
+        b.push(KIND_ANNOUNCEMENT);
+        b.extend_from_slice(&self.nonce.to_le_bytes());
+        b.extend_from_slice(&self.port.to_le_bytes());
+        b.push(self.tcp as u8 | (self.udp as u8) << 1);
+        b.push(name.len() as u8);
+        b.extend_from_slice(name.as_bytes());
+        b
+    }
+
+    pub fn parse(datagram: &[u8]) -> Result<Self, DiscoveryError> {
+        check_preamble(datagram, KIND_ANNOUNCEMENT, ANNOUNCEMENT_LEN)?;
+
+        let transports = datagram[12];
+        let name_len = datagram[13] as usize;
+        let name = datagram
+            .get(ANNOUNCEMENT_LEN..ANNOUNCEMENT_LEN + name_len)
+            .ok_or(DiscoveryError::TooShort)?;
+
+        Ok(Announcement {
+            nonce: read_u32(&datagram[6..10]),
+            port: u16::from_le_bytes([datagram[10], datagram[11]]),
+            tcp: transports & 1 != 0,
+            udp: transports & 2 != 0,
+            name: String::from_utf8(name.to_vec()).map_err(|_| DiscoveryError::BadName)?,
+        })
+    }
+}
+
+fn check_preamble(datagram: &[u8], kind: u8, min_len: usize) -> Result<(), DiscoveryError> {
+    if datagram.len() < 6 {
+        return Err(DiscoveryError::TooShort);
+    }
+
+    let magic = [datagram[0], datagram[1], datagram[2], datagram[3]];
+    if magic != MAGIC {
+        return Err(DiscoveryError::BadMagic(magic));
+    }
+    if datagram[4] != VERSION {
+        return Err(DiscoveryError::UnsupportedVersion(datagram[4]));
+    }
+    if datagram[5] != kind {
+        return Err(DiscoveryError::UnknownKind(datagram[5]));
+    }
+    if datagram.len() < min_len {
+        return Err(DiscoveryError::TooShort);
+    }
+    Ok(())
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Longest prefix of `s` that fits in `max` bytes without splitting a character.
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Receiver side, answers probes with an announcement.
+#[derive(Debug)]
+pub struct Responder {
+    socket: UdpSocket,
+    announcement: Announcement,
+}
+
+impl Responder {
+    /// The nonce in `announcement` is ignored, every answer copies the one from its probe.
+    pub fn bind(addr: SocketAddr, announcement: Announcement) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(Self {
+            socket,
+            announcement,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Waits for one datagram (subject to the socket's read timeout) and answers it if it's a
+    /// probe. Returns who got an answer.
+    pub fn respond_once(&mut self) -> io::Result<Option<SocketAddr>> {
+        let mut buf = [0u8; 64];
+        let (len, from) = self.socket.recv_from(&mut buf)?;
+
+        match Probe::parse(&buf[..len]) {
+            Ok(probe) => {
+                self.announcement.nonce = probe.nonce;
+                self.socket.send_to(&self.announcement.to_bytes(), from)?;
+                Ok(Some(from))
+            }
+            // someone else's traffic on our port
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Answers probes forever, meant for a thread of its own.
+    pub fn run(mut self) -> io::Result<()> {
+        loop {
+            match self.respond_once() {
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                            // ICMP port unreachable from a console that already gave up
+                            | io::ErrorKind::ConnectionReset
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A receiver that answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Server {
+    /// Where to connect, the address the answer came from with the announced port.
+    pub addr: SocketAddr,
+    pub name: String,
+    pub tcp: bool,
+    pub udp: bool,
+}
+
+impl Server {
+    /// TCP unless the receiver only does UDP.
+    pub fn transport(&self) -> Transport {
+        if self.udp && !self.tcp {
+            Transport::Udp
+        } else {
+            Transport::Tcp
+        }
+    }
+}
+
+/// Console side, sends probes and collects the answers without blocking.
+#[derive(Debug)]
+pub struct Browser {
+    socket: UdpSocket,
+    nonce: u32,
+    servers: Vec<Server>,
+}
+
+impl Browser {
+    pub fn new() -> io::Result<Self> {
+        Self::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+
+    /// Sends probes from `addr` instead of any address, e.g. loopback to find a receiver on the
+    /// same machine.
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        // only has to differ between runs so stale answers get ignored
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+            .unwrap_or(0);
+
+        Ok(Self {
+            socket,
+            nonce,
+            servers: Vec::new(),
+        })
+    }
+
+    /// Forgets what was found so far and probes `dest` again.
+    pub fn probe(&mut self, dest: SocketAddr) -> io::Result<()> {
+        self.nonce = self.nonce.wrapping_add(1);
+        self.servers.clear();
+        self.socket
+            .send_to(&Probe { nonce: self.nonce }.to_bytes(), dest)?;
+        Ok(())
+    }
+
+    /// Probes every receiver on the local network.
+    pub fn probe_broadcast(&mut self) -> io::Result<()> {
+        self.probe(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, PORT)))
+    }
+
+    /// Reads pending answers, returns whether the list changed.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let announcement = match Announcement::parse(&buf[..len]) {
+                Ok(a) if a.nonce == self.nonce => a,
+                _ => continue,
+            };
+
+            let server = Server {
+                addr: SocketAddr::new(from.ip(), announcement.port),
+                name: announcement.name,
+                tcp: announcement.tcp,
+                udp: announcement.udp,
+            };
+            match self.servers.iter_mut().find(|s| s.addr == server.addr) {
+                Some(known) if *known == server => {}
+                Some(known) => {
+                    *known = server;
+                    changed = true;
+                }
+                None => {
+                    self.servers.push(server);
+                    changed = true;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Everyone who answered the last probe, in order of arrival.
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    const LOOPBACK: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+    fn announcement(port: u16, name: &str) -> Announcement {
+        Announcement {
+            nonce: 0,
+            port,
+            tcp: true,
+            udp: true,
+            name: name.to_owned(),
+        }
+    }
+
+    /// Answers probes on loopback in the background.
+    fn responder(port: u16, name: &str) -> SocketAddr {
+        let responder = Responder::bind(LOOPBACK, announcement(port, name)).unwrap();
+        responder.socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let addr = responder.local_addr().unwrap();
+        thread::spawn(move || responder.run());
+        addr
+    }
+
+    /// Polls `browser` until it knows `count` servers.
+    fn wait_for(browser: &mut Browser, count: usize) {
+        let start = Instant::now();
+        while browser.servers().len() < count {
+            assert!(start.elapsed() < Duration::from_secs(5), "only found {:?}", browser.servers());
+            browser.poll().unwrap();
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn finds_receivers_over_loopback() {
+        let first = responder(5000, "desk");
+        let second = responder(6000, "attic");
+
+        let mut browser = Browser::bind(LOOPBACK).unwrap();
+        browser.probe(first).unwrap();
+        browser.probe(second).unwrap();
+        // probing again forgot the first one
+        wait_for(&mut browser, 1);
+        assert_eq!(browser.servers()[0].name, "attic");
+
+        browser.probe(first).unwrap();
+        wait_for(&mut browser, 1);
+        let server = &browser.servers()[0];
+        assert_eq!(server.addr, SocketAddr::from((Ipv4Addr::LOCALHOST, 5000)));
+        assert_eq!(server.name, "desk");
+        assert!(server.tcp && server.udp);
+        assert_eq!(server.transport(), Transport::Tcp);
+        // nothing new since
+        assert!(!browser.poll().unwrap());
+    }
+
+    #[test]
+    fn ignores_stale_and_foreign_answers() {
+        let mut browser = Browser::bind(LOOPBACK).unwrap();
+        let browser_addr = browser.socket.local_addr().unwrap();
+        let fake = UdpSocket::bind(LOOPBACK).unwrap();
+        browser.probe(fake.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0u8; 64];
+        let (len, _) = fake.recv_from(&mut buf).unwrap();
+        let probe = Probe::parse(&buf[..len]).unwrap();
+
+        // an answer to an older probe, and something that isn't an answer at all
+        let stale = Announcement {
+            nonce: probe.nonce.wrapping_sub(1),
+            ..announcement(5000, "old")
+        };
+        fake.send_to(&stale.to_bytes(), browser_addr).unwrap();
+        fake.send_to(b"hello", browser_addr).unwrap();
+        let current = Announcement {
+            nonce: probe.nonce,
+            tcp: false,
+            ..announcement(5000, "new")
+        };
+        fake.send_to(&current.to_bytes(), browser_addr).unwrap();
+        // the same answer twice is one server
+        fake.send_to(&current.to_bytes(), browser_addr).unwrap();
+
+        wait_for(&mut browser, 1);
+        thread::sleep(Duration::from_millis(20));
+        browser.poll().unwrap();
+        assert_eq!(browser.servers().len(), 1);
+        assert_eq!(browser.servers()[0].name, "new");
+        assert_eq!(browser.servers()[0].transport(), Transport::Udp);
+    }
+
+    #[test]
+    fn messages() {
+        let probe = Probe { nonce: 0xdead_beef };
+        assert_eq!(Probe::parse(&probe.to_bytes()), Ok(probe));
+
+        let long = "é".repeat(200);
+        let announcement = Announcement {
+            nonce: 7,
+            port: 5555,
+            tcp: false,
+            udp: true,
+            name: long.clone(),
+        };
+        let parsed = Announcement::parse(&announcement.to_bytes()).unwrap();
+        // cut at 255 bytes without splitting a character
+        assert_eq!(parsed.name, long[..254]);
+        assert_eq!((parsed.nonce, parsed.port, parsed.tcp, parsed.udp), (7, 5555, false, true));
+
+        // `receiver --name ""`
+        let unnamed = Announcement {
+            name: String::new(),
+            ..announcement.clone()
+        };
+        let bytes = unnamed.to_bytes();
+        assert_eq!(bytes.len(), ANNOUNCEMENT_LEN);
+        assert_eq!(Announcement::parse(&bytes), Ok(unnamed));
+        assert_eq!(Announcement::parse(&bytes[..13]), Err(DiscoveryError::TooShort));
+
+        let bytes = announcement.to_bytes();
+        assert_eq!(Announcement::parse(&bytes[..20]), Err(DiscoveryError::TooShort));
+        assert_eq!(Probe::parse(&bytes), Err(DiscoveryError::UnknownKind(KIND_ANNOUNCEMENT)));
+        let mut bad = probe.to_bytes();
+        bad[4] = VERSION + 1;
+        assert_eq!(Probe::parse(&bad), Err(DiscoveryError::UnsupportedVersion(VERSION + 1)));
+        bad[0] = 0;
+        assert_eq!(Probe::parse(&bad), Err(DiscoveryError::BadMagic([0, b'T', b'R', b'D'])));
+        assert_eq!(Probe::parse(&bad[..3]), Err(DiscoveryError::TooShort));
+    }
+}
@@ -0,0 +1,375 @@
+//! Exif metadata for stills: when, on what and with which camera settings.
+//!
+//! [`tiff`] builds the little-endian TIFF structure: the main IFD with the make, model, software,
+//! date and a description listing every setting, then an Exif IFD with the date again, the size
+//! and whatever of the settings Exif has tags for. [`app1`] wraps it up for a JPEG, PNG takes it
+//! as it is.
+
+use std::fmt;
+
+use crate::settings::{CameraSettings, Contrast, Setting};
+
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010e;
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_X_RESOLUTION: u16 = 0x011a;
+const TAG_Y_RESOLUTION: u16 = 0x011b;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_YCBCR_POSITIONING: u16 = 0x0213;
+const TAG_EXIF_IFD: u16 = 0x8769;
+
+const TAG_EXIF_VERSION: u16 = 0x9000;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+const TAG_COMPONENTS_CONFIGURATION: u16 = 0x9101;
+const TAG_EXPOSURE_BIAS: u16 = 0x9204;
+const TAG_FLASHPIX_VERSION: u16 = 0xa000;
+const TAG_COLOR_SPACE: u16 = 0xa001;
+const TAG_PIXEL_X_DIMENSION: u16 = 0xa002;
+const TAG_PIXEL_Y_DIMENSION: u16 = 0xa003;
+const TAG_EXPOSURE_MODE: u16 = 0xa402;
+const TAG_WHITE_BALANCE: u16 = 0xa403;
+const TAG_CONTRAST: u16 = 0xa408;
+const TAG_SHARPNESS: u16 = 0xa40a;
+
+pub const MAKE: &str = "Nintendo";
+pub const SOFTWARE: &str = "ctr-camera-rs";
+
+/// A calendar date and time, as the console's clock shows it (it has no idea of time zones).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// `seconds` after 1970-01-01 00:00:00.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86_400) as i64;
+        let time = seconds % 86_400;
+
+        // Howard Hinnant's days_from_civil, backwards
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+
+        Self {
+            year: year.clamp(0, 9999) as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+/// `2024:05:17 14:03:59`, the one format Exif accepts.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// What gets written about a still, besides its size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub time: DateTime,
+    /// The console model.
+    pub model: String,
+    pub settings: CameraSettings,
+}
+
+impl Metadata {
+    /// Every camera setting on one line, `camera outer-right, auto exposure on, ...`.
+    pub fn description(&self) -> String {
+        let settings: Vec<String> = Setting::ALL
+            .into_iter()
+            .filter(|&s| s != Setting::Preview)
+            .map(|s| format!("{} {}", s.label().to_lowercase(), self.settings.value(s)))
+            .collect();
+        settings.join(", ")
+    }
+}
+
+enum Value {
+    Ascii(String),
+    Short(u16),
+    Long(u32),
+    Rational(u32, u32),
+    SignedRational(i32, i32),
+    Undefined(Vec<u8>),
+}
+
+impl Value {
+    /// The field type and the value count.
+    fn kind(&self) -> (u16, u32) {
+        match self {
+            Value::Ascii(s) => (2, s.len() as u32 + 1),
+            Value::Short(_) => (3, 1),
+            Value::Long(_) => (4, 1),
+            Value::Rational(..) => (5, 1),
+            Value::SignedRational(..) => (10, 1),
+            Value::Undefined(bytes) => (7, bytes.len() as u32),
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Value::Ascii(s) => {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.push(0);
+                bytes
+            }
+            Value::Short(v) => v.to_le_bytes().to_vec(),
+            Value::Long(v) => v.to_le_bytes().to_vec(),
+            Value::Rational(n, d) => [n.to_le_bytes(), d.to_le_bytes()].concat(),
+            Value::SignedRational(n, d) => [n.to_le_bytes(), d.to_le_bytes()].concat(),
+            Value::Undefined(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// Length of an IFD holding `entries`, with everything that doesn't fit in an entry.
+fn ifd_len(entries: &[(u16, Value)]) -> usize {
+    let data: usize = entries
+        .iter()
+        .map(|(_, v)| v.bytes().len())
+        .filter(|&len| len > 4)
+        .map(|len| len.next_multiple_of(2))
+        .sum();
+    2 + entries.len() * 12 + 4 + data
+}
+
+/// Appends an IFD whose first byte ends up `at` bytes into the TIFF structure, `entries` sorted
+/// by tag.
+fn write_ifd(out: &mut Vec<u8>, at: usize, entries: &[(u16, Value)], next: u32) {
+    let mut data_at = at + 2 + entries.len() * 12 + 4;
+    let mut data = Vec::new();
+
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, value) in entries {
+        let (kind, count) = value.kind();
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+
+        let mut bytes = value.bytes();
+        if bytes.len() <= 4 {
+            // small values sit in the entry itself, left aligned
+            bytes.resize(4, 0);
+            out.extend_from_slice(&bytes);
+        } else {
+            out.extend_from_slice(&(data_at as u32).to_le_bytes());
+            if bytes.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            data_at += bytes.len();
+            data.extend_from_slice(&bytes);
+        }
+    }
+    out.extend_from_slice(&next.to_le_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// The TIFF structure describing a `width` x `height` still taken with `metadata`.
+pub fn tiff(metadata: &Metadata, width: u16, height: u16) -> Vec<u8> {
+    let settings = &metadata.settings;
+    let time = metadata.time.to_string();
+
+    let mut exif = vec![
+        (TAG_EXIF_VERSION, Value::Undefined(b"0232".to_vec())),
+        (TAG_DATE_TIME_ORIGINAL, Value::Ascii(time.clone())),
+        (TAG_DATE_TIME_DIGITIZED, Value::Ascii(time.clone())),
+        (TAG_COMPONENTS_CONFIGURATION, Value::Undefined(vec![1, 2, 3, 0])),
+    ];
+    if !settings.auto_exposure {
+        exif.push((TAG_EXPOSURE_BIAS, Value::SignedRational(settings.exposure as i32, 1)));
+    }
+    exif.extend([
+        (TAG_FLASHPIX_VERSION, Value::Undefined(b"0100".to_vec())),
+        // sRGB
+        (TAG_COLOR_SPACE, Value::Short(1)),
+        (TAG_PIXEL_X_DIMENSION, Value::Long(width as u32)),
+        (TAG_PIXEL_Y_DIMENSION, Value::Long(height as u32)),
+        (TAG_EXPOSURE_MODE, Value::Short(!settings.auto_exposure as u16)),
+        (TAG_WHITE_BALANCE, Value::Short(!settings.auto_white_balance as u16)),
+        (
+            TAG_CONTRAST,
+            Value::Short(match settings.contrast {
+                Contrast::Normal => 0,
+                Contrast::Low => 1,
+                Contrast::High => 2,
+            }),
+        ),
+        (
+            TAG_SHARPNESS,
+            Value::Short(match settings.sharpness {
+                0 => 0,
+                s if s < 0 => 1,
+                _ => 2,
+            }),
+        ),
+    ]);
+
+    let mut main = vec![
+        (TAG_IMAGE_DESCRIPTION, Value::Ascii(metadata.description())),
+        (TAG_MAKE, Value::Ascii(MAKE.to_owned())),
+        (TAG_MODEL, Value::Ascii(metadata.model.clone())),
+        // the camera is never upside down as far as we know
+        (TAG_ORIENTATION, Value::Short(1)),
+        (TAG_X_RESOLUTION, Value::Rational(72, 1)),
+        (TAG_Y_RESOLUTION, Value::Rational(72, 1)),
+        // inches
+        (TAG_RESOLUTION_UNIT, Value::Short(2)),
+        (TAG_SOFTWARE, Value::Ascii(SOFTWARE.to_owned())),
+        (TAG_DATE_TIME, Value::Ascii(time)),
+        // chroma samples centred, which is what 2x1 subsampling does
+        (TAG_YCBCR_POSITIONING, Value::Short(1)),
+        (TAG_EXIF_IFD, Value::Long(0)),
+    ];
+    let exif_at = 8 + ifd_len(&main);
+    if let Some((_, pointer)) = main.last_mut() {
+        *pointer = Value::Long(exif_at as u32);
+    }
+
+    let mut out = Vec::with_capacity(exif_at + ifd_len(&exif));
+    out.extend_from_slice(b"II");
+    out.extend_from_slice(&42u16.to_le_bytes());
+    out.extend_from_slice(&8u32.to_le_bytes());
+    write_ifd(&mut out, 8, &main, 0);
+    write_ifd(&mut out, exif_at, &exif, 0);
+    out
+}
+
+/// `tiff` as a JPEG APP1 segment, marker included.
+pub fn app1(tiff: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(10 + tiff.len());
+    segment.extend_from_slice(&[0xff, 0xe1]);
+    segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(tiff);
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use kamadak_exif::{In, Reader, Tag};
+
+    use super::*;
+    use crate::handshake::CameraId;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            time: DateTime::from_unix(1_715_954_639),
+            model: "New3DSXL".to_owned(),
+            settings: CameraSettings {
+                camera: CameraId::OuterRight,
+                auto_exposure: false,
+                exposure: -2,
+                contrast: Contrast::High,
+                sharpness: -1,
+                ..CameraSettings::default()
+            },
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(DateTime::from_unix(0).to_string(), "1970:01:01 00:00:00");
+        assert_eq!(DateTime::from_unix(1_715_954_639).to_string(), "2024:05:17 14:03:59");
+        // 2000 is a leap year, 2100 isn't
+        assert_eq!(DateTime::from_unix(951_782_400).to_string(), "2000:02:29 00:00:00");
+        assert_eq!(DateTime::from_unix(4_107_542_399).to_string(), "2100:02:28 23:59:59");
+        assert_eq!(DateTime::from_unix(4_107_542_400).to_string(), "2100:03:01 00:00:00");
+    }
+
+    #[test]
+    fn reads_back() {
+        let tiff = tiff(&metadata(), 640, 480);
+        let exif = Reader::new().read_raw(tiff).unwrap();
+        let get = |tag| {
+            let field = exif.get_field(tag, In::PRIMARY).unwrap_or_else(|| panic!("{}", tag));
+            field.display_value().to_string()
+        };
+        assert_eq!(get(Tag::Make), "\"Nintendo\"");
+        assert_eq!(get(Tag::Model), "\"New3DSXL\"");
+        assert_eq!(get(Tag::Software), "\"ctr-camera-rs\"");
+        assert_eq!(get(Tag::Orientation), "row 0 at top and column 0 at left");
+        assert_eq!(get(Tag::XResolution), "72");
+        assert_eq!(get(Tag::DateTime), "2024-05-17 14:03:59");
+        // from the Exif IFD
+        assert_eq!(get(Tag::ExifVersion), "2.32");
+        assert_eq!(get(Tag::DateTimeOriginal), "2024-05-17 14:03:59");
+        assert_eq!(get(Tag::DateTimeDigitized), "2024-05-17 14:03:59");
+        assert_eq!(get(Tag::PixelXDimension), "640");
+        assert_eq!(get(Tag::PixelYDimension), "480");
+        assert_eq!(get(Tag::ColorSpace), "sRGB");
+        assert_eq!(get(Tag::ExposureBiasValue), "-2");
+        assert_eq!(get(Tag::ExposureMode), "manual exposure");
+        assert_eq!(get(Tag::WhiteBalance), "auto white balance");
+        assert_eq!(get(Tag::Contrast), "hard");
+        assert_eq!(get(Tag::Sharpness), "soft");
+
+        let description = get(Tag::ImageDescription);
+        assert!(description.contains("camera outer-right"), "{}", description);
+        assert!(description.contains("exposure -2"), "{}", description);
+        assert!(!description.contains("preview"), "{}", description);
+    }
+
+    #[test]
+    fn automatic() {
+        let metadata = Metadata {
+            settings: CameraSettings::default(),
+            ..metadata()
+        };
+        let exif = Reader::new().read_raw(tiff(&metadata, 320, 240)).unwrap();
+        let get = |tag| exif.get_field(tag, In::PRIMARY).map(|f| f.display_value().to_string());
+        // no bias without a fixed exposure
+        assert_eq!(get(Tag::ExposureBiasValue), None);
+        assert_eq!(get(Tag::ExposureMode).as_deref(), Some("auto exposure"));
+        assert_eq!(get(Tag::Contrast).as_deref(), Some("normal"));
+        assert_eq!(get(Tag::Sharpness).as_deref(), Some("normal"));
+    }
+
+    #[test]
+    fn odd_length_strings() {
+        // every value outside its entry has to start on an even offset
+        for model in ["3DS", "3DSXL", "New3DS", "2DS"] {
+            let metadata = Metadata {
+                model: model.to_owned(),
+                ..metadata()
+            };
+            let exif = Reader::new().read_raw(tiff(&metadata, 1, 1)).unwrap();
+            let field = exif.get_field(Tag::Model, In::PRIMARY).unwrap();
+            assert_eq!(field.display_value().to_string(), format!("\"{}\"", model));
+        }
+    }
+
+    #[test]
+    fn jpeg_segment() {
+        let tiff = tiff(&metadata(), 640, 480);
+        let segment = app1(&tiff);
+        assert_eq!(segment[..2], [0xff, 0xe1]);
+        assert_eq!(u16::from_be_bytes([segment[2], segment[3]]) as usize, segment.len() - 2);
+        assert_eq!(&segment[4..10], b"Exif\0\0");
+        assert_eq!(segment[10..], tiff);
+    }
+}
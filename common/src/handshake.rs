@@ -0,0 +1,604 @@
+//! Connection handshake, exchanged once right after the TCP connection is made.
+//!
+//! The console sends a [`Hello`] listing everything it can capture, the server answers with the
+//! [`StreamConfig`] it wants, and from then on the connection carries frames as described in
+//! [`protocol`](crate::protocol). Integers are little endian, lists are prefixed with a `u8` count.
+//!
+//! `Hello`: magic `CTRH`, version `u8`, model (`u8` length + UTF-8), formats (`u8` each),
+//! frame rates (`u8` each), resolutions (`u16` width + `u16` height each), cameras (`u8` each),
+//! transport the frames will be sent over (`u8`, see [`Transport`]), stereo layouts (`u8` each,
+//! see [`StereoLayout`]).
+//!
+//! `StreamConfig`: magic `CTRS`, version `u8`, format `u8`, frame rate `u8`, width `u16`,
+//! height `u16`, camera `u8`, JPEG quality `u8` (1-100, ignored for raw formats), stereo layout
+//! `u8` (always mono unless the camera is [`CameraId::BothOuter`]). The resolution is per eye.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::jpeg::DEFAULT_QUALITY;
+use crate::protocol::{PixelFormat, ProtocolError, VERSION};
+use crate::stereo::StereoLayout;
+
+pub const HELLO_MAGIC: [u8; 4] = *b"CTRH";
+pub const CONFIG_MAGIC: [u8; 4] = *b"CTRS";
+
+/// Camera frame rates, in the same order as `CAMU_FrameRate`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameRate {
+    Fps15 = 0,
+    Fps15To5,
+    Fps15To2,
+    Fps10,
+    Fps8_5,
+    Fps5,
+    Fps20,
+    Fps20To5,
+    Fps30,
+    Fps30To5,
+    Fps15To10,
+    Fps20To10,
+    Fps30To10,
+}
+
+impl FrameRate {
+    pub const ALL: [FrameRate; 13] = [
+        FrameRate::Fps15,
+        FrameRate::Fps15To5,
+        FrameRate::Fps15To2,
+        FrameRate::Fps10,
+        FrameRate::Fps8_5,
+        FrameRate::Fps5,
+        FrameRate::Fps20,
+        FrameRate::Fps20To5,
+        FrameRate::Fps30,
+        FrameRate::Fps30To5,
+        FrameRate::Fps15To10,
+        FrameRate::Fps20To10,
+        FrameRate::Fps30To10,
+    ];
+
+    /// Upper end of the rate, variable rates drop below it in low light.
+    pub fn max_fps(self) -> u32 {
+        match self {
+            FrameRate::Fps15 | FrameRate::Fps15To5 | FrameRate::Fps15To2 | FrameRate::Fps15To10 => {
+                15
+            }
+            FrameRate::Fps10 => 10,
+            FrameRate::Fps8_5 => 8,
+            FrameRate::Fps5 => 5,
+            FrameRate::Fps20 | FrameRate::Fps20To5 | FrameRate::Fps20To10 => 20,
+            FrameRate::Fps30 | FrameRate::Fps30To5 | FrameRate::Fps30To10 => 30,
+        }
+    }
+
+    /// Longest the camera can take for one frame, at the bottom end of variable rates.
+    pub fn slowest_frame(self) -> Duration {
+        let millis = match self {
+            FrameRate::Fps15To2 => 500,
+            FrameRate::Fps15To5 | FrameRate::Fps20To5 | FrameRate::Fps30To5 | FrameRate::Fps5 => 200,
+            // 8.5 fps
+            FrameRate::Fps8_5 => 118,
+            FrameRate::Fps10 | FrameRate::Fps15To10 | FrameRate::Fps20To10 | FrameRate::Fps30To10 => 100,
+            FrameRate::Fps15 => 67,
+            FrameRate::Fps20 => 50,
+            FrameRate::Fps30 => 34,
+        };
+        Duration::from_millis(millis)
+    }
+}
+
+impl TryFrom<u8> for FrameRate {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        FrameRate::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or(ProtocolError::UnknownFrameRate(value))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CameraId {
+    OuterRight = 0,
+    Inner = 1,
+    OuterLeft = 2,
+    BothOuter = 3,
+}
+
+impl CameraId {
+    pub const ALL: [CameraId; 4] = [
+        CameraId::OuterRight,
+        CameraId::Inner,
+        CameraId::OuterLeft,
+        CameraId::BothOuter,
+    ];
+}
+
+impl TryFrom<u8> for CameraId {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(CameraId::OuterRight),
+            1 => Ok(CameraId::Inner),
+            2 => Ok(CameraId::OuterLeft),
+            3 => Ok(CameraId::BothOuter),
+            other => Err(ProtocolError::UnknownCamera(other)),
+        }
+    }
+}
+
+/// How frames travel after the handshake. The handshake itself always happens over TCP, and with
+/// UDP the TCP connection stays open so either side can tell when the other one goes away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Transport {
+    #[default]
+    Tcp = 0,
+    /// Datagrams to the same port as the TCP connection, see [`udp`](crate::udp).
+    Udp = 1,
+}
+
+impl TryFrom<u8> for Transport {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Transport::Tcp),
+            1 => Ok(Transport::Udp),
+            other => Err(ProtocolError::UnknownTransport(other)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Resolution {
+    pub const fn new(width: u16, height: u16) -> Self {
+        Self { width, height }
+    }
+}
+
+/// What the console can do, sent by the console.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub model: String,
+    pub formats: Vec<PixelFormat>,
+    pub frame_rates: Vec<FrameRate>,
+    pub resolutions: Vec<Resolution>,
+    pub cameras: Vec<CameraId>,
+    pub transport: Transport,
+    /// How both outer cameras can be packed together, empty if they can't.
+    pub stereo_layouts: Vec<StereoLayout>,
+}
+
+/// What the server picked, sent back by the server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamConfig {
+    pub format: PixelFormat,
+    pub frame_rate: FrameRate,
+    pub resolution: Resolution,
+    pub camera: CameraId,
+    pub quality: u8,
+    pub stereo: StereoLayout,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            format: PixelFormat::Jpeg,
+            frame_rate: FrameRate::Fps30,
+            resolution: Resolution::new(640, 480),
+            camera: CameraId::BothOuter,
+            quality: DEFAULT_QUALITY,
+            stereo: StereoLayout::Mono,
+        }
+    }
+}
+
+impl Hello {
+    /// Moves what's in `config` to the front of each list. Servers without a preference of their
+    /// own take the first entry, see [`StreamConfig::choose`].
+    pub fn prefer(&mut self, config: &StreamConfig) {
+        fn front<T: PartialEq>(items: &mut [T], preferred: &T) {
+            if let Some(i) = items.iter().position(|item| item == preferred) {
+                items[..=i].rotate_right(1);
+            }
+        }
+
+        front(&mut self.formats, &config.format);
+        front(&mut self.frame_rates, &config.frame_rate);
+        front(&mut self.resolutions, &config.resolution);
+        front(&mut self.cameras, &config.camera);
+        front(&mut self.stereo_layouts, &config.stereo);
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), ProtocolError> {
+        let mut b = Vec::with_capacity(64);
+        b.extend_from_slice(&HELLO_MAGIC);
+        b.push(VERSION);
+
+        let model = &self.model.as_bytes()[..self.model.len().min(u8::MAX as usize)];
+        b.push(model.len() as u8);
+        b.extend_from_slice(model);
+
+        push_list(&mut b, &self.formats, |b, f| b.push(*f as u8));
+        push_list(&mut b, &self.frame_rates, |b, r| b.push(*r as u8));
+        push_list(&mut b, &self.resolutions, |b, r| {
+            b.extend_from_slice(&r.width.to_le_bytes());
+            b.extend_from_slice(&r.height.to_le_bytes());
+        });
+        push_list(&mut b, &self.cameras, |b, c| b.push(*c as u8));
+        b.push(self.transport as u8);
+        push_list(&mut b, &self.stereo_layouts, |b, l| b.push(*l as u8));
+
+        out.write_all(&b)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self, ProtocolError> {
+        read_preamble(input, HELLO_MAGIC)?;
+
+        let len = read_u8(input)?;
+        let mut model = vec![0u8; len as usize];
+        read_exact(input, &mut model)?;
+
+        Ok(Hello {
+            model: String::from_utf8_lossy(&model).into_owned(),
+            formats: read_list(input, |r| PixelFormat::try_from(read_u8(r)?))?,
+            frame_rates: read_list(input, |r| FrameRate::try_from(read_u8(r)?))?,
+            resolutions: read_list(input, |r| {
+                Ok(Resolution::new(read_u16(r)?, read_u16(r)?))
+            })?,
+            cameras: read_list(input, |r| CameraId::try_from(read_u8(r)?))?,
+            transport: Transport::try_from(read_u8(input)?)?,
+            stereo_layouts: read_list(input, |r| StereoLayout::try_from(read_u8(r)?))?,
+        })
+    }
+
+    /// Whether every part of `config` was advertised.
+    pub fn supports(&self, config: &StreamConfig) -> bool {
+        self.formats.contains(&config.format)
+            && self.frame_rates.contains(&config.frame_rate)
+            && self.resolutions.contains(&config.resolution)
+            && self.cameras.contains(&config.camera)
+            && (config.stereo == StereoLayout::Mono
+                || (config.camera == CameraId::BothOuter
+                    && self.stereo_layouts.contains(&config.stereo)))
+    }
+}
+
+impl StreamConfig {
+    pub fn write_to<W: Write>(&self, out: &mut W) -> Result<(), ProtocolError> {
+        let mut b = [0u8; 14];
+        b[0..4].copy_from_slice(&CONFIG_MAGIC);
+        b[4] = VERSION;
+        b[5] = self.format as u8;
+        b[6] = self.frame_rate as u8;
+        b[7..9].copy_from_slice(&self.resolution.width.to_le_bytes());
+        b[9..11].copy_from_slice(&self.resolution.height.to_le_bytes());
+        b[11] = self.camera as u8;
+        b[12] = self.quality;
+        b[13] = self.stereo as u8;
+
+        out.write_all(&b)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(input: &mut R) -> Result<Self, ProtocolError> {
+        read_preamble(input, CONFIG_MAGIC)?;
+
+        Ok(StreamConfig {
+            format: PixelFormat::try_from(read_u8(input)?)?,
+            frame_rate: FrameRate::try_from(read_u8(input)?)?,
+            resolution: Resolution::new(read_u16(input)?, read_u16(input)?),
+            camera: CameraId::try_from(read_u8(input)?)?,
+            quality: read_u8(input)?.clamp(1, 100),
+            stereo: StereoLayout::try_from(read_u8(input)?)?,
+        })
+    }
+
+    /// Server side policy: take `preferred` where the console supports it, otherwise fall back to
+    /// the first thing the console advertised.
+    pub fn choose(hello: &Hello, preferred: &StreamConfig) -> Result<StreamConfig, ProtocolError> {
+        fn pick<T: Copy + PartialEq>(options: &[T], preferred: T) -> Option<T> {
+            if options.contains(&preferred) {
+                Some(preferred)
+            } else {
+                options.first().copied()
+            }
+        }
+
+        let camera = pick(&hello.cameras, preferred.camera).ok_or(ProtocolError::NoCommonConfig)?;
+
+        // stereo needs both outer cameras, anything else is mono no matter what was asked for
+        let stereo = match camera {
+            CameraId::BothOuter => {
+                pick(&hello.stereo_layouts, preferred.stereo).unwrap_or(StereoLayout::Mono)
+            }
+            _ => StereoLayout::Mono,
+        };
+
+        Ok(StreamConfig {
+            format: pick(&hello.formats, preferred.format).ok_or(ProtocolError::NoCommonConfig)?,
+            frame_rate: pick(&hello.frame_rates, preferred.frame_rate)
+                .ok_or(ProtocolError::NoCommonConfig)?,
+            resolution: pick(&hello.resolutions, preferred.resolution)
+                .ok_or(ProtocolError::NoCommonConfig)?,
+            camera,
+            quality: preferred.quality,
+            stereo,
+        })
+    }
+}
+
+/// Console side of the handshake: sends `hello` and waits for the server's choice.
+pub fn negotiate<S: Read + Write>(stream: &mut S, hello: &Hello) -> Result<StreamConfig, ProtocolError> {
+    hello.write_to(stream)?;
+    let config = StreamConfig::read_from(stream)?;
+
+    if !hello.supports(&config) {
+        return Err(ProtocolError::NotAdvertised);
+    }
+
+    Ok(config)
+}
+
+/// Server side of the handshake, see [`StreamConfig::choose`].
+pub fn accept<S: Read + Write>(
+    stream: &mut S,
+    preferred: &StreamConfig,
+) -> Result<(Hello, StreamConfig), ProtocolError> {
+    let hello = Hello::read_from(stream)?;
+    let config = StreamConfig::choose(&hello, preferred)?;
+    config.write_to(stream)?;
+    Ok((hello, config))
+}
+
+fn push_list<T>(b: &mut Vec<u8>, items: &[T], mut push: impl FnMut(&mut Vec<u8>, &T)) {
+    let items = &items[..items.len().min(u8::MAX as usize)];
+    b.push(items.len() as u8);
+    for item in items {
+        push(b, item);
+    }
+}
+
+fn read_list<R: Read, T>(
+    input: &mut R,
+    mut read: impl FnMut(&mut R) -> Result<T, ProtocolError>,
+) -> Result<Vec<T>, ProtocolError> {
+    let count = read_u8(input)?;
+    (0..count).map(|_| read(input)).collect()
+}
+
+fn read_preamble<R: Read>(input: &mut R, expected: [u8; 4]) -> Result<(), ProtocolError> {
+    let mut magic = [0u8; 4];
+    read_exact(input, &mut magic)?;
+    if magic != expected {
+        return Err(ProtocolError::BadMagic(magic));
+    }
+
+    let version = read_u8(input)?;
+    if version != VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn read_exact<R: Read>(input: &mut R, buf: &mut [u8]) -> Result<(), ProtocolError> {
+    input.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => ProtocolError::Truncated,
+        _ => ProtocolError::Io(e),
+    })
+}
+
+fn read_u8<R: Read>(input: &mut R) -> Result<u8, ProtocolError> {
+    let mut b = [0u8; 1];
+    read_exact(input, &mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16<R: Read>(input: &mut R) -> Result<u16, ProtocolError> {
+    let mut b = [0u8; 2];
+    read_exact(input, &mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// One side of a connection: reads what the peer already sent, keeps what we write.
+    struct Peer {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Peer {
+        fn new(input: Vec<u8>) -> Self {
+            Self {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hello() -> Hello {
+        Hello {
+            model: "New 3DS XL".to_owned(),
+            formats: vec![PixelFormat::Yuv422, PixelFormat::Jpeg],
+            frame_rates: FrameRate::ALL.to_vec(),
+            resolutions: vec![Resolution::new(640, 480), Resolution::new(400, 240)],
+            cameras: CameraId::ALL.to_vec(),
+            transport: Transport::Udp,
+            stereo_layouts: StereoLayout::ALL.to_vec(),
+        }
+    }
+
+    fn bytes(write: impl FnOnce(&mut Vec<u8>) -> Result<(), ProtocolError>) -> Vec<u8> {
+        let mut b = Vec::new();
+        write(&mut b).unwrap();
+        b
+    }
+
+    #[test]
+    fn round_trips() {
+        let hello = hello();
+        let b = bytes(|b| hello.write_to(b));
+        assert_eq!(Hello::read_from(&mut &b[..]).unwrap(), hello);
+
+        let config = StreamConfig {
+            stereo: StereoLayout::Anaglyph,
+            ..StreamConfig::default()
+        };
+        let b = bytes(|b| config.write_to(b));
+        assert_eq!(b.len(), 14);
+        assert_eq!(b[13], StereoLayout::Anaglyph as u8);
+        assert_eq!(StreamConfig::read_from(&mut &b[..]).unwrap(), config);
+
+        for len in 0..b.len() {
+            let result = StreamConfig::read_from(&mut &b[..len]);
+            assert!(matches!(result, Err(ProtocolError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn choose_and_prefer() {
+        let mut hello = hello();
+        let preferred = StreamConfig {
+            format: PixelFormat::Rgb565,
+            frame_rate: FrameRate::Fps15,
+            resolution: Resolution::new(320, 240),
+            camera: CameraId::Inner,
+            quality: 70,
+            stereo: StereoLayout::TopBottom,
+        };
+        // whatever isn't on offer falls back to the first entry, stereo needs both outer cameras
+        let config = StreamConfig::choose(&hello, &preferred).unwrap();
+        assert_eq!(config.format, PixelFormat::Yuv422);
+        assert_eq!(config.frame_rate, FrameRate::Fps15);
+        assert_eq!(config.resolution, Resolution::new(640, 480));
+        assert_eq!(config.camera, CameraId::Inner);
+        assert_eq!(config.quality, 70);
+        assert_eq!(config.stereo, StereoLayout::Mono);
+        assert!(hello.supports(&config));
+
+        let stereo = StreamConfig {
+            camera: CameraId::BothOuter,
+            ..preferred
+        };
+        assert_eq!(StreamConfig::choose(&hello, &stereo).unwrap().stereo, StereoLayout::TopBottom);
+        assert!(!hello.supports(&StreamConfig { camera: CameraId::Inner, ..stereo }));
+        hello.stereo_layouts.clear();
+        assert_eq!(StreamConfig::choose(&hello, &stereo).unwrap().stereo, StereoLayout::Mono);
+
+        let mut hello = self::hello();
+        hello.prefer(&StreamConfig {
+            format: PixelFormat::Jpeg,
+            resolution: Resolution::new(400, 240),
+            ..stereo
+        });
+        assert_eq!(hello.formats, [PixelFormat::Jpeg, PixelFormat::Yuv422]);
+        assert_eq!(hello.resolutions[0], Resolution::new(400, 240));
+        assert_eq!(hello.cameras[0], CameraId::BothOuter);
+        assert_eq!(hello.stereo_layouts[0], StereoLayout::TopBottom);
+        let mono = StreamConfig {
+            camera: CameraId::Inner,
+            ..stereo
+        };
+        let first = StreamConfig::choose(&hello, &mono).unwrap();
+        assert_eq!(first.format, PixelFormat::Jpeg);
+        assert_eq!(first.resolution, Resolution::new(400, 240));
+
+        hello.formats.clear();
+        let result = StreamConfig::choose(&hello, &stereo);
+        assert!(matches!(result, Err(ProtocolError::NoCommonConfig)));
+    }
+
+    #[test]
+    fn negotiate_and_accept() {
+        let hello = hello();
+        let mut server = Peer::new(bytes(|b| hello.write_to(b)));
+        let (seen, config) = accept(&mut server, &StreamConfig::default()).unwrap();
+        assert_eq!(seen, hello);
+
+        let mut console = Peer::new(server.output);
+        assert_eq!(negotiate(&mut console, &hello).unwrap(), config);
+        assert_eq!(console.output, bytes(|b| hello.write_to(b)));
+
+        // a config the console never offered
+        let odd = StreamConfig {
+            resolution: Resolution::new(1, 1),
+            ..config
+        };
+        let mut console = Peer::new(bytes(|b| odd.write_to(b)));
+        assert!(matches!(negotiate(&mut console, &hello), Err(ProtocolError::NotAdvertised)));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let hello = hello();
+        let config = StreamConfig::default();
+        for version in [1, VERSION - 1, VERSION + 1] {
+            let mut b = bytes(|b| hello.write_to(b));
+            b[4] = version;
+            let rejected =
+                |result| matches!(result, Err(ProtocolError::UnsupportedVersion(v)) if v == version);
+            assert!(rejected(Hello::read_from(&mut &b[..]).map(drop)));
+            let mut server = Peer::new(b);
+            assert!(rejected(accept(&mut server, &config).map(drop)));
+            // nothing was answered
+            assert!(server.output.is_empty());
+
+            let mut b = bytes(|b| config.write_to(b));
+            b[4] = version;
+            assert!(rejected(StreamConfig::read_from(&mut &b[..]).map(drop)));
+            let mut console = Peer::new(b);
+            assert!(rejected(negotiate(&mut console, &hello).map(drop)));
+        }
+
+        let mut b = bytes(|b| config.write_to(b));
+        b[0..4].copy_from_slice(&HELLO_MAGIC);
+        let result = StreamConfig::read_from(&mut &b[..]);
+        assert!(matches!(result, Err(ProtocolError::BadMagic(m)) if m == HELLO_MAGIC));
+    }
+
+    #[test]
+    fn slowest_frame() {
+        for rate in FrameRate::ALL {
+            // never quicker than the top rate allows, give or take the 8.5 in `Fps8_5`
+            assert!(rate.slowest_frame() >= Duration::from_secs(1) / (rate.max_fps() + 1), "{:?}", rate);
+        }
+        assert_eq!(FrameRate::Fps15To2.slowest_frame(), Duration::from_millis(500));
+        assert_eq!(FrameRate::Fps30To5.slowest_frame(), Duration::from_millis(200));
+        assert_eq!(FrameRate::Fps30.slowest_frame(), Duration::from_millis(34));
+    }
+}
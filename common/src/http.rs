@@ -0,0 +1,542 @@
+//! Minimal HTTP server for viewing the camera in a browser (or OBS, VLC, ...).
+//!
+//! Serves a small index page on `/`, a `multipart/x-mixed-replace` MJPEG stream on
+//! `/stream.mjpg` and a single frame on `/snapshot.jpg`. Everything is non-blocking so it can be
+//! polled once per frame from the main loop.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use thiserror::Error;
+
+pub const DEFAULT_PORT: u16 = 8080;
+
+const BOUNDARY: &str = "ctrcameraframe";
+
+/// Requests with headers larger than this get dropped.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HttpError {
+    #[error("Malformed request line")]
+    Malformed,
+    #[error("Request headers too large")]
+    TooLarge,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    Index,
+    Stream,
+    Snapshot,
+    NotFound,
+    MethodNotAllowed,
+}
+
+/// Parses the request line once the whole header block has arrived.
+///
+/// Returns `Ok(None)` if `buf` doesn't contain the blank line ending the headers yet.
+pub fn parse_request(buf: &[u8]) -> Result<Option<Request>, HttpError> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if buf.len() > MAX_REQUEST_LEN => return Err(HttpError::TooLarge),
+        None => return Ok(None),
+    };
+
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| HttpError::Malformed)?;
+    let line = head.lines().next().ok_or(HttpError::Malformed)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) => (m, t, v),
+        _ => return Err(HttpError::Malformed),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed);
+    }
+
+    // we don't care about the query string
+    let path = target.split('?').next().unwrap_or(target);
+
+    Ok(Some(Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+    }))
+}
+
+pub fn route(request: &Request) -> Route {
+    if request.method != "GET" {
+        return Route::MethodNotAllowed;
+    }
+
+    match request.path.as_str() {
+        "/" | "/index.html" => Route::Index,
+        "/stream.mjpg" | "/stream" => Route::Stream,
+        "/snapshot.jpg" => Route::Snapshot,
+        _ => Route::NotFound,
+    }
+}
+
+pub fn index_page() -> String {
+    format!(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head><meta charset=\"utf-8\"><title>ctr-camera-rs</title></head>\n\
+         <body style=\"background:#222;color:#eee;font-family:sans-serif;text-align:center\">\n\
+         <h1>ctr-camera-rs v{}</h1>\n\
+         <img src=\"/stream.mjpg\" alt=\"camera stream\"><br>\n\
+         <a style=\"color:#8cf\" href=\"/snapshot.jpg\">Snapshot</a>\n\
+         </body>\n\
+         </html>\n",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// A complete response with a body, the connection gets closed afterwards.
+pub fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )
+    .into_bytes();
+    out.extend_from_slice(body);
+    out
+}
+
+pub fn stream_header() -> Vec<u8> {
+    format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        BOUNDARY
+    )
+    .into_bytes()
+}
+
+/// One part of the multipart stream.
+pub fn stream_part(jpeg: &[u8]) -> Vec<u8> {
+    let mut out = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    )
+    .into_bytes();
+    out.extend_from_slice(jpeg);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ClientState {
+    /// Still receiving the request headers.
+    Request,
+    Streaming,
+    /// Waiting for the next frame to answer `/snapshot.jpg`.
+    Snapshot,
+    /// Response queued, close once it's sent.
+    Closing,
+}
+
+struct Client {
+    stream: TcpStream,
+    state: ClientState,
+    request: Vec<u8>,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl Client {
+    fn queue(&mut self, data: Vec<u8>) {
+        self.pending = data;
+        self.written = 0;
+    }
+
+    fn is_idle(&self) -> bool {
+        self.written >= self.pending.len()
+    }
+
+    /// Returns `false` once the client should be dropped.
+    fn read_request(&mut self) -> bool {
+        let mut buf = [0u8; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => self.request.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+
+        let request = match parse_request(&self.request) {
+            Ok(Some(request)) => request,
+            Ok(None) => return true,
+            Err(_) => {
+                self.queue(response("400 Bad Request", "text/plain", b"Bad request\n"));
+                self.state = ClientState::Closing;
+                return true;
+            }
+        };
+
+        self.state = match route(&request) {
+            Route::Index => {
+                self.queue(response(
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    index_page().as_bytes(),
+                ));
+                ClientState::Closing
+            }
+            Route::Stream => {
+                self.queue(stream_header());
+                ClientState::Streaming
+            }
+            Route::Snapshot => ClientState::Snapshot,
+            Route::NotFound => {
+                self.queue(response("404 Not Found", "text/plain", b"Not found\n"));
+                ClientState::Closing
+            }
+            Route::MethodNotAllowed => {
+                self.queue(response(
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    b"Method not allowed\n",
+                ));
+                ClientState::Closing
+            }
+        };
+        true
+    }
+
+    /// Returns `false` once the client should be dropped.
+    fn flush(&mut self) -> bool {
+        while !self.is_idle() {
+            match self.stream.write(&self.pending[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+
+        self.state != ClientState::Closing
+    }
+}
+
+/// Non-blocking MJPEG over HTTP server.
+pub struct HttpServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl HttpServer {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts new clients, reads requests and pushes out pending data. Call once per frame.
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    let _ = stream.set_nodelay(true);
+                    self.clients.push(Client {
+                        stream,
+                        state: ClientState::Request,
+                        request: Vec::new(),
+                        pending: Vec::new(),
+                        written: 0,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.clients.retain_mut(|client| {
+            if client.state == ClientState::Request && !client.read_request() {
+                return false;
+            }
+            client.flush()
+        });
+
+        Ok(())
+    }
+
+    /// Whether anyone is waiting for a frame, so the caller can skip capturing otherwise.
+    pub fn wants_frame(&self) -> bool {
+        self.clients
+            .iter()
+            .any(|c| matches!(c.state, ClientState::Streaming | ClientState::Snapshot))
+    }
+
+    /// Number of clients currently watching the stream.
+    pub fn viewers(&self) -> usize {
+        self.clients
+            .iter()
+            .filter(|c| c.state == ClientState::Streaming)
+            .count()
+    }
+
+    /// Hands a new JPEG frame to every client that's ready for one.
+    ///
+    /// Clients still busy sending the previous frame skip this one rather than falling behind.
+    pub fn push_frame(&mut self, jpeg: &[u8]) {
+        for client in &mut self.clients {
+            if !client.is_idle() {
+                continue;
+            }
+            match client.state {
+                ClientState::Streaming => client.queue(stream_part(jpeg)),
+                ClientState::Snapshot => {
+                    client.queue(response("200 OK", "image/jpeg", jpeg));
+                    client.state = ClientState::Closing;
+                }
+                ClientState::Request | ClientState::Closing => {}
+            }
+        }
+
+        self.clients.retain_mut(|client| client.flush());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::jpeg::JpegEncoder;
+    use crate::stream::{FrameSource, TestPattern};
+
+    fn server() -> HttpServer {
+        HttpServer::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).unwrap()
+    }
+
+    fn jpeg(width: u16, height: u16) -> Vec<u8> {
+        let mut pattern = TestPattern::new(width, height);
+        let mut yuyv = vec![0; pattern.frame_size()];
+        pattern.capture(&mut yuyv).unwrap();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(80)
+            .encode_yuyv(&yuyv, width as usize, height as usize, &mut jpeg)
+            .unwrap();
+        jpeg
+    }
+
+    fn connect(server: &HttpServer, request: &[u8]) -> TcpStream {
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+        stream.write_all(request).unwrap();
+        stream
+    }
+
+    /// Whatever arrived, `false` once the server closed the connection.
+    fn read_some(stream: &mut TcpStream, input: &mut Vec<u8>) -> bool {
+        let mut buf = [0u8; 8192];
+        match stream.read(&mut buf) {
+            Ok(0) => false,
+            Ok(n) => {
+                input.extend_from_slice(&buf[..n]);
+                true
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                true
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    struct Response {
+        status: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Status line and headers of `head`, up to the blank line.
+    fn parse_head(head: &[u8]) -> (String, Vec<(String, String)>) {
+        let head = std::str::from_utf8(head).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().to_owned();
+        let headers = lines
+            .map(|l| {
+                let (name, value) = l.split_once(':').unwrap();
+                (name.trim().to_owned(), value.trim().to_owned())
+            })
+            .collect();
+        (status, headers)
+    }
+
+    /// Sends `request` and polls the server until it closes the connection, handing it `frame`
+    /// whenever it wants one.
+    fn fetch(server: &mut HttpServer, request: &[u8], frame: Option<&[u8]>) -> Response {
+        let mut stream = connect(server, request);
+        let mut input = Vec::new();
+        for _ in 0..500 {
+            server.poll().unwrap();
+            if let Some(frame) = frame.filter(|_| server.wants_frame()) {
+                server.push_frame(frame);
+            }
+            if !read_some(&mut stream, &mut input) {
+                let end = input.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+                let (status, headers) = parse_head(&input[..end]);
+                let body = input[end + 4..].to_vec();
+                return Response { status, headers, body };
+            }
+        }
+        panic!("the server never closed the connection, got {} bytes", input.len());
+    }
+
+    #[test]
+    fn index() {
+        let mut server = server();
+        for path in ["/", "/index.html", "/?from=obs"] {
+            let request = format!("GET {} HTTP/1.1\r\nHost: 3ds\r\n\r\n", path);
+            let response = fetch(&mut server, request.as_bytes(), None);
+            assert_eq!(response.status, "HTTP/1.1 200 OK", "{}", path);
+            assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+            assert_eq!(response.body, index_page().as_bytes());
+            let len = response.header("Content-Length").unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), response.body.len());
+        }
+        assert!(index_page().contains("<img src=\"/stream.mjpg\""));
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut server = server();
+        let frame = jpeg(64, 48);
+        let response = fetch(&mut server, b"GET /snapshot.jpg HTTP/1.0\r\n\r\n", Some(&frame));
+        assert_eq!(response.status, "HTTP/1.1 200 OK");
+        assert_eq!(response.header("Content-Type"), Some("image/jpeg"));
+        assert_eq!(response.header("Content-Length"), Some(frame.len().to_string().as_str()));
+        // exactly one picture, and a whole one
+        assert_eq!(response.body, frame);
+        let mut decoder = jpeg_decoder::Decoder::new(&response.body[..]);
+        decoder.decode().unwrap();
+        assert_eq!(decoder.info().unwrap().width, 64);
+        assert!(!server.wants_frame());
+    }
+
+    #[test]
+    fn stream() {
+        let mut server = server();
+        assert!(!server.wants_frame());
+        let mut stream = connect(&server, b"GET /stream.mjpg HTTP/1.1\r\n\r\n");
+        let mut input = Vec::new();
+        for _ in 0..500 {
+            server.poll().unwrap();
+            read_some(&mut stream, &mut input);
+            if input.ends_with(b"\r\n\r\n") {
+                break;
+            }
+        }
+        let (status, headers) = parse_head(&input[..input.len() - 4]);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let content_type = &headers.iter().find(|(n, _)| n == "Content-Type").unwrap().1;
+        assert_eq!(content_type, &format!("multipart/x-mixed-replace; boundary={}", BOUNDARY));
+        assert_eq!(server.viewers(), 1);
+        assert!(server.wants_frame());
+
+        for frame in [jpeg(32, 16), jpeg(48, 32)] {
+            input.clear();
+            server.push_frame(&frame);
+            let part_len = stream_part(&frame).len();
+            for _ in 0..500 {
+                if input.len() >= part_len {
+                    break;
+                }
+                server.poll().unwrap();
+                read_some(&mut stream, &mut input);
+            }
+            assert_eq!(input.len(), part_len);
+
+            let end = input.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            let (boundary, headers) = parse_head(&input[..end]);
+            assert_eq!(boundary, format!("--{}", BOUNDARY));
+            assert_eq!(headers[0], ("Content-Type".to_owned(), "image/jpeg".to_owned()));
+            let len: usize = headers[1].1.parse().unwrap();
+            assert_eq!(len, frame.len());
+            assert_eq!(&input[end + 4..end + 4 + len], &frame[..]);
+            assert_eq!(&input[end + 4 + len..], b"\r\n");
+        }
+
+        // the viewer leaving is noticed on the next frame
+        drop(stream);
+        for _ in 0..100 {
+            server.push_frame(&jpeg(16, 8));
+            if server.viewers() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(server.viewers(), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let mut server = server();
+        let cases: [(&[u8], &str); 6] = [
+            (b"GARBAGE\r\n\r\n", "400 Bad Request"),
+            (b"GET / SPDY/3\r\n\r\n", "400 Bad Request"),
+            (b"GET /\xff HTTP/1.1\r\n\r\n", "400 Bad Request"),
+            (b"GET /secret HTTP/1.1\r\n\r\n", "404 Not Found"),
+            (b"POST / HTTP/1.1\r\nContent-Length: 0\r\n\r\n", "405 Method Not Allowed"),
+            (b"HEAD /stream.mjpg HTTP/1.1\r\n\r\n", "405 Method Not Allowed"),
+        ];
+        for (request, status) in cases {
+            let response = fetch(&mut server, request, None);
+            assert_eq!(response.status, format!("HTTP/1.1 {}", status));
+            assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        }
+        assert!(!server.wants_frame());
+
+        // headers that never end
+        let huge = vec![b'a'; MAX_REQUEST_LEN + 10];
+        let response = fetch(&mut server, &huge, None);
+        assert_eq!(response.status, "HTTP/1.1 400 Bad Request");
+    }
+
+    #[test]
+    fn requests() {
+        assert_eq!(parse_request(b"GET / HTTP/1.1\r\nHost: x\r\n"), Ok(None));
+        let request = parse_request(b"GET /stream?fps=5 HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request, Request { method: "GET".into(), path: "/stream".into() });
+        assert_eq!(route(&request), Route::Stream);
+        assert_eq!(parse_request(b"GET /\r\n\r\n"), Err(HttpError::Malformed));
+        assert_eq!(parse_request(&[b'x'; MAX_REQUEST_LEN + 1]), Err(HttpError::TooLarge));
+    }
+}
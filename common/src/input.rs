@@ -0,0 +1,653 @@
+//! Buttons, without `ctru`.
+//!
+//! [`Keys`] uses the same bits as the console's HID service (and `ctru`'s `KeyPad`), so turning
+//! one into the other is just copying the number. What the buttons do is up to a [`KeyMap`], which
+//! [`Buttons`] uses to turn every scan into [`Action`]s.
+
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+use std::time::{Duration, Instant};
+
+/// A set of buttons.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Keys(u32);
+
+impl Keys {
+    pub const A: Keys = Keys(1 << 0);
+    pub const B: Keys = Keys(1 << 1);
+    pub const SELECT: Keys = Keys(1 << 2);
+    pub const START: Keys = Keys(1 << 3);
+    pub const DPAD_RIGHT: Keys = Keys(1 << 4);
+    pub const DPAD_LEFT: Keys = Keys(1 << 5);
+    pub const DPAD_UP: Keys = Keys(1 << 6);
+    pub const DPAD_DOWN: Keys = Keys(1 << 7);
+    pub const R: Keys = Keys(1 << 8);
+    pub const L: Keys = Keys(1 << 9);
+    pub const X: Keys = Keys(1 << 10);
+    pub const Y: Keys = Keys(1 << 11);
+    pub const ZL: Keys = Keys(1 << 14);
+    pub const ZR: Keys = Keys(1 << 15);
+
+    /// Every button with a name, as written in the config file.
+    pub const NAMED: [(Keys, &'static str); 14] = [
+        (Keys::A, "A"),
+        (Keys::B, "B"),
+        (Keys::X, "X"),
+        (Keys::Y, "Y"),
+        (Keys::L, "L"),
+        (Keys::R, "R"),
+        (Keys::ZL, "ZL"),
+        (Keys::ZR, "ZR"),
+        (Keys::START, "START"),
+        (Keys::SELECT, "SELECT"),
+        (Keys::DPAD_UP, "UP"),
+        (Keys::DPAD_DOWN, "DOWN"),
+        (Keys::DPAD_LEFT, "LEFT"),
+        (Keys::DPAD_RIGHT, "RIGHT"),
+    ];
+
+    pub const fn empty() -> Self {
+        Keys(0)
+    }
+
+    /// Unknown bits (the touch screen, the circle pads) are kept, they just don't have a name.
+    pub const fn from_bits(bits: u32) -> Self {
+        Keys(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Every key in `other` is in here too.
+    pub const fn contains(self, other: Keys) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// At least one key in `other` is in here too.
+    pub const fn intersects(self, other: Keys) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// `A`, `l+r`, ..., named buttons joined with `+` in any case.
+    pub fn parse(text: &str) -> Option<Keys> {
+        let mut keys = Keys::empty();
+        for name in text.split('+') {
+            let name = name.trim();
+            let (key, _) = Keys::NAMED
+                .iter()
+                .find(|(_, n)| n.eq_ignore_ascii_case(name))?;
+            keys |= *key;
+        }
+        Some(keys)
+    }
+}
+
+/// The named buttons joined with `+`, what [`Keys::parse`] reads.
+impl fmt::Display for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (key, name) in Keys::NAMED {
+            if self.contains(key) {
+                if !first {
+                    f.write_str("+")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BitOr for Keys {
+    type Output = Keys;
+
+    fn bitor(self, rhs: Keys) -> Keys {
+        Keys(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Keys {
+    fn bitor_assign(&mut self, rhs: Keys) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Keys {
+    type Output = Keys;
+
+    fn bitand(self, rhs: Keys) -> Keys {
+        Keys(self.0 & rhs.0)
+    }
+}
+
+impl Not for Keys {
+    type Output = Keys;
+
+    fn not(self) -> Keys {
+        Keys(!self.0)
+    }
+}
+
+/// What the buttons did since the last scan.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyState {
+    /// Down right now.
+    pub held: Keys,
+    /// Went down since the last scan.
+    pub pressed: Keys,
+    /// Went up since the last scan.
+    pub released: Keys,
+}
+
+impl KeyState {
+    /// The state after `previous` when `held` are down now.
+    pub fn next(previous: Keys, held: Keys) -> Self {
+        Self {
+            held,
+            pressed: held & !previous,
+            released: previous & !held,
+        }
+    }
+}
+
+/// Something the user can ask for. What each one does depends on the screen, and screens ignore
+/// the ones they have no use for, so the same button can do different things in different places.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Exit,
+    /// Connect, pick the highlighted entry, or the next value in the settings.
+    Confirm,
+    Back,
+    Settings,
+    /// Serve the camera over HTTP.
+    Serve,
+    /// Start or stop the RTSP server.
+    Rtsp,
+    Up,
+    Down,
+    Left,
+    Right,
+    Rename,
+    Favourite,
+    MoveUp,
+    MoveDown,
+    Delete,
+    /// Look for receivers again.
+    Search,
+    /// Start or stop recording to the SD card.
+    Record,
+    /// Save a still to the SD card.
+    Photo,
+}
+
+impl Action {
+    pub const ALL: [Action; 18] = [
+        Action::Exit,
+        Action::Confirm,
+        Action::Back,
+        Action::Settings,
+        Action::Serve,
+        Action::Rtsp,
+        Action::Up,
+        Action::Down,
+        Action::Left,
+        Action::Right,
+        Action::Rename,
+        Action::Favourite,
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::Delete,
+        Action::Search,
+        Action::Record,
+        Action::Photo,
+    ];
+
+    /// What it's called in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Exit => "exit",
+            Action::Confirm => "confirm",
+            Action::Back => "back",
+            Action::Settings => "settings",
+            Action::Serve => "serve",
+            Action::Rtsp => "rtsp",
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::Left => "left",
+            Action::Right => "right",
+            Action::Rename => "rename",
+            Action::Favourite => "favourite",
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::Delete => "delete",
+            Action::Search => "search",
+            Action::Record => "record",
+            Action::Photo => "photo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|a| a.name() == name)
+    }
+}
+
+fn position(action: Action) -> usize {
+    Action::ALL.iter().position(|&a| a == action).unwrap_or(usize::MAX)
+}
+
+/// How long a button has to stay down for a [`Binding::hold`].
+pub const LONG_PRESS: Duration = Duration::from_millis(600);
+
+/// Buttons that trigger an action: all of `keys` down together, and for `hold` kept down for
+/// [`LONG_PRESS`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Binding {
+    pub keys: Keys,
+    pub hold: bool,
+}
+
+impl Binding {
+    pub const fn press(keys: Keys) -> Self {
+        Self { keys, hold: false }
+    }
+
+    pub const fn hold(keys: Keys) -> Self {
+        Self { keys, hold: true }
+    }
+
+    /// `B`, `L+R` or `hold START`.
+    pub fn parse(text: &str) -> Option<Binding> {
+        let text = text.trim();
+        let (keys, hold) = match text.strip_prefix("hold ") {
+            Some(keys) => (keys, true),
+            None => (text, false),
+        };
+        let keys = Keys::parse(keys)?;
+        (!keys.is_empty()).then_some(Binding { keys, hold })
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.hold {
+            f.write_str("hold ")?;
+        }
+        write!(f, "{}", self.keys)
+    }
+}
+
+/// Which buttons do what. An action can have several bindings and a binding several actions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMap {
+    bindings: Vec<(Action, Binding)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let press = |action, keys| (action, Binding::press(keys));
+        Self {
+            bindings: vec![
+                press(Action::Exit, Keys::START),
+                press(Action::Confirm, Keys::A),
+                press(Action::Back, Keys::B),
+                press(Action::Settings, Keys::X),
+                press(Action::Serve, Keys::Y),
+                press(Action::Rtsp, Keys::SELECT),
+                press(Action::Up, Keys::DPAD_UP),
+                press(Action::Down, Keys::DPAD_DOWN),
+                press(Action::Left, Keys::DPAD_LEFT),
+                press(Action::Right, Keys::DPAD_RIGHT),
+                press(Action::Rename, Keys::X),
+                press(Action::Favourite, Keys::Y),
+                press(Action::MoveUp, Keys::L),
+                press(Action::MoveDown, Keys::R),
+                press(Action::Delete, Keys::SELECT),
+                press(Action::Search, Keys::X),
+                press(Action::Record, Keys::L),
+                press(Action::Photo, Keys::R),
+            ],
+        }
+    }
+}
+
+impl KeyMap {
+    pub fn bindings(&self, action: Action) -> impl Iterator<Item = Binding> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(a, _)| *a == action)
+            .map(|(_, b)| *b)
+    }
+
+    /// Replaces whatever `action` was bound to, nothing at all switches it off.
+    pub fn set(&mut self, action: Action, bindings: &[Binding]) {
+        self.bindings.retain(|(a, _)| *a != action);
+        self.bindings.extend(bindings.iter().map(|&b| (action, b)));
+        // the same map always compares (and saves) the same
+        self.bindings.sort_by_key(|(a, _)| position(*a));
+    }
+
+    /// The bindings of `action` for on-screen help, `-` if it has none.
+    pub fn label(&self, action: Action) -> String {
+        let labels: Vec<String> = self.bindings(action).map(|b| b.to_string()).collect();
+        if labels.is_empty() {
+            "-".to_owned()
+        } else {
+            labels.join("/")
+        }
+    }
+}
+
+/// Turns scans into actions with a [`KeyMap`].
+///
+/// Everything fires once per press, holding a button doesn't repeat it. A combo fires once its
+/// last button goes down, a `hold` binding once its buttons have been down for [`LONG_PRESS`]. If
+/// the same buttons also have a plain binding, that one waits for them to go up again and only
+/// fires if the long press didn't.
+///
+/// Buttons that are part of a combo wait too, since nobody presses `L` and `R` in the same scan:
+/// their own binding fires when they go up, or once they've been down for [`COMBO_WINDOW`]
+/// without the rest of the combo, and not at all if the combo happens.
+#[derive(Clone, Debug)]
+pub struct Buttons {
+    map: KeyMap,
+    // every distinct set of keys in the map, with when it went down
+    presses: Vec<(Keys, Option<Press>)>,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Press {
+    since: Instant,
+    // fired already, or part of a combo that went down since
+    done: bool,
+}
+
+/// How long a button that's part of a combo waits for the rest of it.
+pub const COMBO_WINDOW: Duration = Duration::from_millis(200);
+
+impl Buttons {
+    pub fn new(map: KeyMap) -> Self {
+        let mut presses: Vec<(Keys, Option<Press>)> = Vec::new();
+        for (_, binding) in &map.bindings {
+            if !presses.iter().any(|(keys, _)| *keys == binding.keys) {
+                presses.push((binding.keys, None));
+            }
+        }
+        Self { map, presses }
+    }
+
+    pub fn map(&self) -> &KeyMap {
+        &self.map
+    }
+
+    /// Actions for the scan at `now`, in [`Action::ALL`] order. Has to be called for every scan, even
+    /// when nothing changed, for long presses and combo windows to end on time.
+    pub fn update(&mut self, keys: KeyState, now: Instant) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let map = &self.map;
+        let fire = |actions: &mut Vec<Action>, combo: Keys, hold: bool| {
+            actions.extend(
+                map.bindings
+                    .iter()
+                    .filter(|(_, b)| b.keys == combo && b.hold == hold)
+                    .map(|(a, _)| *a),
+            );
+        };
+        let has_hold = |combo: Keys| map.bindings.iter().any(|(_, b)| b.keys == combo && b.hold);
+        let combos: Vec<Keys> = self.presses.iter().map(|(keys, _)| *keys).collect();
+        let in_combo = |combo: Keys| combos.iter().any(|c| *c != combo && c.contains(combo));
+
+        let mut started = Vec::new();
+        for (combo, press) in &mut self.presses {
+            let combo = *combo;
+            if press.is_some() || !keys.held.contains(combo) || !keys.pressed.intersects(combo) {
+                continue;
+            }
+            let waits = has_hold(combo) || in_combo(combo);
+            if !waits {
+                fire(&mut actions, combo, false);
+            }
+            *press = Some(Press {
+                since: now,
+                done: !waits,
+            });
+            started.push(combo);
+        }
+
+        for (combo, press) in &mut self.presses {
+            let combo = *combo;
+            let Some(p) = press else {
+                continue;
+            };
+            if keys.released.intersects(combo) || !keys.held.contains(combo) {
+                if !p.done {
+                    fire(&mut actions, combo, false);
+                }
+                *press = None;
+                continue;
+            }
+            // a combo going down swallows its parts
+            if started.iter().any(|s| *s != combo && s.contains(combo)) {
+                p.done = true;
+            }
+            if p.done {
+                continue;
+            }
+
+            let hold = has_hold(combo);
+            // otherwise it's part of a combo, and the rest of it isn't coming
+            let due = if hold { LONG_PRESS } else { COMBO_WINDOW };
+            if now.saturating_duration_since(p.since) >= due {
+                p.done = true;
+                fire(&mut actions, combo, hold);
+            }
+        }
+
+        actions.sort_by_key(|a| position(*a));
+        actions.dedup();
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds scans to `buttons` one after the other, remembering what was down.
+    struct Scans {
+        buttons: Buttons,
+        held: Keys,
+        start: Instant,
+    }
+
+    impl Scans {
+        fn new(map: KeyMap) -> Self {
+            Self {
+                buttons: Buttons::new(map),
+                held: Keys::empty(),
+                start: Instant::now(),
+            }
+        }
+
+        /// `held` down `ms` milliseconds in.
+        fn at(&mut self, ms: u64, held: Keys) -> Vec<Action> {
+            let state = KeyState::next(self.held, held);
+            self.held = held;
+            self.buttons.update(state, self.start + Duration::from_millis(ms))
+        }
+    }
+
+    fn map(bindings: &[(Action, &str)]) -> KeyMap {
+        let mut map = KeyMap { bindings: Vec::new() };
+        for &(action, text) in bindings {
+            let mut all: Vec<Binding> = map.bindings(action).collect();
+            all.push(Binding::parse(text).unwrap());
+            map.set(action, &all);
+        }
+        map
+    }
+
+    #[test]
+    fn holding_doesnt_repeat() {
+        let mut scans = Scans::new(KeyMap::default());
+        assert_eq!(scans.at(0, Keys::A), [Action::Confirm]);
+        for ms in (16..5000).step_by(16) {
+            assert_eq!(scans.at(ms, Keys::A), [], "{}", ms);
+        }
+        assert_eq!(scans.at(5000, Keys::empty()), []);
+        assert_eq!(scans.at(5016, Keys::A), [Action::Confirm]);
+        // another button going down doesn't press A again
+        assert_eq!(scans.at(5032, Keys::A | Keys::B), [Action::Back]);
+        assert_eq!(scans.at(5048, Keys::A), []);
+    }
+
+    #[test]
+    fn one_button_several_actions() {
+        // every screen picks the one it wants
+        let mut scans = Scans::new(KeyMap::default());
+        assert_eq!(scans.at(0, Keys::X), [Action::Settings, Action::Rename, Action::Search]);
+        assert_eq!(scans.at(16, Keys::L), [Action::MoveUp, Action::Record]);
+    }
+
+    #[test]
+    fn long_press() {
+        let map = map(&[(Action::Back, "B"), (Action::Exit, "hold B")]);
+        let mut scans = Scans::new(map);
+        let long = LONG_PRESS.as_millis() as u64;
+
+        // a tap is back, once it's let go
+        assert_eq!(scans.at(0, Keys::B), []);
+        assert_eq!(scans.at(long - 1, Keys::B), []);
+        assert_eq!(scans.at(long - 1, Keys::empty()), [Action::Back]);
+
+        // held long enough it's exit, right away and without the back
+        assert_eq!(scans.at(1000, Keys::B), []);
+        assert_eq!(scans.at(1000 + long - 1, Keys::B), []);
+        assert_eq!(scans.at(1000 + long, Keys::B), [Action::Exit]);
+        assert_eq!(scans.at(1000 + long * 3, Keys::B), []);
+        assert_eq!(scans.at(1000 + long * 3, Keys::empty()), []);
+
+        // a hold alone
+        let mut scans = Scans::new(map_hold_only());
+        assert_eq!(scans.at(0, Keys::START), []);
+        assert_eq!(scans.at(100, Keys::empty()), []);
+        assert_eq!(scans.at(200, Keys::START), []);
+        assert_eq!(scans.at(200 + long, Keys::START), [Action::Exit]);
+    }
+
+    fn map_hold_only() -> KeyMap {
+        map(&[(Action::Exit, "hold START")])
+    }
+
+    #[test]
+    fn combos_swallow_their_parts() {
+        let map = map(&[(Action::Record, "L"), (Action::Photo, "R"), (Action::Settings, "L+R")]);
+        let mut scans = Scans::new(map);
+
+        // L first, then R, the way hands do it
+        assert_eq!(scans.at(0, Keys::L), []);
+        assert_eq!(scans.at(50, Keys::L | Keys::R), [Action::Settings]);
+        assert_eq!(scans.at(1000, Keys::L | Keys::R), []);
+        // let go one at a time, still nothing
+        assert_eq!(scans.at(1010, Keys::R), []);
+        assert_eq!(scans.at(1020, Keys::empty()), []);
+
+        // both in the same scan
+        assert_eq!(scans.at(2000, Keys::L | Keys::R), [Action::Settings]);
+        assert_eq!(scans.at(2100, Keys::empty()), []);
+
+        // a quick tap of L alone fires when it goes up
+        assert_eq!(scans.at(3000, Keys::L), []);
+        assert_eq!(scans.at(3050, Keys::empty()), [Action::Record]);
+
+        // held, L gives up on R once the window passes
+        let window = COMBO_WINDOW.as_millis() as u64;
+        assert_eq!(scans.at(4000, Keys::L), []);
+        assert_eq!(scans.at(4000 + window - 1, Keys::L), []);
+        assert_eq!(scans.at(4000 + window, Keys::L), [Action::Record]);
+        assert_eq!(scans.at(4000 + window * 2, Keys::empty()), []);
+
+        // R after the window still makes the combo, L already went
+        assert_eq!(scans.at(5000, Keys::L), []);
+        assert_eq!(scans.at(5000 + window, Keys::L), [Action::Record]);
+        assert_eq!(scans.at(5000 + window * 2, Keys::L | Keys::R), [Action::Settings]);
+        assert_eq!(scans.at(6000, Keys::empty()), []);
+    }
+
+    #[test]
+    fn keys_outside_combos_dont_wait() {
+        let map = map(&[(Action::Confirm, "A"), (Action::Record, "L"), (Action::Settings, "L+R")]);
+        let mut scans = Scans::new(map);
+        assert_eq!(scans.at(0, Keys::A), [Action::Confirm]);
+        // R has no binding of its own
+        assert_eq!(scans.at(10, Keys::A | Keys::R), []);
+        assert_eq!(scans.at(20, Keys::A | Keys::R | Keys::L), [Action::Settings]);
+        assert_eq!(scans.at(30, Keys::empty()), []);
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(Keys::parse("l + r"), Some(Keys::L | Keys::R));
+        assert_eq!(Keys::parse("Start"), Some(Keys::START));
+        assert_eq!(Keys::parse("L+Q"), None);
+        for (key, name) in Keys::NAMED {
+            assert_eq!(key.to_string(), name);
+            assert_eq!(Keys::parse(name), Some(key));
+        }
+        // named order, whatever order they were written in
+        let combo = Keys::parse("DOWN+ZR+A").unwrap();
+        assert_eq!(combo.to_string(), "A+ZR+DOWN");
+        assert_eq!(Keys::parse(&combo.to_string()), Some(combo));
+        // the touch screen has no name
+        assert_eq!((Keys::from_bits(1 << 20) | Keys::A).to_string(), "A");
+
+        let state = KeyState::next(Keys::A | Keys::B, Keys::B | Keys::X);
+        assert_eq!(state.pressed, Keys::X);
+        assert_eq!(state.released, Keys::A);
+        assert_eq!(state.held, Keys::B | Keys::X);
+    }
+
+    #[test]
+    fn bindings() {
+        assert_eq!(Binding::parse("hold START"), Some(Binding::hold(Keys::START)));
+        assert_eq!(Binding::parse(" L+R "), Some(Binding::press(Keys::L | Keys::R)));
+        for bad in ["", "hold ", "hold", "Q", "L+"] {
+            assert_eq!(Binding::parse(bad), None, "{:?}", bad);
+        }
+        for text in ["B", "hold B", "L+R", "hold START+SELECT"] {
+            assert_eq!(Binding::parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn key_maps() {
+        let defaults = KeyMap::default();
+        for action in Action::ALL {
+            assert_eq!(Action::from_name(action.name()), Some(action));
+            // every action can be reached out of the box
+            assert_eq!(defaults.bindings(action).count(), 1, "{:?}", action);
+        }
+        assert_eq!(Action::from_name("fly"), None);
+
+        let mut map = defaults.clone();
+        map.set(Action::Exit, &[Binding::hold(Keys::B), Binding::press(Keys::START)]);
+        map.set(Action::Rtsp, &[]);
+        assert_eq!(map.label(Action::Exit), "hold B/START");
+        assert_eq!(map.label(Action::Rtsp), "-");
+        // written back the way it was read
+        let mut again = defaults;
+        for action in Action::ALL {
+            let bindings: Vec<Binding> = map
+                .bindings(action)
+                .map(|b| Binding::parse(&b.to_string()).unwrap())
+                .collect();
+            again.set(action, &bindings);
+        }
+        assert_eq!(again, map);
+    }
+}
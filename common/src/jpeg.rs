@@ -0,0 +1,632 @@
+//! Baseline JPEG encoder taking YUYV (4:2:2) input directly.
+//!
+//! The camera already gives us Y'CbCr with the chroma halved horizontally, which is exactly what
+//! a 2x1 subsampled JPEG stores, so frames go straight to the DCT without any colour conversion.
+//! Uses the standard (Annex K) Huffman tables, which is also what RTP/JPEG expects.
+
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum JpegError {
+    #[error("Width {0} is odd, YUYV needs an even width")]
+    OddWidth(usize),
+    #[error("Expected {expected} bytes of YUYV data, got {actual}")]
+    WrongSize { expected: usize, actual: usize },
+    #[error("Image dimensions must be between 1 and 65535")]
+    BadDimensions,
+}
+
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
+    20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58,
+    59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Annex K.1 tables, natural order
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69,
+    56, 14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104,
+    113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99,
+    99, 47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// Annex K.3 tables: code counts per length (1-16), then the symbols
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_LUMA_VALS: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_CHROMA_VALS: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_LUMA_VALS: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61,
+    0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52,
+    0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25,
+    0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45,
+    0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64,
+    0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83,
+    0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99,
+    0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6,
+    0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3,
+    0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8,
+    0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALS: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61,
+    0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33,
+    0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18,
+    0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44,
+    0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63,
+    0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a,
+    0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97,
+    0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca,
+    0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7,
+    0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+];
+
+// AAN scale factors, cos(k*pi/16) * sqrt(2) for k > 0
+const AAN_SCALE: [f32; 8] = [
+    1.0, 1.387_039_8, 1.306_563, 1.175_875_6, 1.0, 0.785_694_96, 0.541_196_1, 0.275_899_38,
+];
+
+pub const DEFAULT_QUALITY: u8 = 80;
+
+/// (code, length) for every symbol.
+struct HuffmanTable {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanTable {
+    fn new(bits: &[u8; 16], vals: &[u8]) -> Self {
+        let mut codes = [(0u16, 0u8); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for (len, &count) in bits.iter().enumerate() {
+            for _ in 0..count {
+                codes[vals[k] as usize] = (code, len as u8 + 1);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Self { codes }
+    }
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self { out, acc: 0, bits: 0 }
+    }
+
+    #[inline]
+    fn write(&mut self, code: u16, len: u8) {
+        self.acc = (self.acc << len) | code as u32 & ((1 << len) - 1);
+        self.bits += len as u32;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.acc >> self.bits) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                // byte stuffing, so the decoder doesn't see a marker
+                self.out.push(0);
+            }
+        }
+    }
+
+    /// Pads the last byte with 1 bits.
+    fn flush(&mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits as u8;
+            self.write((1 << pad) - 1, pad);
+        }
+    }
+}
+
+/// Scales one of the Annex K tables the way libjpeg does, `quality` is 1-100.
+fn scale_table(base: &[u8; 64], quality: u8) -> [u8; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+
+    let mut table = [0u8; 64];
+    for (t, &b) in table.iter_mut().zip(base) {
+        *t = ((b as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    }
+    table
+}
+
+pub struct JpegEncoder {
+    quality: u8,
+    /// Zigzag order, as written in the DQT segment.
+    luma_quant: [u8; 64],
+    chroma_quant: [u8; 64],
+    /// Natural order, folds in the AAN scale factors.
+    luma_divisors: [f32; 64],
+    chroma_divisors: [f32; 64],
+    dc_luma: HuffmanTable,
+    ac_luma: HuffmanTable,
+    dc_chroma: HuffmanTable,
+    ac_chroma: HuffmanTable,
+}
+
+impl JpegEncoder {
+    pub fn new(quality: u8) -> Self {
+        let mut encoder = Self {
+            quality: 0,
+            luma_quant: [0; 64],
+            chroma_quant: [0; 64],
+            luma_divisors: [0.0; 64],
+            chroma_divisors: [0.0; 64],
+            dc_luma: HuffmanTable::new(&DC_LUMA_BITS, &DC_LUMA_VALS),
+            ac_luma: HuffmanTable::new(&AC_LUMA_BITS, &AC_LUMA_VALS),
+            dc_chroma: HuffmanTable::new(&DC_CHROMA_BITS, &DC_CHROMA_VALS),
+            ac_chroma: HuffmanTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALS),
+        };
+        encoder.set_quality(quality);
+        encoder
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    /// 1 (smallest) to 100 (best), values outside are clamped.
+    pub fn set_quality(&mut self, quality: u8) {
+        let quality = quality.clamp(1, 100);
+        self.quality = quality;
+
+        let luma = scale_table(&LUMA_QUANT, quality);
+        let chroma = scale_table(&CHROMA_QUANT, quality);
+
+        for i in 0..64 {
+            self.luma_quant[i] = luma[ZIGZAG[i]];
+            self.chroma_quant[i] = chroma[ZIGZAG[i]];
+
+            let aan = AAN_SCALE[i / 8] * AAN_SCALE[i % 8] * 8.0;
+            self.luma_divisors[i] = 1.0 / (luma[i] as f32 * aan);
+            self.chroma_divisors[i] = 1.0 / (chroma[i] as f32 * aan);
+        }
+    }
+
+    /// Quantization tables in zigzag order, luma then chroma.
+    pub fn quant_tables(&self) -> (&[u8; 64], &[u8; 64]) {
+        (&self.luma_quant, &self.chroma_quant)
+    }
+
+    /// Encodes a `width`x`height` YUYV frame as a complete JFIF image, appending to `out`.
+    pub fn encode_yuyv(
+        &self,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JpegError> {
+        check_input(src, width, height)?;
+
+        self.write_headers(width, height, out);
+        self.encode_scan(src, width, height, out)?;
+        out.extend_from_slice(&[0xFF, 0xD9]); // EOI
+
+        Ok(())
+    }
+
+    /// Writes everything from SOI up to and including the SOS segment.
+    pub fn write_headers(&self, width: usize, height: usize, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[0xFF, 0xD8]); // SOI
+
+        // APP0 JFIF 1.01, no thumbnail
+        out.extend_from_slice(&[
+            0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0,
+        ]);
+
+        // DQT, both tables in one segment
+        out.extend_from_slice(&[0xFF, 0xDB, 0, 132, 0]);
+        out.extend_from_slice(&self.luma_quant);
+        out.push(1);
+        out.extend_from_slice(&self.chroma_quant);
+
+        // SOF0, Y is 2x1 against Cb and Cr
+        out.extend_from_slice(&[0xFF, 0xC0, 0, 17, 8]);
+        out.extend_from_slice(&(height as u16).to_be_bytes());
+        out.extend_from_slice(&(width as u16).to_be_bytes());
+        out.extend_from_slice(&[3, 1, 0x21, 0, 2, 0x11, 1, 3, 0x11, 1]);
+
+        // DHT
+        let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &DC_LUMA_BITS, &DC_LUMA_VALS),
+            (0x10, &AC_LUMA_BITS, &AC_LUMA_VALS),
+            (0x01, &DC_CHROMA_BITS, &DC_CHROMA_VALS),
+            (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALS),
+        ];
+        let len: usize = 2 + tables.iter().map(|(_, _, v)| 17 + v.len()).sum::<usize>();
+        out.extend_from_slice(&[0xFF, 0xC4]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        for (class, bits, vals) in tables {
+            out.push(class);
+            out.extend_from_slice(bits);
+            out.extend_from_slice(vals);
+        }
+
+        // SOS
+        out.extend_from_slice(&[0xFF, 0xDA, 0, 12, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+    }
+
+    /// Writes only the entropy coded data, without any markers.
+    pub fn encode_scan(
+        &self,
+        src: &[u8],
+        width: usize,
+        height: usize,
+        out: &mut Vec<u8>,
+    ) -> Result<(), JpegError> {
+        check_input(src, width, height)?;
+
+        let mut writer = BitWriter::new(out);
+        let mut prev_dc = [0i32; 3];
+        let mut block = [0f32; 64];
+
+        // one MCU is 16x8 pixels: two Y blocks, one Cb and one Cr
+        for mcu_y in (0..height).step_by(8) {
+            for mcu_x in (0..width).step_by(16) {
+                for half in 0..2 {
+                    load_block(src, width, height, mcu_x + half * 8, mcu_y, 0, &mut block);
+                    self.encode_block(&mut writer, &mut block, 0, &mut prev_dc[0]);
+                }
+                load_block(src, width, height, mcu_x, mcu_y, 1, &mut block);
+                self.encode_block(&mut writer, &mut block, 1, &mut prev_dc[1]);
+                load_block(src, width, height, mcu_x, mcu_y, 3, &mut block);
+                self.encode_block(&mut writer, &mut block, 2, &mut prev_dc[2]);
+            }
+        }
+
+        writer.flush();
+        Ok(())
+    }
+
+    fn encode_block(
+        &self,
+        writer: &mut BitWriter,
+        block: &mut [f32; 64],
+        component: usize,
+        prev_dc: &mut i32,
+    ) {
+        let (divisors, dc, ac) = if component == 0 {
+            (&self.luma_divisors, &self.dc_luma, &self.ac_luma)
+        } else {
+            (&self.chroma_divisors, &self.dc_chroma, &self.ac_chroma)
+        };
+
+        fdct(block);
+
+        let mut coefficients = [0i32; 64];
+        for (i, c) in coefficients.iter_mut().enumerate() {
+            let natural = ZIGZAG[i];
+            *c = (block[natural] * divisors[natural]).round() as i32;
+        }
+
+        let diff = coefficients[0] - *prev_dc;
+        *prev_dc = coefficients[0];
+        let (bits, size) = magnitude(diff);
+        let (code, len) = dc.codes[size as usize];
+        writer.write(code, len);
+        writer.write(bits, size);
+
+        let mut run = 0;
+        for &c in &coefficients[1..] {
+            if c == 0 {
+                run += 1;
+                continue;
+            }
+            while run >= 16 {
+                let (code, len) = ac.codes[0xF0]; // ZRL
+                writer.write(code, len);
+                run -= 16;
+            }
+            let (bits, size) = magnitude(c);
+            let (code, len) = ac.codes[(run << 4) | size as usize];
+            writer.write(code, len);
+            writer.write(bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            let (code, len) = ac.codes[0x00]; // EOB
+            writer.write(code, len);
+        }
+    }
+}
+
+impl Default for JpegEncoder {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUALITY)
+    }
+}
+
+fn check_input(src: &[u8], width: usize, height: usize) -> Result<(), JpegError> {
+    if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+        return Err(JpegError::BadDimensions);
+    }
+    if width & 1 != 0 {
+        return Err(JpegError::OddWidth(width));
+    }
+    let expected = width * height * 2;
+    if src.len() != expected {
+        return Err(JpegError::WrongSize {
+            expected,
+            actual: src.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Loads an 8x8 block of one YUYV channel, level shifted to -128..127.
+///
+/// `offset` is 0 for Y (luma at every even byte), 1 for U and 3 for V. For chroma `x` is the
+/// pixel x of the MCU, since every chroma sample covers two pixels the block spans 16 pixels.
+/// Edges are padded by repeating the last row/column.
+fn load_block(
+    src: &[u8],
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    offset: usize,
+    block: &mut [f32; 64],
+) {
+    let row_len = width * 2;
+    for row in 0..8 {
+        let line = &src[(y + row).min(height - 1) * row_len..][..row_len];
+        for col in 0..8 {
+            let sample = if offset == 0 {
+                line[(x + col).min(width - 1) * 2]
+            } else {
+                // chroma is stored once per pixel pair, at byte 4n+1 (U) and 4n+3 (V)
+                let pair = (x / 2 + col).min(width / 2 - 1);
+                line[pair * 4 + offset]
+            };
+            block[row * 8 + col] = sample as f32 - 128.0;
+        }
+    }
+}
+
+/// Bits and size category for a coefficient, as used by both DC and AC coding.
+#[inline]
+fn magnitude(value: i32) -> (u16, u8) {
+    let abs = value.unsigned_abs();
+    let size = 32 - abs.leading_zeros();
+    let bits = if value < 0 {
+        (value - 1) as u32 & ((1 << size) - 1)
+    } else {
+        value as u32
+    };
+    (bits as u16, size as u8)
+}
+
+/// Forward DCT, AAN algorithm (same as libjpeg's `jfdctflt.c`). Output is scaled, the
+/// divisors in [`JpegEncoder`] take care of that.
+fn fdct(data: &mut [f32; 64]) {
+    for pass in 0..2 {
+        for i in 0..8 {
+            let idx = |k: usize| if pass == 0 { i * 8 + k } else { k * 8 + i };
+            let d = |k: usize| data[idx(k)];
+
+            let tmp0 = d(0) + d(7);
+            let tmp7 = d(0) - d(7);
+            let tmp1 = d(1) + d(6);
+            let tmp6 = d(1) - d(6);
+            let tmp2 = d(2) + d(5);
+            let tmp5 = d(2) - d(5);
+            let tmp3 = d(3) + d(4);
+            let tmp4 = d(3) - d(4);
+
+            // even part
+            let tmp10 = tmp0 + tmp3;
+            let tmp13 = tmp0 - tmp3;
+            let tmp11 = tmp1 + tmp2;
+            let tmp12 = tmp1 - tmp2;
+
+            let z1 = (tmp12 + tmp13) * 0.707_106_77;
+
+            let out0 = tmp10 + tmp11;
+            let out4 = tmp10 - tmp11;
+            let out2 = tmp13 + z1;
+            let out6 = tmp13 - z1;
+
+            // odd part
+            let tmp10 = tmp4 + tmp5;
+            let tmp11 = tmp5 + tmp6;
+            let tmp12 = tmp6 + tmp7;
+
+            let z5 = (tmp10 - tmp12) * 0.382_683_43;
+            let z2 = 0.541_196_1 * tmp10 + z5;
+            let z4 = 1.306_563 * tmp12 + z5;
+            let z3 = tmp11 * 0.707_106_77;
+
+            let z11 = tmp7 + z3;
+            let z13 = tmp7 - z3;
+
+            let outputs = [
+                out0,
+                z11 + z4,
+                out2,
+                z13 - z2,
+                out4,
+                z13 + z2,
+                out6,
+                z11 - z4,
+            ];
+            for (k, value) in outputs.into_iter().enumerate() {
+                data[idx(k)] = value;
+            }
+        }
+    }
+}
+
+/// Returns the entropy coded data of a baseline JPEG, i.e. everything between the SOS segment and
+/// the EOI marker. This is what RTP/JPEG carries, the headers get rebuilt on the other side.
+pub fn scan_data(jpeg: &[u8]) -> Option<&[u8]> {
+    let mut pos = 2;
+    if jpeg.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    loop {
+        if *jpeg.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *jpeg.get(pos + 1)?;
+        let len = u16::from_be_bytes([*jpeg.get(pos + 2)?, *jpeg.get(pos + 3)?]) as usize;
+        pos += 2 + len;
+
+        if marker == 0xDA {
+            let end = jpeg.len().checked_sub(2)?;
+            if pos > end || jpeg[end..] != [0xFF, 0xD9] {
+                return None;
+            }
+            return Some(&jpeg[pos..end]);
+        }
+    }
+}
+
+/// Where a segment goes that has to come after SOI and the leading `markers` segments (e.g.
+/// `0xE0` for JFIF), as an offset into `jpeg`.
+pub fn segments_end(jpeg: &[u8], markers: &[u8]) -> Option<usize> {
+    if jpeg.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut pos = 2;
+    loop {
+        if *jpeg.get(pos)? != 0xFF {
+            return None;
+        }
+        if !markers.contains(jpeg.get(pos + 1)?) {
+            return Some(pos);
+        }
+        let len = u16::from_be_bytes([*jpeg.get(pos + 2)?, *jpeg.get(pos + 3)?]) as usize;
+        pos += 2 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yuv::{self, Range, RgbLayout};
+
+    /// Smooth gradients in every channel with a soft diagonal edge, roughly what a camera sees.
+    fn frame(width: usize, height: usize) -> Vec<u8> {
+        let mut yuyv = Vec::with_capacity(width * height * 2);
+        for y in 0..height {
+            for x in (0..width).step_by(2) {
+                let luma = |x: usize| {
+                    let edge = if x + y > (width + height) / 2 { 60 } else { 0 };
+                    (40 + x * 120 / width + y * 40 / height + edge) as u8
+                };
+                let u = (80 + x * 90 / width) as u8;
+                let v = (170 - y * 90 / height) as u8;
+                yuyv.extend_from_slice(&[luma(x), u, luma(x + 1), v]);
+            }
+        }
+        yuyv
+    }
+
+    fn psnr(a: &[u8], b: &[u8]) -> f64 {
+        let mse = a.iter().zip(b).map(|(&a, &b)| (a as f64 - b as f64).powi(2)).sum::<f64>() / a.len() as f64;
+        10.0 * (255.0 * 255.0 / mse.max(1e-9)).log10()
+    }
+
+    fn decode(jpeg: &[u8]) -> (u16, u16, Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::RGB24);
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn decodes_at_any_size_and_quality() {
+        // whole MCUs, partial ones in either direction, a single MCU and less than one
+        let sizes = [(64, 32), (100, 30), (18, 9), (16, 8), (2, 1), (640, 480)];
+        // (quality, lowest acceptable PSNR in dB)
+        let qualities = [(1, 15.0), (50, 28.0), (DEFAULT_QUALITY, 30.0), (100, 38.0)];
+
+        for (width, height) in sizes {
+            let yuyv = frame(width, height);
+            let mut rgb = Vec::new();
+            yuv::convert(&yuyv, width, height, RgbLayout::Rgb888, Range::Full, &mut rgb).unwrap();
+
+            let mut sizes = Vec::new();
+            for (quality, min_psnr) in qualities {
+                let mut jpeg = Vec::new();
+                JpegEncoder::new(quality).encode_yuyv(&yuyv, width, height, &mut jpeg).unwrap();
+
+                let (w, h, pixels) = decode(&jpeg);
+                assert_eq!((w as usize, h as usize), (width, height));
+                let psnr = psnr(&pixels, &rgb);
+                assert!(psnr >= min_psnr, "{}x{} q{}: {:.1} dB", width, height, quality, psnr);
+                sizes.push(jpeg.len());
+            }
+            // every step up in quality costs bytes
+            assert!(sizes.windows(2).all(|s| s[0] <= s[1]), "{}x{}: {:?}", width, height, sizes);
+        }
+    }
+
+    #[test]
+    fn appends_to_out() {
+        let yuyv = frame(32, 16);
+        let mut out = b"junk".to_vec();
+        JpegEncoder::new(75).encode_yuyv(&yuyv, 32, 16, &mut out).unwrap();
+        assert_eq!(&out[..4], b"junk");
+        assert_eq!(decode(&out[4..]).0, 32);
+    }
+
+    #[test]
+    fn quality_clamps() {
+        assert_eq!(JpegEncoder::new(0).quality(), 1);
+        assert_eq!(JpegEncoder::new(255).quality(), 100);
+        // quality 100 keeps every coefficient
+        assert!(JpegEncoder::new(100).quant_tables().0.iter().all(|&q| q == 1));
+    }
+
+    #[test]
+    fn bad_input() {
+        let encoder = JpegEncoder::new(80);
+        let mut out = Vec::new();
+        assert_eq!(encoder.encode_yuyv(&[0; 6], 3, 1, &mut out), Err(JpegError::OddWidth(3)));
+        assert_eq!(
+            encoder.encode_yuyv(&[0; 6], 4, 1, &mut out),
+            Err(JpegError::WrongSize { expected: 8, actual: 6 })
+        );
+        assert_eq!(encoder.encode_yuyv(&[], 0, 0, &mut out), Err(JpegError::BadDimensions));
+    }
+
+    #[test]
+    fn segments() {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(80).encode_yuyv(&frame(16, 8), 16, 8, &mut jpeg).unwrap();
+        // right after JFIF
+        let after_jfif = segments_end(&jpeg, &[0xE0]).unwrap();
+        assert_eq!(&jpeg[2..4], &[0xFF, 0xE0]);
+        assert_eq!(after_jfif, 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize);
+        assert_eq!(segments_end(&jpeg, &[]), Some(2));
+        assert_eq!(segments_end(b"nope", &[]), None);
+
+        let scan = scan_data(&jpeg).unwrap();
+        assert!(jpeg.ends_with(&[scan, &[0xFF, 0xD9]].concat()));
+    }
+}
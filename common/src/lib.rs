@@ -0,0 +1,33 @@
+//! Platform independent parts of ctr-camera-rs.
+//!
+//! Nothing in here depends on `ctru`, so it builds (and can be poked at) on a normal Linux host.
+
+pub mod avi;
+pub mod config;
+pub mod crc;
+pub mod deflate;
+pub mod discovery;
+pub mod exif;
+pub mod handshake;
+pub mod http;
+pub mod input;
+pub mod jpeg;
+pub mod menu;
+pub mod mpo;
+pub mod photo;
+pub mod png;
+pub mod preview;
+pub mod protocol;
+pub mod queue;
+pub mod reconnect;
+pub mod record;
+pub mod rtp;
+pub mod rtsp;
+pub mod settings;
+pub mod stats;
+pub mod stereo;
+pub mod stream;
+pub mod udp;
+pub mod vision;
+pub mod y4m;
+pub mod yuv;
@@ -0,0 +1,48 @@
+//! Selection state for the D-pad driven lists on the console.
+
+/// Highlighted entry in a list of `len` entries, wrapping around at both ends.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cursor {
+    index: usize,
+    len: usize,
+}
+
+impl Cursor {
+    pub fn new(len: usize) -> Self {
+        Self { index: 0, len }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// For lists that grow or shrink while shown, keeps the cursor inside.
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len;
+        self.index = self.index.min(len.saturating_sub(1));
+    }
+
+    pub fn select(&mut self, index: usize) {
+        self.index = index.min(self.len.saturating_sub(1));
+    }
+
+    pub fn up(&mut self) {
+        if self.len > 0 {
+            self.index = (self.index + self.len - 1) % self.len;
+        }
+    }
+
+    pub fn down(&mut self) {
+        if self.len > 0 {
+            self.index = (self.index + 1) % self.len;
+        }
+    }
+}
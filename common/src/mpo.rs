@@ -0,0 +1,271 @@
+//! Stereo stills as MPO (CIPA DC-007), which is what the console's own camera saves 3D pictures
+//! as and what 3D TVs and viewers open.
+//!
+//! Just two JPEGs back to back, left eye first. Each gets an APP2 `MPF` segment after its JFIF
+//! and Exif segments, the first one with an index of both pictures. Offsets in the index count
+//! from the TIFF header inside the first picture's APP2, except the first picture's own which is
+//! always 0.
+
+use thiserror::Error;
+
+use crate::jpeg;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MpoError {
+    #[error("Not a JPEG")]
+    NotJpeg,
+    #[error("MPO files can't be bigger than 4 GB")]
+    TooBig,
+}
+
+const TAG_VERSION: u16 = 0xb000;
+const TAG_NUMBER_OF_IMAGES: u16 = 0xb001;
+const TAG_ENTRIES: u16 = 0xb002;
+const TAG_INDIVIDUAL_NUMBER: u16 = 0xb101;
+const TAG_BASE_VIEWPOINT: u16 = 0xb204;
+
+/// Representative image, baseline JPEG, multi-frame disparity.
+const ATTRIBUTE_FIRST: u32 = 0x2002_0002;
+/// Baseline JPEG, multi-frame disparity.
+const ATTRIBUTE_OTHER: u32 = 0x0002_0002;
+
+const UNDEFINED: u16 = 7;
+const LONG: u16 = 4;
+
+const IFD_LEN: usize = 2 + 3 * 12 + 4;
+const ENTRY_LEN: usize = 16;
+/// TIFF header, index IFD, the two entries, attribute IFD.
+const FIRST_LEN: usize = 8 + IFD_LEN + 2 * ENTRY_LEN + IFD_LEN;
+/// TIFF header, attribute IFD.
+const OTHER_LEN: usize = 8 + IFD_LEN;
+
+/// Joins two JPEGs of the `left` and `right` eye into one MPO, appended to `out`.
+pub fn join(left: &[u8], right: &[u8], out: &mut Vec<u8>) -> Result<(), MpoError> {
+    // after JFIF and Exif
+    let left_at = jpeg::segments_end(left, &[0xE0, 0xE1]).ok_or(MpoError::NotJpeg)?;
+    let right_at = jpeg::segments_end(right, &[0xE0, 0xE1]).ok_or(MpoError::NotJpeg)?;
+
+    let first_len = left.len() + segment_len(FIRST_LEN);
+    let second_len = right.len() + segment_len(OTHER_LEN);
+    // where the offsets count from
+    let tiff_at = left_at + segment_len(0);
+    let (Ok(first), Ok(second), Ok(second_offset)) = (
+        u32::try_from(first_len),
+        u32::try_from(second_len),
+        u32::try_from(first_len - tiff_at),
+    ) else {
+        return Err(MpoError::TooBig);
+    };
+
+    let start = out.len();
+    out.reserve(first_len + second_len);
+
+    out.extend_from_slice(&left[..left_at]);
+    let mut tiff = tiff_header();
+    let entries_at = 8 + IFD_LEN;
+    let attributes_at = entries_at + 2 * ENTRY_LEN;
+    write_ifd(
+        &mut tiff,
+        &[
+            (TAG_VERSION, UNDEFINED, 4, u32::from_le_bytes(*b"0100")),
+            (TAG_NUMBER_OF_IMAGES, LONG, 1, 2),
+            (TAG_ENTRIES, UNDEFINED, (2 * ENTRY_LEN) as u32, entries_at as u32),
+        ],
+        attributes_at as u32,
+    );
+    for (attribute, len, offset) in [(ATTRIBUTE_FIRST, first, 0), (ATTRIBUTE_OTHER, second, second_offset)] {
+        tiff.extend_from_slice(&attribute.to_le_bytes());
+        tiff.extend_from_slice(&len.to_le_bytes());
+        tiff.extend_from_slice(&offset.to_le_bytes());
+        // no dependent images
+        tiff.extend_from_slice(&[0; 4]);
+    }
+    write_attributes(&mut tiff, 1);
+    debug_assert_eq!(tiff.len(), FIRST_LEN);
+    write_segment(out, &tiff);
+    out.extend_from_slice(&left[left_at..]);
+    debug_assert_eq!(out.len() - start, first_len);
+
+    out.extend_from_slice(&right[..right_at]);
+    let mut tiff = tiff_header();
+    write_attributes(&mut tiff, 2);
+    debug_assert_eq!(tiff.len(), OTHER_LEN);
+    write_segment(out, &tiff);
+    out.extend_from_slice(&right[right_at..]);
+
+    Ok(())
+}
+
+/// Marker, length and `MPF\0` in front of a `tiff_len` TIFF structure.
+fn segment_len(tiff_len: usize) -> usize {
+    2 + 2 + 4 + tiff_len
+}
+
+fn write_segment(out: &mut Vec<u8>, tiff: &[u8]) {
+    out.extend_from_slice(&[0xFF, 0xE2]);
+    out.extend_from_slice(&((segment_len(tiff.len()) - 2) as u16).to_be_bytes());
+    out.extend_from_slice(b"MPF\0");
+    out.extend_from_slice(tiff);
+}
+
+fn tiff_header() -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(FIRST_LEN);
+    tiff.extend_from_slice(b"II");
+    tiff.extend_from_slice(&42u16.to_le_bytes());
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff
+}
+
+/// Every value here fits in its entry: tag, type, count, value.
+fn write_ifd(out: &mut Vec<u8>, entries: &[(u16, u16, u32, u32)], next: u32) {
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for &(tag, kind, count, value) in entries {
+        out.extend_from_slice(&tag.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.extend_from_slice(&next.to_le_bytes());
+}
+
+/// Which picture this is, counting from 1, and that the first one is the base view.
+fn write_attributes(out: &mut Vec<u8>, number: u32) {
+    write_ifd(
+        out,
+        &[
+            (TAG_VERSION, UNDEFINED, 4, u32::from_le_bytes(*b"0100")),
+            (TAG_INDIVIDUAL_NUMBER, LONG, 1, number),
+            (TAG_BASE_VIEWPOINT, LONG, 1, 1),
+        ],
+        0,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::{self, DateTime, Metadata};
+    use crate::jpeg::JpegEncoder;
+
+    /// A flat `luma` picture with JFIF and Exif segments, like a still has.
+    fn eye(width: usize, height: usize, luma: u8) -> Vec<u8> {
+        let yuyv = [luma, 128].repeat(width * height);
+        let mut plain = Vec::new();
+        JpegEncoder::new(90).encode_yuyv(&yuyv, width, height, &mut plain).unwrap();
+        let metadata = Metadata {
+            time: DateTime::from_unix(0),
+            model: "3DS".to_owned(),
+            settings: Default::default(),
+        };
+        let at = jpeg::segments_end(&plain, &[0xE0]).unwrap();
+        let app1 = exif::app1(&exif::tiff(&metadata, width as u16, height as u16));
+        [&plain[..at], &app1, &plain[at..]].concat()
+    }
+
+    fn u16_at(b: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([b[at], b[at + 1]])
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+    }
+
+    /// The TIFF structure in the APP2 `MPF` segment of `jpeg`.
+    fn mpf(jpeg: &[u8]) -> &[u8] {
+        let at = jpeg::segments_end(jpeg, &[0xE0, 0xE1]).unwrap();
+        assert_eq!(jpeg[at..at + 2], [0xFF, 0xE2]);
+        let len = u16::from_be_bytes([jpeg[at + 2], jpeg[at + 3]]) as usize;
+        assert_eq!(&jpeg[at + 4..at + 8], b"MPF\0");
+        let tiff = &jpeg[at + 8..at + 2 + len];
+        assert_eq!(&tiff[..4], b"II*\0");
+        tiff
+    }
+
+    /// Tag, type, count and value of every entry in the IFD `at` bytes into `tiff`, and the
+    /// offset of the next one.
+    fn ifd(tiff: &[u8], at: usize) -> (Vec<(u16, u16, u32, u32)>, u32) {
+        let count = u16_at(tiff, at) as usize;
+        let entries = (0..count)
+            .map(|i| at + 2 + i * 12)
+            .map(|e| {
+                (u16_at(tiff, e), u16_at(tiff, e + 2), u32_at(tiff, e + 4), u32_at(tiff, e + 8))
+            })
+            .collect();
+        (entries, u32_at(tiff, at + 2 + count * 12))
+    }
+
+    fn value(entries: &[(u16, u16, u32, u32)], tag: u16) -> u32 {
+        entries.iter().find(|e| e.0 == tag).unwrap_or_else(|| panic!("{:#x}", tag)).3
+    }
+
+    fn decode(jpeg: &[u8]) -> (u16, u16, Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn index_points_at_both_pictures() {
+        let (left, right) = (eye(32, 16, 60), eye(32, 16, 200));
+        let mut mpo = b"something before".to_vec();
+        join(&left, &right, &mut mpo).unwrap();
+        let mpo = &mpo[16..];
+        assert_eq!(mpo.len(), left.len() + right.len() + 2 * 8 + FIRST_LEN + OTHER_LEN);
+
+        let tiff = mpf(mpo);
+        let tiff_at = tiff.as_ptr() as usize - mpo.as_ptr() as usize;
+        let (index, next) = ifd(tiff, u32_at(tiff, 4) as usize);
+        let tags: Vec<u16> = index.iter().map(|e| e.0).collect();
+        assert_eq!(tags, [TAG_VERSION, TAG_NUMBER_OF_IMAGES, TAG_ENTRIES]);
+        assert_eq!(value(&index, TAG_VERSION).to_le_bytes(), *b"0100");
+        assert_eq!(value(&index, TAG_NUMBER_OF_IMAGES), 2);
+
+        let entries = value(&index, TAG_ENTRIES) as usize;
+        let picture = |i: usize| {
+            let at = entries + i * ENTRY_LEN;
+            (u32_at(tiff, at), u32_at(tiff, at + 4) as usize, u32_at(tiff, at + 8) as usize)
+        };
+        let (first_attribute, first_len, first_offset) = picture(0);
+        let (other_attribute, other_len, other_offset) = picture(1);
+        assert_eq!((first_attribute, other_attribute), (ATTRIBUTE_FIRST, ATTRIBUTE_OTHER));
+        assert_eq!(first_offset, 0);
+        assert_eq!(tiff_at + other_offset, first_len);
+        assert_eq!(first_len + other_len, mpo.len());
+
+        let (first, other) = mpo.split_at(first_len);
+        assert_eq!(first[..2], [0xFF, 0xD8]);
+        assert_eq!(first[first.len() - 2..], [0xFF, 0xD9]);
+        assert_eq!(other[..2], [0xFF, 0xD8]);
+        assert_eq!(other[other.len() - 2..], [0xFF, 0xD9]);
+
+        // each picture numbers itself, the left one being the base view
+        let (attributes, _) = ifd(tiff, next as usize);
+        assert_eq!(value(&attributes, TAG_INDIVIDUAL_NUMBER), 1);
+        assert_eq!(value(&attributes, TAG_BASE_VIEWPOINT), 1);
+        let other_tiff = mpf(other);
+        let (attributes, next) = ifd(other_tiff, u32_at(other_tiff, 4) as usize);
+        assert_eq!(value(&attributes, TAG_INDIVIDUAL_NUMBER), 2);
+        assert_eq!(next, 0);
+
+        for (jpeg, luma) in [(first, 60), (other, 200)] {
+            let (width, height, pixels) = decode(jpeg);
+            assert_eq!((width, height), (32, 16));
+            assert!(pixels.iter().all(|&p| p.abs_diff(luma) < 8), "{}", luma);
+        }
+        // and the Exif segments are still there, right after JFIF
+        for jpeg in [first, other] {
+            let at = jpeg::segments_end(jpeg, &[0xE0]).unwrap();
+            assert_eq!(&jpeg[at + 4..at + 10], b"Exif\0\0");
+        }
+    }
+
+    #[test]
+    fn not_jpeg() {
+        let picture = eye(16, 8, 128);
+        let mut out = Vec::new();
+        assert_eq!(join(b"nope", &picture, &mut out), Err(MpoError::NotJpeg));
+        assert_eq!(join(&picture, &[], &mut out), Err(MpoError::NotJpeg));
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+//! Stills on the SD card, numbered the way cameras number them (DCF) so they turn up next to
+//! everything else in `DCIM`: `DCIM/100CTRCM/CTR_0001.JPG`, `CTR_0002.PNG`, ... and a new folder
+//! after `CTR_9999`.
+//!
+//! Captures come in as YUYV and go out as JPEG ([`jpeg`](crate::jpeg)), PNG ([`png`](crate::png))
+//! or, for both eyes side by side, MPO ([`mpo`](crate::mpo)), all with [`exif`](crate::exif)
+//! metadata.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::exif::{self, Metadata};
+use crate::jpeg::{self, JpegEncoder, JpegError};
+use crate::mpo::{self, MpoError};
+use crate::png::{self, PngError};
+use crate::record::next_number;
+use crate::yuv::{self, ConvertError, Range, RgbLayout};
+
+/// Five characters after the folder number, `100CTRCM`.
+pub const DIR_SUFFIX: &str = "CTRCM";
+/// Four characters before the file number, `CTR_0001.JPG`.
+pub const FILE_PREFIX: &str = "CTR_";
+
+const FIRST_DIR: u32 = 100;
+const LAST_DIR: u32 = 999;
+const LAST_FILE: u32 = 9999;
+
+/// JPEG quality for stills, higher than what gets streamed since there's no hurry.
+pub const QUALITY: u8 = 95;
+
+#[derive(Debug, Error)]
+pub enum PhotoError {
+    #[error("JPEG encoding error: {0}")]
+    Jpeg(#[from] JpegError),
+    #[error("PNG encoding error: {0}")]
+    Png(#[from] PngError),
+    #[error("MPO error: {0}")]
+    Mpo(#[from] MpoError),
+    #[error("Conversion error: {0}")]
+    Convert(#[from] ConvertError),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Every DCIM folder up to {LAST_DIR} is full")]
+    Full,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PhotoFormat {
+    #[default]
+    Jpeg,
+    /// Lossless, and a lot bigger.
+    Png,
+}
+
+impl PhotoFormat {
+    pub const ALL: [PhotoFormat; 2] = [PhotoFormat::Jpeg, PhotoFormat::Png];
+
+    /// What it's called in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            PhotoFormat::Jpeg => "jpeg",
+            PhotoFormat::Png => "png",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PhotoFormat> {
+        PhotoFormat::ALL.into_iter().find(|f| f.name() == name)
+    }
+}
+
+/// One YUYV capture.
+#[derive(Copy, Clone, Debug)]
+pub struct Still<'a> {
+    pub yuyv: &'a [u8],
+    pub width: u16,
+    pub height: u16,
+    /// Both eyes next to each other, which becomes an MPO when saved as JPEG.
+    pub side_by_side: bool,
+}
+
+/// Encodes `still` as `format`, returning the file and the extension it needs.
+pub fn encode(still: &Still, format: PhotoFormat, metadata: &Metadata) -> Result<(Vec<u8>, &'static str), PhotoError> {
+    let (width, height) = (still.width as usize, still.height as usize);
+    let mut out = Vec::new();
+
+    match format {
+        PhotoFormat::Png => {
+            let mut rgb = Vec::new();
+            yuv::convert(still.yuyv, width, height, RgbLayout::Rgb888, Range::Full, &mut rgb)?;
+            let tiff = exif::tiff(metadata, still.width, still.height);
+            png::encode_rgb(&rgb, width, height, Some(&tiff), &mut out)?;
+            Ok((out, "PNG"))
+        }
+        PhotoFormat::Jpeg if still.side_by_side => {
+            let eye = width / 2;
+            let (mut left, mut right) = (Vec::with_capacity(eye * height * 2), Vec::with_capacity(eye * height * 2));
+            for row in still.yuyv.chunks_exact(width * 2) {
+                let (l, r) = row.split_at(eye * 2);
+                left.extend_from_slice(l);
+                right.extend_from_slice(r);
+            }
+            let left = encode_jpeg(&left, eye, height, metadata)?;
+            let right = encode_jpeg(&right, eye, height, metadata)?;
+            mpo::join(&left, &right, &mut out)?;
+            Ok((out, "MPO"))
+        }
+        PhotoFormat::Jpeg => Ok((encode_jpeg(still.yuyv, width, height, metadata)?, "JPG")),
+    }
+}
+
+/// A JPEG with an Exif segment after the JFIF one.
+fn encode_jpeg(yuyv: &[u8], width: usize, height: usize, metadata: &Metadata) -> Result<Vec<u8>, PhotoError> {
+    let mut plain = Vec::new();
+    JpegEncoder::new(QUALITY).encode_yuyv(yuyv, width, height, &mut plain)?;
+
+    // the encoder always starts with SOI and JFIF
+    let at = jpeg::segments_end(&plain, &[0xE0]).unwrap_or(2);
+    let app1 = exif::app1(&exif::tiff(metadata, width as u16, height as u16));
+    let mut out = Vec::with_capacity(plain.len() + app1.len());
+    out.extend_from_slice(&plain[..at]);
+    out.extend_from_slice(&app1);
+    out.extend_from_slice(&plain[at..]);
+    Ok(out)
+}
+
+/// Encodes `still` and writes it to the next free number under `dcim`, returning where it went.
+pub fn save(dcim: &Path, still: &Still, format: PhotoFormat, metadata: &Metadata) -> Result<PathBuf, PhotoError> {
+    let (file, extension) = encode(still, format, metadata)?;
+    let path = next_path(dcim, extension)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, file)?;
+    Ok(path)
+}
+
+/// Where the next picture goes: after the last one in our newest folder, or the first one in a
+/// new folder once that's full. Numbers are shared between extensions, like DCF wants.
+pub fn next_path(dcim: &Path, extension: &str) -> Result<PathBuf, PhotoError> {
+    let mut number = newest_dir(dcim)?.unwrap_or(FIRST_DIR);
+    loop {
+        let dir = dcim.join(format!("{}{}", number, DIR_SUFFIX));
+        let mut file = 1;
+        for ext in ["JPG", "PNG", "MPO"] {
+            file = file.max(next_number(&dir, FILE_PREFIX, ext)?);
+        }
+        if file <= LAST_FILE {
+            return Ok(dir.join(format!("{}{:04}.{}", FILE_PREFIX, file, extension)));
+        }
+        if number == LAST_DIR {
+            return Err(PhotoError::Full);
+        }
+        number += 1;
+    }
+}
+
+/// Number of the highest `NNNCTRCM` folder in `dcim`.
+fn newest_dir(dcim: &Path) -> io::Result<Option<u32>> {
+    let entries = match fs::read_dir(dcim) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut newest = None;
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if let Some(number) = dir_number(name) {
+            newest = newest.max(Some(number));
+        }
+    }
+    Ok(newest)
+}
+
+/// The number in `NNNCTRCM`, if it's one of ours and in the range DCF allows.
+pub fn dir_number(name: &str) -> Option<u32> {
+    let digits = name.get(..3)?;
+    let suffix = name.get(3..)?;
+    if !suffix.eq_ignore_ascii_case(DIR_SUFFIX) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let number = digits.parse().ok()?;
+    (FIRST_DIR..=LAST_DIR).contains(&number).then_some(number)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::exif::DateTime;
+    use crate::stream::{FrameSource, TestPattern};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("ctr-camera-photo-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn touch(dcim: &Path, path: &str) {
+        let path = dcim.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    fn metadata() -> Metadata {
+        Metadata {
+            time: DateTime::from_unix(1_715_954_639),
+            model: "New3DSXL".to_owned(),
+            settings: Default::default(),
+        }
+    }
+
+    fn pattern(width: u16, height: u16) -> Vec<u8> {
+        let mut pattern = TestPattern::new(width, height);
+        let mut yuyv = vec![0; pattern.frame_size()];
+        pattern.capture(&mut yuyv).unwrap();
+        yuyv
+    }
+
+    fn decode_jpeg(jpeg: &[u8]) -> (u16, u16, Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        (info.width, info.height, pixels)
+    }
+
+    fn decode_png(file: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = ::png::Decoder::new(Cursor::new(file)).read_info().unwrap();
+        let (width, height) = (reader.info().width, reader.info().height);
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgb).unwrap();
+        (width, height, rgb)
+    }
+
+    fn model(exif: &kamadak_exif::Exif) -> String {
+        let field = exif.get_field(kamadak_exif::Tag::Model, kamadak_exif::In::PRIMARY).unwrap();
+        field.display_value().to_string()
+    }
+
+    #[test]
+    fn jpeg_and_png() {
+        let yuyv = pattern(64, 32);
+        let still = Still { yuyv: &yuyv, width: 64, height: 32, side_by_side: false };
+
+        let (jpeg, extension) = encode(&still, PhotoFormat::Jpeg, &metadata()).unwrap();
+        assert_eq!(extension, "JPG");
+        let (width, height, pixels) = decode_jpeg(&jpeg);
+        assert_eq!((width, height), (64, 32));
+        let exif = kamadak_exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&jpeg))
+            .unwrap();
+        assert_eq!(model(&exif), "\"New3DSXL\"");
+
+        let (png, extension) = encode(&still, PhotoFormat::Png, &metadata()).unwrap();
+        assert_eq!(extension, "PNG");
+        let (width, height, rgb) = decode_png(&png);
+        assert_eq!((width, height), (64, 32));
+        let mut expected = Vec::new();
+        yuv::convert(&yuyv, 64, 32, RgbLayout::Rgb888, Range::Full, &mut expected).unwrap();
+        assert_eq!(rgb, expected);
+        let exif = kamadak_exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&png))
+            .unwrap();
+        assert_eq!(model(&exif), "\"New3DSXL\"");
+
+        // the same picture, give or take what JPEG loses
+        let off: usize = rgb.iter().zip(&pixels).map(|(a, b)| a.abs_diff(*b) as usize).sum();
+        assert!(off / rgb.len() < 6, "{}", off / rgb.len());
+    }
+
+    #[test]
+    fn side_by_side_is_mpo() {
+        let (eye, height) = (32usize, 16usize);
+        // a pattern on the left, flat grey on the right
+        let left = pattern(eye as u16, height as u16);
+        let mut yuyv = Vec::new();
+        for row in left.chunks_exact(eye * 2) {
+            yuyv.extend_from_slice(row);
+            yuyv.extend_from_slice(&[128; 64]);
+        }
+        let still = Still {
+            yuyv: &yuyv,
+            width: eye as u16 * 2,
+            height: height as u16,
+            side_by_side: true,
+        };
+        let (mpo, extension) = encode(&still, PhotoFormat::Jpeg, &metadata()).unwrap();
+        assert_eq!(extension, "MPO");
+
+        // the second picture starts right after the first one's EOI
+        let second = (2..mpo.len() - 1)
+            .find(|&i| mpo[i - 2..i + 2] == [0xFF, 0xD9, 0xFF, 0xD8])
+            .unwrap();
+        let (first, second) = mpo.split_at(second);
+        let (width, height, pixels) = decode_jpeg(first);
+        assert_eq!((width, height), (32, 16));
+        assert!(pixels.iter().any(|&p| p.abs_diff(128) > 30));
+        let (width, height, pixels) = decode_jpeg(second);
+        assert_eq!((width, height), (32, 16));
+        assert!(pixels.iter().all(|&p| p.abs_diff(128) < 8));
+
+        // PNG keeps them side by side
+        let (png, extension) = encode(&still, PhotoFormat::Png, &metadata()).unwrap();
+        assert_eq!(extension, "PNG");
+        assert_eq!(decode_png(&png).0, 64);
+    }
+
+    #[test]
+    fn numbers_go_on_after_gaps() {
+        let dcim = temp_dir("gaps");
+        // nothing there yet, not even DCIM
+        assert_eq!(next_path(&dcim, "JPG").unwrap(), dcim.join("100CTRCM/CTR_0001.JPG"));
+
+        touch(&dcim, "100CTRCM/CTR_0001.JPG");
+        touch(&dcim, "102CTRCM/CTR_0002.JPG");
+        touch(&dcim, "102CTRCM/CTR_0004.PNG");
+        // not ours
+        touch(&dcim, "103NIN03/HNI_0050.JPG");
+        touch(&dcim, "102CTRCM/CTR_0009.AVI");
+        touch(&dcim, "102CTRCM/NOTE_0007.JPG");
+        // numbers are shared between extensions, whatever their case
+        assert_eq!(next_path(&dcim, "MPO").unwrap(), dcim.join("102CTRCM/CTR_0005.MPO"));
+        touch(&dcim, "102CTRCM/ctr_0006.mpo");
+        assert_eq!(next_path(&dcim, "PNG").unwrap(), dcim.join("102CTRCM/CTR_0007.PNG"));
+
+        // a full folder moves on to the next one
+        touch(&dcim, "102CTRCM/CTR_9999.JPG");
+        assert_eq!(next_path(&dcim, "JPG").unwrap(), dcim.join("103CTRCM/CTR_0001.JPG"));
+        fs::remove_dir_all(&dcim).unwrap();
+    }
+
+    #[test]
+    fn full() {
+        let dcim = temp_dir("full");
+        touch(&dcim, "998CTRCM/CTR_9999.JPG");
+        assert_eq!(next_path(&dcim, "JPG").unwrap(), dcim.join("999CTRCM/CTR_0001.JPG"));
+        touch(&dcim, "999CTRCM/CTR_9998.PNG");
+        assert_eq!(next_path(&dcim, "JPG").unwrap(), dcim.join("999CTRCM/CTR_9999.JPG"));
+        touch(&dcim, "999CTRCM/CTR_9999.MPO");
+        assert!(matches!(next_path(&dcim, "JPG"), Err(PhotoError::Full)));
+
+        let yuyv = pattern(16, 8);
+        let still = Still { yuyv: &yuyv, width: 16, height: 8, side_by_side: false };
+        let saved = save(&dcim, &still, PhotoFormat::Png, &metadata());
+        assert!(matches!(saved, Err(PhotoError::Full)));
+        assert_eq!(fs::read_dir(dcim.join("999CTRCM")).unwrap().count(), 2);
+        fs::remove_dir_all(&dcim).unwrap();
+    }
+
+    #[test]
+    fn saves() {
+        let dcim = temp_dir("save");
+        let yuyv = pattern(16, 8);
+        let still = Still { yuyv: &yuyv, width: 16, height: 8, side_by_side: false };
+        let saved = save(&dcim, &still, PhotoFormat::Png, &metadata()).unwrap();
+        assert_eq!(saved, dcim.join("100CTRCM/CTR_0001.PNG"));
+        assert_eq!(decode_png(&fs::read(&saved).unwrap()).0, 16);
+        let saved = save(&dcim, &still, PhotoFormat::Jpeg, &metadata()).unwrap();
+        assert_eq!(saved, dcim.join("100CTRCM/CTR_0002.JPG"));
+        assert_eq!(decode_jpeg(&fs::read(&saved).unwrap()).0, 16);
+        fs::remove_dir_all(&dcim).unwrap();
+    }
+
+    #[test]
+    fn folder_names() {
+        assert_eq!(dir_number("100CTRCM"), Some(100));
+        assert_eq!(dir_number("123ctrcm"), Some(123));
+        assert_eq!(dir_number("999CTRCM"), Some(999));
+        // DCF starts at 100
+        assert_eq!(dir_number("099CTRCM"), None);
+        assert_eq!(dir_number("100NIN03"), None);
+        assert_eq!(dir_number("1000CTRCM"), None);
+        assert_eq!(dir_number("+12CTRCM"), None);
+        assert_eq!(dir_number("10"), None);
+    }
+
+    #[test]
+    fn format_names() {
+        for format in PhotoFormat::ALL {
+            assert_eq!(PhotoFormat::from_name(format.name()), Some(format));
+        }
+        assert_eq!(PhotoFormat::from_name("gif"), None);
+    }
+}
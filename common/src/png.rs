@@ -0,0 +1,217 @@
+//! PNG stills, for when JPEG artefacts would get in the way.
+//!
+//! 8-bit RGB, each row with whichever filter leaves the smallest sum of differences (the usual
+//! heuristic), compressed by [`deflate`](crate::deflate). Metadata goes in an `eXIf` chunk, the
+//! same bytes a JPEG carries in its APP1 segment (see [`exif`](crate::exif)).
+
+use thiserror::Error;
+
+use crate::crc::Crc32;
+use crate::deflate;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum PngError {
+    #[error("Expected {expected} bytes of RGB data, got {actual}")]
+    WrongSize { expected: usize, actual: usize },
+    #[error("Image dimensions must be between 1 and 2^31 - 1")]
+    BadDimensions,
+}
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Encodes `width` x `height` packed RGB (see [`yuv::convert`](crate::yuv::convert)) as a
+/// complete PNG, appending to `out`. `exif` is a TIFF structure like [`exif::tiff`] makes.
+///
+/// [`exif::tiff`]: crate::exif::tiff
+pub fn encode_rgb(
+    rgb: &[u8],
+    width: usize,
+    height: usize,
+    exif: Option<&[u8]>,
+    out: &mut Vec<u8>,
+) -> Result<(), PngError> {
+    if width == 0 || height == 0 || width > i32::MAX as usize || height > i32::MAX as usize {
+        return Err(PngError::BadDimensions);
+    }
+    let expected = width * height * 3;
+    if rgb.len() != expected {
+        return Err(PngError::WrongSize {
+            expected,
+            actual: rgb.len(),
+        });
+    }
+
+    out.extend_from_slice(&SIGNATURE);
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per sample, truecolour, deflate, adaptive filtering, not interlaced
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    chunk(out, b"IHDR", &header);
+
+    if let Some(exif) = exif {
+        chunk(out, b"eXIf", exif);
+    }
+
+    let mut compressed = Vec::new();
+    deflate::zlib(&filter(rgb, width * 3), &mut compressed);
+    chunk(out, b"IDAT", &compressed);
+
+    chunk(out, b"IEND", &[]);
+    Ok(())
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.finish().to_be_bytes());
+}
+
+/// Every row with a filter type byte in front, ready for compression.
+fn filter(rgb: &[u8], stride: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rgb.len() + rgb.len() / stride);
+    let mut candidate = vec![0; stride];
+    let mut best = vec![0; stride];
+    let zeros = vec![0; stride];
+
+    for (y, row) in rgb.chunks_exact(stride).enumerate() {
+        let above = if y == 0 { &zeros[..] } else { &rgb[(y - 1) * stride..y * stride] };
+
+        let mut best_kind = 0;
+        let mut best_cost = u64::MAX;
+        for kind in 0..5u8 {
+            for x in 0..stride {
+                let left = if x >= 3 { row[x - 3] } else { 0 };
+                let up_left = if x >= 3 { above[x - 3] } else { 0 };
+                let predicted = match kind {
+                    0 => 0,
+                    1 => left,
+                    2 => above[x],
+                    3 => ((left as u16 + above[x] as u16) / 2) as u8,
+                    _ => paeth(left, above[x], up_left),
+                };
+                candidate[x] = row[x].wrapping_sub(predicted);
+            }
+            // small differences either way are what compresses
+            let cost = candidate.iter().map(|&b| (b as i8).unsigned_abs() as u64).sum();
+            if cost < best_cost {
+                best_cost = cost;
+                best_kind = kind;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+
+        out.push(best_kind);
+        out.extend_from_slice(&best);
+    }
+    out
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn picture(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height * 3).map(|i| ((i * 7) ^ (i / 191)) as u8).collect()
+    }
+
+    fn decode(file: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(Cursor::new(file)).read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        let (width, height) = (info.width, info.height);
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgb).unwrap();
+        (width, height, rgb)
+    }
+
+    /// Kind and data of every chunk after the signature.
+    fn chunks(file: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(file[..8], SIGNATURE);
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at < file.len() {
+            let len = u32::from_be_bytes(file[at..at + 4].try_into().unwrap()) as usize;
+            chunks.push((&file[at + 4..at + 8], &file[at + 8..at + 8 + len]));
+            at += 12 + len;
+        }
+        assert_eq!(at, file.len());
+        chunks
+    }
+
+    #[test]
+    fn decodes() {
+        // one and two pixels too, which leave some filters nothing to look at
+        for (width, height) in [(64, 20), (1, 1), (2, 1), (1, 3), (33, 7)] {
+            let rgb = picture(width, height);
+            let mut out = Vec::new();
+            encode_rgb(&rgb, width, height, None, &mut out).unwrap();
+            let (w, h, decoded) = decode(&out);
+            assert_eq!((w as usize, h as usize), (width, height));
+            assert_eq!(decoded, rgb, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn exif_before_the_pixels() {
+        let rgb = picture(8, 4);
+        let tiff = b"II*\0\x08\0\0\0\0\0\0\0\0\0";
+        let mut out = b"already here".to_vec();
+        encode_rgb(&rgb, 8, 4, Some(tiff), &mut out).unwrap();
+        let file = &out[12..];
+        // the decoder checks every CRC and wants eXIf before IDAT
+        assert_eq!(decode(file).2, rgb);
+        let found = chunks(file);
+        let kinds: Vec<&[u8]> = found.iter().map(|c| c.0).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"eXIf", b"IDAT", b"IEND"]);
+        assert_eq!(found[1].1, tiff);
+
+        let mut out = Vec::new();
+        encode_rgb(&rgb, 8, 4, None, &mut out).unwrap();
+        let kinds: Vec<&[u8]> = chunks(&out).iter().map(|c| c.0).collect();
+        assert_eq!(kinds, [&b"IHDR"[..], b"IDAT", b"IEND"]);
+    }
+
+    #[test]
+    fn bad_input() {
+        let rgb = picture(4, 4);
+        let mut out = Vec::new();
+        assert_eq!(
+            encode_rgb(&rgb[1..], 4, 4, None, &mut out),
+            Err(PngError::WrongSize { expected: rgb.len(), actual: rgb.len() - 1 })
+        );
+        assert_eq!(encode_rgb(&[], 0, 4, None, &mut out), Err(PngError::BadDimensions));
+        assert_eq!(encode_rgb(&[], 4, 0, None, &mut out), Err(PngError::BadDimensions));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn predictor() {
+        assert_eq!(paeth(10, 20, 10), 20);
+        assert_eq!(paeth(20, 10, 10), 20);
+        assert_eq!(paeth(10, 10, 20), 10);
+        assert_eq!(paeth(0, 255, 128), 128);
+        assert_eq!(paeth(10, 30, 20), 20);
+    }
+}
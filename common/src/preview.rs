@@ -0,0 +1,311 @@
+//! Drawing captured frames onto the console's screens.
+//!
+//! The LCDs are portrait panels mounted sideways, so a framebuffer is stored column by column,
+//! each column running from the bottom of the screen to the top. Frames are scaled to fit with
+//! nearest neighbour sampling, keeping their aspect ratio, and the rest of the screen is black.
+//! Framebuffers are RGB565, the one format both screens and the console agree on.
+
+use crate::protocol::PixelFormat;
+use crate::stereo::{self, Eye, StereoLayout};
+use crate::yuv::{self, Range};
+
+/// Where the preview goes. The console takes whichever screen is left.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PreviewScreen {
+    Off,
+    #[default]
+    Bottom,
+    Top,
+    /// Top screen with the stereo pair on the parallax barrier.
+    Top3D,
+}
+
+impl PreviewScreen {
+    pub const ALL: [PreviewScreen; 4] = [
+        PreviewScreen::Off,
+        PreviewScreen::Bottom,
+        PreviewScreen::Top,
+        PreviewScreen::Top3D,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PreviewScreen::Off => "off",
+            PreviewScreen::Bottom => "bottom",
+            PreviewScreen::Top => "top",
+            PreviewScreen::Top3D => "top 3D",
+        }
+    }
+
+    /// Whether the console has to move to the bottom screen.
+    pub fn uses_top(self) -> bool {
+        matches!(self, PreviewScreen::Top | PreviewScreen::Top3D)
+    }
+}
+
+/// Part of a frame, in pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// Which parts of a `width` x `height` frame with header `flags` show which eye.
+///
+/// Packed pairs are split in half, a frame-sequential frame is only one eye, and everything else
+/// is the same picture for both.
+pub fn eyes(flags: u16, width: usize, height: usize) -> Vec<(Eye, Rect)> {
+    match stereo::parse_flags(flags) {
+        (StereoLayout::SideBySide, _) => vec![
+            (Eye::Left, Rect::new(0, 0, width / 2, height)),
+            (Eye::Right, Rect::new(width / 2, 0, width / 2, height)),
+        ],
+        (StereoLayout::TopBottom, _) => vec![
+            (Eye::Left, Rect::new(0, 0, width, height / 2)),
+            (Eye::Right, Rect::new(0, height / 2, width, height / 2)),
+        ],
+        (StereoLayout::FrameSequential, eye) => vec![(eye, Rect::new(0, 0, width, height))],
+        _ => vec![
+            (Eye::Left, Rect::new(0, 0, width, height)),
+            (Eye::Right, Rect::new(0, 0, width, height)),
+        ],
+    }
+}
+
+/// Largest size with the aspect ratio of `width` x `height` that fits on `screen`.
+fn fit(
+    width: usize,
+    height: usize,
+    (screen_width, screen_height): (usize, usize),
+) -> (usize, usize) {
+    if width == 0 || height == 0 {
+        (0, 0)
+    } else if width * screen_height >= height * screen_width {
+        (screen_width, height * screen_width / width)
+    } else {
+        (width * screen_height / height, screen_height)
+    }
+}
+
+/// RGB565 value of pixel `(x, y)` of a `format` frame `width` pixels wide.
+fn sample(frame: &[u8], format: PixelFormat, width: usize, x: usize, y: usize) -> u16 {
+    let i = y * width + x;
+    match format.capture_format() {
+        PixelFormat::Rgb565 => frame
+            .get(i * 2..i * 2 + 2)
+            .map_or(0, |p| u16::from_le_bytes([p[0], p[1]])),
+        _ => {
+            let pair = i & !1;
+            let Some(p) = frame.get(pair * 2..pair * 2 + 4) else {
+                return 0;
+            };
+            let luma = p[(i & 1) * 2];
+            let [r, g, b] = yuv::yuv_to_rgb(luma, p[1], p[3], Range::Full);
+            ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3)
+        }
+    }
+}
+
+/// Draws `region` of a `format` frame `width` pixels wide into the RGB565 framebuffer `fb` of a
+/// screen that's `screen` in landscape.
+pub fn draw(
+    frame: &[u8],
+    format: PixelFormat,
+    width: usize,
+    region: Rect,
+    fb: &mut [u8],
+    screen: (usize, usize),
+) {
+    let (screen_width, screen_height) = screen;
+    let (w, h) = fit(region.width, region.height, screen);
+    let (left, top) = ((screen_width - w) / 2, (screen_height - h) / 2);
+
+    let pixels = fb.chunks_exact_mut(2).take(screen_width * screen_height);
+    for (i, out) in pixels.enumerate() {
+        // column by column, bottom to top
+        let x = i / screen_height;
+        let y = screen_height - 1 - i % screen_height;
+
+        let inside = (left..left + w).contains(&x) && (top..top + h).contains(&y);
+        let pixel = if inside {
+            let fx = region.x + (x - left) * region.width / w;
+            let fy = region.y + (y - top) * region.height / h;
+            sample(frame, format, width, fx, fy)
+        } else {
+            0
+        };
+        out.copy_from_slice(&pixel.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: u16 = 0;
+    const WHITE: u16 = 0xffff;
+
+    /// The framebuffer as rows of the landscape screen, top row first.
+    fn rows(fb: &[u8], (screen_width, screen_height): (usize, usize)) -> Vec<Vec<u16>> {
+        (0..screen_height)
+            .map(|y| {
+                (0..screen_width)
+                    .map(|x| {
+                        let i = x * screen_height + (screen_height - 1 - y);
+                        u16::from_le_bytes([fb[i * 2], fb[i * 2 + 1]])
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn rgb565(pixels: &[u16]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| p.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn eyes_from_flags() {
+        let (w, h) = (640, 240);
+        let whole = Rect::new(0, 0, w, h);
+        for layout in StereoLayout::ALL {
+            for eye in [Eye::Left, Eye::Right] {
+                let expected = match layout {
+                    StereoLayout::SideBySide => vec![
+                        (Eye::Left, Rect::new(0, 0, 320, h)),
+                        (Eye::Right, Rect::new(320, 0, 320, h)),
+                    ],
+                    StereoLayout::TopBottom => vec![
+                        (Eye::Left, Rect::new(0, 0, w, 120)),
+                        (Eye::Right, Rect::new(0, 120, w, 120)),
+                    ],
+                    StereoLayout::FrameSequential => vec![(eye, whole)],
+                    // already one picture by the time it's sent
+                    StereoLayout::Mono | StereoLayout::Anaglyph | StereoLayout::Depth => {
+                        vec![(Eye::Left, whole), (Eye::Right, whole)]
+                    }
+                };
+                let flags = stereo::flags(layout, eye);
+                assert_eq!(eyes(flags, w, h), expected, "{:?} {:?}", layout, eye);
+                // reserved bits don't matter
+                assert_eq!(eyes(flags | 0x8000, w, h), expected, "{:?} {:?}", layout, eye);
+            }
+        }
+
+        // an odd pixel out goes unused
+        let side_by_side = stereo::flags(StereoLayout::SideBySide, Eye::Left);
+        assert_eq!(eyes(side_by_side, 641, 3)[1].1, Rect::new(320, 0, 320, 3));
+    }
+
+    #[test]
+    fn fits_keeping_the_aspect_ratio() {
+        let top = (400, 240);
+        // the same shape fills the screen
+        assert_eq!(fit(800, 480, top), (400, 240));
+        // wider, bars above and below
+        assert_eq!(fit(640, 240, top), (400, 150));
+        assert_eq!(fit(640, 480, top), (320, 240));
+        // taller, bars on the sides
+        assert_eq!(fit(240, 400, top), (144, 240));
+        // smaller frames get scaled up
+        assert_eq!(fit(160, 120, top), (320, 240));
+        assert_eq!(fit(1, 1, top), (240, 240));
+        assert_eq!(fit(40, 12, (320, 240)), (320, 96));
+        assert_eq!(fit(0, 240, top), (0, 0));
+        assert_eq!(fit(400, 0, top), (0, 0));
+    }
+
+    #[test]
+    fn draws_column_by_column() {
+        let screen = (4, 2);
+        // a 2x1 frame scaled up twice, so every pixel covers two columns and both rows
+        let frame = rgb565(&[0x1234, 0xabcd]);
+        let mut fb = vec![0xee; 4 * 2 * 2];
+        draw(&frame, PixelFormat::Rgb565, 2, Rect::new(0, 0, 2, 1), &mut fb, screen);
+        assert_eq!(rows(&fb, screen), [[0x1234, 0x1234, 0xabcd, 0xabcd]; 2]);
+
+        // the first column is stored bottom to top
+        let frame = rgb565(&[1, 2, 3, 4]);
+        draw(&frame, PixelFormat::Rgb565, 2, Rect::new(0, 0, 2, 2), &mut fb, (2, 2));
+        assert_eq!(fb[..8], rgb565(&[3, 1, 4, 2]));
+    }
+
+    #[test]
+    fn letterboxes() {
+        let screen = (8, 4);
+        let frame = rgb565(&[7; 4 * 4]);
+        let mut fb = vec![0xee; 8 * 4 * 2];
+        draw(&frame, PixelFormat::Rgb565, 4, Rect::new(0, 0, 4, 4), &mut fb, screen);
+        let row = [BLACK, BLACK, 7, 7, 7, 7, BLACK, BLACK];
+        assert_eq!(rows(&fb, screen), [row; 4]);
+
+        // and the other way around
+        let screen = (4, 8);
+        let mut fb = vec![0xee; 4 * 8 * 2];
+        draw(&frame, PixelFormat::Rgb565, 4, Rect::new(0, 0, 4, 4), &mut fb, screen);
+        let rows = rows(&fb, screen);
+        assert_eq!(rows[..2], [[BLACK; 4]; 2]);
+        assert_eq!(rows[2..6], [[7; 4]; 4]);
+        assert_eq!(rows[6..], [[BLACK; 4]; 2]);
+    }
+
+    #[test]
+    fn draws_one_eye() {
+        // side by side, left eye 1s and right eye 2s
+        let frame = rgb565(&[1, 1, 2, 2, 1, 1, 2, 2]);
+        let flags = stereo::flags(StereoLayout::SideBySide, Eye::Left);
+        let screen = (2, 2);
+        for (eye, region) in eyes(flags, 4, 2) {
+            let mut fb = vec![0; 2 * 2 * 2];
+            draw(&frame, PixelFormat::Rgb565, 4, region, &mut fb, screen);
+            let value = if eye == Eye::Left { 1 } else { 2 };
+            assert_eq!(rows(&fb, screen), [[value; 2]; 2], "{:?}", eye);
+        }
+    }
+
+    #[test]
+    fn samples_every_format() {
+        // white, black, then a colour, full range like the cameras
+        let yuyv = [255, 128, 0, 128, 90, 60, 140, 200];
+        let [r, g, b] = yuv::yuv_to_rgb(90, 60, 200, Range::Full);
+        let colour = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+        // JPEG frames are drawn from the YUYV capture
+        for format in [PixelFormat::Yuv422, PixelFormat::Jpeg] {
+            assert_eq!(sample(&yuyv, format, 4, 0, 0), WHITE);
+            assert_eq!(sample(&yuyv, format, 4, 1, 0), BLACK);
+            assert_eq!(sample(&yuyv, format, 4, 2, 0), colour);
+            // pixels 2 and 3 share their chroma
+            let [r, g, b] = yuv::yuv_to_rgb(140, 60, 200, Range::Full);
+            let next = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+            assert_eq!(sample(&yuyv, format, 4, 3, 0), next);
+            // past the end of a short frame is black, not a panic
+            assert_eq!(sample(&yuyv, format, 4, 0, 1), BLACK);
+        }
+
+        let frame = rgb565(&[0xf800, 0x07e0, 0x001f]);
+        assert_eq!(sample(&frame, PixelFormat::Rgb565, 3, 0, 0), 0xf800);
+        assert_eq!(sample(&frame, PixelFormat::Rgb565, 3, 2, 0), 0x001f);
+        assert_eq!(sample(&frame, PixelFormat::Rgb565, 3, 0, 1), BLACK);
+    }
+
+    #[test]
+    fn screens() {
+        assert!(!PreviewScreen::Off.uses_top());
+        assert!(!PreviewScreen::Bottom.uses_top());
+        assert!(PreviewScreen::Top.uses_top());
+        assert!(PreviewScreen::Top3D.uses_top());
+        assert_eq!(PreviewScreen::default(), PreviewScreen::Bottom);
+    }
+}
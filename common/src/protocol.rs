@@ -0,0 +1,539 @@
+//! Framing used for frames sent over the TCP connection.
+//!
+//! Every frame is a fixed 32 byte header followed by the payload. All integers are little endian.
+//!
+//! | offset | size | field                                       |
+//! |--------|------|---------------------------------------------|
+//! | 0      | 4    | magic, `CTRC`                               |
+//! | 4      | 1    | protocol version ([`VERSION`])              |
+//! | 5      | 1    | pixel format ([`PixelFormat`])              |
+//! | 6      | 2    | flags, stereo layout ([`stereo`](crate::stereo)) |
+//! | 8      | 4    | frame sequence number, wraps around         |
+//! | 12     | 8    | capture timestamp in µs since stream start  |
+//! | 20     | 2    | width in pixels                             |
+//! | 22     | 2    | height in pixels                            |
+//! | 24     | 4    | payload length in bytes                     |
+//! | 28     | 4    | CRC-32 of the payload                       |
+//!
+//! A receiver that sees a version it doesn't know should drop the connection, the header
+//! layout is only guaranteed to stay the same within a version. The handshake
+//! ([`handshake`](crate::handshake)), UDP datagrams ([`udp`](crate::udp)) and discovery
+//! ([`discovery`](crate::discovery)) carry the same version, so it goes up whenever any of them
+//! changes:
+//!
+//! 1. the first one
+//! 2. the transport in the `Hello`
+//! 3. stereo layouts in the `Hello` and `StreamConfig`
+//!
+//! Receivers answer every frame with an 8 byte ack on the TCP connection: magic `CTRA` and the
+//! frame's sequence number (`u32`). The console only uses them for its statistics, so receivers
+//! that don't send them still work.
+
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use crate::crc::crc32;
+
+pub const MAGIC: [u8; 4] = *b"CTRC";
+pub const VERSION: u8 = 3;
+pub const HEADER_LEN: usize = 32;
+
+pub const ACK_MAGIC: [u8; 4] = *b"CTRA";
+pub const ACK_LEN: usize = 8;
+
+/// Port receivers listen on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 5000;
+
+/// Upper bound on the payload size, so a corrupted length can't make the decoder allocate gigabytes.
+pub const MAX_PAYLOAD_LEN: u32 = 8 * 1024 * 1024;
+
+/// Pixel format of the payload, the raw ones mirror `ctru`'s `OutputFormat`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PixelFormat {
+    Yuv422 = 0,
+    Rgb565 = 1,
+    /// Baseline JFIF image, encoded on the console from YUV422 frames.
+    Jpeg = 2,
+}
+
+impl PixelFormat {
+    /// Format the camera has to capture in to produce this one.
+    pub fn capture_format(self) -> PixelFormat {
+        match self {
+            PixelFormat::Jpeg => PixelFormat::Yuv422,
+            other => other,
+        }
+    }
+
+    /// Bytes per pixel of the raw capture, see [`PixelFormat::capture_format`].
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Yuv422 | PixelFormat::Rgb565 | PixelFormat::Jpeg => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for PixelFormat {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PixelFormat::Yuv422),
+            1 => Ok(PixelFormat::Rgb565),
+            2 => Ok(PixelFormat::Jpeg),
+            other => Err(ProtocolError::UnknownFormat(other)),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Bad magic {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unknown pixel format {0}")]
+    UnknownFormat(u8),
+    #[error("Unknown frame rate {0}")]
+    UnknownFrameRate(u8),
+    #[error("Unknown camera {0}")]
+    UnknownCamera(u8),
+    #[error("Unknown transport {0}")]
+    UnknownTransport(u8),
+    #[error("Unknown stereo layout {0}")]
+    UnknownStereoLayout(u8),
+    #[error("No configuration supported by both sides")]
+    NoCommonConfig,
+    #[error("Server chose a configuration the console didn't advertise")]
+    NotAdvertised,
+    #[error("Payload of {0} bytes is too large")]
+    PayloadTooLarge(u32),
+    #[error("Checksum mismatch, expected {expected:08x} got {actual:08x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+    #[error("Stream ended in the middle of a frame")]
+    Truncated,
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub format: PixelFormat,
+    pub flags: u16,
+    pub sequence: u32,
+    pub timestamp_us: u64,
+    pub width: u16,
+    pub height: u16,
+    pub payload_len: u32,
+    pub checksum: u32,
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut b = [0u8; HEADER_LEN];
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4] = VERSION;
+        b[5] = self.format as u8;
+        b[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        b[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+        b[12..20].copy_from_slice(&self.timestamp_us.to_le_bytes());
+        b[20..22].copy_from_slice(&self.width.to_le_bytes());
+        b[22..24].copy_from_slice(&self.height.to_le_bytes());
+        b[24..28].copy_from_slice(&self.payload_len.to_le_bytes());
+        b[28..32].copy_from_slice(&self.checksum.to_le_bytes());
+        b
+    }
+
+    pub fn parse(b: &[u8; HEADER_LEN]) -> Result<Self, ProtocolError> {
+        let magic = [b[0], b[1], b[2], b[3]];
+        if magic != MAGIC {
+            return Err(ProtocolError::BadMagic(magic));
+        }
+        if b[4] != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(b[4]));
+        }
+
+        let payload_len = u32::from_le_bytes([b[24], b[25], b[26], b[27]]);
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(ProtocolError::PayloadTooLarge(payload_len));
+        }
+
+        Ok(FrameHeader {
+            format: PixelFormat::try_from(b[5])?,
+            flags: u16::from_le_bytes([b[6], b[7]]),
+            sequence: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+            timestamp_us: u64::from_le_bytes(b[12..20].try_into().unwrap()),
+            width: u16::from_le_bytes([b[20], b[21]]),
+            height: u16::from_le_bytes([b[22], b[23]]),
+            payload_len,
+            checksum: u32::from_le_bytes([b[28], b[29], b[30], b[31]]),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8>,
+}
+
+/// Writes frames, keeping track of the sequence number.
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    sequence: u32,
+    flags: u16,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flags for the headers from now on.
+    pub fn set_flags(&mut self, flags: u16) {
+        self.flags = flags;
+    }
+
+    /// Builds the header for `payload` and advances the sequence number.
+    pub fn next_header(
+        &mut self,
+        format: PixelFormat,
+        width: u16,
+        height: u16,
+        timestamp_us: u64,
+        payload: &[u8],
+    ) -> FrameHeader {
+        let header = FrameHeader {
+            format,
+            flags: self.flags,
+            sequence: self.sequence,
+            timestamp_us,
+            width,
+            height,
+            payload_len: payload.len() as u32,
+            checksum: crc32(payload),
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        header
+    }
+
+    pub fn encode<W: Write>(
+        &mut self,
+        out: &mut W,
+        format: PixelFormat,
+        width: u16,
+        height: u16,
+        timestamp_us: u64,
+        payload: &[u8],
+    ) -> io::Result<FrameHeader> {
+        let header = self.next_header(format, width, height, timestamp_us, payload);
+        out.write_all(&header.to_bytes())?;
+        out.write_all(payload)?;
+        Ok(header)
+    }
+}
+
+/// Incremental decoder, feed it bytes as they arrive and pull complete frames out.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    header: Option<FrameHeader>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns the next complete frame, or `None` if more data is needed.
+    pub fn decode(&mut self) -> Result<Option<Frame>, ProtocolError> {
+        let header = match self.header {
+            Some(header) => header,
+            None => {
+                if self.buf.len() < HEADER_LEN {
+                    return Ok(None);
+                }
+                let header = FrameHeader::parse(self.buf[..HEADER_LEN].try_into().unwrap())?;
+                self.buf.drain(..HEADER_LEN);
+                self.header = Some(header);
+                header
+            }
+        };
+
+        let len = header.payload_len as usize;
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+
+        let payload: Vec<u8> = self.buf.drain(..len).collect();
+        self.header = None;
+
+        let actual = crc32(&payload);
+        if actual != header.checksum {
+            return Err(ProtocolError::ChecksumMismatch {
+                expected: header.checksum,
+                actual,
+            });
+        }
+
+        Ok(Some(Frame { header, payload }))
+    }
+
+    /// Call once the input is exhausted, errors if a frame was cut off.
+    pub fn finish(&self) -> Result<(), ProtocolError> {
+        if self.header.is_some() || !self.buf.is_empty() {
+            Err(ProtocolError::Truncated)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn encode_ack(sequence: u32) -> [u8; ACK_LEN] {
+    let mut b = [0u8; ACK_LEN];
+    b[0..4].copy_from_slice(&ACK_MAGIC);
+    b[4..8].copy_from_slice(&sequence.to_le_bytes());
+    b
+}
+
+/// Splits acks out of whatever came back on the connection, holding on to partial ones.
+#[derive(Debug, Default)]
+pub struct AckDecoder {
+    buf: Vec<u8>,
+}
+
+impl AckDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the sequence numbers of every ack completed by `data`.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<u32>, ProtocolError> {
+        self.buf.extend_from_slice(data);
+
+        let mut acks = Vec::new();
+        let mut chunks = self.buf.chunks_exact(ACK_LEN);
+        for ack in &mut chunks {
+            let magic: [u8; 4] = ack[0..4].try_into().unwrap();
+            if magic != ACK_MAGIC {
+                return Err(ProtocolError::BadMagic(magic));
+            }
+            acks.push(u32::from_le_bytes(ack[4..8].try_into().unwrap()));
+        }
+        let used = self.buf.len() - chunks.remainder().len();
+        self.buf.drain(..used);
+        Ok(acks)
+    }
+}
+
+/// Blocking read of a single frame.
+///
+/// Returns `Ok(None)` if the stream ended cleanly before the next frame.
+pub fn read_frame<R: Read>(input: &mut R) -> Result<Option<Frame>, ProtocolError> {
+    let mut header = [0u8; HEADER_LEN];
+    match read_full(input, &mut header)? {
+        0 => return Ok(None),
+        HEADER_LEN => {}
+        _ => return Err(ProtocolError::Truncated),
+    }
+    let header = FrameHeader::parse(&header)?;
+
+    let mut payload = vec![0u8; header.payload_len as usize];
+    if read_full(input, &mut payload)? != payload.len() {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let actual = crc32(&payload);
+    if actual != header.checksum {
+        return Err(ProtocolError::ChecksumMismatch {
+            expected: header.checksum,
+            actual,
+        });
+    }
+
+    Ok(Some(Frame { header, payload }))
+}
+
+/// Like `read_exact`, but reports how much was read when hitting EOF.
+fn read_full<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames() -> (Vec<u8>, Vec<FrameHeader>) {
+        let mut encoder = FrameEncoder::new();
+        let mut out = Vec::new();
+        let mut headers = Vec::new();
+        for (i, len) in [0usize, 1, 300, 5000].into_iter().enumerate() {
+            let payload: Vec<u8> = (0..len).map(|b| (b * 31 + i) as u8).collect();
+            encoder.set_flags(i as u16);
+            headers.push(encoder.encode(&mut out, PixelFormat::Yuv422, 64, 48, 1000 * i as u64, &payload).unwrap());
+        }
+        (out, headers)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = FrameHeader {
+            format: PixelFormat::Jpeg,
+            flags: 0x0102,
+            sequence: u32::MAX,
+            timestamp_us: 0x0123_4567_89ab_cdef,
+            width: 640,
+            height: 480,
+            payload_len: 1234,
+            checksum: 0xdead_beef,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!((&bytes[..4], bytes[4]), (&b"CTRC"[..], VERSION));
+        assert_eq!(FrameHeader::parse(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn decoder_round_trip() {
+        let (out, headers) = frames();
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&out);
+        for (i, header) in headers.iter().enumerate() {
+            let frame = decoder.decode().unwrap().unwrap();
+            assert_eq!(frame.header, *header);
+            assert_eq!(frame.header.sequence, i as u32);
+            assert_eq!(frame.header.flags, i as u16);
+            assert_eq!(frame.payload.len(), header.payload_len as usize);
+            assert_eq!(crc32(&frame.payload), header.checksum);
+        }
+        assert_eq!(decoder.decode().unwrap(), None);
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn decoder_byte_by_byte() {
+        let (out, headers) = frames();
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for byte in &out {
+            decoder.feed(std::slice::from_ref(byte));
+            while let Some(frame) = decoder.decode().unwrap() {
+                decoded.push(frame.header);
+            }
+        }
+        assert_eq!(decoded, headers);
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn corrupted_header() {
+        let (out, _) = frames();
+        let header: [u8; HEADER_LEN] = out[..HEADER_LEN].try_into().unwrap();
+
+        let mut bad = header;
+        bad[0] = b'X';
+        assert!(matches!(FrameHeader::parse(&bad), Err(ProtocolError::BadMagic(m)) if &m == b"XTRC"));
+        let mut bad = header;
+        bad[4] = VERSION + 1;
+        assert!(matches!(FrameHeader::parse(&bad), Err(ProtocolError::UnsupportedVersion(v)) if v == VERSION + 1));
+        let mut bad = header;
+        bad[5] = 9;
+        assert!(matches!(FrameHeader::parse(&bad), Err(ProtocolError::UnknownFormat(9))));
+        let mut bad = header;
+        bad[24..28].copy_from_slice(&(MAX_PAYLOAD_LEN + 1).to_le_bytes());
+        assert!(matches!(FrameHeader::parse(&bad), Err(ProtocolError::PayloadTooLarge(_))));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&[0; HEADER_LEN]);
+        assert!(matches!(decoder.decode(), Err(ProtocolError::BadMagic(_))));
+    }
+
+    #[test]
+    fn corrupted_payload() {
+        let (mut out, _) = frames();
+        // the second frame's only payload byte
+        out[2 * HEADER_LEN] ^= 0xff;
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&out);
+        decoder.decode().unwrap().unwrap();
+        assert!(matches!(decoder.decode(), Err(ProtocolError::ChecksumMismatch { .. })));
+        // the next frame still decodes
+        assert_eq!(decoder.decode().unwrap().unwrap().header.sequence, 2);
+    }
+
+    #[test]
+    fn truncated_body() {
+        let (out, _) = frames();
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&out[..out.len() - 1]);
+        for _ in 0..3 {
+            decoder.decode().unwrap().unwrap();
+        }
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert!(matches!(decoder.finish(), Err(ProtocolError::Truncated)));
+
+        // cut inside a header
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&out[..HEADER_LEN - 1]);
+        assert_eq!(decoder.decode().unwrap(), None);
+        assert!(matches!(decoder.finish(), Err(ProtocolError::Truncated)));
+    }
+
+    /// Hands out at most a few bytes per read, like a slow socket.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(7);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn read_frame_short_reads() {
+        let (out, headers) = frames();
+        let mut input = Trickle(&out);
+        for header in &headers {
+            assert_eq!(read_frame(&mut input).unwrap().unwrap().header, *header);
+        }
+        assert!(read_frame(&mut input).unwrap().is_none());
+
+        let mut input = Trickle(&out[..out.len() - 5]);
+        for _ in 0..3 {
+            read_frame(&mut input).unwrap().unwrap();
+        }
+        assert!(matches!(read_frame(&mut input), Err(ProtocolError::Truncated)));
+
+        let mut input = &out[..10];
+        assert!(matches!(read_frame(&mut input), Err(ProtocolError::Truncated)));
+    }
+
+    #[test]
+    fn acks() {
+        let mut acks = Vec::new();
+        for sequence in [0, 7, u32::MAX] {
+            acks.extend_from_slice(&encode_ack(sequence));
+        }
+        let mut decoder = AckDecoder::new();
+        assert_eq!(decoder.feed(&acks[..5]).unwrap(), []);
+        assert_eq!(decoder.feed(&acks[5..20]).unwrap(), [0, 7]);
+        assert_eq!(decoder.feed(&acks[20..]).unwrap(), [u32::MAX]);
+        assert!(matches!(decoder.feed(b"nopenope"), Err(ProtocolError::BadMagic(_))));
+    }
+}
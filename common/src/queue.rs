@@ -0,0 +1,319 @@
+//! Bounded frame queue between the capture and network threads.
+//!
+//! A slow network mustn't stall the camera and a slow camera mustn't stall the network, so the
+//! queue never blocks the producer: once it's full a frame gets dropped, either the oldest one
+//! waiting (lowest latency) or the new one (smoothest playback), see [`DropPolicy`].
+
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// What to give up when a frame arrives and the queue is full.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the frame that's been waiting longest, the receiver always gets the newest picture.
+    #[default]
+    DropOldest,
+    /// Drop the frame that just arrived, what's queued goes out undisturbed.
+    DropNewest,
+}
+
+impl DropPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            DropPolicy::DropOldest => "drop-oldest",
+            DropPolicy::DropNewest => "drop-newest",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [DropPolicy::DropOldest, DropPolicy::DropNewest]
+            .into_iter()
+            .find(|p| p.name() == name)
+    }
+}
+
+#[derive(Debug)]
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    dropped: u64,
+}
+
+/// Multi-producer, multi-consumer queue of at most `capacity` items. Share it with an `Arc`.
+#[derive(Debug)]
+pub struct FrameQueue<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+    capacity: usize,
+    policy: DropPolicy,
+}
+
+impl<T> FrameQueue<T> {
+    /// A `capacity` of 0 is treated as 1.
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+                dropped: 0,
+            }),
+            ready: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    // a panic while holding the lock can't leave the state half updated, so poisoning is ignored
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    /// Adds `item`, dropping one if the queue is full. Returns `false` once the queue is closed,
+    /// in which case `item` is dropped too.
+    pub fn push(&self, item: T) -> bool {
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+
+        if state.items.len() >= self.capacity {
+            state.dropped += 1;
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    state.items.pop_front();
+                }
+                DropPolicy::DropNewest => return true,
+            }
+        }
+        state.items.push_back(item);
+        drop(state);
+
+        self.ready.notify_one();
+        true
+    }
+
+    /// Takes the next item, waiting up to `timeout` for one. Returns `None` on timeout, or once
+    /// the queue is closed and empty.
+    pub fn pop(&self, timeout: Duration) -> Option<T> {
+        let state = self.lock();
+        let (mut state, _) = self
+            .ready
+            .wait_timeout_while(state, timeout, |s| s.items.is_empty() && !s.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        state.items.pop_front()
+    }
+
+    /// Stops accepting items and wakes everyone waiting. What's queued can still be taken.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Items dropped because the queue was full, since it was created.
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+
+    const POLICIES: [DropPolicy; 2] = [DropPolicy::DropOldest, DropPolicy::DropNewest];
+    const NO_WAIT: Duration = Duration::ZERO;
+
+    fn drain<T>(queue: &FrameQueue<T>) -> Vec<T> {
+        std::iter::from_fn(|| queue.pop(NO_WAIT)).collect()
+    }
+
+    #[test]
+    fn full_queue() {
+        let oldest = FrameQueue::new(3, DropPolicy::DropOldest);
+        let newest = FrameQueue::new(3, DropPolicy::DropNewest);
+        for i in 0..10 {
+            assert!(oldest.push(i));
+            assert!(newest.push(i));
+        }
+        assert_eq!((oldest.len(), oldest.dropped()), (3, 7));
+        assert_eq!((newest.len(), newest.dropped()), (3, 7));
+        assert_eq!(drain(&oldest), [7, 8, 9]);
+        assert_eq!(drain(&newest), [0, 1, 2]);
+
+        let tiny = FrameQueue::new(0, DropPolicy::DropOldest);
+        assert_eq!(tiny.capacity(), 1);
+        tiny.push(1);
+        tiny.push(2);
+        assert_eq!(drain(&tiny), [2]);
+    }
+
+    #[test]
+    fn close() {
+        for policy in POLICIES {
+            let queue = FrameQueue::new(4, policy);
+            queue.push(1);
+            queue.close();
+            assert!(queue.is_closed());
+            assert!(!queue.push(2));
+            // what was queued still comes out, then nothing without waiting
+            assert_eq!(queue.pop(Duration::from_secs(5)), Some(1));
+            let start = Instant::now();
+            assert_eq!(queue.pop(Duration::from_secs(5)), None);
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn pop_times_out() {
+        let queue = FrameQueue::<u8>::new(2, DropPolicy::DropOldest);
+        let start = Instant::now();
+        assert_eq!(queue.pop(Duration::from_millis(30)), None);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn close_wakes_blocked_consumers() {
+        for policy in POLICIES {
+            let queue = Arc::new(FrameQueue::<u32>::new(2, policy));
+            let consumers: Vec<_> = (0..3)
+                .map(|_| {
+                    let queue = queue.clone();
+                    thread::spawn(move || {
+                        let start = Instant::now();
+                        (queue.pop(Duration::from_secs(30)), start.elapsed())
+                    })
+                })
+                .collect();
+            thread::sleep(Duration::from_millis(50));
+            queue.close();
+            for consumer in consumers {
+                let (item, waited) = consumer.join().unwrap();
+                assert_eq!(item, None);
+                assert!(waited < Duration::from_secs(10), "{:?}", waited);
+            }
+        }
+    }
+
+    #[test]
+    fn push_wakes_a_blocked_consumer() {
+        let queue = Arc::new(FrameQueue::new(2, DropPolicy::DropOldest));
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.pop(Duration::from_secs(30)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.push(7);
+        assert_eq!(consumer.join().unwrap(), Some(7));
+    }
+
+    /// Several producers and consumers at once: nothing is duplicated or reordered, nothing goes
+    /// missing that isn't counted as dropped, and the queue never grows past its capacity.
+    fn contention(policy: DropPolicy) {
+        const PRODUCERS: u64 = 4;
+        const ITEMS: u64 = 20_000;
+        const CAPACITY: usize = 3;
+        let queue = Arc::new(FrameQueue::new(CAPACITY, policy));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let watcher = {
+            let (queue, done) = (queue.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    assert!(queue.len() <= CAPACITY);
+                    thread::yield_now();
+                }
+            })
+        };
+        let consumers: Vec<_> = (0..3)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut seen = Vec::new();
+                    while let Some(item) = queue.pop(Duration::from_secs(10)) {
+                        seen.push(item);
+                    }
+                    seen
+                })
+            })
+            .collect();
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..ITEMS {
+                        assert!(queue.push((producer, i)));
+                    }
+                })
+            })
+            .collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        queue.close();
+        let mut all = Vec::new();
+        for consumer in consumers {
+            let seen = consumer.join().unwrap();
+            // every consumer gets each producer's items in the order they were pushed
+            for producer in 0..PRODUCERS {
+                let order: Vec<u64> =
+                    seen.iter().filter(|i| i.0 == producer).map(|i| i.1).collect();
+                assert!(order.windows(2).all(|w| w[0] < w[1]), "{:?}", policy);
+            }
+            all.extend(seen);
+        }
+        done.store(true, Ordering::Relaxed);
+        watcher.join().unwrap();
+
+        let received = all.len() as u64;
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len() as u64, received, "{:?} handed something out twice", policy);
+        assert_eq!(received + queue.dropped(), PRODUCERS * ITEMS, "{:?}", policy);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn contention_drop_oldest() {
+        contention(DropPolicy::DropOldest);
+    }
+
+    #[test]
+    fn contention_drop_newest() {
+        contention(DropPolicy::DropNewest);
+    }
+
+    #[test]
+    fn names() {
+        for policy in POLICIES {
+            assert_eq!(DropPolicy::from_name(policy.name()), Some(policy));
+        }
+        assert_eq!(DropPolicy::from_name("drop-everything"), None);
+    }
+}
@@ -0,0 +1,243 @@
+//! When to try reconnecting after the server went away.
+//!
+//! Waits grow exponentially up to a cap, with some random jitter so a room full of consoles
+//! doesn't hammer a restarted server in lockstep. Nothing in here reads the clock or touches the
+//! network: the caller passes the time in, asks [`Reconnect::poll`] what to do and reports back
+//! how the attempt went.
+
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Backoff {
+    /// Wait before the first attempt.
+    pub initial: Duration,
+    /// Longest wait between attempts.
+    pub max: Duration,
+    /// How much longer every wait is than the one before.
+    pub factor: u32,
+    /// Fraction of every wait that's randomised, 0.25 means ±25%.
+    pub jitter: f32,
+    /// Attempts before giving up, `None` to keep trying.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+            factor: 2,
+            jitter: 0.25,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait before attempt `attempt` (0 based), without jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.factor.max(1).saturating_pow(attempt);
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Poll {
+    /// Nothing to do yet, the next attempt is this far away.
+    Wait(Duration),
+    /// Time to connect, report the outcome with [`Reconnect::failed`].
+    Attempt,
+    /// Out of attempts.
+    GiveUp,
+}
+
+/// One run of reconnect attempts, from losing the connection to getting it back or giving up.
+#[derive(Clone, Debug)]
+pub struct Reconnect {
+    backoff: Backoff,
+    attempt: u32,
+    next: Instant,
+    rng: u64,
+}
+
+impl Reconnect {
+    /// Starts counting down to the first attempt. `seed` drives the jitter.
+    pub fn new(backoff: Backoff, now: Instant, seed: u64) -> Self {
+        let mut reconnect = Self {
+            backoff,
+            attempt: 0,
+            next: now,
+            rng: scramble(seed),
+        };
+        reconnect.next = now + reconnect.jittered(0);
+        reconnect
+    }
+
+    /// Number of attempts that failed so far.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Time left until the next attempt, for the countdown.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.next.saturating_duration_since(now)
+    }
+
+    pub fn poll(&self, now: Instant) -> Poll {
+        if self
+            .backoff
+            .max_attempts
+            .is_some_and(|max| self.attempt >= max)
+        {
+            Poll::GiveUp
+        } else if now >= self.next {
+            Poll::Attempt
+        } else {
+            Poll::Wait(self.next - now)
+        }
+    }
+
+    /// The attempt [`Reconnect::poll`] asked for didn't work, schedules the next one.
+    pub fn failed(&mut self, now: Instant) {
+        self.attempt += 1;
+        self.next = now + self.jittered(self.attempt);
+    }
+
+    fn jittered(&mut self, attempt: u32) -> Duration {
+        let delay = self.backoff.delay(attempt);
+        let jitter = self.backoff.jitter.clamp(0.0, 1.0);
+
+        // xorshift64, plenty for spreading out retries
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let unit = (self.rng >> 11) as f32 / (1u64 << 53) as f32;
+
+        delay.mul_f32(1.0 + jitter * (unit * 2.0 - 1.0))
+    }
+}
+
+/// Spreads the bits of `seed` around (the splitmix64 finaliser). Xorshift needs a while to get
+/// going from a small seed like a console ID, and it gets stuck on 0.
+pub fn scramble(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) | 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f32) -> Duration {
+        Duration::from_secs_f32(s)
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_cap() {
+        let backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|a| backoff.delay(a).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30]);
+        // no overflow however long it goes on
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+
+        let flat = Backoff {
+            factor: 0,
+            ..backoff
+        };
+        assert_eq!(flat.delay(5), backoff.initial);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let backoff = Backoff {
+            max_attempts: None,
+            ..Backoff::default()
+        };
+        let start = Instant::now();
+        for seed in 0..50 {
+            let mut reconnect = Reconnect::new(backoff, start, seed);
+            let mut now = start;
+            let mut waits = Vec::new();
+            for attempt in 0..12 {
+                let wait = reconnect.remaining(now);
+                let delay = backoff.delay(attempt).as_secs_f32();
+                assert!(wait >= secs(delay * 0.75) && wait <= secs(delay * 1.25), "{:?}", wait);
+                waits.push(wait);
+
+                now += wait;
+                assert_eq!(reconnect.poll(now), Poll::Attempt);
+                reconnect.failed(now);
+            }
+            // capped waits still differ, or consoles would stay in step
+            assert!(waits[6..].windows(2).any(|w| w[0] != w[1]), "{:?}", waits);
+        }
+
+        let steady = Backoff {
+            jitter: 0.0,
+            ..backoff
+        };
+        let reconnect = Reconnect::new(steady, start, 42);
+        assert_eq!(reconnect.remaining(start), steady.initial);
+    }
+
+    #[test]
+    fn seeds_spread_out() {
+        let start = Instant::now();
+        let first: Vec<Duration> = (0..20)
+            .map(|seed| Reconnect::new(Backoff::default(), start, seed).remaining(start))
+            .collect();
+        let mut distinct = first.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() > 15, "{:?}", first);
+    }
+
+    #[test]
+    fn waits_then_attempts() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        };
+        let start = Instant::now();
+        let mut reconnect = Reconnect::new(backoff, start, 1);
+        assert_eq!(reconnect.poll(start), Poll::Wait(secs(1.0)));
+        assert_eq!(reconnect.poll(start + secs(0.25)), Poll::Wait(secs(0.75)));
+        assert_eq!(reconnect.poll(start + secs(1.0)), Poll::Attempt);
+        // still due until the outcome is reported
+        assert_eq!(reconnect.poll(start + secs(5.0)), Poll::Attempt);
+
+        // the next wait counts from when the attempt failed
+        reconnect.failed(start + secs(5.0));
+        assert_eq!(reconnect.attempt(), 1);
+        assert_eq!(reconnect.poll(start + secs(6.0)), Poll::Wait(secs(1.0)));
+        assert_eq!(reconnect.remaining(start + secs(8.0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn gives_up() {
+        let backoff = Backoff {
+            max_attempts: Some(3),
+            ..Backoff::default()
+        };
+        let mut now = Instant::now();
+        let mut reconnect = Reconnect::new(backoff, now, 7);
+        for attempt in 0..3 {
+            assert_eq!(reconnect.attempt(), attempt);
+            now += reconnect.remaining(now);
+            assert_eq!(reconnect.poll(now), Poll::Attempt);
+            reconnect.failed(now);
+        }
+        assert_eq!(reconnect.poll(now), Poll::GiveUp);
+        assert_eq!(reconnect.poll(now + Duration::from_secs(3600)), Poll::GiveUp);
+
+        let never = Backoff {
+            max_attempts: Some(0),
+            ..backoff
+        };
+        assert_eq!(Reconnect::new(never, now, 7).poll(now), Poll::GiveUp);
+    }
+}
@@ -0,0 +1,423 @@
+//! Recording to the SD card without a server, in [`avi`](crate::avi) or [`y4m`](crate::y4m)
+//! files.
+//!
+//! Long recordings get split into numbered files (`REC_0001.AVI`, `REC_0002.AVI`, ...) once one
+//! gets too big or too long, so nothing runs into FAT32's 4 GB limit and a pulled SD card only
+//! costs the last file. The clock is passed in, like everywhere else.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::avi::AviWriter;
+use crate::protocol::PixelFormat;
+use crate::y4m::Y4mWriter;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Container {
+    /// Motion JPEG, small enough to record for hours.
+    #[default]
+    Avi,
+    /// Raw YUV 4:2:2, nothing lost but huge.
+    Y4m,
+}
+
+impl Container {
+    pub const ALL: [Container; 2] = [Container::Avi, Container::Y4m];
+
+    /// What it's called in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Container::Avi => "avi",
+            Container::Y4m => "y4m",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Container> {
+        Container::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Avi => "AVI",
+            Container::Y4m => "Y4M",
+        }
+    }
+
+    /// What frames have to be handed to [`Recorder::write`] as.
+    pub fn format(self) -> PixelFormat {
+        match self {
+            Container::Avi => PixelFormat::Jpeg,
+            Container::Y4m => PixelFormat::Yuv422,
+        }
+    }
+}
+
+/// When to start the next file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_bytes: u64,
+    /// `None` to only go by size.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // old AVI readers choke past 1 GB
+            max_bytes: 1 << 30,
+            max_duration: Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+enum Writer {
+    Avi(AviWriter<BufWriter<File>>),
+    Y4m(Y4mWriter<BufWriter<File>>),
+}
+
+impl Writer {
+    fn len(&self) -> u64 {
+        match self {
+            Writer::Avi(avi) => avi.len(),
+            Writer::Y4m(y4m) => y4m.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Writer::Avi(avi) => avi.is_empty(),
+            Writer::Y4m(y4m) => y4m.is_empty(),
+        }
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Writer::Avi(avi) => avi.write_frame(frame),
+            Writer::Y4m(y4m) => y4m.write_frame(frame),
+        }
+    }
+
+    fn finish(self, duration: Duration) -> io::Result<()> {
+        let file = match self {
+            Writer::Avi(avi) => avi.finish(duration)?,
+            Writer::Y4m(y4m) => y4m.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+/// Writes frames to numbered files in one directory, starting a new one whenever the
+/// [`Limits`] say so.
+pub struct Recorder {
+    dir: PathBuf,
+    container: Container,
+    width: u16,
+    height: u16,
+    fps: u32,
+    limits: Limits,
+    // the file being written and when it was started
+    current: Option<(Writer, Instant)>,
+    files: Vec<PathBuf>,
+    frames: u64,
+}
+
+impl Recorder {
+    /// Opens the first file in `dir`, creating it if needed. `width` and `height` are the frame
+    /// size, `fps` the rate to play back at where the container can't work it out.
+    pub fn start(
+        dir: &Path,
+        container: Container,
+        width: u16,
+        height: u16,
+        fps: u32,
+        limits: Limits,
+        now: Instant,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut recorder = Self {
+            dir: dir.to_owned(),
+            container,
+            width,
+            height,
+            fps,
+            limits,
+            current: None,
+            files: Vec::new(),
+            frames: 0,
+        };
+        recorder.open(now)?;
+        Ok(recorder)
+    }
+
+    pub fn container(&self) -> Container {
+        self.container
+    }
+
+    /// Every file written to so far, the current one last.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Frames written over all files.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Appends `frame`, a JPEG or a YUYV capture depending on the [`Container`], moving on to
+    /// the next file first if this one is full.
+    pub fn write(&mut self, frame: &[u8], now: Instant) -> io::Result<()> {
+        let full = match &self.current {
+            Some((writer, started)) => {
+                !writer.is_empty()
+                    && (writer.len() + frame.len() as u64 + 32 > self.limits.max_bytes
+                        || self
+                            .limits
+                            .max_duration
+                            .is_some_and(|max| now.saturating_duration_since(*started) >= max))
+            }
+            None => true,
+        };
+        if full {
+            self.close(now)?;
+            self.open(now)?;
+        }
+
+        if let Some((writer, _)) = &mut self.current {
+            writer.write_frame(frame)?;
+            self.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// Finishes the current file, returning every file written.
+    pub fn finish(mut self, now: Instant) -> io::Result<Vec<PathBuf>> {
+        self.close(now)?;
+        Ok(std::mem::take(&mut self.files))
+    }
+
+    fn open(&mut self, now: Instant) -> io::Result<()> {
+        let number = next_number(&self.dir, "REC_", self.container.extension())?;
+        let path = self
+            .dir
+            .join(format!("REC_{:04}.{}", number, self.container.extension()));
+        let file = BufWriter::new(File::create(&path)?);
+        let writer = match self.container {
+            Container::Avi => Writer::Avi(AviWriter::new(file, self.width, self.height, self.fps)?),
+            Container::Y4m => Writer::Y4m(Y4mWriter::new(file, self.width, self.height, self.fps)?),
+        };
+        self.current = Some((writer, now));
+        self.files.push(path);
+        Ok(())
+    }
+
+    fn close(&mut self, now: Instant) -> io::Result<()> {
+        match self.current.take() {
+            Some((writer, started)) => writer.finish(now.saturating_duration_since(started)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Anything left open still gets its index, best effort.
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.close(Instant::now());
+    }
+}
+
+/// One more than the highest `<prefix><number>.<extension>` in `dir`, ignoring case, 1 if there
+/// are none (or no `dir`).
+pub fn next_number(dir: &Path, prefix: &str, extension: &str) -> io::Result<u32> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(1),
+        Err(e) => return Err(e),
+    };
+
+    let mut highest = 0;
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if let Some(number) = numbered(name, prefix, extension) {
+            highest = highest.max(number);
+        }
+    }
+    Ok(highest + 1)
+}
+
+/// The number in `<prefix><number>.<extension>`.
+pub fn numbered(name: &str, prefix: &str, extension: &str) -> Option<u32> {
+    let (stem, ext) = name.rsplit_once('.')?;
+    if !ext.eq_ignore_ascii_case(extension) || stem.len() <= prefix.len() {
+        return None;
+    }
+    let start = stem.get(..prefix.len())?;
+    let digits = &stem[prefix.len()..];
+    if !start.eq_ignore_ascii_case(prefix) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avi::HEADER_LEN;
+    use crate::y4m;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("ctr-camera-record-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn u32_at(b: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+    }
+
+    fn names(files: &[PathBuf]) -> Vec<&str> {
+        files.iter().map(|f| f.file_name().unwrap().to_str().unwrap()).collect()
+    }
+
+    /// Total frames and µs per frame from the `avih` of a finished AVI.
+    fn avi_frames(path: &Path) -> (u32, u32) {
+        let b = fs::read(path).unwrap();
+        assert_eq!(&b[..4], b"RIFF");
+        assert_eq!(u32_at(&b, 4) as usize, b.len() - 8);
+        (u32_at(&b, 48), u32_at(&b, 32))
+    }
+
+    #[test]
+    fn rolls_over_at_max_bytes() {
+        let dir = temp_dir("bytes");
+        let now = Instant::now();
+        // an empty file is the headers and an empty index, every 100 byte frame adds a chunk and
+        // an index entry, and the recorder keeps some slack: room for three
+        let empty = HEADER_LEN + 8;
+        let per_frame = 8 + 100 + 16;
+        let limits = Limits {
+            max_bytes: empty + 3 * per_frame + 50,
+            max_duration: None,
+        };
+        let mut recorder = Recorder::start(&dir, Container::Avi, 64, 48, 30, limits, now).unwrap();
+        for _ in 0..7 {
+            recorder.write(&[1; 100], now).unwrap();
+        }
+        assert_eq!(recorder.frames(), 7);
+        let files = recorder.finish(now).unwrap();
+
+        assert_eq!(names(&files), ["REC_0001.AVI", "REC_0002.AVI", "REC_0003.AVI"]);
+        let frames: Vec<u32> = files.iter().map(|f| avi_frames(f).0).collect();
+        assert_eq!(frames, [3, 3, 1]);
+        for file in &files {
+            assert!(fs::metadata(file).unwrap().len() <= limits.max_bytes);
+        }
+
+        // a new recording carries on from the last number
+        let recorder = Recorder::start(&dir, Container::Avi, 64, 48, 30, limits, now).unwrap();
+        assert_eq!(names(recorder.files()), ["REC_0004.AVI"]);
+        drop(recorder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_frames_get_a_file_each() {
+        let dir = temp_dir("oversized");
+        let now = Instant::now();
+        let limits = Limits {
+            max_bytes: 10,
+            max_duration: None,
+        };
+        let mut recorder = Recorder::start(&dir, Container::Avi, 64, 48, 30, limits, now).unwrap();
+        for _ in 0..3 {
+            recorder.write(&[1; 100], now).unwrap();
+        }
+        let files = recorder.finish(now).unwrap();
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|f| avi_frames(f).0 == 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rolls_over_at_max_duration() {
+        let dir = temp_dir("duration");
+        let start = Instant::now();
+        let limits = Limits {
+            max_bytes: u64::MAX,
+            max_duration: Some(Duration::from_secs(10)),
+        };
+        let mut recorder = Recorder::start(&dir, Container::Y4m, 4, 2, 30, limits, start).unwrap();
+        assert_eq!(recorder.container(), Container::Y4m);
+        for second in 0..25 {
+            recorder.write(&[0; 16], start + Duration::from_secs(second)).unwrap();
+        }
+        let files = recorder.finish(start + Duration::from_secs(25)).unwrap();
+        assert_eq!(names(&files), ["REC_0001.Y4M", "REC_0002.Y4M", "REC_0003.Y4M"]);
+
+        let header = y4m::header(4, 2, 30);
+        let frame_len = 6 + 16;
+        for (file, frames) in files.iter().zip([10, 10, 5]) {
+            let b = fs::read(file).unwrap();
+            assert!(b.starts_with(header.as_bytes()));
+            assert_eq!(b.len(), header.len() + frames * frame_len, "{:?}", file);
+            let mut frames = b[header.len()..].chunks_exact(frame_len);
+            assert!(frames.all(|f| f.starts_with(b"FRAME\n")));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn avi_rate_from_the_clock() {
+        let dir = temp_dir("rate");
+        let start = Instant::now();
+        let limits = Limits {
+            max_bytes: u64::MAX,
+            max_duration: Some(Duration::from_secs(1)),
+        };
+        let mut recorder = Recorder::start(&dir, Container::Avi, 64, 48, 30, limits, start).unwrap();
+        // 10 fps rather than the 30 asked for
+        for frame in 0..15 {
+            recorder.write(&[1; 10], start + Duration::from_millis(frame * 100)).unwrap();
+        }
+        let files = recorder.finish(start + Duration::from_millis(1500)).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(avi_frames(&files[0]), (10, 100_000));
+        assert_eq!(avi_frames(&files[1]), (5, 100_000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn numbering() {
+        assert_eq!(numbered("REC_0012.AVI", "REC_", "AVI"), Some(12));
+        assert_eq!(numbered("rec_0003.avi", "REC_", "AVI"), Some(3));
+        assert_eq!(numbered("REC_0003.Y4M", "REC_", "AVI"), None);
+        assert_eq!(numbered("REC_.AVI", "REC_", "AVI"), None);
+        assert_eq!(numbered("REC_12a.AVI", "REC_", "AVI"), None);
+        assert_eq!(numbered("é.AVI", "REC_", "AVI"), None);
+
+        let dir = temp_dir("numbers");
+        assert_eq!(next_number(&dir, "REC_", "AVI").unwrap(), 1);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["REC_0007.AVI", "REC_0002.avi", "REC_0009.Y4M", "OTHER_0020.AVI"] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        assert_eq!(next_number(&dir, "REC_", "AVI").unwrap(), 8);
+        assert_eq!(next_number(&dir, "REC_", "Y4M").unwrap(), 10);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn containers() {
+        for container in Container::ALL {
+            assert_eq!(Container::from_name(container.name()), Some(container));
+        }
+        assert_eq!(Container::from_name("mkv"), None);
+        assert_eq!(Container::Avi.format(), PixelFormat::Jpeg);
+        assert_eq!(Container::Y4m.format(), PixelFormat::Yuv422);
+    }
+}
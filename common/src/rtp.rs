@@ -0,0 +1,217 @@
+//! RTP packetisation of JPEG frames, RFC 2435.
+//!
+//! Only what our encoder produces is supported: baseline, 4:2:2 (RTP/JPEG type 0), standard
+//! Huffman tables and no restart markers. The quantization tables are sent in-band (Q = 255) with
+//! the first packet of every frame.
+
+use crate::jpeg;
+
+/// Static payload type for JPEG, RFC 3551.
+pub const PAYLOAD_TYPE: u8 = 26;
+
+/// RTP video clock.
+pub const CLOCK_RATE: u32 = 90_000;
+
+pub const DEFAULT_PACKET_LEN: usize = 1400;
+
+const RTP_HEADER_LEN: usize = 12;
+const JPEG_HEADER_LEN: usize = 8;
+const QUANT_HEADER_LEN: usize = 4;
+
+#[derive(Debug)]
+pub struct JpegPacketizer {
+    ssrc: u32,
+    sequence: u16,
+    packet_len: usize,
+}
+
+impl JpegPacketizer {
+    pub fn new(ssrc: u32) -> Self {
+        Self {
+            ssrc,
+            sequence: 0,
+            packet_len: DEFAULT_PACKET_LEN,
+        }
+    }
+
+    pub fn ssrc(&self) -> u32 {
+        self.ssrc
+    }
+
+    /// Sequence number the next packet will carry, for the `RTP-Info` header.
+    pub fn next_sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Splits one complete JFIF image (as made by [`JpegEncoder`](crate::jpeg::JpegEncoder)) into
+    /// RTP packets. `tables` are the luma and chroma quantization tables in zigzag order.
+    ///
+    /// Returns `None` if `jpeg` isn't something we can packetise.
+    pub fn packetize(
+        &mut self,
+        jpeg: &[u8],
+        tables: (&[u8; 64], &[u8; 64]),
+        width: u16,
+        height: u16,
+        timestamp: u32,
+    ) -> Option<Vec<Vec<u8>>> {
+        let scan = jpeg::scan_data(jpeg)?;
+
+        // dimensions are sent in units of 8 pixels, in a single byte
+        if width == 0 || height == 0 || width > 2040 || height > 2040 {
+            return None;
+        }
+        if width & 7 != 0 || height & 7 != 0 {
+            return None;
+        }
+
+        let mut packets = Vec::new();
+        let mut offset = 0;
+        while offset < scan.len() || packets.is_empty() {
+            let first = offset == 0;
+            let mut room = self.packet_len - RTP_HEADER_LEN - JPEG_HEADER_LEN;
+            if first {
+                room -= QUANT_HEADER_LEN + 128;
+            }
+            let end = (offset + room).min(scan.len());
+            let last = end == scan.len();
+
+            let mut packet = Vec::with_capacity(self.packet_len);
+
+            // RTP header: V=2, no padding/extension/CSRC
+            packet.push(0x80);
+            packet.push(PAYLOAD_TYPE | if last { 0x80 } else { 0 });
+            packet.extend_from_slice(&self.sequence.to_be_bytes());
+            packet.extend_from_slice(&timestamp.to_be_bytes());
+            packet.extend_from_slice(&self.ssrc.to_be_bytes());
+            self.sequence = self.sequence.wrapping_add(1);
+
+            // JPEG header: type-specific, 24 bit fragment offset, type 0 (4:2:2), Q, size
+            packet.push(0);
+            packet.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            packet.push(0);
+            packet.push(255);
+            packet.push((width / 8) as u8);
+            packet.push((height / 8) as u8);
+
+            if first {
+                // MBZ, 8 bit precision for both tables, length
+                packet.extend_from_slice(&[0, 0, 0, 128]);
+                packet.extend_from_slice(tables.0);
+                packet.extend_from_slice(tables.1);
+            }
+
+            packet.extend_from_slice(&scan[offset..end]);
+            packets.push(packet);
+            offset = end;
+        }
+
+        Some(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::JpegEncoder;
+    use crate::stream::{FrameSource, TestPattern};
+
+    fn jpeg(width: u16, height: u16) -> (Vec<u8>, JpegEncoder) {
+        let mut pattern = TestPattern::new(width, height);
+        let mut yuyv = vec![0; pattern.frame_size()];
+        pattern.capture(&mut yuyv).unwrap();
+        let encoder = JpegEncoder::new(80);
+        let mut out = Vec::new();
+        encoder.encode_yuyv(&yuyv, width as usize, height as usize, &mut out).unwrap();
+        (out, encoder)
+    }
+
+    struct Packet<'a> {
+        marker: bool,
+        sequence: u16,
+        timestamp: u32,
+        ssrc: u32,
+        offset: usize,
+        jpeg_header: &'a [u8],
+        tables: Option<&'a [u8]>,
+        data: &'a [u8],
+    }
+
+    fn parse(packet: &[u8]) -> Packet<'_> {
+        assert_eq!(packet[0], 0x80);
+        assert_eq!(packet[1] & 0x7f, PAYLOAD_TYPE);
+        let offset = u32::from_be_bytes([0, packet[13], packet[14], packet[15]]) as usize;
+        let (tables, data) = if offset == 0 {
+            assert_eq!(&packet[20..24], &[0, 0, 0, 128]);
+            (Some(&packet[24..152]), &packet[152..])
+        } else {
+            (None, &packet[20..])
+        };
+        Packet {
+            marker: packet[1] & 0x80 != 0,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+            ssrc: u32::from_be_bytes(packet[8..12].try_into().unwrap()),
+            offset,
+            jpeg_header: &packet[12..20],
+            tables,
+            data,
+        }
+    }
+
+    #[test]
+    fn fragments_rfc_2435() {
+        let (image, encoder) = jpeg(320, 240);
+        let scan = jpeg::scan_data(&image).unwrap();
+
+        let mut packetizer = JpegPacketizer::new(0x1234_5678);
+        packetizer.packet_len = 500;
+        packetizer.sequence = u16::MAX - 1;
+        let packets = packetizer.packetize(&image, encoder.quant_tables(), 320, 240, 9000).unwrap();
+        assert!(packets.len() > 3);
+        assert!(packets.iter().all(|p| p.len() <= 500));
+
+        let mut data = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            let packet = parse(packet);
+            assert_eq!(packet.sequence, (u16::MAX - 1).wrapping_add(i as u16));
+            assert_eq!(packet.timestamp, 9000);
+            assert_eq!(packet.ssrc, 0x1234_5678);
+            // only the last packet of the frame has the marker bit
+            assert_eq!(packet.marker, i == packets.len() - 1);
+            // type-specific 0, type 0, Q 255, 40 x 30 blocks of 8
+            assert_eq!(packet.jpeg_header[0], 0);
+            assert_eq!(&packet.jpeg_header[4..], &[0, 255, 40, 30]);
+            // in-band tables only up front
+            assert_eq!(packet.tables.is_some(), i == 0);
+            assert_eq!(packet.offset, data.len());
+            data.extend_from_slice(packet.data);
+        }
+        let (luma, chroma) = encoder.quant_tables();
+        assert_eq!(parse(&packets[0]).tables.unwrap(), [&luma[..], &chroma[..]].concat());
+        assert_eq!(data, scan);
+        assert_eq!(packetizer.next_sequence(), (u16::MAX - 1).wrapping_add(packets.len() as u16));
+    }
+
+    #[test]
+    fn small_frame_is_one_packet() {
+        let (image, encoder) = jpeg(16, 8);
+        let packets = JpegPacketizer::new(1).packetize(&image, encoder.quant_tables(), 16, 8, 0).unwrap();
+        assert_eq!(packets.len(), 1);
+        let packet = parse(&packets[0]);
+        assert!(packet.marker && packet.tables.is_some());
+        assert_eq!(packet.data, jpeg::scan_data(&image).unwrap());
+    }
+
+    #[test]
+    fn refuses_what_rtp_jpeg_cant_carry() {
+        let (image, encoder) = jpeg(20, 8);
+        let mut packetizer = JpegPacketizer::new(1);
+        // not a multiple of 8
+        assert!(packetizer.packetize(&image, encoder.quant_tables(), 20, 8, 0).is_none());
+        assert!(packetizer.packetize(&image, encoder.quant_tables(), 0, 8, 0).is_none());
+        assert!(packetizer.packetize(&image, encoder.quant_tables(), 2048, 8, 0).is_none());
+        assert!(packetizer.packetize(b"not a jpeg", encoder.quant_tables(), 16, 8, 0).is_none());
+        assert_eq!(packetizer.next_sequence(), 0);
+    }
+}
@@ -0,0 +1,1007 @@
+//! RTSP server, so VLC, ffmpeg or an NVR can open `rtsp://<console>/cam`.
+//!
+//! Implements the subset of RFC 2326 those clients use (OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE,
+//! TEARDOWN and GET_PARAMETER as keepalive), with RTP/JPEG over either UDP or interleaved in the
+//! RTSP connection. Sessions live as long as their RTSP connection. Like the HTTP server everything
+//! is non-blocking and meant to be polled from the main loop.
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::reconnect::scramble;
+use crate::rtp::{JpegPacketizer, CLOCK_RATE, PAYLOAD_TYPE};
+
+pub const DEFAULT_PORT: u16 = 554;
+
+/// Path of the only stream we serve.
+pub const PATH: &str = "/cam";
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+const SESSION_TIMEOUT: u32 = 60;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RtspError {
+    #[error("Malformed request")]
+    Malformed,
+    #[error("Request too large")]
+    TooLarge,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtspRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RtspRequest {
+    /// Case insensitive header lookup.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn cseq(&self) -> Option<&str> {
+        self.header("CSeq")
+    }
+
+    /// Session id without the `;timeout=` part.
+    pub fn session(&self) -> Option<&str> {
+        self.header("Session")
+            .map(|s| s.split(';').next().unwrap_or(s).trim())
+    }
+
+    /// Path part of the URI, `rtsp://host:port/cam/track0` gives `/cam/track0`.
+    pub fn path(&self) -> &str {
+        let uri = self.uri.as_str();
+        match uri.strip_prefix("rtsp://") {
+            Some(rest) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+            None => uri,
+        }
+    }
+}
+
+/// Parses one request from the start of `buf`.
+///
+/// Returns the request and how many bytes it took, or `None` if it isn't complete yet.
+pub fn parse_request(buf: &[u8]) -> Result<Option<(RtspRequest, usize)>, RtspError> {
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => end,
+        None if buf.len() > MAX_REQUEST_LEN => return Err(RtspError::TooLarge),
+        None => return Ok(None),
+    };
+
+    let head = std::str::from_utf8(&buf[..end]).map_err(|_| RtspError::Malformed)?;
+    let mut lines = head.split("\r\n");
+
+    let mut parts = lines.next().ok_or(RtspError::Malformed)?.split_whitespace();
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(u), Some(v)) => (m, u, v),
+        _ => return Err(RtspError::Malformed),
+    };
+    if version != "RTSP/1.0" {
+        return Err(RtspError::Malformed);
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(RtspError::Malformed)?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let request = RtspRequest {
+        method: method.to_owned(),
+        uri: uri.to_owned(),
+        headers,
+    };
+
+    // we don't expect bodies, but skip them if a client sends one
+    let body: usize = request
+        .header("Content-Length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let len = end + 4 + body;
+    if buf.len() < len {
+        return Ok(None);
+    }
+
+    Ok(Some((request, len)))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransportSpec {
+    Udp { rtp_port: u16, rtcp_port: u16 },
+    Interleaved { rtp_channel: u8, rtcp_channel: u8 },
+}
+
+/// Picks the first transport from a `Transport` header that we can do.
+pub fn parse_transport(header: &str) -> Option<TransportSpec> {
+    for spec in header.split(',') {
+        let mut params = spec.split(';').map(str::trim);
+        let protocol = params.next()?;
+
+        let pair = |value: &str| -> Option<(u16, u16)> {
+            match value.split_once('-') {
+                Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+                None => {
+                    let a: u16 = value.parse().ok()?;
+                    Some((a, a.checked_add(1)?))
+                }
+            }
+        };
+
+        let params: Vec<&str> = params.collect();
+        let find = |key: &str| {
+            params
+                .iter()
+                .find_map(|p| p.strip_prefix(key)?.strip_prefix('='))
+        };
+
+        if params.contains(&"multicast") {
+            continue;
+        }
+
+        match protocol {
+            "RTP/AVP" | "RTP/AVP/UDP" => {
+                if let Some((rtp_port, rtcp_port)) = find("client_port").and_then(pair) {
+                    return Some(TransportSpec::Udp {
+                        rtp_port,
+                        rtcp_port,
+                    });
+                }
+            }
+            "RTP/AVP/TCP" => {
+                let (rtp, rtcp) = find("interleaved").and_then(pair).unwrap_or((0, 1));
+                if rtp <= u8::MAX as u16 && rtcp <= u8::MAX as u16 {
+                    return Some(TransportSpec::Interleaved {
+                        rtp_channel: rtp as u8,
+                        rtcp_channel: rtcp as u8,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Session description for the camera stream.
+pub fn sdp(host: IpAddr, session_id: u64, fps: u32) -> String {
+    let mut sdp = String::new();
+    let _ = write!(
+        sdp,
+        "v=0\r\n\
+         o=- {id} 1 IN {family} {host}\r\n\
+         s=ctr-camera-rs\r\n\
+         c=IN {family} {any}\r\n\
+         t=0 0\r\n\
+         a=control:*\r\n\
+         m=video 0 RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} JPEG/{clock}\r\n\
+         a=framerate:{fps}\r\n\
+         a=control:track0\r\n",
+        id = session_id,
+        family = if host.is_ipv4() { "IP4" } else { "IP6" },
+        host = host,
+        any = if host.is_ipv4() { "0.0.0.0" } else { "::" },
+        pt = PAYLOAD_TYPE,
+        clock = CLOCK_RATE,
+        fps = fps,
+    );
+    sdp
+}
+
+/// Builds a response. `cseq` is echoed back as required.
+pub fn response(
+    status: &str,
+    cseq: Option<&str>,
+    headers: &[(&str, String)],
+    body: Option<(&str, &str)>,
+) -> Vec<u8> {
+    let mut out = format!("RTSP/1.0 {}\r\n", status);
+    if let Some(cseq) = cseq {
+        let _ = write!(out, "CSeq: {}\r\n", cseq);
+    }
+    out.push_str("Server: ctr-camera-rs\r\n");
+    for (name, value) in headers {
+        let _ = write!(out, "{}: {}\r\n", name, value);
+    }
+    match body {
+        Some((content_type, body)) => {
+            let _ = write!(
+                out,
+                "Content-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                content_type,
+                body.len(),
+                body
+            );
+        }
+        None => out.push_str("\r\n"),
+    }
+    out.into_bytes()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Target {
+    Udp(SocketAddr),
+    Interleaved(u8),
+}
+
+#[derive(Debug)]
+struct Session {
+    id: String,
+    target: Target,
+    playing: bool,
+}
+
+struct Client {
+    stream: TcpStream,
+    input: Vec<u8>,
+    pending: Vec<u8>,
+    written: usize,
+    session: Option<Session>,
+}
+
+impl Client {
+    fn is_playing(&self) -> bool {
+        self.session.as_ref().is_some_and(|s| s.playing)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.written >= self.pending.len()
+    }
+
+    fn queue(&mut self, data: &[u8]) {
+        if self.is_idle() {
+            self.pending.clear();
+            self.written = 0;
+        }
+        self.pending.extend_from_slice(data);
+    }
+
+    /// Returns `false` once the client should be dropped.
+    fn flush(&mut self) -> bool {
+        while !self.is_idle() {
+            match self.stream.write(&self.pending[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Whether `path` is our stream or its track: [`PATH`], with a `/`, `/track0` (what the SDP
+/// says) or `/trackID=<n>` (what some clients make up) after it.
+fn serves(path: &str) -> bool {
+    match path.strip_prefix(PATH) {
+        Some("" | "/" | "/track0") => true,
+        Some(rest) => rest
+            .strip_prefix("/trackID=")
+            .is_some_and(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())),
+        None => false,
+    }
+}
+
+/// Non-blocking RTSP server with a single stream at [`PATH`].
+pub struct RtspServer {
+    listener: TcpListener,
+    rtp: UdpSocket,
+    clients: Vec<Client>,
+    packetizer: JpegPacketizer,
+    started: Instant,
+    next_session: u64,
+    fps: u32,
+}
+
+impl RtspServer {
+    /// `fps` is only advertised in the SDP, frames go out whenever they're pushed.
+    pub fn bind(addr: SocketAddr, fps: u32) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        let rtp = UdpSocket::bind(SocketAddr::new(addr.ip(), 0))?;
+        rtp.set_nonblocking(true)?;
+
+        // different on every run, so SSRCs and session ids can't be guessed from the last one
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let seed = scramble(now ^ addr.port() as u64);
+
+        Ok(Self {
+            listener,
+            rtp,
+            clients: Vec::new(),
+            packetizer: JpegPacketizer::new((seed >> 32) as u32),
+            started: Instant::now(),
+            next_session: seed,
+            fps,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Whether any client is playing, so the caller only captures when needed.
+    pub fn wants_frame(&self) -> bool {
+        self.clients.iter().any(Client::is_playing)
+    }
+
+    /// Number of sessions currently playing.
+    pub fn viewers(&self) -> usize {
+        self.clients.iter().filter(|c| c.is_playing()).count()
+    }
+
+    /// Accepts new clients and answers their requests. Call once per frame.
+    pub fn poll(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    let _ = stream.set_nodelay(true);
+                    self.clients.push(Client {
+                        stream,
+                        input: Vec::new(),
+                        pending: Vec::new(),
+                        written: 0,
+                        session: None,
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut clients = std::mem::take(&mut self.clients);
+        clients.retain_mut(|client| self.serve(client) && client.flush());
+        self.clients = clients;
+
+        // nobody reads what clients send to the RTP port (RTCP ends up there at worst)
+        let mut buf = [0u8; 1500];
+        while self.rtp.recv_from(&mut buf).is_ok() {}
+
+        Ok(())
+    }
+
+    /// Sends a frame to every playing session. `tables` are the quantization tables of the
+    /// encoder that made `jpeg`, see [`JpegEncoder::quant_tables`](crate::jpeg::JpegEncoder::quant_tables).
+    pub fn push_frame(
+        &mut self,
+        jpeg: &[u8],
+        tables: (&[u8; 64], &[u8; 64]),
+        width: u16,
+        height: u16,
+    ) {
+        if !self.wants_frame() {
+            return;
+        }
+
+        let timestamp = self.rtp_time();
+        let packets = match self
+            .packetizer
+            .packetize(jpeg, tables, width, height, timestamp)
+        {
+            Some(packets) => packets,
+            None => return,
+        };
+
+        for client in &mut self.clients {
+            let target = match &client.session {
+                Some(session) if session.playing => session.target,
+                _ => continue,
+            };
+
+            match target {
+                Target::Udp(addr) => {
+                    for packet in &packets {
+                        // a full socket buffer just means a dropped packet, same as on the wire
+                        let _ = self.rtp.send_to(packet, addr);
+                    }
+                }
+                Target::Interleaved(channel) => {
+                    // still sending the last frame, skip this one instead of queueing up
+                    if !client.is_idle() {
+                        continue;
+                    }
+                    for packet in &packets {
+                        client.queue(&[b'$', channel]);
+                        client.queue(&(packet.len() as u16).to_be_bytes());
+                        client.queue(packet);
+                    }
+                }
+            }
+        }
+
+        self.clients.retain_mut(|client| client.flush());
+    }
+
+    fn rtp_time(&self) -> u32 {
+        let elapsed = self.started.elapsed();
+        (elapsed.as_secs() as u32)
+            .wrapping_mul(CLOCK_RATE)
+            .wrapping_add(elapsed.subsec_micros() * (CLOCK_RATE / 1000) / 1000)
+    }
+
+    /// Reads and answers requests, returns `false` once the client should be dropped.
+    fn serve(&mut self, client: &mut Client) -> bool {
+        let mut buf = [0u8; 2048];
+        loop {
+            match client.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => client.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+
+        loop {
+            // interleaved RTCP from the client, `$`, channel, u16 length, data
+            if client.input.first() == Some(&b'$') {
+                if client.input.len() < 4 {
+                    return true;
+                }
+                let len = 4 + u16::from_be_bytes([client.input[2], client.input[3]]) as usize;
+                if client.input.len() < len {
+                    return true;
+                }
+                client.input.drain(..len);
+                continue;
+            }
+
+            match parse_request(&client.input) {
+                Ok(Some((request, len))) => {
+                    client.input.drain(..len);
+                    let reply = self.handle(client, &request);
+                    client.queue(&reply);
+                }
+                Ok(None) => return true,
+                Err(_) => {
+                    client.queue(&response("400 Bad Request", None, &[], None));
+                    client.input.clear();
+                    return true;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, client: &mut Client, request: &RtspRequest) -> Vec<u8> {
+        let cseq = request.cseq();
+
+        if request.method != "OPTIONS" && !serves(request.path()) {
+            return response("404 Not Found", cseq, &[], None);
+        }
+
+        // everything after SETUP has to name the session
+        if matches!(request.method.as_str(), "PLAY" | "PAUSE" | "TEARDOWN") {
+            let known = client.session.as_ref().map(|s| s.id.as_str());
+            if known.is_none() || request.session() != known {
+                return response("454 Session Not Found", cseq, &[], None);
+            }
+        }
+
+        match request.method.as_str() {
+            "OPTIONS" => response(
+                "200 OK",
+                cseq,
+                &[(
+                    "Public",
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER".to_owned(),
+                )],
+                None,
+            ),
+            "DESCRIBE" => {
+                let local = match client.stream.local_addr() {
+                    Ok(local) => local,
+                    Err(_) => return response("500 Internal Server Error", cseq, &[], None),
+                };
+                let base = format!("rtsp://{}{}/", local, PATH);
+                response(
+                    "200 OK",
+                    cseq,
+                    &[("Content-Base", base)],
+                    Some((
+                        "application/sdp",
+                        &sdp(local.ip(), self.next_session, self.fps),
+                    )),
+                )
+            }
+            "SETUP" => self.setup(client, request),
+            "PLAY" => {
+                let url = format!("rtsp://{}{}/track0", local_addr(client), PATH);
+                let session = client.session.as_mut().unwrap();
+                session.playing = true;
+
+                response(
+                    "200 OK",
+                    cseq,
+                    &[
+                        ("Session", session.id.clone()),
+                        ("Range", "npt=0.000-".to_owned()),
+                        (
+                            "RTP-Info",
+                            format!(
+                                "url={};seq={};rtptime={}",
+                                url,
+                                self.packetizer.next_sequence(),
+                                self.rtp_time()
+                            ),
+                        ),
+                    ],
+                    None,
+                )
+            }
+            "PAUSE" => {
+                let session = client.session.as_mut().unwrap();
+                session.playing = false;
+                response("200 OK", cseq, &[("Session", session.id.clone())], None)
+            }
+            "TEARDOWN" => {
+                let session = client.session.take().unwrap();
+                response("200 OK", cseq, &[("Session", session.id)], None)
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => response("200 OK", cseq, &[], None),
+            _ => response("501 Not Implemented", cseq, &[], None),
+        }
+    }
+
+    fn setup(&mut self, client: &mut Client, request: &RtspRequest) -> Vec<u8> {
+        let cseq = request.cseq();
+
+        let spec = match request.header("Transport").and_then(parse_transport) {
+            Some(spec) => spec,
+            None => return response("461 Unsupported Transport", cseq, &[], None),
+        };
+
+        let (target, transport) = match spec {
+            TransportSpec::Udp {
+                rtp_port,
+                rtcp_port,
+            } => {
+                let peer = match client.stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(_) => return response("500 Internal Server Error", cseq, &[], None),
+                };
+                let server_port = self.rtp.local_addr().map(|a| a.port()).unwrap_or(0);
+                (
+                    Target::Udp(SocketAddr::new(peer.ip(), rtp_port)),
+                    format!(
+                        "RTP/AVP;unicast;client_port={}-{};server_port={}-{};ssrc={:08X}",
+                        rtp_port,
+                        rtcp_port,
+                        server_port,
+                        server_port.wrapping_add(1),
+                        self.packetizer.ssrc()
+                    ),
+                )
+            }
+            TransportSpec::Interleaved {
+                rtp_channel,
+                rtcp_channel,
+            } => (
+                Target::Interleaved(rtp_channel),
+                format!(
+                    "RTP/AVP/TCP;unicast;interleaved={}-{};ssrc={:08X}",
+                    rtp_channel,
+                    rtcp_channel,
+                    self.packetizer.ssrc()
+                ),
+            ),
+        };
+
+        let id = match &client.session {
+            Some(session) => session.id.clone(),
+            None => {
+                self.next_session = self.next_session.wrapping_add(1);
+                format!("{:016X}", scramble(self.next_session))
+            }
+        };
+        client.session = Some(Session {
+            id: id.clone(),
+            target,
+            playing: false,
+        });
+
+        response(
+            "200 OK",
+            cseq,
+            &[
+                ("Transport", transport),
+                ("Session", format!("{};timeout={}", id, SESSION_TIMEOUT)),
+            ],
+            None,
+        )
+    }
+}
+
+fn local_addr(client: &Client) -> String {
+    client
+        .stream
+        .local_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::jpeg::{self, JpegEncoder};
+    use crate::stream::{FrameSource, TestPattern};
+
+    struct Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+
+        fn session(&self) -> String {
+            let session = self.header("Session").expect("no session");
+            session.split(';').next().unwrap().to_owned()
+        }
+    }
+
+    /// Just enough of an RTSP client to walk through a session, polling the server in between.
+    struct TestClient {
+        stream: TcpStream,
+        input: Vec<u8>,
+        cseq: u32,
+    }
+
+    impl TestClient {
+        fn connect(server: &RtspServer) -> Self {
+            let stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+            Self {
+                stream,
+                input: Vec::new(),
+                cseq: 0,
+            }
+        }
+
+        fn read_some(&mut self) {
+            let mut buf = [0u8; 8192];
+            match self.stream.read(&mut buf) {
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        fn request(&mut self, server: &mut RtspServer, method: &str, uri: &str, headers: &[(&str, &str)]) -> Response {
+            self.cseq += 1;
+            let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, uri, self.cseq);
+            for (name, value) in headers {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            request.push_str("\r\n");
+            self.stream.write_all(request.as_bytes()).unwrap();
+
+            for _ in 0..200 {
+                server.poll().unwrap();
+                self.read_some();
+                if let Some(response) = self.response() {
+                    assert_eq!(response.header("CSeq"), Some(self.cseq.to_string().as_str()));
+                    return response;
+                }
+            }
+            panic!("no answer to {}", method);
+        }
+
+        fn response(&mut self) -> Option<Response> {
+            let end = self.input.windows(4).position(|w| w == b"\r\n\r\n")?;
+            let head = std::str::from_utf8(&self.input[..end]).unwrap().to_owned();
+            let mut lines = head.split("\r\n");
+            let status_line = lines.next().unwrap();
+            assert!(status_line.starts_with("RTSP/1.0 "), "{}", status_line);
+            let status = status_line[9..12].parse().unwrap();
+            let headers: Vec<(String, String)> = lines
+                .map(|l| {
+                    let (n, v) = l.split_once(':').unwrap();
+                    (n.trim().to_owned(), v.trim().to_owned())
+                })
+                .collect();
+            let len: usize = headers
+                .iter()
+                .find(|(n, _)| n == "Content-Length")
+                .map_or(0, |(_, v)| v.parse().unwrap());
+            if self.input.len() < end + 4 + len {
+                return None;
+            }
+            let body = String::from_utf8(self.input[end + 4..end + 4 + len].to_vec()).unwrap();
+            self.input.drain(..end + 4 + len);
+            Some(Response { status, headers, body })
+        }
+
+        /// Interleaved RTP packets on `channel` up to and including the one with the marker bit.
+        fn interleaved_frame(&mut self, channel: u8) -> Vec<Vec<u8>> {
+            let mut packets = Vec::new();
+            for _ in 0..200 {
+                self.read_some();
+                while self.input.len() >= 4 {
+                    assert_eq!(self.input[0], b'$');
+                    assert_eq!(self.input[1], channel);
+                    let len = u16::from_be_bytes([self.input[2], self.input[3]]) as usize;
+                    if self.input.len() < 4 + len {
+                        break;
+                    }
+                    let packet: Vec<u8> = self.input.drain(..4 + len).skip(4).collect();
+                    let marker = packet[1] & 0x80 != 0;
+                    packets.push(packet);
+                    if marker {
+                        return packets;
+                    }
+                }
+            }
+            panic!("frame never finished, got {} packets", packets.len());
+        }
+    }
+
+    fn server() -> RtspServer {
+        RtspServer::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), 15).unwrap()
+    }
+
+    fn frame() -> (Vec<u8>, JpegEncoder) {
+        let mut pattern = TestPattern::new(320, 240);
+        let mut yuyv = vec![0; pattern.frame_size()];
+        pattern.capture(&mut yuyv).unwrap();
+        let encoder = JpegEncoder::new(80);
+        let mut jpeg = Vec::new();
+        encoder.encode_yuyv(&yuyv, 320, 240, &mut jpeg).unwrap();
+        (jpeg, encoder)
+    }
+
+    /// The scan data carried by `packets`, checking the fragment offsets line up.
+    fn reassemble(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut scan = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet[1] & 0x7f, PAYLOAD_TYPE);
+            assert_eq!(packet[1] & 0x80 != 0, i == packets.len() - 1);
+            let offset = u32::from_be_bytes([0, packet[13], packet[14], packet[15]]) as usize;
+            assert_eq!(offset, scan.len());
+            // the first one carries the quantization tables
+            let data = if offset == 0 { &packet[20 + 4 + 128..] } else { &packet[20..] };
+            scan.extend_from_slice(data);
+        }
+        scan
+    }
+
+    #[test]
+    fn interleaved_session() {
+        let mut server = server();
+        let base = format!("rtsp://{}{}", server.local_addr().unwrap(), PATH);
+        let mut client = TestClient::connect(&server);
+
+        let options = client.request(&mut server, "OPTIONS", "*", &[]);
+        assert_eq!(options.status, 200);
+        for method in ["DESCRIBE", "SETUP", "PLAY", "TEARDOWN"] {
+            assert!(options.header("Public").unwrap().contains(method));
+        }
+
+        let describe = client.request(&mut server, "DESCRIBE", &base, &[("Accept", "application/sdp")]);
+        assert_eq!(describe.status, 200);
+        assert_eq!(describe.header("Content-Type"), Some("application/sdp"));
+        assert_eq!(describe.header("Content-Base"), Some(format!("{}/", base).as_str()));
+        assert!(describe.body.starts_with("v=0\r\n"));
+        assert!(describe.body.contains("m=video 0 RTP/AVP 26\r\n"));
+        assert!(describe.body.contains("a=rtpmap:26 JPEG/90000\r\n"));
+        assert!(describe.body.contains("a=framerate:15\r\n"));
+        assert!(describe.body.contains("c=IN IP4 0.0.0.0\r\n"));
+
+        // nothing to play before SETUP
+        assert_eq!(client.request(&mut server, "PLAY", &base, &[("Session", "1")]).status, 454);
+        assert_eq!(client.request(&mut server, "DESCRIBE", "rtsp://x/elsewhere", &[]).status, 404);
+
+        let track = format!("{}/track0", base);
+        let setup = client.request(&mut server, "SETUP", &track, &[("Transport", "RTP/AVP/TCP;unicast;interleaved=2-3")]);
+        assert_eq!(setup.status, 200);
+        assert!(setup.header("Transport").unwrap().starts_with("RTP/AVP/TCP;unicast;interleaved=2-3;ssrc="));
+        let session = setup.session();
+        assert!(setup.header("Session").unwrap().ends_with(";timeout=60"));
+        assert!(!server.wants_frame());
+
+        assert_eq!(client.request(&mut server, "PLAY", &base, &[("Session", "nope")]).status, 454);
+        let play = client.request(&mut server, "PLAY", &base, &[("Session", &session)]);
+        assert_eq!(play.status, 200);
+        assert!(play.header("RTP-Info").unwrap().starts_with(&format!("url={};seq=", track)));
+        assert!(server.wants_frame());
+        assert_eq!(server.viewers(), 1);
+
+        let (jpeg, encoder) = frame();
+        for _ in 0..2 {
+            server.push_frame(&jpeg, encoder.quant_tables(), 320, 240);
+            let packets = client.interleaved_frame(2);
+            assert!(packets.len() > 1);
+            assert_eq!(reassemble(&packets), jpeg::scan_data(&jpeg).unwrap());
+        }
+
+        // keepalive, and RTCP from the client is skipped
+        client.stream.write_all(&[b'$', 3, 0, 2, 0xaa, 0xbb]).unwrap();
+        let keepalive = client.request(&mut server, "GET_PARAMETER", &base, &[("Session", &session)]);
+        assert_eq!(keepalive.status, 200);
+
+        let pause = client.request(&mut server, "PAUSE", &base, &[("Session", &session)]);
+        assert_eq!(pause.status, 200);
+        assert!(!server.wants_frame());
+
+        let teardown = client.request(&mut server, "TEARDOWN", &base, &[("Session", &session)]);
+        assert_eq!(teardown.status, 200);
+        assert_eq!(teardown.session(), session);
+        assert_eq!(client.request(&mut server, "PLAY", &base, &[("Session", &session)]).status, 454);
+    }
+
+    #[test]
+    fn udp_session() {
+        let mut server = server();
+        let base = format!("rtsp://{}{}", server.local_addr().unwrap(), PATH);
+        let mut client = TestClient::connect(&server);
+
+        let rtp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        rtp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let port = rtp.local_addr().unwrap().port();
+        let transport = format!("RTP/AVP;unicast;client_port={}-{}", port, port + 1);
+        let setup = client.request(&mut server, "SETUP", &format!("{}/track0", base), &[("Transport", &transport)]);
+        assert_eq!(setup.status, 200);
+        assert!(setup.header("Transport").unwrap().starts_with(&format!("{};server_port=", transport)));
+
+        let play = client.request(&mut server, "PLAY", &base, &[("Session", &setup.session())]);
+        assert_eq!(play.status, 200);
+
+        let (jpeg, encoder) = frame();
+        server.push_frame(&jpeg, encoder.quant_tables(), 320, 240);
+        let mut packets = Vec::new();
+        let mut buf = [0u8; 2048];
+        loop {
+            let len = rtp.recv(&mut buf).unwrap();
+            packets.push(buf[..len].to_vec());
+            if buf[1] & 0x80 != 0 {
+                break;
+            }
+        }
+        assert_eq!(reassemble(&packets), jpeg::scan_data(&jpeg).unwrap());
+
+        // the session goes away with the connection
+        drop(client);
+        for _ in 0..100 {
+            server.poll().unwrap();
+            if server.viewers() == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(server.viewers(), 0);
+    }
+
+    #[test]
+    fn sessions_belong_to_their_connection() {
+        let mut server = server();
+        let base = format!("rtsp://{}{}", server.local_addr().unwrap(), PATH);
+        let track = format!("{}/track0", base);
+        let transport = [("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1")];
+        let (mut viewer, mut other) = (TestClient::connect(&server), TestClient::connect(&server));
+
+        let session = viewer.request(&mut server, "SETUP", &track, &transport).session();
+        assert_eq!(viewer.request(&mut server, "PLAY", &base, &[("Session", &session)]).status, 200);
+        let own = other.request(&mut server, "SETUP", &track, &transport).session();
+        assert_ne!(own, session);
+
+        // someone else's session id, or one that's off by one, gets nowhere
+        let next = format!("{:016X}", u64::from_str_radix(&own, 16).unwrap().wrapping_add(1));
+        for id in [session.as_str(), next.as_str(), "0000000000000000"] {
+            for method in ["PLAY", "PAUSE", "TEARDOWN"] {
+                let response = other.request(&mut server, method, &base, &[("Session", id)]);
+                assert_eq!(response.status, 454, "{} {}", method, id);
+            }
+        }
+        assert_eq!(server.viewers(), 1);
+        let (jpeg, encoder) = frame();
+        server.push_frame(&jpeg, encoder.quant_tables(), 320, 240);
+        assert!(!viewer.interleaved_frame(0).is_empty());
+    }
+
+    #[test]
+    fn unguessable_ids() {
+        let mut ssrcs = Vec::new();
+        let mut sessions = Vec::new();
+        for _ in 0..4 {
+            let mut server = server();
+            let mut client = TestClient::connect(&server);
+            let uri = format!("rtsp://{}{}/track0", server.local_addr().unwrap(), PATH);
+            let transport = [("Transport", "RTP/AVP/TCP;unicast")];
+            let setup = client.request(&mut server, "SETUP", &uri, &transport);
+            let ssrc = setup.header("Transport").unwrap().split("ssrc=").nth(1).unwrap();
+            ssrcs.push(ssrc.to_owned());
+            sessions.push(setup.session());
+        }
+        // every server starts somewhere else
+        for ids in [&mut ssrcs, &mut sessions] {
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids.len(), 4, "{:?}", ids);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        for path in ["/cam", "/cam/", "/cam/track0", "/cam/trackID=1"] {
+            assert!(serves(path), "{}", path);
+        }
+        for path in ["/", "/camera", "/camXYZ", "/cam/track1", "/cam/trackID=", "/cam/trackID=x"] {
+            assert!(!serves(path), "{}", path);
+        }
+
+        let mut server = server();
+        let mut client = TestClient::connect(&server);
+        let host = server.local_addr().unwrap();
+        let describe = |client: &mut TestClient, server: &mut RtspServer, path: &str| {
+            client.request(server, "DESCRIBE", &format!("rtsp://{}{}", host, path), &[]).status
+        };
+        assert_eq!(describe(&mut client, &mut server, "/cam/"), 200);
+        assert_eq!(describe(&mut client, &mut server, "/streamXYZ"), 404);
+        assert_eq!(describe(&mut client, &mut server, "/camXYZ"), 404);
+    }
+
+    #[test]
+    fn unsupported_transport() {
+        let mut server = server();
+        let mut client = TestClient::connect(&server);
+        let uri = format!("rtsp://{}{}/track0", server.local_addr().unwrap(), PATH);
+        let setup = client.request(&mut server, "SETUP", &uri, &[("Transport", "RTP/AVP;multicast")]);
+        assert_eq!(setup.status, 461);
+        assert_eq!(client.request(&mut server, "RECORD", &uri, &[]).status, 501);
+    }
+
+    #[test]
+    fn requests() {
+        let text = b"SETUP rtsp://10.0.0.2:554/cam/track0 RTSP/1.0\r\ncseq: 3\r\nSession: ABC;timeout=60\r\nContent-Length: 2\r\n\r\nhiOPTIONS";
+        assert_eq!(parse_request(&text[..20]), Ok(None));
+        let (request, len) = parse_request(text).unwrap().unwrap();
+        assert_eq!(&text[len..], b"OPTIONS");
+        assert_eq!(request.method, "SETUP");
+        assert_eq!(request.path(), "/cam/track0");
+        assert_eq!(request.cseq(), Some("3"));
+        assert_eq!(request.session(), Some("ABC"));
+
+        assert_eq!(parse_request(b"SETUP x HTTP/1.1\r\n\r\n"), Err(RtspError::Malformed));
+        assert_eq!(parse_request(&[b'a'; MAX_REQUEST_LEN + 1]), Err(RtspError::TooLarge));
+    }
+
+    #[test]
+    fn transports() {
+        assert_eq!(
+            parse_transport("RTP/AVP;unicast;client_port=5000-5001"),
+            Some(TransportSpec::Udp { rtp_port: 5000, rtcp_port: 5001 })
+        );
+        assert_eq!(
+            parse_transport("RTP/AVP/TCP;unicast"),
+            Some(TransportSpec::Interleaved { rtp_channel: 0, rtcp_channel: 1 })
+        );
+        // first one we can do
+        assert_eq!(
+            parse_transport("RTP/AVP;multicast;port=9000-9001, RTP/AVP/UDP;unicast;client_port=6000"),
+            Some(TransportSpec::Udp { rtp_port: 6000, rtcp_port: 6001 })
+        );
+        assert_eq!(parse_transport("RTP/AVP/TCP;interleaved=300-301"), None);
+        assert_eq!(parse_transport("RAW/RAW/UDP;unicast"), None);
+    }
+}
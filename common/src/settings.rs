@@ -0,0 +1,384 @@
+//! Camera selection and image settings, and the menu that edits them.
+//!
+//! Where the preview goes is in here too, it's the one other thing the menu edits.
+//!
+//! Only the model lives here, the console applies it to the hardware after every change. The
+//! value ranges are the ones `CAMU` accepts. Brightness is the exception, the camera has no such
+//! register so it's a luma offset applied to YUV frames after capture, see
+//! [`yuv::adjust_brightness`](crate::yuv::adjust_brightness).
+
+use crate::config::camera_name;
+use crate::handshake::CameraId;
+use crate::preview::PreviewScreen;
+use crate::stereo::StereoLayout;
+
+/// White balance presets, mirroring `CAMU_WhiteBalance`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WhiteBalance {
+    Auto,
+    Temp3200K,
+    Temp4150K,
+    Temp5200K,
+    Temp6000K,
+    Temp7000K,
+}
+
+impl WhiteBalance {
+    pub const ALL: [WhiteBalance; 6] = [
+        WhiteBalance::Auto,
+        WhiteBalance::Temp3200K,
+        WhiteBalance::Temp4150K,
+        WhiteBalance::Temp5200K,
+        WhiteBalance::Temp6000K,
+        WhiteBalance::Temp7000K,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WhiteBalance::Auto => "auto",
+            WhiteBalance::Temp3200K => "3200K",
+            WhiteBalance::Temp4150K => "4150K",
+            WhiteBalance::Temp5200K => "5200K",
+            WhiteBalance::Temp6000K => "6000K",
+            WhiteBalance::Temp7000K => "7000K",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Contrast {
+    Low,
+    Normal,
+    High,
+}
+
+impl Contrast {
+    pub const ALL: [Contrast; 3] = [Contrast::Low, Contrast::Normal, Contrast::High];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Contrast::Low => "low",
+            Contrast::Normal => "normal",
+            Contrast::High => "high",
+        }
+    }
+}
+
+pub const EXPOSURE_RANGE: (i8, i8) = (-5, 5);
+pub const SHARPNESS_RANGE: (i8, i8) = (-4, 5);
+pub const BRIGHTNESS_RANGE: (i8, i8) = (-5, 5);
+
+/// Luma added per brightness step.
+pub const BRIGHTNESS_STEP: i16 = 12;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CameraSettings {
+    /// Which camera to capture from, also offered first in the handshake.
+    pub camera: CameraId,
+    /// How both outer cameras get packed, only used when [`CameraId::BothOuter`] is selected.
+    pub stereo: StereoLayout,
+    pub auto_exposure: bool,
+    pub auto_white_balance: bool,
+    pub trimming: bool,
+    pub exposure: i8,
+    pub white_balance: WhiteBalance,
+    pub sharpness: i8,
+    pub contrast: Contrast,
+    pub brightness: i8,
+    pub noise_filter: bool,
+    pub preview: PreviewScreen,
+}
+
+impl Default for CameraSettings {
+    /// What the cameras start with after `CAMU_Activate`.
+    fn default() -> Self {
+        Self {
+            camera: CameraId::BothOuter,
+            stereo: StereoLayout::Mono,
+            auto_exposure: true,
+            auto_white_balance: true,
+            trimming: false,
+            exposure: 0,
+            white_balance: WhiteBalance::Auto,
+            sharpness: 0,
+            contrast: Contrast::Normal,
+            brightness: 0,
+            noise_filter: true,
+            preview: PreviewScreen::Bottom,
+        }
+    }
+}
+
+impl CameraSettings {
+    /// Offset to add to every luma sample, 0 means leave frames alone.
+    pub fn luma_offset(&self) -> i16 {
+        self.brightness as i16 * BRIGHTNESS_STEP
+    }
+
+    pub fn value(&self, setting: Setting) -> String {
+        fn on_off(b: bool) -> String {
+            if b { "on" } else { "off" }.to_owned()
+        }
+
+        match setting {
+            Setting::Camera => camera_name(self.camera).to_owned(),
+            Setting::Stereo => self.stereo.name().to_owned(),
+            Setting::AutoExposure => on_off(self.auto_exposure),
+            Setting::AutoWhiteBalance => on_off(self.auto_white_balance),
+            Setting::Trimming => on_off(self.trimming),
+            Setting::Exposure => format!("{:+}", self.exposure),
+            Setting::WhiteBalance => self.white_balance.name().to_owned(),
+            Setting::Sharpness => format!("{:+}", self.sharpness),
+            Setting::Contrast => self.contrast.name().to_owned(),
+            Setting::Brightness => format!("{:+}", self.brightness),
+            Setting::NoiseFilter => on_off(self.noise_filter),
+            Setting::Preview => self.preview.name().to_owned(),
+        }
+    }
+
+    /// Steps `setting` by `delta` (left/right on the D-pad), booleans just flip. Returns which
+    /// settings changed, since some are tied together: picking a manual exposure turns auto
+    /// exposure off, and auto white balance is the same thing as the `Auto` preset.
+    pub fn adjust(&mut self, setting: Setting, delta: i8) -> Vec<Setting> {
+        let before = *self;
+
+        match setting {
+            Setting::Camera => self.camera = cycle(&CameraId::ALL, self.camera, delta),
+            Setting::Stereo => {
+                self.stereo = cycle(&StereoLayout::ALL, self.stereo, delta);
+                // there's only a second eye with both outer cameras
+                if self.stereo != StereoLayout::Mono {
+                    self.camera = CameraId::BothOuter;
+                }
+            }
+            Setting::AutoExposure => self.auto_exposure = !self.auto_exposure,
+            Setting::AutoWhiteBalance => {
+                self.auto_white_balance = !self.auto_white_balance;
+                if self.auto_white_balance {
+                    self.white_balance = WhiteBalance::Auto;
+                } else if self.white_balance == WhiteBalance::Auto {
+                    // daylight, the most likely thing to want instead
+                    self.white_balance = WhiteBalance::Temp5200K;
+                }
+            }
+            Setting::Trimming => self.trimming = !self.trimming,
+            Setting::Exposure => {
+                self.exposure = step(self.exposure, delta, EXPOSURE_RANGE);
+                self.auto_exposure = false;
+            }
+            Setting::WhiteBalance => {
+                self.white_balance = cycle(&WhiteBalance::ALL, self.white_balance, delta);
+                self.auto_white_balance = self.white_balance == WhiteBalance::Auto;
+            }
+            Setting::Sharpness => self.sharpness = step(self.sharpness, delta, SHARPNESS_RANGE),
+            Setting::Contrast => self.contrast = cycle(&Contrast::ALL, self.contrast, delta),
+            Setting::Brightness => {
+                self.brightness = step(self.brightness, delta, BRIGHTNESS_RANGE)
+            }
+            Setting::NoiseFilter => self.noise_filter = !self.noise_filter,
+            Setting::Preview => self.preview = cycle(&PreviewScreen::ALL, self.preview, delta),
+        }
+
+        Setting::ALL
+            .into_iter()
+            .filter(|&s| self.value(s) != before.value(s))
+            .collect()
+    }
+}
+
+fn step(value: i8, delta: i8, (min, max): (i8, i8)) -> i8 {
+    value.saturating_add(delta).clamp(min, max)
+}
+
+/// Moves through `options` without wrapping, like the numeric settings.
+fn cycle<T: Copy + PartialEq>(options: &[T], current: T, delta: i8) -> T {
+    let i = options.iter().position(|&o| o == current).unwrap_or(0) as isize;
+    let j = (i + delta as isize).clamp(0, options.len() as isize - 1);
+    options[j as usize]
+}
+
+/// One line of the settings menu.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Setting {
+    Camera,
+    Stereo,
+    AutoExposure,
+    AutoWhiteBalance,
+    Trimming,
+    Exposure,
+    WhiteBalance,
+    Sharpness,
+    Contrast,
+    Brightness,
+    NoiseFilter,
+    Preview,
+}
+
+impl Setting {
+    /// In menu order.
+    pub const ALL: [Setting; 12] = [
+        Setting::Camera,
+        Setting::Stereo,
+        Setting::AutoExposure,
+        Setting::AutoWhiteBalance,
+        Setting::Trimming,
+        Setting::Exposure,
+        Setting::WhiteBalance,
+        Setting::Sharpness,
+        Setting::Contrast,
+        Setting::Brightness,
+        Setting::NoiseFilter,
+        Setting::Preview,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Setting::Camera => "Camera",
+            Setting::Stereo => "3D",
+            Setting::AutoExposure => "Auto exposure",
+            Setting::AutoWhiteBalance => "Auto white balance",
+            Setting::Trimming => "Trimming",
+            Setting::Exposure => "Exposure",
+            Setting::WhiteBalance => "White balance",
+            Setting::Sharpness => "Sharpness",
+            Setting::Contrast => "Contrast",
+            Setting::Brightness => "Brightness",
+            Setting::NoiseFilter => "Noise filter",
+            Setting::Preview => "Preview",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_exposure() {
+        let mut settings = CameraSettings::default();
+        assert!(settings.auto_exposure);
+        assert_eq!(
+            settings.adjust(Setting::Exposure, 2),
+            [Setting::AutoExposure, Setting::Exposure]
+        );
+        assert_eq!((settings.auto_exposure, settings.exposure), (false, 2));
+        // already manual, only the value changes
+        assert_eq!(settings.adjust(Setting::Exposure, -1), [Setting::Exposure]);
+        // turning auto exposure back on keeps the manual value for next time
+        assert_eq!(settings.adjust(Setting::AutoExposure, 1), [Setting::AutoExposure]);
+        assert_eq!((settings.auto_exposure, settings.exposure), (true, 1));
+    }
+
+    #[test]
+    fn white_balance_follows_its_auto_switch() {
+        let mut settings = CameraSettings::default();
+        assert_eq!(
+            settings.adjust(Setting::AutoWhiteBalance, 1),
+            [Setting::AutoWhiteBalance, Setting::WhiteBalance]
+        );
+        assert!(!settings.auto_white_balance);
+        assert_eq!(settings.white_balance, WhiteBalance::Temp5200K);
+        assert_eq!(
+            settings.adjust(Setting::AutoWhiteBalance, 1),
+            [Setting::AutoWhiteBalance, Setting::WhiteBalance]
+        );
+        assert_eq!(settings.white_balance, WhiteBalance::Auto);
+
+        // and the other way around
+        assert_eq!(
+            settings.adjust(Setting::WhiteBalance, 1),
+            [Setting::AutoWhiteBalance, Setting::WhiteBalance]
+        );
+        assert_eq!(
+            (settings.auto_white_balance, settings.white_balance),
+            (false, WhiteBalance::Temp3200K)
+        );
+        assert_eq!(settings.adjust(Setting::WhiteBalance, 2), [Setting::WhiteBalance]);
+        assert_eq!(settings.white_balance, WhiteBalance::Temp5200K);
+        // back to the start of the list is auto again
+        assert_eq!(
+            settings.adjust(Setting::WhiteBalance, -3),
+            [Setting::AutoWhiteBalance, Setting::WhiteBalance]
+        );
+        assert!(settings.auto_white_balance);
+    }
+
+    #[test]
+    fn stereo_needs_both_outer_cameras() {
+        let mut settings = CameraSettings {
+            camera: CameraId::Inner,
+            ..CameraSettings::default()
+        };
+        assert_eq!(settings.adjust(Setting::Stereo, 1), [Setting::Camera, Setting::Stereo]);
+        assert_eq!(settings.camera, CameraId::BothOuter);
+        assert_eq!(settings.stereo, StereoLayout::SideBySide);
+        // back to mono leaves the cameras alone
+        assert_eq!(settings.adjust(Setting::Stereo, -1), [Setting::Stereo]);
+        assert_eq!(settings.camera, CameraId::BothOuter);
+        // and one camera is fine for mono
+        assert_eq!(settings.adjust(Setting::Camera, -1), [Setting::Camera]);
+        assert_eq!(settings.camera, CameraId::OuterLeft);
+        assert_eq!(settings.stereo, StereoLayout::Mono);
+    }
+
+    #[test]
+    fn ranges_clamp() {
+        let mut settings = CameraSettings::default();
+        for (setting, (min, max)) in [
+            (Setting::Exposure, EXPOSURE_RANGE),
+            (Setting::Sharpness, SHARPNESS_RANGE),
+            (Setting::Brightness, BRIGHTNESS_RANGE),
+        ] {
+            let get = |s: &CameraSettings| match setting {
+                Setting::Exposure => s.exposure,
+                Setting::Sharpness => s.sharpness,
+                _ => s.brightness,
+            };
+            for _ in 0..20 {
+                settings.adjust(setting, 1);
+            }
+            assert_eq!(get(&settings), max, "{:?}", setting);
+            assert!(!settings.adjust(setting, 1).contains(&setting));
+            settings.adjust(setting, i8::MIN);
+            assert_eq!(get(&settings), min, "{:?}", setting);
+            assert!(!settings.adjust(setting, -1).contains(&setting));
+        }
+        assert_eq!(settings.luma_offset(), BRIGHTNESS_RANGE.0 as i16 * BRIGHTNESS_STEP);
+
+        // lists stop at their ends too
+        settings.camera = CameraId::ALL[0];
+        assert_eq!(settings.adjust(Setting::Camera, -1), []);
+        settings.preview = PreviewScreen::Top3D;
+        assert_eq!(settings.adjust(Setting::Preview, 1), []);
+        assert_eq!(settings.adjust(Setting::Contrast, 5), [Setting::Contrast]);
+        assert_eq!(settings.contrast, Contrast::High);
+        assert_eq!(settings.adjust(Setting::Contrast, 1), []);
+    }
+
+    #[test]
+    fn switches_flip() {
+        let mut settings = CameraSettings::default();
+        for setting in [Setting::Trimming, Setting::NoiseFilter] {
+            let before = settings.value(setting);
+            // whichever way
+            assert_eq!(settings.adjust(setting, -1), [setting]);
+            assert_ne!(settings.value(setting), before);
+            assert_eq!(settings.adjust(setting, 1), [setting]);
+            assert_eq!(settings.value(setting), before);
+        }
+    }
+
+    #[test]
+    fn values() {
+        let settings = CameraSettings {
+            exposure: 3,
+            sharpness: -2,
+            ..CameraSettings::default()
+        };
+        assert_eq!(settings.value(Setting::Camera), "both-outer");
+        assert_eq!(settings.value(Setting::AutoExposure), "on");
+        assert_eq!(settings.value(Setting::Exposure), "+3");
+        assert_eq!(settings.value(Setting::Sharpness), "-2");
+        assert_eq!(settings.value(Setting::Brightness), "+0");
+    }
+}
@@ -0,0 +1,323 @@
+//! Streaming statistics, summed up once a second for the on-screen overlay.
+//!
+//! The streaming loop reports what happens to every frame and [`Stats::tick`] turns the last
+//! second into a [`Snapshot`]. Time is always passed in, so nothing here reads the clock.
+//!
+//! Latency and backlog come from the receiver's acks (see [`protocol`](crate::protocol)): the
+//! backlog is everything sent that hasn't been acked yet, which covers the TCP send queue as well
+//! as anything still in flight. Until the first ack arrives both are unknown.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How often [`Stats::tick`] hands out a snapshot.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames remembered while waiting for their ack, older ones are forgotten.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// One second of streaming.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    /// Frames the camera delivered per second.
+    pub capture_fps: f32,
+    /// Frames written to the connection per second.
+    pub send_fps: f32,
+    /// Frames lost since the connection started.
+    pub dropped: u64,
+    /// Payload bits per second, headers included.
+    pub bitrate: u64,
+    /// Average size of the frames sent in the last second, in bytes.
+    pub average_frame: usize,
+    /// Bytes and frames sent but not acked yet.
+    pub backlog: Option<(usize, usize)>,
+    /// Smoothed round trip time.
+    pub rtt: Option<Duration>,
+}
+
+impl Snapshot {
+    /// The overlay text, short enough for the 40 columns of the bottom screen.
+    pub fn lines(&self) -> [String; 3] {
+        let backlog = match self.backlog {
+            Some((bytes, frames)) => format!("{} ({} frames)", size(bytes), frames),
+            None => "?".to_owned(),
+        };
+        let rtt = match self.rtt {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None => "?".to_owned(),
+        };
+
+        [
+            format!(
+                "fps {:.1} in, {:.1} out, {} dropped",
+                self.capture_fps, self.send_fps, self.dropped
+            ),
+            format!(
+                "{:.2} Mbit/s, {} per frame",
+                self.bitrate as f64 / 1_000_000.0,
+                size(self.average_frame)
+            ),
+            format!("backlog {}, rtt {}", backlog, rtt),
+        ]
+    }
+}
+
+fn size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    sequence: u32,
+    sent: Instant,
+    bytes: usize,
+}
+
+/// Collects what the streaming loop reports, see the module docs.
+#[derive(Debug)]
+pub struct Stats {
+    window_start: Instant,
+    captured: u32,
+    sent: u32,
+    bytes: u64,
+    dropped: u64,
+    in_flight: VecDeque<InFlight>,
+    acked: bool,
+    rtt: Option<Duration>,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            captured: 0,
+            sent: 0,
+            bytes: 0,
+            dropped: 0,
+            in_flight: VecDeque::new(),
+            acked: false,
+            rtt: None,
+        }
+    }
+
+    /// `frames` more frames came out of the camera.
+    pub fn captured(&mut self, frames: u32) {
+        self.captured += frames;
+    }
+
+    /// `frames` more frames were lost before they were sent, e.g. the capture timed out or the
+    /// queue to the network thread was full.
+    pub fn dropped(&mut self, frames: u64) {
+        self.dropped += frames;
+    }
+
+    /// Frame `sequence`, `bytes` long with its header, was written to the connection.
+    pub fn sent(&mut self, now: Instant, sequence: u32, bytes: usize) {
+        self.sent += 1;
+        self.bytes += bytes as u64;
+
+        if self.in_flight.len() == MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(InFlight {
+            sequence,
+            sent: now,
+            bytes,
+        });
+    }
+
+    /// The receiver got frame `sequence`, and everything before it.
+    pub fn acked(&mut self, now: Instant, sequence: u32) {
+        self.acked = true;
+
+        // acks come in order, so anything older was either acked or lost
+        while let Some(frame) = self.in_flight.front() {
+            // sequence numbers wrap, compare the difference instead
+            if (sequence.wrapping_sub(frame.sequence) as i32) < 0 {
+                break;
+            }
+            let frame = self.in_flight.pop_front().unwrap();
+            if frame.sequence == sequence {
+                let sample = now.saturating_duration_since(frame.sent);
+                // same smoothing as TCP's SRTT
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => (rtt * 7 + sample) / 8,
+                    None => sample,
+                });
+            }
+        }
+    }
+
+    /// Returns a snapshot of the last [`INTERVAL`] once it's over, and starts the next one.
+    pub fn tick(&mut self, now: Instant) -> Option<Snapshot> {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < INTERVAL {
+            return None;
+        }
+        let seconds = elapsed.as_secs_f32();
+
+        let backlog = self.acked.then(|| {
+            let bytes = self.in_flight.iter().map(|f| f.bytes).sum();
+            (bytes, self.in_flight.len())
+        });
+
+        let snapshot = Snapshot {
+            capture_fps: self.captured as f32 / seconds,
+            send_fps: self.sent as f32 / seconds,
+            dropped: self.dropped,
+            bitrate: (self.bytes as f32 * 8.0 / seconds) as u64,
+            average_frame: self.bytes.checked_div(self.sent as u64).unwrap_or(0) as usize,
+            backlog,
+            rtt: self.rtt,
+        };
+
+        self.window_start = now;
+        self.captured = 0;
+        self.sent = 0;
+        self.bytes = 0;
+        Some(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock() -> impl Fn(u64) -> Instant {
+        let start = Instant::now();
+        move |ms| start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn one_second() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        for i in 0..10 {
+            stats.captured(2);
+            stats.sent(at(i as u64 * 100), i, 1000);
+        }
+        stats.dropped(3);
+        stats.acked(at(950), 7);
+
+        assert_eq!(stats.tick(at(999)), None);
+        let snapshot = stats.tick(at(1000)).unwrap();
+        assert_eq!(snapshot.capture_fps, 20.0);
+        assert_eq!(snapshot.send_fps, 10.0);
+        assert_eq!(snapshot.dropped, 3);
+        assert_eq!(snapshot.bitrate, 80_000);
+        assert_eq!(snapshot.average_frame, 1000);
+        // 8 and 9 not acked yet
+        assert_eq!(snapshot.backlog, Some((2000, 2)));
+        assert_eq!(snapshot.rtt, Some(Duration::from_millis(250)));
+
+        // the next window starts from scratch, apart from the drops and what's in flight
+        let snapshot = stats.tick(at(2000)).unwrap();
+        assert_eq!((snapshot.capture_fps, snapshot.send_fps), (0.0, 0.0));
+        assert_eq!((snapshot.bitrate, snapshot.average_frame), (0, 0));
+        assert_eq!(snapshot.dropped, 3);
+        assert_eq!(snapshot.backlog, Some((2000, 2)));
+    }
+
+    #[test]
+    fn late_ticks_average_over_the_whole_gap() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        for i in 0..30 {
+            stats.captured(1);
+            stats.sent(at(i * 50), i as u32, 500);
+        }
+        let snapshot = stats.tick(at(2000)).unwrap();
+        assert_eq!(snapshot.capture_fps, 15.0);
+        assert_eq!(snapshot.send_fps, 15.0);
+        assert_eq!(snapshot.bitrate, 60_000);
+    }
+
+    #[test]
+    fn smoothed_rtt() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        let mut expected = Duration::from_millis(80);
+        stats.sent(at(0), 0, 100);
+        stats.acked(at(80), 0);
+        assert_eq!(stats.rtt, Some(expected));
+
+        // a steady 160 ms pulls it up an eighth of the way each time
+        for i in 1..20u32 {
+            let sent = i as u64 * 1000;
+            stats.sent(at(sent), i, 100);
+            stats.acked(at(sent + 160), i);
+            expected = (expected * 7 + Duration::from_millis(160)) / 8;
+            assert_eq!(stats.rtt, Some(expected));
+        }
+        assert!(expected > Duration::from_millis(150) && expected < Duration::from_millis(160));
+
+        // acking a frame that isn't in flight any more leaves it alone
+        stats.acked(at(30_000), 5);
+        assert_eq!(stats.rtt, Some(expected));
+    }
+
+    #[test]
+    fn cumulative_acks() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        for i in 0..5 {
+            stats.sent(at(0), i, 10 * (i as usize + 1));
+        }
+        // acking 3 also covers 0 to 2, only 3 is a sample
+        stats.acked(at(40), 3);
+        assert_eq!(stats.rtt, Some(Duration::from_millis(40)));
+        assert_eq!(stats.tick(at(1000)).unwrap().backlog, Some((50, 1)));
+
+        // sequence numbers wrap around
+        let mut stats = Stats::new(at(0));
+        stats.sent(at(0), u32::MAX - 1, 10);
+        stats.sent(at(0), u32::MAX, 20);
+        stats.sent(at(0), 0, 30);
+        stats.acked(at(10), u32::MAX);
+        assert_eq!(stats.tick(at(1000)).unwrap().backlog, Some((30, 1)));
+        stats.acked(at(1010), 0);
+        assert_eq!(stats.tick(at(2000)).unwrap().backlog, Some((0, 0)));
+    }
+
+    #[test]
+    fn forgets_old_frames() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        stats.acked(at(0), 0);
+        for i in 0..MAX_IN_FLIGHT as u32 + 10 {
+            stats.sent(at(0), i, 1);
+        }
+        assert_eq!(stats.in_flight.len(), MAX_IN_FLIGHT);
+        assert_eq!(stats.in_flight[0].sequence, 10);
+    }
+
+    #[test]
+    fn overlay() {
+        let at = clock();
+        let mut stats = Stats::new(at(0));
+        stats.sent(at(0), 0, 10);
+        let snapshot = stats.tick(at(1000)).unwrap();
+        assert_eq!(snapshot.backlog, None);
+        assert_eq!(snapshot.rtt, None);
+        assert_eq!(snapshot.lines()[2], "backlog ?, rtt ?");
+
+        let snapshot = Snapshot {
+            capture_fps: 29.97,
+            send_fps: 30.0,
+            dropped: 12_345,
+            bitrate: 12_500_000,
+            average_frame: 52_000,
+            backlog: Some((100_000, 20)),
+            rtt: Some(Duration::from_millis(250)),
+        };
+        let lines = snapshot.lines();
+        assert_eq!(lines[0], "fps 30.0 in, 30.0 out, 12345 dropped");
+        assert_eq!(lines[1], "12.50 Mbit/s, 50.8 KiB per frame");
+        assert_eq!(lines[2], "backlog 97.7 KiB (20 frames), rtt 250 ms");
+        assert!(lines.iter().all(|line| line.len() <= 40), "{:?}", lines);
+    }
+}
@@ -0,0 +1,249 @@
+//! Packing the two outer cameras into one stream.
+//!
+//! Both eyes are captured at the same time and sent as a single frame, either next to each other
+//! or on top of each other, or one after the other with the eye marked on every frame. The layout
+//! travels in the flags of every [`FrameHeader`](crate::protocol::FrameHeader):
+//!
+//! | bits | field                                     |
+//! |------|-------------------------------------------|
+//! | 0-1  | layout ([`StereoLayout`])                 |
+//! | 2    | eye, 0 left 1 right (frame-sequential)    |
+//! | 3-15 | reserved (0)                              |
+//!
+//! Frames with all flags clear are plain 2D frames, which is what older receivers expect. The
+//! anaglyph and depth layouts are processed into plain 2D frames on the console (see
+//! [`vision`](crate::vision)), so they're sent with clear flags too.
+
+use crate::protocol::{PixelFormat, ProtocolError};
+use crate::vision::{self, BlockMatcher};
+
+const LAYOUT_MASK: u16 = 0b11;
+const RIGHT_EYE: u16 = 1 << 2;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StereoLayout {
+    /// One eye only, the left one when both outer cameras are selected.
+    #[default]
+    Mono = 0,
+    /// Left eye on the left half, right eye on the right half, twice the width.
+    SideBySide = 1,
+    /// Left eye on top, right eye below, twice the height.
+    TopBottom = 2,
+    /// Left and right eye as alternating frames of the normal size.
+    FrameSequential = 3,
+    /// Red/cyan anaglyph of both eyes, normal size.
+    Anaglyph = 4,
+    /// Grey disparity map from block matching the eyes, normal size, brighter is closer.
+    Depth = 5,
+}
+
+impl StereoLayout {
+    pub const ALL: [StereoLayout; 6] = [
+        StereoLayout::Mono,
+        StereoLayout::SideBySide,
+        StereoLayout::TopBottom,
+        StereoLayout::FrameSequential,
+        StereoLayout::Anaglyph,
+        StereoLayout::Depth,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StereoLayout::Mono => "mono",
+            StereoLayout::SideBySide => "side-by-side",
+            StereoLayout::TopBottom => "top-bottom",
+            StereoLayout::FrameSequential => "frame-sequential",
+            StereoLayout::Anaglyph => "anaglyph",
+            StereoLayout::Depth => "depth",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        StereoLayout::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    /// Size of a packed frame made from two `width` x `height` eyes.
+    pub fn frame_size(self, width: u16, height: u16) -> (u16, u16) {
+        match self {
+            StereoLayout::SideBySide => (width.saturating_mul(2), height),
+            StereoLayout::TopBottom => (width, height.saturating_mul(2)),
+            StereoLayout::Mono
+            | StereoLayout::FrameSequential
+            | StereoLayout::Anaglyph
+            | StereoLayout::Depth => (width, height),
+        }
+    }
+}
+
+impl TryFrom<u8> for StereoLayout {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        StereoLayout::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or(ProtocolError::UnknownStereoLayout(value))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+/// Header flags for a frame in `layout`. `eye` only matters for frame-sequential.
+pub fn flags(layout: StereoLayout, eye: Eye) -> u16 {
+    if matches!(layout, StereoLayout::Anaglyph | StereoLayout::Depth) {
+        return 0;
+    }
+
+    let mut flags = layout as u16;
+    if layout == StereoLayout::FrameSequential && eye == Eye::Right {
+        flags |= RIGHT_EYE;
+    }
+    flags
+}
+
+/// Reads back what [`flags`] wrote. Unknown bits are ignored.
+pub fn parse_flags(flags: u16) -> (StereoLayout, Eye) {
+    // two bits, always a valid layout
+    let layout = StereoLayout::ALL[(flags & LAYOUT_MASK) as usize];
+    let eye = if flags & RIGHT_EYE != 0 {
+        Eye::Right
+    } else {
+        Eye::Left
+    };
+    (layout, eye)
+}
+
+/// Puts pairs of eyes together into frames.
+///
+/// Holds a buffer for each eye for the capture to fill. With frame-sequential packing every
+/// capture gives two frames, the right eye is kept for the next one so both halves of a pair
+/// really were taken at the same time.
+#[derive(Debug)]
+pub struct StereoPacker {
+    layout: StereoLayout,
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    left: Vec<u8>,
+    right: Vec<u8>,
+    right_pending: bool,
+    last_flags: u16,
+    matcher: BlockMatcher,
+    // luma planes for the depth layout
+    left_luma: Vec<u8>,
+    right_luma: Vec<u8>,
+}
+
+impl StereoPacker {
+    /// `width` and `height` are per eye, `format` is what the cameras capture.
+    pub fn new(layout: StereoLayout, width: u16, height: u16, format: PixelFormat) -> Self {
+        let format = format.capture_format();
+        let eye_len = width as usize * height as usize * format.bytes_per_pixel();
+        Self {
+            layout,
+            width: width as usize,
+            height: height as usize,
+            format,
+            left: vec![0; eye_len],
+            right: vec![0; eye_len],
+            right_pending: false,
+            last_flags: 0,
+            matcher: BlockMatcher::default(),
+            left_luma: Vec::new(),
+            right_luma: Vec::new(),
+        }
+    }
+
+    /// Block matching settings for [`StereoLayout::Depth`].
+    pub fn set_matcher(&mut self, matcher: BlockMatcher) {
+        self.matcher = matcher;
+    }
+
+    pub fn layout(&self) -> StereoLayout {
+        self.layout
+    }
+
+    /// Size of the frames [`StereoPacker::pack`] produces.
+    pub fn frame_size(&self) -> (u16, u16) {
+        self.layout
+            .frame_size(self.width as u16, self.height as u16)
+    }
+
+    /// Whether the eye buffers have to be refilled before the next [`StereoPacker::pack`].
+    pub fn needs_capture(&self) -> bool {
+        !self.right_pending
+    }
+
+    /// Buffers for the capture to fill, left and right.
+    pub fn eyes_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        (&mut self.left, &mut self.right)
+    }
+
+    /// Writes the next frame into `out`, which has to be exactly as large as
+    /// [`StereoPacker::frame_size`] says.
+    pub fn pack(&mut self, out: &mut [u8]) {
+        let row = self.width * self.format.bytes_per_pixel();
+
+        match self.layout {
+            StereoLayout::Mono => out.copy_from_slice(&self.left),
+            StereoLayout::SideBySide => {
+                let rows = out.chunks_exact_mut(row * 2);
+                for ((out, left), right) in rows
+                    .zip(self.left.chunks_exact(row))
+                    .zip(self.right.chunks_exact(row))
+                {
+                    out[..row].copy_from_slice(left);
+                    out[row..].copy_from_slice(right);
+                }
+            }
+            StereoLayout::TopBottom => {
+                let (top, bottom) = out.split_at_mut(self.left.len());
+                top.copy_from_slice(&self.left);
+                bottom.copy_from_slice(&self.right);
+            }
+            StereoLayout::FrameSequential => {
+                if self.right_pending {
+                    out.copy_from_slice(&self.right);
+                } else {
+                    out.copy_from_slice(&self.left);
+                }
+            }
+            StereoLayout::Anaglyph => vision::anaglyph(&self.left, &self.right, self.format, out),
+            StereoLayout::Depth => {
+                vision::luma(&self.left, self.format, &mut self.left_luma);
+                vision::luma(&self.right, self.format, &mut self.right_luma);
+                let map = self.matcher.compute(
+                    &self.left_luma,
+                    &self.right_luma,
+                    self.width,
+                    self.height,
+                );
+                map.render(
+                    self.width,
+                    self.height,
+                    self.matcher.max_disparity,
+                    self.format,
+                    out,
+                );
+            }
+        }
+
+        let eye = if self.right_pending {
+            Eye::Right
+        } else {
+            Eye::Left
+        };
+        self.last_flags = flags(self.layout, eye);
+        self.right_pending = self.layout == StereoLayout::FrameSequential && !self.right_pending;
+    }
+
+    /// Header flags for the frame [`StereoPacker::pack`] wrote last.
+    pub fn last_flags(&self) -> u16 {
+        self.last_flags
+    }
+}
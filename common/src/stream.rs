@@ -0,0 +1,332 @@
+use std::io::{self, Write};
+use std::mem;
+use std::time::Instant;
+
+use thiserror::Error;
+
+use crate::handshake::StreamConfig;
+use crate::jpeg::{JpegEncoder, JpegError};
+use crate::protocol::{FrameEncoder, FrameHeader, PixelFormat};
+
+/// Anything that can fill a buffer with a single camera frame.
+///
+/// On the console this is the camera service, on a host it can be a [`TestPattern`]
+/// or whatever else produces frames of a fixed size.
+pub trait FrameSource {
+    type Error;
+
+    fn width(&self) -> u16;
+
+    fn height(&self) -> u16;
+
+    fn format(&self) -> PixelFormat;
+
+    /// Size in bytes of one frame.
+    fn frame_size(&self) -> usize {
+        self.width() as usize * self.height() as usize * self.format().bytes_per_pixel()
+    }
+
+    /// Fills `buf` (exactly [`FrameSource::frame_size`] bytes long) with the next frame.
+    fn capture(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Header flags for the frame captured last, see [`stereo`](crate::stereo).
+    fn flags(&self) -> u16 {
+        0
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StreamError<E> {
+    #[error("Capture error")]
+    Capture(E),
+    #[error("Encoding error")]
+    Encode(#[from] JpegError),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+/// Pulls frames out of a [`FrameSource`] and writes them to a sink using the
+/// [`protocol`](crate::protocol) framing.
+///
+/// The frame buffer is kept around between calls so the connected loop doesn't
+/// allocate 600 KiB every frame.
+pub struct Streamer {
+    buf: Vec<u8>,
+    jpeg: Option<JpegEncoder>,
+    jpeg_buf: Vec<u8>,
+    encoder: FrameEncoder,
+    started: Instant,
+    frames_sent: u64,
+}
+
+impl Streamer {
+    /// Sends frames exactly as the source captures them.
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            jpeg: None,
+            jpeg_buf: Vec::new(),
+            encoder: FrameEncoder::new(),
+            started: Instant::now(),
+            frames_sent: 0,
+        }
+    }
+
+    /// Sends frames the way the server asked for during the handshake, compressing them if it
+    /// picked [`PixelFormat::Jpeg`].
+    pub fn for_config(config: &StreamConfig) -> Self {
+        let mut streamer = Self::new();
+        if config.format == PixelFormat::Jpeg {
+            streamer.jpeg = Some(JpegEncoder::new(config.quality));
+        }
+        streamer
+    }
+
+    /// Captures one frame from `source` and writes it to `out`.
+    ///
+    /// Returns the header that was sent.
+    pub fn send_frame<S: FrameSource, W: Write>(
+        &mut self,
+        source: &mut S,
+        out: &mut W,
+    ) -> Result<FrameHeader, StreamError<S::Error>> {
+        self.buf.resize(source.frame_size(), 0);
+        source
+            .capture(&mut self.buf)
+            .map_err(StreamError::Capture)?;
+
+        let buf = mem::take(&mut self.buf);
+        let result = self.send(
+            &buf,
+            source.format(),
+            source.width(),
+            source.height(),
+            source.flags(),
+            out,
+        );
+        self.buf = buf;
+        result
+    }
+
+    /// Writes a frame captured elsewhere, e.g. on another thread, to `out`. `frame` is a
+    /// `width` x `height` `format` capture with header `flags`.
+    ///
+    /// Returns the header that was sent.
+    pub fn send<W: Write, E>(
+        &mut self,
+        frame: &[u8],
+        format: PixelFormat,
+        width: u16,
+        height: u16,
+        flags: u16,
+        out: &mut W,
+    ) -> Result<FrameHeader, StreamError<E>> {
+        self.encoder.set_flags(flags);
+
+        let timestamp = self.started.elapsed().as_micros() as u64;
+
+        let (format, payload) = match &self.jpeg {
+            Some(jpeg) if format == PixelFormat::Yuv422 => {
+                self.jpeg_buf.clear();
+                jpeg.encode_yuyv(frame, width as usize, height as usize, &mut self.jpeg_buf)?;
+                (PixelFormat::Jpeg, self.jpeg_buf.as_slice())
+            }
+            _ => (format, frame),
+        };
+
+        let header = self
+            .encoder
+            .encode(out, format, width, height, timestamp, payload)?;
+        out.flush()?;
+
+        self.frames_sent += 1;
+        Ok(header)
+    }
+
+    pub fn frames_sent(&self) -> u64 {
+        self.frames_sent
+    }
+
+    /// The raw capture behind the frame sent last, before any compression.
+    pub fn last_frame(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Default for Streamer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Captures YUV422 frames and compresses them, for the outputs that only deal in JPEG
+/// (HTTP, RTSP, recordings).
+pub struct JpegCapture {
+    buf: Vec<u8>,
+    jpeg: JpegEncoder,
+    out: Vec<u8>,
+}
+
+impl JpegCapture {
+    pub fn new(quality: u8) -> Self {
+        Self {
+            buf: Vec::new(),
+            jpeg: JpegEncoder::new(quality),
+            out: Vec::new(),
+        }
+    }
+
+    pub fn encoder(&self) -> &JpegEncoder {
+        &self.jpeg
+    }
+
+    /// Captures a frame from `source`, which has to produce YUV422, and returns it as a JPEG.
+    pub fn capture<S: FrameSource>(&mut self, source: &mut S) -> Result<&[u8], StreamError<S::Error>> {
+        self.buf.resize(source.frame_size(), 0);
+        source
+            .capture(&mut self.buf)
+            .map_err(StreamError::Capture)?;
+
+        self.out.clear();
+        self.jpeg.encode_yuyv(
+            &self.buf,
+            source.width() as usize,
+            source.height() as usize,
+            &mut self.out,
+        )?;
+        Ok(&self.out)
+    }
+
+    /// The raw YUV422 capture behind the JPEG returned last.
+    pub fn last_frame(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// Synthetic YUV422 (YUYV) frame source drawing scrolling colour bars.
+#[derive(Debug, Clone)]
+pub struct TestPattern {
+    width: u16,
+    height: u16,
+    frame: usize,
+}
+
+// (Y, U, V) of the usual 75% colour bars
+const BARS: [(u8, u8, u8); 8] = [
+    (180, 128, 128),
+    (162, 44, 142),
+    (131, 156, 44),
+    (112, 72, 58),
+    (84, 184, 198),
+    (65, 100, 212),
+    (35, 212, 114),
+    (16, 128, 128),
+];
+
+impl TestPattern {
+    /// `width` has to be even, YUYV stores two pixels per macropixel.
+    pub fn new(width: u16, height: u16) -> Self {
+        assert_eq!(width % 2, 0, "YUV422 frames need an even width");
+        Self {
+            width,
+            height,
+            frame: 0,
+        }
+    }
+}
+
+impl FrameSource for TestPattern {
+    type Error = std::convert::Infallible;
+
+    fn width(&self) -> u16 {
+        self.width
+    }
+
+    fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn format(&self) -> PixelFormat {
+        PixelFormat::Yuv422
+    }
+
+    fn capture(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let width = self.width as usize;
+        let bar_width = (width / BARS.len()).max(1);
+
+        for row in buf.chunks_exact_mut(width * 2) {
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let column = (x * 2 + self.frame * 4) % width;
+                let (luma, u, v) = BARS[(column / bar_width).min(BARS.len() - 1)];
+                px.copy_from_slice(&[luma, u, luma, v]);
+            }
+        }
+
+        self.frame = self.frame.wrapping_add(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use crate::protocol::read_frame;
+
+    #[test]
+    fn streams_test_pattern_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frames = Vec::new();
+            while let Some(frame) = read_frame(&mut stream).unwrap() {
+                frames.push(frame);
+            }
+            frames
+        });
+
+        let mut source = TestPattern::new(64, 16);
+        let mut streamer = Streamer::new();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            let header = streamer.send_frame(&mut source, &mut stream).unwrap();
+            sent.push((header, streamer.last_frame().to_vec()));
+        }
+        drop(stream);
+
+        let received = receiver.join().unwrap();
+        assert_eq!(streamer.frames_sent(), 5);
+        assert_eq!(received.len(), 5);
+        for (i, (frame, (header, payload))) in received.iter().zip(&sent).enumerate() {
+            assert_eq!(frame.header, *header);
+            assert_eq!(frame.header.sequence, i as u32);
+            assert_eq!((frame.header.width, frame.header.height), (64, 16));
+            assert_eq!(frame.header.format, PixelFormat::Yuv422);
+            assert_eq!(frame.payload, *payload);
+        }
+        // the bars scroll, so consecutive frames differ
+        assert_ne!(received[0].payload, received[1].payload);
+    }
+
+    #[test]
+    fn reports_dropped_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || drop(listener.accept().unwrap()));
+
+        let mut source = TestPattern::new(320, 240);
+        let mut streamer = Streamer::new();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        receiver.join().unwrap();
+
+        // the first writes can still land in the socket buffer
+        let error = (0..100)
+            .find_map(|_| streamer.send_frame(&mut source, &mut stream).err())
+            .expect("writes kept working after the receiver hung up");
+        assert!(matches!(error, StreamError::Io(_)), "{:?}", error);
+    }
+}
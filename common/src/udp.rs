@@ -0,0 +1,445 @@
+//! UDP transport for frames, to avoid TCP head-of-line blocking on flaky Wi-Fi.
+//!
+//! Each frame, framed exactly like on TCP (see [`protocol`](crate::protocol)), is split into
+//! datagrams small enough to not get fragmented by IP. Every datagram starts with a 16 byte header,
+//! little endian:
+//!
+//! | offset | size | field                         |
+//! |--------|------|-------------------------------|
+//! | 0      | 4    | magic, `CTRU`                 |
+//! | 4      | 1    | protocol version              |
+//! | 5      | 1    | reserved (0)                  |
+//! | 6      | 2    | fragment index                |
+//! | 8      | 2    | fragment count                |
+//! | 10     | 2    | fragment payload length       |
+//! | 12     | 4    | frame id, wraps around        |
+//!
+//! The receiver puts fragments back together and gives up on a frame as soon as a newer one is
+//! complete, so a lost datagram costs one frame instead of stalling the stream.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+
+use thiserror::Error;
+
+use crate::protocol::VERSION;
+
+pub const MAGIC: [u8; 4] = *b"CTRU";
+pub const HEADER_LEN: usize = 16;
+
+/// Datagram size that fits the usual 1500 byte Ethernet MTU with IP and UDP headers on top.
+pub const DEFAULT_DATAGRAM_LEN: usize = 1400;
+
+/// How many incomplete frames the receiver keeps around before dropping the oldest.
+const MAX_PENDING: usize = 4;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum UdpError {
+    #[error("Datagram too short")]
+    TooShort,
+    #[error("Bad magic {0:02x?}")]
+    BadMagic([u8; 4]),
+    #[error("Unsupported protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Fragment {index} out of {count} is invalid")]
+    BadFragment { index: u16, count: u16 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub index: u16,
+    pub count: u16,
+    pub len: u16,
+    pub frame_id: u32,
+}
+
+impl FragmentHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut b = [0u8; HEADER_LEN];
+        b[0..4].copy_from_slice(&MAGIC);
+        b[4] = VERSION;
+        b[6..8].copy_from_slice(&self.index.to_le_bytes());
+        b[8..10].copy_from_slice(&self.count.to_le_bytes());
+        b[10..12].copy_from_slice(&self.len.to_le_bytes());
+        b[12..16].copy_from_slice(&self.frame_id.to_le_bytes());
+        b
+    }
+
+    pub fn parse(datagram: &[u8]) -> Result<Self, UdpError> {
+        if datagram.len() < HEADER_LEN {
+            return Err(UdpError::TooShort);
+        }
+        let b = &datagram[..HEADER_LEN];
+
+        let magic = [b[0], b[1], b[2], b[3]];
+        if magic != MAGIC {
+            return Err(UdpError::BadMagic(magic));
+        }
+        if b[4] != VERSION {
+            return Err(UdpError::UnsupportedVersion(b[4]));
+        }
+
+        let header = FragmentHeader {
+            index: u16::from_le_bytes([b[6], b[7]]),
+            count: u16::from_le_bytes([b[8], b[9]]),
+            len: u16::from_le_bytes([b[10], b[11]]),
+            frame_id: u32::from_le_bytes([b[12], b[13], b[14], b[15]]),
+        };
+
+        if header.count == 0
+            || header.index >= header.count
+            || datagram.len() - HEADER_LEN < header.len as usize
+        {
+            return Err(UdpError::BadFragment {
+                index: header.index,
+                count: header.count,
+            });
+        }
+        Ok(header)
+    }
+}
+
+/// Splits frames into datagrams.
+#[derive(Debug)]
+pub struct Fragmenter {
+    datagram_len: usize,
+    next_id: u32,
+}
+
+impl Fragmenter {
+    /// `datagram_len` includes the fragment header.
+    pub fn new(datagram_len: usize) -> Self {
+        assert!(datagram_len > HEADER_LEN, "datagrams need room for a payload");
+        Self {
+            datagram_len,
+            next_id: 0,
+        }
+    }
+
+    pub fn fragments(&mut self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let chunk_len = (self.datagram_len - HEADER_LEN).min(u16::MAX as usize);
+        let chunks: Vec<&[u8]> = if frame.is_empty() {
+            vec![&[]]
+        } else {
+            frame.chunks(chunk_len).collect()
+        };
+
+        let frame_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let count = chunks.len() as u16;
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let header = FragmentHeader {
+                    index: index as u16,
+                    count,
+                    len: chunk.len() as u16,
+                    frame_id,
+                };
+                let mut datagram = Vec::with_capacity(HEADER_LEN + chunk.len());
+                datagram.extend_from_slice(&header.to_bytes());
+                datagram.extend_from_slice(chunk);
+                datagram
+            })
+            .collect()
+    }
+}
+
+impl Default for Fragmenter {
+    fn default() -> Self {
+        Self::new(DEFAULT_DATAGRAM_LEN)
+    }
+}
+
+/// `Write`r sending each frame as a burst of datagrams on a connected socket.
+///
+/// Everything written between two `flush` calls is one frame, which is how
+/// [`Streamer`](crate::stream::Streamer) writes.
+#[derive(Debug)]
+pub struct UdpFrameWriter {
+    socket: UdpSocket,
+    fragmenter: Fragmenter,
+    buf: Vec<u8>,
+}
+
+impl UdpFrameWriter {
+    /// `socket` has to be `connect`ed to the receiver already.
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            fragmenter: Fragmenter::default(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for UdpFrameWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        for datagram in self.fragmenter.fragments(&self.buf) {
+            self.socket.send(&datagram)?;
+        }
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+/// Puts frames back together on the receiving end.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    pending: BTreeMap<u32, Partial>,
+    last_complete: Option<u32>,
+    dropped: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of frames given up on so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Feeds one datagram, returns the frame it completed, if any.
+    pub fn push(&mut self, datagram: &[u8]) -> Result<Option<Vec<u8>>, UdpError> {
+        let header = FragmentHeader::parse(datagram)?;
+
+        // late fragment of a frame we already finished or gave up on
+        if let Some(last) = self.last_complete {
+            if !is_newer(header.frame_id, last) {
+                return Ok(None);
+            }
+        }
+
+        let partial = self.pending.entry(header.frame_id).or_insert_with(|| Partial {
+            fragments: vec![None; header.count as usize],
+            received: 0,
+        });
+        if partial.fragments.len() != header.count as usize {
+            return Err(UdpError::BadFragment {
+                index: header.index,
+                count: header.count,
+            });
+        }
+
+        let slot = &mut partial.fragments[header.index as usize];
+        if slot.is_none() {
+            let start = HEADER_LEN;
+            *slot = Some(datagram[start..start + header.len as usize].to_vec());
+            partial.received += 1;
+        }
+
+        if partial.received == partial.fragments.len() {
+            let partial = self.pending.remove(&header.frame_id).unwrap();
+            let frame = partial.fragments.into_iter().flatten().flatten().collect();
+
+            // anything older than this frame is never getting shown now
+            let before = self.pending.len();
+            self.pending.retain(|&id, _| is_newer(id, header.frame_id));
+            self.dropped += (before - self.pending.len()) as u64;
+            self.last_complete = Some(header.frame_id);
+
+            return Ok(Some(frame));
+        }
+
+        while self.pending.len() > MAX_PENDING {
+            let oldest = self.oldest_pending();
+            self.pending.remove(&oldest);
+            self.dropped += 1;
+        }
+
+        Ok(None)
+    }
+
+    fn oldest_pending(&self) -> u32 {
+        let mut ids = self.pending.keys().copied();
+        let first = ids.next().unwrap();
+        ids.fold(first, |oldest, id| if is_newer(oldest, id) { id } else { oldest })
+    }
+}
+
+/// Wrapping comparison of frame ids, `a` is newer than `b`.
+fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    /// Datagrams of up to 100 bytes, so a few hundred bytes are already several fragments.
+    fn small() -> Fragmenter {
+        Fragmenter::new(HEADER_LEN + 100)
+    }
+
+    #[test]
+    fn fragments_and_reassembles() {
+        let mut fragmenter = small();
+        let mut reassembler = Reassembler::new();
+        for (len, count) in [(0, 1), (1, 1), (100, 1), (101, 2), (1000, 10)] {
+            let data = frame(len, len as u8);
+            let datagrams = fragmenter.fragments(&data);
+            assert_eq!(datagrams.len(), count, "{} bytes", len);
+            assert!(datagrams.iter().all(|d| d.len() <= HEADER_LEN + 100));
+
+            let (last, rest) = datagrams.split_last().unwrap();
+            for datagram in rest {
+                assert_eq!(reassembler.push(datagram).unwrap(), None);
+            }
+            assert_eq!(reassembler.push(last).unwrap(), Some(data));
+        }
+        assert_eq!(reassembler.dropped(), 0);
+    }
+
+    #[test]
+    fn reordered() {
+        let mut fragmenter = small();
+        let first = frame(450, 1);
+        let second = frame(300, 2);
+        let mut a = fragmenter.fragments(&first);
+        let mut b = fragmenter.fragments(&second);
+        a.reverse();
+        b.swap(0, 2);
+
+        // the two frames' fragments interleaved, each out of order
+        let mut reassembler = Reassembler::new();
+        let mut complete = Vec::new();
+        for datagram in a[..3].iter().chain(&b[..2]).chain(&a[3..]).chain(&b[2..]) {
+            complete.extend(reassembler.push(datagram).unwrap());
+        }
+        assert_eq!(complete, [first, second]);
+        assert_eq!(reassembler.dropped(), 0);
+    }
+
+    #[test]
+    fn duplicated() {
+        let mut fragmenter = small();
+        let data = frame(250, 3);
+        let datagrams = fragmenter.fragments(&data);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&datagrams[2]).unwrap(), Some(data));
+        // the whole frame again is old news
+        for datagram in &datagrams {
+            assert_eq!(reassembler.push(datagram).unwrap(), None);
+        }
+        assert_eq!(reassembler.dropped(), 0);
+    }
+
+    #[test]
+    fn lost_fragment_costs_one_frame() {
+        let mut fragmenter = small();
+        let lost = fragmenter.fragments(&frame(300, 4));
+        let next = frame(300, 5);
+        let datagrams = fragmenter.fragments(&next);
+
+        let mut reassembler = Reassembler::new();
+        // the middle fragment never arrives
+        reassembler.push(&lost[0]).unwrap();
+        reassembler.push(&lost[2]).unwrap();
+        let mut complete = Vec::new();
+        for datagram in &datagrams {
+            complete.extend(reassembler.push(datagram).unwrap());
+        }
+        assert_eq!(complete, [next]);
+        assert_eq!(reassembler.dropped(), 1);
+
+        // and showing up late doesn't bring it back
+        assert_eq!(reassembler.push(&lost[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn gives_up_on_old_frames() {
+        let mut fragmenter = small();
+        let mut reassembler = Reassembler::new();
+        for i in 0..MAX_PENDING + 2 {
+            let datagrams = fragmenter.fragments(&frame(200, i as u8));
+            assert_eq!(reassembler.push(&datagrams[0]).unwrap(), None);
+        }
+        assert_eq!(reassembler.dropped(), 2);
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+    }
+
+    #[test]
+    fn frame_ids_wrap_around() {
+        let mut fragmenter = small();
+        fragmenter.next_id = u32::MAX - 1;
+        let mut reassembler = Reassembler::new();
+
+        let frames: Vec<Vec<u8>> = (0..4).map(|i| frame(150, i)).collect();
+        let datagrams: Vec<Vec<Vec<u8>>> = frames.iter().map(|f| fragmenter.fragments(f)).collect();
+        let ids: Vec<u32> = datagrams.iter().map(|d| FragmentHeader::parse(&d[0]).unwrap().frame_id).collect();
+        assert_eq!(ids, [u32::MAX - 1, u32::MAX, 0, 1]);
+
+        // MAX - 1 and 0 arrive, MAX only half, 1 not at all yet
+        let mut complete = Vec::new();
+        for datagram in datagrams[0].iter().chain(&datagrams[1][..1]).chain(&datagrams[2]) {
+            complete.extend(reassembler.push(datagram).unwrap());
+        }
+        assert_eq!(complete, [frames[0].clone(), frames[2].clone()]);
+        // u32::MAX is older than 0
+        assert_eq!(reassembler.dropped(), 1);
+        assert_eq!(reassembler.push(&datagrams[1][1]).unwrap(), None);
+
+        for datagram in &datagrams[3] {
+            complete.extend(reassembler.push(datagram).unwrap());
+        }
+        assert_eq!(complete.last(), Some(&frames[3]));
+
+        assert!(is_newer(0, u32::MAX));
+        assert!(!is_newer(u32::MAX, 0));
+        assert!(!is_newer(5, 5));
+    }
+
+    #[test]
+    fn bad_datagrams() {
+        let mut reassembler = Reassembler::new();
+        let good = small().fragments(&frame(150, 0));
+
+        assert_eq!(reassembler.push(&good[0][..HEADER_LEN - 1]), Err(UdpError::TooShort));
+        let mut bad = good[0].clone();
+        bad[0] = b'X';
+        assert_eq!(reassembler.push(&bad), Err(UdpError::BadMagic(*b"XTRU")));
+        let mut bad = good[0].clone();
+        bad[4] = VERSION - 1;
+        assert_eq!(reassembler.push(&bad), Err(UdpError::UnsupportedVersion(VERSION - 1)));
+        // index past the count
+        let mut bad = good[0].clone();
+        bad[6..8].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(reassembler.push(&bad), Err(UdpError::BadFragment { index: 2, count: 2 }));
+        // payload shorter than the header says
+        assert!(matches!(reassembler.push(&good[0][..HEADER_LEN + 10]), Err(UdpError::BadFragment { .. })));
+
+        // same frame, different fragment count
+        reassembler.push(&good[0]).unwrap();
+        let mut bad = good[1].clone();
+        bad[8..10].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(reassembler.push(&bad), Err(UdpError::BadFragment { index: 1, count: 3 }));
+    }
+}
@@ -0,0 +1,320 @@
+//! Image processing on left/right pairs from the outer cameras.
+//!
+//! Red/cyan anaglyphs turn a pair into one ordinary 2D frame that looks 3D through the usual
+//! glasses, and block matching gives a coarse disparity (inverse depth) map. Everything works on
+//! the capture formats directly, YUYV or RGB565, so the results go through the same encoders as
+//! normal frames.
+
+use crate::protocol::PixelFormat;
+use crate::yuv::{self, Range};
+
+/// What the JPEG encoder assumes YUYV frames are in.
+const RANGE: Range = Range::Full;
+
+/// Builds a red/cyan anaglyph: red from `left`, green and blue from `right`.
+///
+/// All three buffers hold one `format` frame of the same size. RGB565 is exact, YUYV goes
+/// through RGB and back so it loses a little chroma, shared between each pair of pixels anyway.
+pub fn anaglyph(left: &[u8], right: &[u8], format: PixelFormat, out: &mut [u8]) {
+    match format.capture_format() {
+        PixelFormat::Rgb565 => {
+            let pixels = left.chunks_exact(2).zip(right.chunks_exact(2));
+            for ((l, r), out) in pixels.zip(out.chunks_exact_mut(2)) {
+                let l = u16::from_le_bytes([l[0], l[1]]);
+                let r = u16::from_le_bytes([r[0], r[1]]);
+                out.copy_from_slice(&((l & 0xf800) | (r & 0x07ff)).to_le_bytes());
+            }
+        }
+        _ => {
+            let pairs = left.chunks_exact(4).zip(right.chunks_exact(4));
+            for ((l, r), out) in pairs.zip(out.chunks_exact_mut(4)) {
+                let first = mix(l[0], r[0], l, r);
+                let second = mix(l[2], r[2], l, r);
+                out[0] = first[0];
+                out[1] = (first[1] as u16 + second[1] as u16).div_ceil(2) as u8;
+                out[2] = second[0];
+                out[3] = (first[2] as u16 + second[2] as u16).div_ceil(2) as u8;
+            }
+        }
+    }
+}
+
+/// One anaglyph pixel from the luma of each eye and the chroma of their YUYV pairs.
+fn mix(left_y: u8, right_y: u8, left: &[u8], right: &[u8]) -> [u8; 3] {
+    let [red, _, _] = yuv::yuv_to_rgb(left_y, left[1], left[3], RANGE);
+    let [_, green, blue] = yuv::yuv_to_rgb(right_y, right[1], right[3], RANGE);
+    yuv::rgb_to_yuv(red, green, blue, RANGE)
+}
+
+/// Pulls the luma out of a `format` frame into `out`, one byte per pixel.
+pub fn luma(frame: &[u8], format: PixelFormat, out: &mut Vec<u8>) {
+    out.clear();
+    match format.capture_format() {
+        PixelFormat::Rgb565 => out.extend(frame.chunks_exact(2).map(|p| {
+            let p = u16::from_le_bytes([p[0], p[1]]);
+            let r = ((p >> 11) & 0x1f) as u32 * 255 / 31;
+            let g = ((p >> 5) & 0x3f) as u32 * 255 / 63;
+            let b = (p & 0x1f) as u32 * 255 / 31;
+            ((r * 77 + g * 150 + b * 29) >> 8) as u8
+        })),
+        _ => out.extend(frame.iter().step_by(2)),
+    }
+}
+
+/// Block matching settings. Smaller blocks give a finer map, a larger search range finds closer
+/// objects, both cost time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlockMatcher {
+    /// Side of the square blocks in pixels.
+    pub block: usize,
+    /// Largest shift searched, in pixels.
+    pub max_disparity: usize,
+}
+
+impl Default for BlockMatcher {
+    /// Good enough for 400x240 at a few frames per second on an old 3DS.
+    fn default() -> Self {
+        Self {
+            block: 8,
+            max_disparity: 32,
+        }
+    }
+}
+
+/// Disparity per block, row by row. Larger is closer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisparityMap {
+    pub columns: usize,
+    pub rows: usize,
+    /// Block size the map was computed with.
+    pub block: usize,
+    /// Shift in pixels for every block.
+    pub values: Vec<u8>,
+}
+
+impl DisparityMap {
+    /// Disparity of the block covering pixel `(x, y)`.
+    pub fn at(&self, x: usize, y: usize) -> u8 {
+        let column = (x / self.block).min(self.columns.saturating_sub(1));
+        let row = (y / self.block).min(self.rows.saturating_sub(1));
+        self.values
+            .get(row * self.columns + column)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Draws the map as a `width` x `height` grey `format` frame into `out`, `max_disparity` being
+    /// white.
+    pub fn render(
+        &self,
+        width: usize,
+        height: usize,
+        max_disparity: usize,
+        format: PixelFormat,
+        out: &mut [u8],
+    ) {
+        let max = max_disparity.max(1);
+        let shade = |x, y| (self.at(x, y) as usize * 255 / max).min(255) as u8;
+
+        match format.capture_format() {
+            PixelFormat::Rgb565 => {
+                for (i, out) in out.chunks_exact_mut(2).take(width * height).enumerate() {
+                    let v = shade(i % width, i / width) as u16;
+                    let packed = ((v >> 3) << 11) | ((v >> 2) << 5) | (v >> 3);
+                    out.copy_from_slice(&packed.to_le_bytes());
+                }
+            }
+            _ => {
+                for (i, out) in out.chunks_exact_mut(4).take(width * height / 2).enumerate() {
+                    let (x, y) = (i * 2 % width, i * 2 / width);
+                    let [luma, u, v] =
+                        yuv::rgb_to_yuv(shade(x, y), shade(x, y), shade(x, y), RANGE);
+                    out.copy_from_slice(&[luma, u, luma, v]);
+                }
+            }
+        }
+    }
+}
+
+impl BlockMatcher {
+    /// Matches every block of `left` against `right` shifted left by up to `max_disparity` pixels,
+    /// keeping the shift with the smallest sum of absolute differences. Both are luma planes of
+    /// `width` x `height`, see [`luma`].
+    ///
+    /// Flat areas match everywhere equally well and come out as 0, like the far background.
+    pub fn compute(&self, left: &[u8], right: &[u8], width: usize, height: usize) -> DisparityMap {
+        let block = self.block.max(1);
+        let columns = width.div_ceil(block);
+        let rows = height.div_ceil(block);
+        let mut values = Vec::with_capacity(columns * rows);
+
+        for row in 0..rows {
+            let y0 = row * block;
+            let y1 = (y0 + block).min(height);
+            for column in 0..columns {
+                let x0 = column * block;
+                let x1 = (x0 + block).min(width);
+
+                let mut best = (u32::MAX, 0);
+                for d in 0..=self.max_disparity.min(x0).min(u8::MAX as usize) {
+                    let mut sad = 0u32;
+                    for y in y0..y1 {
+                        let l = &left[y * width + x0..y * width + x1];
+                        let r = &right[y * width + x0 - d..y * width + x1 - d];
+                        sad += l
+                            .iter()
+                            .zip(r)
+                            .map(|(&a, &b)| a.abs_diff(b) as u32)
+                            .sum::<u32>();
+                        if sad >= best.0 {
+                            break;
+                        }
+                    }
+                    if sad < best.0 {
+                        best = (sad, d);
+                    }
+                }
+                values.push(best.1 as u8);
+            }
+        }
+
+        DisparityMap {
+            columns,
+            rows,
+            block,
+            values,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 32;
+
+    /// Busy enough that every block only matches itself.
+    fn texture(x: usize, y: usize) -> u8 {
+        ((x * 7919 + y * 104_729) % 251) as u8
+    }
+
+    /// A textured background with no disparity and an 8x8 block at `(24, 8)` in the left eye,
+    /// seen `shift` pixels further left by the right eye.
+    fn pair(shift: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut left: Vec<u8> =
+            (0..WIDTH * HEIGHT).map(|i| texture(i % WIDTH, i / WIDTH)).collect();
+        let mut right = left.clone();
+        for y in 8..16 {
+            for x in 0..8 {
+                let v = texture(x + 300, y + 300);
+                left[y * WIDTH + 24 + x] = v;
+                right[y * WIDTH + 24 - shift + x] = v;
+            }
+        }
+        (left, right)
+    }
+
+    #[test]
+    fn finds_a_shifted_block() {
+        let (left, right) = pair(6);
+        let map = BlockMatcher::default().compute(&left, &right, WIDTH, HEIGHT);
+        assert_eq!((map.columns, map.rows, map.block), (8, 4, 8));
+        for row in 0..map.rows {
+            for column in 0..map.columns {
+                let value = map.values[row * map.columns + column];
+                match (row, column) {
+                    (1, 3) => assert_eq!(value, 6, "{:?}", map.values),
+                    // partly hidden behind the block in the right eye, anything goes
+                    (1, 2) => {}
+                    _ => assert_eq!(value, 0, "{:?}", map.values),
+                }
+            }
+        }
+        assert_eq!(map.at(24, 8), 6);
+        assert_eq!(map.at(31, 15), 6);
+        assert_eq!(map.at(32, 15), 0);
+        // past the edge is the last block
+        assert_eq!(map.at(1000, 1000), map.values[map.values.len() - 1]);
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        let matcher = BlockMatcher::default();
+        let flat = vec![128; WIDTH * HEIGHT];
+        let map = matcher.compute(&flat, &flat, WIDTH, HEIGHT);
+        assert!(map.values.iter().all(|&d| d == 0), "{:?}", map.values);
+
+        let (same, _) = pair(0);
+        let map = matcher.compute(&same, &same, WIDTH, HEIGHT);
+        assert!(map.values.iter().all(|&d| d == 0), "{:?}", map.values);
+
+        // sizes that aren't a multiple of the block
+        let map = matcher.compute(&flat[..30 * 13], &flat[..30 * 13], 30, 13);
+        assert_eq!((map.columns, map.rows), (4, 2));
+    }
+
+    #[test]
+    fn search_range() {
+        // too close for the matcher to see
+        let (left, right) = pair(12);
+        let near = BlockMatcher {
+            block: 8,
+            max_disparity: 8,
+        };
+        let map = near.compute(&left, &right, WIDTH, HEIGHT);
+        assert_ne!(map.at(24, 8), 12);
+        let map = BlockMatcher::default().compute(&left, &right, WIDTH, HEIGHT);
+        assert_eq!(map.at(24, 8), 12);
+    }
+
+    #[test]
+    fn renders_grey() {
+        let map = DisparityMap {
+            columns: 2,
+            rows: 1,
+            block: 2,
+            values: vec![0, 32],
+        };
+        let mut out = [0u8; 8 * 2];
+        map.render(4, 2, 32, PixelFormat::Rgb565, &mut out);
+        let pixels: Vec<u16> =
+            out.chunks_exact(2).map(|p| u16::from_le_bytes([p[0], p[1]])).collect();
+        assert_eq!(pixels, [0, 0, 0xffff, 0xffff, 0, 0, 0xffff, 0xffff]);
+
+        map.render(4, 2, 32, PixelFormat::Yuv422, &mut out);
+        assert_eq!(out[..8], [0, 128, 0, 128, 255, 128, 255, 128]);
+    }
+
+    #[test]
+    fn anaglyph_channels() {
+        let [wy, wu, wv] = yuv::rgb_to_yuv(255, 255, 255, RANGE);
+        let [ky, ku, kv] = yuv::rgb_to_yuv(0, 0, 0, RANGE);
+        let white = [wy, wu, wy, wv];
+        let black = [ky, ku, ky, kv];
+        let mut out = [0u8; 4];
+
+        anaglyph(&white, &black, PixelFormat::Yuv422, &mut out);
+        let rgb = yuv::yuv_to_rgb(out[0], out[1], out[3], RANGE);
+        assert!(rgb[0] > 250 && rgb[1] < 5 && rgb[2] < 5, "{:?}", rgb);
+        anaglyph(&black, &white, PixelFormat::Yuv422, &mut out);
+        let rgb = yuv::yuv_to_rgb(out[0], out[1], out[3], RANGE);
+        assert!(rgb[0] < 5 && rgb[1] > 250 && rgb[2] > 250, "{:?}", rgb);
+
+        let mut out = [0u8; 2];
+        anaglyph(&0xffffu16.to_le_bytes(), &0x1234u16.to_le_bytes(), PixelFormat::Rgb565, &mut out);
+        assert_eq!(u16::from_le_bytes(out), 0xf800 | 0x1234);
+    }
+
+    #[test]
+    fn luma_planes() {
+        let mut out = Vec::new();
+        luma(&[10, 128, 20, 128, 30, 100, 40, 150], PixelFormat::Yuv422, &mut out);
+        assert_eq!(out, [10, 20, 30, 40]);
+
+        let pixels = [0xffffu16, 0, 0xf800, 0x07e0];
+        let frame: Vec<u8> = pixels.iter().flat_map(|p| p.to_le_bytes()).collect();
+        luma(&frame, PixelFormat::Rgb565, &mut out);
+        assert_eq!(out, [255, 0, 76, 149]);
+    }
+}
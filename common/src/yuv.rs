@@ -0,0 +1,279 @@
+//! YUV 4:2:2 (YUYV, what the camera outputs as `OutputFormat::Yuv422`) to RGB conversion, and
+//! back for single pixels.
+//!
+//! Uses the BT.601 matrix in 16.16 fixed point, so it's cheap enough to run on the console too.
+
+use thiserror::Error;
+
+/// Which range the Y/U/V samples use.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum Range {
+    /// Y in 0-255, chroma in 0-255 (JPEG style). What the cameras output, and what JFIF expects,
+    /// so the JPEG encoder can take captures as they are.
+    #[default]
+    Full,
+    /// Y in 16-235, chroma in 16-240 (studio swing).
+    Limited,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RgbLayout {
+    /// R, G, B
+    Rgb888,
+    /// R, G, B, 255
+    Rgba8888,
+    /// B, G, R
+    Bgr24,
+    /// 5-6-5 packed in a little endian `u16`, same as `OutputFormat::Rgb565`.
+    Rgb565,
+}
+
+impl RgbLayout {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            RgbLayout::Rgb888 | RgbLayout::Bgr24 => 3,
+            RgbLayout::Rgba8888 => 4,
+            RgbLayout::Rgb565 => 2,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConvertError {
+    #[error("Width {0} is odd, YUYV needs an even width")]
+    OddWidth(usize),
+    #[error("Expected {expected} bytes of YUYV data, got {actual}")]
+    WrongSize { expected: usize, actual: usize },
+}
+
+/// Per-range coefficients, all scaled by 2^16.
+struct Coefficients {
+    y_offset: i32,
+    y_scale: i32,
+    r_v: i32,
+    g_u: i32,
+    g_v: i32,
+    b_u: i32,
+}
+
+const fn fixed(x: f64) -> i32 {
+    (x * 65536.0 + 0.5) as i32
+}
+
+const FULL: Coefficients = Coefficients {
+    y_offset: 0,
+    y_scale: fixed(1.0),
+    r_v: fixed(1.402),
+    g_u: fixed(0.344136),
+    g_v: fixed(0.714136),
+    b_u: fixed(1.772),
+};
+
+const LIMITED: Coefficients = Coefficients {
+    y_offset: 16,
+    y_scale: fixed(255.0 / 219.0),
+    r_v: fixed(1.402 * 255.0 / 224.0),
+    g_u: fixed(0.344136 * 255.0 / 224.0),
+    g_v: fixed(0.714136 * 255.0 / 224.0),
+    b_u: fixed(1.772 * 255.0 / 224.0),
+};
+
+impl Range {
+    fn coefficients(self) -> &'static Coefficients {
+        match self {
+            Range::Full => &FULL,
+            Range::Limited => &LIMITED,
+        }
+    }
+}
+
+/// Converts a single Y/U/V sample to R, G, B.
+pub fn yuv_to_rgb(y: u8, u: u8, v: u8, range: Range) -> [u8; 3] {
+    let c = range.coefficients();
+    let (u, v) = (u as i32 - 128, v as i32 - 128);
+    pixel(c, y, c.r_v * v, c.g_u * u + c.g_v * v, c.b_u * u)
+}
+
+/// Converts R, G, B back to a single Y/U/V sample, the inverse of [`yuv_to_rgb`].
+pub fn rgb_to_yuv(r: u8, g: u8, b: u8, range: Range) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = fixed(0.299) * r + fixed(0.587) * g + fixed(0.114) * b;
+    let u = -fixed(0.168736) * r - fixed(0.331264) * g + fixed(0.5) * b;
+    let v = fixed(0.5) * r - fixed(0.418688) * g - fixed(0.081312) * b;
+
+    let (y, u, v) = match range {
+        Range::Full => (y, u, v),
+        Range::Limited => (
+            y / 255 * 219 + (16 << 16),
+            u / 255 * 224,
+            v / 255 * 224,
+        ),
+    };
+    [
+        clamp((y + (1 << 15)) >> 16),
+        clamp(((u + (1 << 15)) >> 16) + 128),
+        clamp(((v + (1 << 15)) >> 16) + 128),
+    ]
+}
+
+#[inline]
+fn pixel(c: &Coefficients, y: u8, r: i32, g: i32, b: i32) -> [u8; 3] {
+    let y = (y as i32 - c.y_offset) * c.y_scale + (1 << 15);
+    [clamp((y + r) >> 16), clamp((y - g) >> 16), clamp((y + b) >> 16)]
+}
+
+#[inline]
+fn clamp(x: i32) -> u8 {
+    x.clamp(0, 255) as u8
+}
+
+#[inline]
+fn store(out: &mut [u8], [r, g, b]: [u8; 3], layout: RgbLayout) {
+    match layout {
+        RgbLayout::Rgb888 => out.copy_from_slice(&[r, g, b]),
+        RgbLayout::Rgba8888 => out.copy_from_slice(&[r, g, b, 255]),
+        RgbLayout::Bgr24 => out.copy_from_slice(&[b, g, r]),
+        RgbLayout::Rgb565 => {
+            let packed = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+            out.copy_from_slice(&packed.to_le_bytes());
+        }
+    }
+}
+
+/// Converts a `width`x`height` YUYV frame into `out`, which is resized to fit.
+pub fn convert(
+    src: &[u8],
+    width: usize,
+    height: usize,
+    layout: RgbLayout,
+    range: Range,
+    out: &mut Vec<u8>,
+) -> Result<(), ConvertError> {
+    if width & 1 != 0 {
+        return Err(ConvertError::OddWidth(width));
+    }
+    let expected = width * height * 2;
+    if src.len() != expected {
+        return Err(ConvertError::WrongSize {
+            expected,
+            actual: src.len(),
+        });
+    }
+
+    let bpp = layout.bytes_per_pixel();
+    out.resize(width * height * bpp, 0);
+
+    let c = range.coefficients();
+    for (yuyv, rgb) in src.chunks_exact(4).zip(out.chunks_exact_mut(bpp * 2)) {
+        let (u, v) = (yuyv[1] as i32 - 128, yuyv[3] as i32 - 128);
+        let (r, g, b) = (c.r_v * v, c.g_u * u + c.g_v * v, c.b_u * u);
+
+        let (first, second) = rgb.split_at_mut(bpp);
+        store(first, pixel(c, yuyv[0], r, g, b), layout);
+        store(second, pixel(c, yuyv[2], r, g, b), layout);
+    }
+
+    Ok(())
+}
+
+/// Adds `offset` to every luma sample of a YUYV buffer in place, leaving chroma alone.
+pub fn adjust_brightness(yuyv: &mut [u8], offset: i16) {
+    if offset == 0 {
+        return;
+    }
+    for y in yuyv.iter_mut().step_by(2) {
+        *y = (*y as i16 + offset).clamp(0, 255) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (Y, U, V) and the RGB it should come out as
+    const FULL_GOLDEN: [([u8; 3], [u8; 3]); 7] = [
+        ([0, 128, 128], [0, 0, 0]),
+        ([255, 128, 128], [255, 255, 255]),
+        ([128, 128, 128], [128, 128, 128]),
+        ([76, 85, 255], [254, 0, 0]),
+        ([150, 44, 21], [0, 255, 1]),
+        ([29, 255, 107], [0, 0, 254]),
+        ([16, 128, 128], [16, 16, 16]),
+    ];
+
+    const LIMITED_GOLDEN: [([u8; 3], [u8; 3]); 6] = [
+        ([16, 128, 128], [0, 0, 0]),
+        ([235, 128, 128], [255, 255, 255]),
+        ([81, 90, 240], [254, 0, 0]),
+        ([145, 54, 34], [0, 255, 1]),
+        ([41, 240, 110], [0, 0, 255]),
+        // below black and above white clip
+        ([0, 128, 128], [0, 0, 0]),
+    ];
+
+    #[test]
+    fn golden_pixels() {
+        for (range, golden) in [(Range::Full, &FULL_GOLDEN[..]), (Range::Limited, &LIMITED_GOLDEN[..])] {
+            for &([y, u, v], rgb) in golden {
+                assert_eq!(yuv_to_rgb(y, u, v, range), rgb, "{:?} {:?}", range, (y, u, v));
+            }
+        }
+        assert_eq!(Range::default(), Range::Full);
+    }
+
+    #[test]
+    fn primaries_back_to_yuv() {
+        for (range, golden) in [(Range::Full, &FULL_GOLDEN[..4]), (Range::Limited, &LIMITED_GOLDEN[..3])] {
+            for &(yuv, [r, g, b]) in golden {
+                // the RGB above is rounded, so a step either way is fine
+                let back = rgb_to_yuv(r, g, b, range);
+                assert!(back.iter().zip(yuv).all(|(a, b)| a.abs_diff(b) <= 1), "{:?} {:?}", back, yuv);
+            }
+        }
+        assert_eq!(rgb_to_yuv(255, 0, 0, Range::Full), [76, 85, 255]);
+        assert_eq!(rgb_to_yuv(0, 0, 255, Range::Limited), [41, 240, 110]);
+    }
+
+    /// White, black, then two red pixels sharing their chroma.
+    const FRAME: [u8; 8] = [255, 128, 0, 128, 76, 85, 76, 255];
+
+    #[test]
+    fn golden_layouts() {
+        let golden: [(RgbLayout, &[u8]); 4] = [
+            (RgbLayout::Rgb888, &[255, 255, 255, 0, 0, 0, 254, 0, 0, 254, 0, 0]),
+            (RgbLayout::Rgba8888, &[255, 255, 255, 255, 0, 0, 0, 255, 254, 0, 0, 255, 254, 0, 0, 255]),
+            (RgbLayout::Bgr24, &[255, 255, 255, 0, 0, 0, 0, 0, 254, 0, 0, 254]),
+            (RgbLayout::Rgb565, &[0xff, 0xff, 0x00, 0x00, 0x00, 0xf8, 0x00, 0xf8]),
+        ];
+        let mut out = vec![1; 3];
+        for (layout, expected) in golden {
+            convert(&FRAME, 4, 1, layout, Range::Full, &mut out).unwrap();
+            assert_eq!(out, expected, "{:?}", layout);
+            assert_eq!(out.len(), 4 * layout.bytes_per_pixel());
+        }
+
+        // two rows come out as two rows
+        let frame = [FRAME, FRAME].concat();
+        convert(&frame, 4, 2, RgbLayout::Rgb888, Range::Full, &mut out).unwrap();
+        assert_eq!(out[..12], out[12..]);
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut out = Vec::new();
+        assert_eq!(convert(&FRAME, 3, 1, RgbLayout::Rgb888, Range::Full, &mut out), Err(ConvertError::OddWidth(3)));
+        assert_eq!(
+            convert(&FRAME, 4, 2, RgbLayout::Rgb888, Range::Full, &mut out),
+            Err(ConvertError::WrongSize { expected: 16, actual: 8 })
+        );
+    }
+
+    #[test]
+    fn brightness() {
+        let mut frame = FRAME;
+        adjust_brightness(&mut frame, 10);
+        assert_eq!(frame, [255, 128, 10, 128, 86, 85, 86, 255]);
+        adjust_brightness(&mut frame, -100);
+        assert_eq!(frame, [155, 128, 0, 128, 0, 85, 0, 255]);
+    }
+}
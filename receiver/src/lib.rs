@@ -0,0 +1,422 @@
+//! Desktop side of ctr-camera-rs: waits for a console to connect, runs the handshake and writes
+//! every frame it gets to a directory or stdout.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ctr_camera_common::config;
+use ctr_camera_common::discovery::{self, Announcement, Responder};
+use ctr_camera_common::handshake::{CameraId, FrameRate, Hello, Resolution, StreamConfig, Transport};
+use ctr_camera_common::protocol::{self, Frame, PixelFormat, ProtocolError};
+use ctr_camera_common::stereo::{self, StereoLayout};
+use ctr_camera_common::udp::{Reassembler, UdpError};
+use ctr_camera_common::yuv::{self, ConvertError, Range, RgbLayout};
+use thiserror::Error;
+
+pub mod sink;
+
+use sink::FrameSink;
+
+pub const USAGE: &str = "\
+Usage: ctr-camera-receiver [OPTIONS]
+
+Options:
+  -p, --port <PORT>       Port to listen on (default 5000)
+  -o, --output <DIR|->    Directory to write frames to, or - for stdout (default frames)
+  -f, --format <FORMAT>   Preferred pixel format: jpeg, yuv422, rgb565 (default jpeg)
+  -q, --quality <1-100>   JPEG quality (default 80)
+  -s, --size <WxH>        Preferred resolution (default 640x480)
+  -r, --fps <FPS>         Preferred frame rate: 5, 10, 15, 20, 30 (default 30)
+      --camera <CAMERA>   Camera to ask for: inner, outer-left, outer-right, both-outer
+                          (default whatever is selected on the console)
+      --stereo <LAYOUT>   3D layout for both-outer: mono, side-by-side, top-bottom,
+                          frame-sequential, anaglyph, depth
+                          (default whatever is selected on the console)
+  -c, --rgb <LAYOUT>      Convert YUV422 frames to rgb888, rgba8888, bgr24 or rgb565
+      --range <RANGE>     YUV range used for --rgb: full, limited (default full, what the
+                          cameras output)
+  -1, --once              Exit after the first connection ends
+  -n, --name <NAME>       Name consoles see when searching (default the host name)
+      --no-discovery      Don't answer consoles searching the network
+  -h, --help              Print this help";
+
+#[derive(Debug, Error)]
+pub enum ReceiverError {
+    #[error("{0}")]
+    Args(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("Conversion error: {0}")]
+    Convert(#[from] ConvertError),
+    #[error("UDP error: {0}")]
+    Udp(#[from] UdpError),
+}
+
+/// Everything the command line can set, see [`USAGE`].
+#[derive(Debug)]
+pub struct Options {
+    pub port: u16,
+    pub sink: FrameSink,
+    pub preferred: StreamConfig,
+    /// `None` leaves the choice to the console.
+    pub camera: Option<CameraId>,
+    /// `None` leaves the choice to the console.
+    pub stereo: Option<StereoLayout>,
+    pub rgb: Option<RgbLayout>,
+    pub range: Range,
+    pub once: bool,
+    pub name: String,
+    pub discovery: bool,
+}
+
+/// Accepts consoles on `options.port` one after the other, until the first one leaves if
+/// `options.once` is set.
+pub fn run(mut options: Options) -> Result<(), ReceiverError> {
+    options.sink.prepare()?;
+
+    let listener = TcpListener::bind(("0.0.0.0", options.port))?;
+    // consoles picking the UDP transport send frames to the same port
+    let udp = UdpSocket::bind(("0.0.0.0", options.port))?;
+    udp.set_read_timeout(Some(Duration::from_millis(100)))?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+
+    if options.discovery {
+        start_responder(&options);
+    }
+
+    for connection in listener.incoming() {
+        let stream = connection?;
+        let peer = stream.peer_addr()?;
+        eprintln!("{} connected", peer);
+
+        let mut output = Output::new(&mut options.sink, options.rgb.map(|layout| (layout, options.range)));
+        match receive(stream, &udp, &mut output, &options.preferred, options.camera, options.stereo) {
+            Ok(frames) => eprintln!("{} disconnected after {} frames", peer, frames),
+            Err(e) => eprintln!("{} dropped: {}", peer, e),
+        }
+
+        if options.once {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers discovery probes in the background. Not being discoverable isn't fatal, consoles can
+/// still type the address in.
+fn start_responder(options: &Options) {
+    let announcement = Announcement {
+        nonce: 0,
+        port: options.port,
+        tcp: true,
+        udp: true,
+        name: options.name.clone(),
+    };
+
+    match Responder::bind(SocketAddr::from(([0, 0, 0, 0], discovery::PORT)), announcement) {
+        Ok(responder) => {
+            eprintln!("Discoverable as \"{}\"", options.name);
+            std::thread::spawn(move || {
+                if let Err(e) = responder.run() {
+                    eprintln!("Discovery stopped: {}", e);
+                }
+            });
+        }
+        Err(e) => eprintln!("Not discoverable, port {} unavailable: {}", discovery::PORT, e),
+    }
+}
+
+/// Where frames go once they're received, converted to RGB first if `convert` says so.
+pub struct Output<'a> {
+    sink: &'a mut FrameSink,
+    convert: Option<(RgbLayout, Range)>,
+    rgb: Vec<u8>,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(sink: &'a mut FrameSink, convert: Option<(RgbLayout, Range)>) -> Self {
+        Self {
+            sink,
+            convert,
+            rgb: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, frame: &Frame) -> Result<(), ReceiverError> {
+        let header = frame.header;
+        let eye = match stereo::parse_flags(header.flags) {
+            (StereoLayout::FrameSequential, eye) => Some(eye),
+            _ => None,
+        };
+        match self.convert {
+            Some((layout, range)) if header.format == PixelFormat::Yuv422 => {
+                yuv::convert(
+                    &frame.payload,
+                    header.width as usize,
+                    header.height as usize,
+                    layout,
+                    range,
+                    &mut self.rgb,
+                )?;
+                self.sink
+                    .write(header.sequence, eye, sink::rgb_extension(layout), &self.rgb)?;
+            }
+            _ => self.sink.write(
+                header.sequence,
+                eye,
+                sink::extension(header.format),
+                &frame.payload,
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Runs the handshake and writes every frame to `output` until the console disconnects.
+///
+/// Frames come over `stream` or `udp`, whichever the console says it's using.
+///
+/// Returns the number of frames received.
+pub fn receive(
+    mut stream: TcpStream,
+    udp: &UdpSocket,
+    output: &mut Output,
+    preferred: &StreamConfig,
+    camera: Option<CameraId>,
+    stereo: Option<StereoLayout>,
+) -> Result<u64, ReceiverError> {
+    let hello = Hello::read_from(&mut stream)?;
+
+    // the console lists the camera selected on it first
+    let mut preferred = *preferred;
+    match (camera, hello.cameras.first()) {
+        (Some(camera), _) => preferred.camera = camera,
+        (None, Some(&selected)) => preferred.camera = selected,
+        (None, None) => {}
+    }
+    match (stereo, hello.stereo_layouts.first()) {
+        (Some(stereo), _) => preferred.stereo = stereo,
+        (None, Some(&selected)) => preferred.stereo = selected,
+        (None, None) => {}
+    }
+    let config = StreamConfig::choose(&hello, &preferred)?;
+    config.write_to(&mut stream)?;
+
+    eprintln!(
+        "{} offers {} resolutions, streaming {:?} {}x{} @ {:?} from {:?} ({}) over {:?}",
+        hello.model,
+        hello.resolutions.len(),
+        config.format,
+        config.resolution.width,
+        config.resolution.height,
+        config.frame_rate,
+        config.camera,
+        config.stereo.name(),
+        hello.transport
+    );
+
+    match hello.transport {
+        Transport::Tcp => {
+            let mut frames = 0;
+            while let Some(frame) = protocol::read_frame(&mut stream)? {
+                output.write(&frame)?;
+                stream.write_all(&protocol::encode_ack(frame.header.sequence))?;
+                frames += 1;
+            }
+            Ok(frames)
+        }
+        Transport::Udp => receive_udp(stream, udp, output),
+    }
+}
+
+/// Frames come in over `udp`, `control` carries the acks and tells us when the console is gone.
+fn receive_udp(
+    mut control: TcpStream,
+    udp: &UdpSocket,
+    output: &mut Output,
+) -> Result<u64, ReceiverError> {
+    let peer = control.peer_addr()?.ip();
+    control.set_nonblocking(true)?;
+
+    let mut reassembler = Reassembler::new();
+    let mut datagram = vec![0u8; 65536];
+    let mut frames = 0;
+
+    loop {
+        match udp.recv_from(&mut datagram) {
+            Ok((len, from)) if from.ip() == peer => {
+                let data = match reassembler.push(&datagram[..len]) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        eprintln!("Ignoring datagram: {}", e);
+                        continue;
+                    }
+                };
+                match protocol::read_frame(&mut &data[..]) {
+                    Ok(Some(frame)) => {
+                        output.write(&frame)?;
+                        frames += 1;
+                        // only for the console's statistics, not worth waiting for
+                        match control.write_all(&protocol::encode_ack(frame.header.sequence)) {
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                            result => result?,
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => eprintln!("Dropping frame: {}", e),
+                }
+            }
+            Ok(_) => {} // not our console
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        match control.read(&mut [0u8; 64]) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if reassembler.dropped() > 0 {
+        eprintln!("{} incomplete frames dropped", reassembler.dropped());
+    }
+    Ok(frames)
+}
+
+/// Parses the command line, without the program name. `None` means the help was asked for.
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, ReceiverError> {
+    let mut options = Options {
+        port: protocol::DEFAULT_PORT,
+        sink: FrameSink::Directory(PathBuf::from("frames")),
+        preferred: StreamConfig::default(),
+        camera: None,
+        stereo: None,
+        rgb: None,
+        range: Range::default(),
+        once: false,
+        name: default_name(),
+        discovery: true,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| ReceiverError::Args(format!("Missing value for {}", name)))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-1" | "--once" => options.once = true,
+            "--no-discovery" => options.discovery = false,
+            "-n" | "--name" => options.name = value(&arg)?,
+            "-p" | "--port" => {
+                let port = value(&arg)?;
+                options.port = port
+                    .parse()
+                    .map_err(|_| ReceiverError::Args(format!("Invalid port {}", port)))?;
+            }
+            "-o" | "--output" => {
+                options.sink = match value(&arg)?.as_str() {
+                    "-" => FrameSink::Stdout,
+                    dir => FrameSink::Directory(PathBuf::from(dir)),
+                };
+            }
+            "-f" | "--format" => options.preferred.format = parse_format(&value(&arg)?)?,
+            "-q" | "--quality" => {
+                let quality = value(&arg)?;
+                options.preferred.quality = quality
+                    .parse()
+                    .ok()
+                    .filter(|q| (1..=100).contains(q))
+                    .ok_or_else(|| ReceiverError::Args(format!("Invalid quality {}", quality)))?;
+            }
+            "-s" | "--size" => options.preferred.resolution = parse_size(&value(&arg)?)?,
+            "-r" | "--fps" => options.preferred.frame_rate = parse_fps(&value(&arg)?)?,
+            "--camera" => {
+                let camera = value(&arg)?;
+                options.camera = Some(
+                    config::camera_from_name(&camera)
+                        .ok_or_else(|| ReceiverError::Args(format!("Unknown camera {}", camera)))?,
+                );
+            }
+            "--stereo" => {
+                let stereo = value(&arg)?;
+                options.stereo = Some(
+                    StereoLayout::from_name(&stereo)
+                        .ok_or_else(|| ReceiverError::Args(format!("Unknown stereo layout {}", stereo)))?,
+                );
+            }
+            "-c" | "--rgb" => options.rgb = Some(parse_layout(&value(&arg)?)?),
+            "--range" => options.range = parse_range(&value(&arg)?)?,
+            other => return Err(ReceiverError::Args(format!("Unknown argument {}", other))),
+        }
+    }
+
+    Ok(Some(options))
+}
+
+fn default_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "ctr-camera-receiver".to_owned())
+}
+
+fn parse_layout(s: &str) -> Result<RgbLayout, ReceiverError> {
+    match s {
+        "rgb888" | "rgb" => Ok(RgbLayout::Rgb888),
+        "rgba8888" | "rgba" => Ok(RgbLayout::Rgba8888),
+        "bgr24" | "bgr" => Ok(RgbLayout::Bgr24),
+        "rgb565" => Ok(RgbLayout::Rgb565),
+        other => Err(ReceiverError::Args(format!("Unknown RGB layout {}", other))),
+    }
+}
+
+fn parse_range(s: &str) -> Result<Range, ReceiverError> {
+    match s {
+        "limited" => Ok(Range::Limited),
+        "full" => Ok(Range::Full),
+        other => Err(ReceiverError::Args(format!("Unknown range {}", other))),
+    }
+}
+
+fn parse_format(s: &str) -> Result<PixelFormat, ReceiverError> {
+    match s {
+        "jpeg" | "mjpeg" => Ok(PixelFormat::Jpeg),
+        "yuv422" => Ok(PixelFormat::Yuv422),
+        "rgb565" => Ok(PixelFormat::Rgb565),
+        other => Err(ReceiverError::Args(format!("Unknown format {}", other))),
+    }
+}
+
+fn parse_size(s: &str) -> Result<Resolution, ReceiverError> {
+    let invalid = || ReceiverError::Args(format!("Invalid size {}, expected WxH", s));
+
+    let (w, h) = s.split_once('x').ok_or_else(invalid)?;
+    Ok(Resolution::new(
+        w.parse().map_err(|_| invalid())?,
+        h.parse().map_err(|_| invalid())?,
+    ))
+}
+
+fn parse_fps(s: &str) -> Result<FrameRate, ReceiverError> {
+    match s {
+        "5" => Ok(FrameRate::Fps5),
+        "10" => Ok(FrameRate::Fps10),
+        "15" => Ok(FrameRate::Fps15),
+        "20" => Ok(FrameRate::Fps20),
+        "30" => Ok(FrameRate::Fps30),
+        other => Err(ReceiverError::Args(format!("Unsupported frame rate {}", other))),
+    }
+}
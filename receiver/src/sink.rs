@@ -0,0 +1,62 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use ctr_camera_common::protocol::PixelFormat;
+use ctr_camera_common::stereo::Eye;
+use ctr_camera_common::yuv::RgbLayout;
+
+/// Where decoded frames end up.
+#[derive(Debug)]
+pub enum FrameSink {
+    /// Payloads back to back, e.g. to pipe into `ffplay -f rawvideo` or `ffplay -f mjpeg`.
+    Stdout,
+    /// One file per frame, named after the sequence number, and the eye for frame-sequential 3D.
+    Directory(PathBuf),
+}
+
+impl FrameSink {
+    pub fn prepare(&self) -> io::Result<()> {
+        match self {
+            FrameSink::Stdout => Ok(()),
+            FrameSink::Directory(dir) => fs::create_dir_all(dir),
+        }
+    }
+
+    /// Writes one frame, `extension` and `eye` are only used when writing to a directory.
+    pub fn write(&mut self, sequence: u32, eye: Option<Eye>, extension: &str, data: &[u8]) -> io::Result<()> {
+        match self {
+            FrameSink::Stdout => {
+                let mut out = io::stdout().lock();
+                out.write_all(data)?;
+                out.flush()
+            }
+            FrameSink::Directory(dir) => {
+                let eye = match eye {
+                    Some(Eye::Left) => "_l",
+                    Some(Eye::Right) => "_r",
+                    None => "",
+                };
+                let name = format!("frame_{:08}{}.{}", sequence, eye, extension);
+                File::create(dir.join(name))?.write_all(data)
+            }
+        }
+    }
+}
+
+pub fn extension(format: PixelFormat) -> &'static str {
+    match format {
+        PixelFormat::Yuv422 => "yuv",
+        PixelFormat::Rgb565 => "rgb565",
+        PixelFormat::Jpeg => "jpg",
+    }
+}
+
+pub fn rgb_extension(layout: RgbLayout) -> &'static str {
+    match layout {
+        RgbLayout::Rgb888 => "rgb",
+        RgbLayout::Rgba8888 => "rgba",
+        RgbLayout::Bgr24 => "bgr",
+        RgbLayout::Rgb565 => "rgb565",
+    }
+}
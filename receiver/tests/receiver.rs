@@ -0,0 +1,212 @@
+//! Drives the receiver over loopback with a fake console on the other end.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use ctr_camera_common::handshake::{self, CameraId, FrameRate, Hello, Resolution, StreamConfig, Transport};
+use ctr_camera_common::protocol::{AckDecoder, FrameEncoder, PixelFormat};
+use ctr_camera_common::stereo::StereoLayout;
+use ctr_camera_common::stream::{FrameSource, TestPattern};
+use ctr_camera_common::udp::UdpFrameWriter;
+use ctr_camera_common::yuv::{self, Range, RgbLayout};
+use ctr_camera_receiver::sink::FrameSink;
+use ctr_camera_receiver::{parse_args, receive, Output, ReceiverError};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ctr-receiver-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn hello(transport: Transport) -> Hello {
+    Hello {
+        model: "New3DSXL".into(),
+        formats: vec![PixelFormat::Yuv422, PixelFormat::Jpeg],
+        frame_rates: vec![FrameRate::Fps15, FrameRate::Fps30],
+        resolutions: vec![Resolution::new(64, 32), Resolution::new(32, 16)],
+        cameras: vec![CameraId::Inner, CameraId::BothOuter],
+        transport,
+        stereo_layouts: StereoLayout::ALL.to_vec(),
+    }
+}
+
+fn frames(config: &StreamConfig, count: usize) -> Vec<Vec<u8>> {
+    let mut source = TestPattern::new(config.resolution.width, config.resolution.height);
+    (0..count)
+        .map(|_| {
+            let mut frame = vec![0; source.frame_size()];
+            source.capture(&mut frame).unwrap();
+            frame
+        })
+        .collect()
+}
+
+/// Reads acks off the control connection until there are `count` of them.
+fn wait_for_acks(control: &mut TcpStream, count: usize) -> Vec<u32> {
+    let mut acks = AckDecoder::new();
+    let mut seen = Vec::new();
+    let mut buf = [0u8; 64];
+    while seen.len() < count {
+        let n = control.read(&mut buf).unwrap();
+        assert_ne!(n, 0, "receiver hung up");
+        seen.extend(acks.feed(&buf[..n]).unwrap());
+    }
+    seen
+}
+
+/// Connects a fake console to a receiver running [`receive`] on a background thread. Returns the
+/// console's end of the control connection, where the receiver expects UDP frames, and the
+/// receiver's result.
+fn connect(
+    sink: FrameSink,
+    preferred: StreamConfig,
+    convert: Option<(RgbLayout, Range)>,
+) -> (TcpStream, SocketAddr, thread::JoinHandle<Result<u64, ReceiverError>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+    let udp_addr = udp.local_addr().unwrap();
+
+    let console = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let handle = thread::spawn(move || {
+        let mut sink = sink;
+        receive(stream, &udp, &mut Output::new(&mut sink, convert), &preferred, None, None)
+    });
+    (console, udp_addr, handle)
+}
+
+#[test]
+fn tcp_frames_to_directory() {
+    let dir = temp_dir("tcp");
+    let preferred = StreamConfig {
+        format: PixelFormat::Yuv422,
+        resolution: Resolution::new(32, 16),
+        ..StreamConfig::default()
+    };
+    let (mut console, _, handle) = connect(FrameSink::Directory(dir.clone()), preferred, None);
+
+    let config = handshake::negotiate(&mut console, &hello(Transport::Tcp)).unwrap();
+    // what the receiver asked for, and the camera selected on the console
+    assert_eq!(config.format, PixelFormat::Yuv422);
+    assert_eq!(config.resolution, Resolution::new(32, 16));
+    assert_eq!(config.frame_rate, FrameRate::Fps30);
+    assert_eq!(config.camera, CameraId::Inner);
+    assert_eq!(config.stereo, StereoLayout::Mono);
+
+    let sent = frames(&config, 3);
+    let mut encoder = FrameEncoder::new();
+    for frame in &sent {
+        encoder.encode(&mut console, config.format, 32, 16, 0, frame).unwrap();
+    }
+    assert_eq!(wait_for_acks(&mut console, 3), [0, 1, 2]);
+    drop(console);
+
+    assert_eq!(handle.join().unwrap().unwrap(), 3);
+    for (i, frame) in sent.iter().enumerate() {
+        assert_eq!(fs::read(dir.join(format!("frame_{:08}.yuv", i))).unwrap(), *frame);
+    }
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn converts_to_rgb() {
+    let dir = temp_dir("rgb");
+    let preferred = StreamConfig {
+        format: PixelFormat::Yuv422,
+        resolution: Resolution::new(64, 32),
+        ..StreamConfig::default()
+    };
+    let convert = Some((RgbLayout::Rgb888, Range::Full));
+    let (mut console, _, handle) = connect(FrameSink::Directory(dir.clone()), preferred, convert);
+
+    let config = handshake::negotiate(&mut console, &hello(Transport::Tcp)).unwrap();
+    let sent = frames(&config, 1);
+    FrameEncoder::new()
+        .encode(&mut console, config.format, 64, 32, 0, &sent[0])
+        .unwrap();
+    wait_for_acks(&mut console, 1);
+    drop(console);
+
+    assert_eq!(handle.join().unwrap().unwrap(), 1);
+    let mut rgb = Vec::new();
+    yuv::convert(&sent[0], 64, 32, RgbLayout::Rgb888, Range::Full, &mut rgb).unwrap();
+    assert_eq!(fs::read(dir.join("frame_00000000.rgb")).unwrap(), rgb);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn jpeg_over_udp() {
+    let dir = temp_dir("udp");
+    let (mut console, udp_addr, handle) = connect(FrameSink::Directory(dir.clone()), StreamConfig::default(), None);
+
+    let config = handshake::negotiate(&mut console, &hello(Transport::Udp)).unwrap();
+    assert_eq!(config.format, PixelFormat::Jpeg);
+    // 640x480 wasn't offered, so the first resolution
+    assert_eq!(config.resolution, Resolution::new(64, 32));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(udp_addr).unwrap();
+    let mut writer = UdpFrameWriter::new(socket);
+    // bigger than one datagram, so it has to be put back together
+    let payloads: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 5000]).collect();
+    let mut encoder = FrameEncoder::new();
+    for payload in &payloads {
+        encoder.encode(&mut writer, config.format, 64, 32, 0, payload).unwrap();
+        writer.flush().unwrap();
+        // loopback doesn't drop anything as long as the receiver keeps up
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(wait_for_acks(&mut console, 3), [0, 1, 2]);
+    drop(console);
+
+    assert_eq!(handle.join().unwrap().unwrap(), 3);
+    for (i, payload) in payloads.iter().enumerate() {
+        assert_eq!(fs::read(dir.join(format!("frame_{:08}.jpg", i))).unwrap(), *payload);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rejects_bad_hello() {
+    let (mut console, _, handle) = connect(FrameSink::Stdout, StreamConfig::default(), None);
+    console.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    assert!(matches!(handle.join().unwrap(), Err(ReceiverError::Protocol(_))));
+}
+
+fn args(args: &[&str]) -> Result<Option<ctr_camera_receiver::Options>, ReceiverError> {
+    parse_args(args.iter().map(|s| s.to_string()))
+}
+
+#[test]
+fn command_line() {
+    let options = args(&[
+        "-p", "6000", "-o", "-", "-f", "rgb565", "-q", "55", "-s", "320x240", "-r", "15", "--camera", "inner",
+        "--stereo", "anaglyph", "-c", "bgr24", "--range", "full", "--once", "--no-discovery", "-n", "desk",
+    ])
+    .unwrap()
+    .unwrap();
+    assert_eq!(options.port, 6000);
+    assert!(matches!(options.sink, FrameSink::Stdout));
+    assert_eq!(options.preferred.format, PixelFormat::Rgb565);
+    assert_eq!(options.preferred.quality, 55);
+    assert_eq!(options.preferred.resolution, Resolution::new(320, 240));
+    assert_eq!(options.preferred.frame_rate, FrameRate::Fps15);
+    assert_eq!(options.camera, Some(CameraId::Inner));
+    assert_eq!(options.stereo, Some(StereoLayout::Anaglyph));
+    assert_eq!(options.rgb, Some(RgbLayout::Bgr24));
+    assert_eq!(options.range, Range::Full);
+    assert!(options.once && !options.discovery);
+    assert_eq!(options.name, "desk");
+
+    assert!(args(&["--help"]).unwrap().is_none());
+    for bad in [&["-q", "0"][..], &["-s", "640"], &["-r", "60"], &["--camera", "back"], &["-p"], &["--what"]] {
+        assert!(matches!(args(bad), Err(ReceiverError::Args(_))), "{:?}", bad);
+    }
+}
@@ -0,0 +1,1014 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ctr_camera_common::config::{Config, SavedServer};
+use ctr_camera_common::discovery::{Browser, Server};
+use ctr_camera_common::exif::{DateTime, Metadata};
+use ctr_camera_common::handshake::{self, CameraId, FrameRate, Hello, Resolution, StreamConfig, Transport};
+use ctr_camera_common::http::{self, HttpServer};
+use ctr_camera_common::input::{Action, Buttons, KeyMap};
+use ctr_camera_common::menu::Cursor;
+use ctr_camera_common::photo::{self, PhotoFormat, Still};
+use ctr_camera_common::protocol::{AckDecoder, PixelFormat};
+use ctr_camera_common::reconnect::{Backoff, Poll, Reconnect};
+use ctr_camera_common::record::{Container, Recorder};
+use ctr_camera_common::rtsp::{self, RtspServer};
+use ctr_camera_common::settings::{CameraSettings, Setting};
+use ctr_camera_common::stats::{Snapshot, Stats};
+use ctr_camera_common::stereo::StereoLayout;
+use ctr_camera_common::stream::{FrameSource, JpegCapture};
+use ctr_camera_common::udp::UdpFrameWriter;
+
+use crate::pipeline::{self, Pipeline};
+use crate::platform::{Camera, Devices, Display, Input, Network, Platform, SystemInfo, TextEntry, CONNECT_TIMEOUT};
+use crate::state::{self, AppStatus, Choice, Effect, Event, Target};
+use crate::AppError;
+
+/// First console row of the statistics overlay, the last three rows of the screen.
+const STATS_ROW: usize = 28;
+
+/// Where things go on the SD card (or wherever the platform keeps files).
+#[derive(Clone, Debug)]
+pub struct Paths {
+    /// The settings file, see [`Config`].
+    pub config: PathBuf,
+    /// Directory recordings get numbered files in.
+    pub recordings: PathBuf,
+    /// The `DCIM` folder, stills go in numbered folders inside, see [`photo`].
+    pub photos: PathBuf,
+}
+
+/// Everything the app is doing, stepped once a frame by whoever owns the main loop.
+pub struct App<P: Platform> {
+    // shared with the capture thread while streaming
+    camera: Arc<Mutex<P::Camera>>,
+    input: P::Input,
+    text: P::Text,
+    network: P::Network,
+    system: P::System,
+    display: P::Display,
+
+    address: Ipv4Addr,
+    paths: Paths,
+    camera_settings: CameraSettings,
+    config: StreamConfig,
+    status: AppStatus,
+    pipeline_or_none: Option<Pipeline>,
+    http_or_none: Option<HttpServer>,
+    // runs next to everything else, toggled with SELECT
+    rtsp_or_none: Option<RtspServer>,
+    browser_or_none: Option<Browser>,
+    recorder_or_none: Option<Recorder>,
+    // raw frames for recordings that don't want JPEG
+    raw: Vec<u8>,
+    // where to go back to when the connection drops
+    last_server: Option<SavedServer>,
+    reconnect_or_none: Option<(Reconnect, SavedServer)>,
+    countdown: Option<u64>,
+    cursor: Cursor,
+    saved: Config,
+    // false if the config file is there but couldn't be read, it mustn't be saved over then
+    config_readable: bool,
+    // built from the key map in `saved`
+    buttons: Buttons,
+    jpeg_capture: JpegCapture,
+    exiting: bool,
+}
+
+impl<P: Platform> App<P> {
+    /// Sets up the camera, reads the saved servers from the config file and shows the start
+    /// screen.
+    pub fn new(devices: Devices<P>, paths: Paths) -> Self {
+        let camera_settings = CameraSettings::default();
+        let config = StreamConfig::default();
+
+        let mut camera = devices.camera;
+        // the cameras get configured again before every capture, so this can fail for now
+        if let Err(e) = camera.configure(&config, &camera_settings) {
+            println!("{}", e);
+        }
+
+        let (saved, config_readable) = match Config::load(&paths.config) {
+            Ok((saved, errors)) => {
+                for e in errors {
+                    println!("Skipping in {}: {}", paths.config.display(), e);
+                }
+                (saved, true)
+            }
+            Err(e) => {
+                println!("Ignoring {}: {}", paths.config.display(), e);
+                (Config::default(), false)
+            }
+        };
+
+        setup(&devices.system, &devices.network, &saved.keys);
+
+        Self {
+            camera: Arc::new(Mutex::new(camera)),
+            input: devices.input,
+            text: devices.text,
+            address: devices.network.host_address(),
+            network: devices.network,
+            system: devices.system,
+            display: devices.display,
+            paths,
+            camera_settings,
+            config,
+            status: AppStatus::NotConnected,
+            pipeline_or_none: None,
+            http_or_none: None,
+            rtsp_or_none: None,
+            browser_or_none: None,
+            recorder_or_none: None,
+            raw: Vec::new(),
+            last_server: None,
+            reconnect_or_none: None,
+            countdown: None,
+            cursor: Cursor::new(1),
+            buttons: Buttons::new(saved.keys.clone()),
+            saved,
+            config_readable,
+            jpeg_capture: JpegCapture::new(config.quality),
+            exiting: false,
+        }
+    }
+
+    pub fn status(&self) -> AppStatus {
+        self.status
+    }
+
+    pub fn camera_settings(&self) -> &CameraSettings {
+        &self.camera_settings
+    }
+
+    /// The saved servers, as they are in the config file.
+    pub fn saved(&self) -> &Config {
+        &self.saved
+    }
+
+    pub fn camera(&self) -> &Arc<Mutex<P::Camera>> {
+        &self.camera
+    }
+
+    /// Handles this frame's input, moves the streams along and shows the result. Returns `false`
+    /// once the user asked to leave.
+    pub fn step(&mut self) -> bool {
+        let keys = self.input.scan();
+
+        let before = self.status;
+        for action in self.buttons.update(keys, Instant::now()) {
+            // the rest of the buttons were meant for the screen that's gone now
+            if self.status != before {
+                break;
+            }
+            self.handle(Event::Action(action));
+            if self.exiting {
+                return false;
+            }
+        }
+
+        self.handle(Event::Tick);
+
+        self.display.present();
+        true
+    }
+
+    /// Runs `event` and everything the effects it causes report back through [`state::update`].
+    fn handle(&mut self, event: Event) {
+        let mut events = VecDeque::from([event]);
+        while let Some(event) = events.pop_front() {
+            let (status, effects) = state::update(self.status, event);
+            self.status = status;
+            for effect in effects {
+                events.extend(self.run(effect));
+            }
+        }
+    }
+
+    fn run(&mut self, effect: Effect) -> Option<Event> {
+        match effect {
+            Effect::Exit => {
+                println!("Exiting...");
+                // stops streaming and closes the connection
+                self.pipeline_or_none = None;
+                self.exiting = true;
+            }
+            Effect::ShowSetup => {
+                self.display.clear();
+                setup(&self.system, &self.network, self.buttons.map());
+            }
+            Effect::ShowSettings => {
+                self.cursor = Cursor::new(Setting::ALL.len());
+                self.display.clear();
+                draw_settings(&self.camera_settings, &self.cursor, self.buttons.map());
+            }
+            Effect::ShowSaved => {
+                // saved servers, then searching, then the keyboard
+                self.cursor = Cursor::new(self.saved.servers.len() + 2);
+                self.cursor.select(self.saved.last_used_index().unwrap_or(0));
+                self.display.clear();
+                draw_saved(&self.saved, &self.cursor, self.buttons.map());
+            }
+            Effect::ShowServers => {
+                self.cursor = Cursor::new(1);
+                self.display.clear();
+                draw_servers(&[], &self.cursor, self.buttons.map());
+            }
+            Effect::Message(message) => println!("{}", message),
+            Effect::Navigate(action) => self.navigate(action),
+            Effect::Choose => {
+                let index = self.cursor.index();
+                let choice = match self.status {
+                    AppStatus::Picking => match self.saved.servers.get(index) {
+                        Some(server) => Choice::Server(server.clone()),
+                        None if index == self.saved.servers.len() => Choice::Search,
+                        None => Choice::Keyboard,
+                    },
+                    // the last entry is the keyboard
+                    AppStatus::Discovering => match self.browser_or_none.as_ref().and_then(|b| b.servers().get(index)) {
+                        Some(server) => Choice::Server(discovered(server)),
+                        None => Choice::Keyboard,
+                    },
+                    _ => return None,
+                };
+                return Some(Event::Chose(choice));
+            }
+            Effect::FindServers => {
+                return Some(if self.saved.servers.is_empty() {
+                    Event::Chose(Choice::Search)
+                }
+                else {
+                    Event::Listed
+                });
+            }
+            Effect::Search => {
+                return Some(match discover() {
+                    Ok(browser) => {
+                        self.browser_or_none = Some(browser);
+                        Event::SearchStarted
+                    }
+                    Err(e) => {
+                        println!("Search failed: {}", e);
+                        Event::SearchFailed
+                    }
+                });
+            }
+            Effect::PollSearch => {
+                if let Some(ref mut browser) = self.browser_or_none {
+                    match browser.poll() {
+                        Ok(true) => {
+                            self.cursor.set_len(browser.servers().len() + 1);
+                            self.display.clear();
+                            draw_servers(browser.servers(), &self.cursor, self.buttons.map());
+                        }
+                        Ok(false) => {}
+                        Err(e) => println!("Search failed: {}", e),
+                    }
+                }
+            }
+            Effect::StopSearch => self.browser_or_none = None,
+            Effect::Connect(target) => {
+                let attempt = match target {
+                    Target::Server(server) => connect(&mut self.network, &self.system, &self.camera, server, &self.camera_settings).map(Some),
+                    Target::Keyboard => try_connect(&mut self.text, &mut self.network, &self.system, &self.camera, &self.camera_settings),
+                    Target::Retry => {
+                        let server = self.reconnect_or_none.as_ref()?.1.clone();
+                        println!();
+                        connect(&mut self.network, &self.system, &self.camera, server, &self.camera_settings).map(Some)
+                    }
+                };
+                return Some(self.connected(attempt));
+            }
+            Effect::PollStream => {
+                // the pipeline does the streaming, this just shows it
+                let mut lost = None;
+                if let Some(ref pipeline) = self.pipeline_or_none {
+                    if let Some(frame) = pipeline.take_latest() {
+                        self.display.draw_preview(&frame.data, frame.format, frame.width, frame.height, frame.flags);
+                    }
+                    for event in pipeline.events() {
+                        match event {
+                            pipeline::Event::Stats(snapshot) => draw_stats(&snapshot),
+                            pipeline::Event::Lost(e) => lost = Some(e),
+                        }
+                    }
+                }
+
+                if let Some(e) = lost {
+                    self.pipeline_or_none = None;
+                    return Some(Event::Lost(e.to_string()));
+                }
+            }
+            Effect::Disconnect => {
+                if let Some(pipeline) = self.pipeline_or_none.take() {
+                    match pipeline.peer() {
+                        Some(peer) => println!("Disconnected from {}.", peer),
+                        None => println!("Disconnected."),
+                    }
+                }
+            }
+            Effect::StartReconnect => {
+                let server = self.last_server.clone();
+                let Some(server) = server else {
+                    return Some(Event::GaveUp);
+                };
+                self.reconnect_or_none = Some((Reconnect::new(Backoff::default(), Instant::now(), seed()), server));
+                self.countdown = None;
+            }
+            Effect::PollReconnect => {
+                let Some((ref mut reconnect, ref server)) = self.reconnect_or_none else {
+                    return Some(Event::GaveUp);
+                };
+                match reconnect.poll(Instant::now()) {
+                    Poll::Wait(left) => {
+                        // whole seconds, rounded up so it never shows 0 before trying
+                        let seconds = left.as_secs() + 1;
+                        if self.countdown != Some(seconds) {
+                            self.countdown = Some(seconds);
+                            draw_countdown(server, reconnect, seconds, self.buttons.map());
+                        }
+                    }
+                    Poll::Attempt => {
+                        self.countdown = None;
+                        return Some(Event::RetryDue);
+                    }
+                    Poll::GiveUp => {
+                        println!();
+                        println!("Giving up on {} after {} attempts.", server.name, reconnect.attempt());
+                        return Some(Event::GaveUp);
+                    }
+                }
+            }
+            Effect::RetryLater => {
+                if let Some((ref mut reconnect, _)) = self.reconnect_or_none {
+                    reconnect.failed(Instant::now());
+                }
+            }
+            Effect::StopReconnect => self.reconnect_or_none = None,
+            Effect::StartHttp => {
+                let serving = serving_config(&self.camera_settings);
+                match pipeline::lock(&self.camera).configure(&serving, &self.camera_settings).and_then(|_| HttpServer::bind(SocketAddr::from((self.address, http::DEFAULT_PORT))).map_err(|e| AppError::Listen(http::DEFAULT_PORT, e))) {
+                    Ok(server) => {
+                        println!("Open http://{}:{}/ in a browser.", self.address, http::DEFAULT_PORT);
+                        println!("Press {} to stop serving, {} for a picture.", self.buttons.map().label(Action::Back), self.buttons.map().label(Action::Photo));
+                        self.config = serving;
+                        self.jpeg_capture = JpegCapture::new(self.config.quality);
+                        self.http_or_none = Some(server);
+                        return Some(Event::HttpStarted);
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            Effect::PollHttp => {
+                if let Some(ref mut server) = self.http_or_none {
+                    if let Err(e) = server.poll() {
+                        println!("HTTP server error: {}", e);
+                    }
+                }
+            }
+            Effect::StopHttp => self.http_or_none = None,
+            Effect::ToggleRtsp => {
+                if self.rtsp_or_none.take().is_some() {
+                    println!("RTSP server stopped.");
+                }
+                else {
+                    let serving = serving_config(&self.camera_settings);
+                    match pipeline::lock(&self.camera).configure(&serving, &self.camera_settings).and_then(|_| RtspServer::bind(SocketAddr::from((self.address, rtsp::DEFAULT_PORT)), serving.frame_rate.max_fps()).map_err(|e| AppError::Listen(rtsp::DEFAULT_PORT, e))) {
+                        Ok(server) => {
+                            println!("RTSP on rtsp://{}{}", self.address, rtsp::PATH);
+                            self.config = serving;
+                            self.jpeg_capture = JpegCapture::new(self.config.quality);
+                            self.rtsp_or_none = Some(server);
+                        }
+                        Err(e) => println!("{}", e),
+                    }
+                }
+            }
+            Effect::PollRtsp => {
+                if let Some(ref mut server) = self.rtsp_or_none {
+                    if let Err(e) = server.poll() {
+                        println!("RTSP server error: {}", e);
+                    }
+                }
+            }
+            Effect::ServeFrames => self.serve_frames(),
+            Effect::StartRecording => {
+                let container = self.saved.record_format;
+                let recording = recording_config(&self.camera_settings, container);
+                let mut camera = pipeline::lock(&self.camera);
+                let started = camera.configure(&recording, &self.camera_settings).and_then(|_| {
+                    let source = camera.source(recording, &self.camera_settings);
+                    // both eyes side by side when recording in 3D
+                    let (width, height) = (source.width(), source.height());
+                    Recorder::start(&self.paths.recordings, container, width, height, recording.frame_rate.max_fps(), self.saved.record_limits, Instant::now()).map_err(AppError::from)
+                });
+                drop(camera);
+                match started {
+                    Ok(recorder) => {
+                        if let Some(path) = recorder.files().last() {
+                            println!("Recording to {}", path.display());
+                        }
+                        println!("Press {} to stop, {} for a picture.", self.buttons.map().label(Action::Back), self.buttons.map().label(Action::Photo));
+                        self.config = recording;
+                        self.jpeg_capture = JpegCapture::new(self.config.quality);
+                        self.recorder_or_none = Some(recorder);
+                        return Some(Event::RecordingStarted);
+                    }
+                    Err(e) => println!("Couldn't start recording: {}", e),
+                }
+            }
+            Effect::TakePhoto => match self.take_photo() {
+                Ok(path) => println!("Saved {}", path.display()),
+                Err(e) => println!("Couldn't take a picture: {}", e),
+            },
+            Effect::RecordFrame => return self.record_frame().err().map(|e| Event::RecordingFailed(e.to_string())),
+            Effect::StopRecording => {
+                if let Some(recorder) = self.recorder_or_none.take() {
+                    let frames = recorder.frames();
+                    match recorder.finish(Instant::now()) {
+                        Ok(files) => {
+                            println!("Recorded {} frames to {} file(s):", frames, files.len());
+                            for file in files {
+                                println!("  {}", file.display());
+                            }
+                        }
+                        Err(e) => println!("Couldn't finish the recording: {}", AppError::from(e)),
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Moves around whichever list or menu is showing.
+    fn navigate(&mut self, action: Action) {
+        match self.status {
+            AppStatus::Picking => {
+                let index = self.cursor.index();
+                let saved = index < self.saved.servers.len();
+                let mut changed = false;
+
+                match action {
+                    Action::Up => self.cursor.up(),
+                    Action::Down => self.cursor.down(),
+                    Action::Rename if saved => match self.text.text(&self.saved.servers[index].name, 32) {
+                        Ok(Some(name)) => {
+                            self.saved.rename(index, &name);
+                            changed = true;
+                        }
+                        Ok(None) => {}
+                        Err(e) => println!("{}", e),
+                    },
+                    Action::Favourite if saved => {
+                        self.cursor.select(self.saved.toggle_favourite(index));
+                        changed = true;
+                    }
+                    Action::MoveUp if saved => {
+                        self.cursor.select(self.saved.move_up(index));
+                        changed = true;
+                    }
+                    Action::MoveDown if saved => {
+                        self.cursor.select(self.saved.move_down(index));
+                        changed = true;
+                    }
+                    Action::Delete if saved => {
+                        self.saved.remove(index);
+                        self.cursor.set_len(self.saved.servers.len() + 2);
+                        changed = true;
+                    }
+                    _ => {}
+                }
+
+                if changed {
+                    self.save_config();
+                }
+
+                if changed || self.cursor.index() != index {
+                    self.display.clear();
+                    draw_saved(&self.saved, &self.cursor, self.buttons.map());
+                }
+            }
+            AppStatus::Discovering => {
+                let Some(ref mut browser) = self.browser_or_none else {
+                    return;
+                };
+
+                match action {
+                    Action::Up => self.cursor.up(),
+                    Action::Down => self.cursor.down(),
+                    Action::Search => {
+                        if let Err(e) = browser.probe_broadcast() {
+                            println!("Search failed: {}", e);
+                        }
+                        self.cursor = Cursor::new(1);
+                        self.display.clear();
+                        draw_servers(&[], &self.cursor, self.buttons.map());
+                        return;
+                    }
+                    _ => return,
+                }
+                self.display.clear();
+                draw_servers(browser.servers(), &self.cursor, self.buttons.map());
+            }
+            AppStatus::Settings => {
+                let index = self.cursor.index();
+                let setting = Setting::ALL[index];
+
+                let delta = match action {
+                    Action::Left => -1,
+                    Action::Right | Action::Confirm => 1,
+                    _ => 0,
+                };
+
+                let mut changed = Vec::new();
+                if delta != 0 {
+                    changed = self.camera_settings.adjust(setting, delta);
+                    if changed.contains(&Setting::Preview) {
+                        self.display.set_preview(self.camera_settings.preview);
+                    }
+                    if changed.contains(&Setting::Camera) || changed.contains(&Setting::Stereo) {
+                        // the new camera needs the whole configuration, not just this setting
+                        self.config.camera = self.camera_settings.camera;
+                        self.config.stereo = self.camera_settings.stereo;
+                        if let Err(e) = pipeline::lock(&self.camera).configure(&self.config, &self.camera_settings) {
+                            println!("Couldn't switch cameras: {}", e);
+                        }
+                    }
+                    else {
+                        for &setting in &changed {
+                            if let Err(e) = pipeline::lock(&self.camera).apply(&self.camera_settings, setting) {
+                                println!("Couldn't set {}: {}", setting.label(), AppError::from(e));
+                            }
+                        }
+                    }
+                }
+
+                match action {
+                    Action::Up => self.cursor.up(),
+                    Action::Down => self.cursor.down(),
+                    _ => {}
+                }
+
+                if !changed.is_empty() || self.cursor.index() != index {
+                    self.display.clear();
+                    draw_settings(&self.camera_settings, &self.cursor, self.buttons.map());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the saved servers and settings back, unless the file couldn't be read in the first
+    /// place and would only get replaced with defaults.
+    fn save_config(&self) {
+        if !self.config_readable {
+            println!("Not saving over {}, it couldn't be read.", self.paths.config.display());
+            return;
+        }
+        if let Err(e) = self.saved.save(&self.paths.config) {
+            println!("{}", AppError::from(e));
+        }
+    }
+
+    /// Sets up streaming after a connection attempt, reporting how it went.
+    fn connected(&mut self, attempt: Result<Option<(SavedServer, Connection, StreamConfig)>, AppError>) -> Event {
+        let (mut server, connection, negotiated) = match attempt {
+            Ok(Some(connected)) => connected,
+            Ok(None) => {
+                println!("Cancelled");
+                return Event::ConnectFailed;
+            }
+            Err(e) => {
+                println!("{}", e);
+                return Event::ConnectFailed;
+            }
+        };
+
+        println!("Connected to {}.", server.name);
+        println!("Streaming {:?} {}x{} @ {:?}", negotiated.format, negotiated.resolution.width, negotiated.resolution.height, negotiated.frame_rate);
+        if negotiated.stereo != StereoLayout::Mono {
+            println!("in 3D, {}", negotiated.stereo.name());
+        }
+        self.camera_settings.camera = negotiated.camera;
+        self.camera_settings.stereo = negotiated.stereo;
+        // let go of the cameras before the capture thread wants them
+        let initialised = pipeline::lock(&self.camera).configure(&negotiated, &self.camera_settings);
+        if let Err(e) = initialised {
+            println!("{}", e);
+            return Event::ConnectFailed;
+        }
+
+        server.preferred = Some(negotiated);
+        self.last_server = Some(server.clone());
+        self.saved.remember(server);
+        self.save_config();
+
+        self.config = negotiated;
+        match Pipeline::start(self.camera.clone(), self.config, &self.camera_settings, connection, self.saved.queue_length, self.saved.drop_policy) {
+            Ok(pipeline) => {
+                self.pipeline_or_none = Some(pipeline);
+                Event::Connected
+            }
+            Err(e) => {
+                println!("{}", e);
+                Event::ConnectFailed
+            }
+        }
+    }
+
+    /// Captures a frame into the recording. Camera hiccups just cost a frame, only failing to
+    /// write ends the recording.
+    fn record_frame(&mut self) -> std::io::Result<()> {
+        let Some(ref mut recorder) = self.recorder_or_none else {
+            return Ok(());
+        };
+
+        let mut camera = pipeline::lock(&self.camera);
+        let mut source = camera.source(self.config, &self.camera_settings);
+        let (width, height) = (source.width(), source.height());
+
+        let frame = match recorder.container() {
+            Container::Avi => match self.jpeg_capture.capture(&mut source) {
+                Ok(jpeg) => jpeg,
+                Err(e) => {
+                    println!("{}", AppError::from(e));
+                    return Ok(());
+                }
+            },
+            Container::Y4m => {
+                self.raw.resize(source.frame_size(), 0);
+                if let Err(e) = source.capture(&mut self.raw) {
+                    println!("{}", AppError::from(e));
+                    return Ok(());
+                }
+                &self.raw
+            }
+        };
+        recorder.write(frame, Instant::now())?;
+
+        let raw = match recorder.container() {
+            Container::Avi => self.jpeg_capture.last_frame(),
+            Container::Y4m => &self.raw,
+        };
+        self.display.draw_preview(raw, PixelFormat::Yuv422, width, height, source.flags());
+        Ok(())
+    }
+
+    /// Captures a still at the biggest size the camera has and saves it to the SD card, then
+    /// puts the camera back the way it was.
+    fn take_photo(&mut self) -> Result<PathBuf, AppError> {
+        let format = self.saved.photo_format;
+        let mpo = self.saved.photo_mpo && format == PhotoFormat::Jpeg && self.camera_settings.camera == CameraId::BothOuter;
+
+        let mut camera = pipeline::lock(&self.camera);
+        let config = photo_config(&self.camera_settings, &camera.resolutions(), mpo);
+        camera.configure(&config, &self.camera_settings)?;
+        let mut source = camera.source(config, &self.camera_settings);
+        let (width, height) = (source.width(), source.height());
+        self.raw.resize(source.frame_size(), 0);
+        let captured = source.capture(&mut self.raw);
+        let flags = source.flags();
+        drop(source);
+        if let Err(e) = camera.configure(&self.config, &self.camera_settings) {
+            println!("{}", e);
+        }
+        drop(camera);
+        captured?;
+
+        self.display.draw_preview(&self.raw, PixelFormat::Yuv422, width, height, flags);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let metadata = Metadata {
+            time: DateTime::from_unix(now),
+            model: self.system.model().unwrap_or_else(|_| "3DS".to_owned()),
+            settings: self.camera_settings,
+        };
+        let still = Still {
+            yuyv: &self.raw,
+            width,
+            height,
+            // only if both eyes actually made it into the frame
+            side_by_side: mpo && width == config.resolution.width * 2,
+        };
+        Ok(photo::save(&self.paths.photos, &still, format, &metadata)?)
+    }
+
+    /// Captures a frame for the HTTP and RTSP clients, if any of them wants one.
+    fn serve_frames(&mut self) {
+        let http_wants = self.status == AppStatus::Serving && self.http_or_none.as_ref().is_some_and(|s| s.wants_frame());
+        let rtsp_wants = self.rtsp_or_none.as_ref().is_some_and(|s| s.wants_frame());
+
+        // only bother the camera if someone is watching
+        if !http_wants && !rtsp_wants {
+            return;
+        }
+
+        if self.config.format != PixelFormat::Jpeg {
+            // left over from a receiver that asked for raw frames
+            let serving = serving_config(&self.camera_settings);
+            if pipeline::lock(&self.camera).configure(&serving, &self.camera_settings).is_ok() {
+                self.config = serving;
+                self.jpeg_capture = JpegCapture::new(self.config.quality);
+            }
+        }
+
+        let (luma, chroma) = self.jpeg_capture.encoder().quant_tables();
+        let tables = (*luma, *chroma);
+
+        let mut camera = pipeline::lock(&self.camera);
+        let mut source = camera.source(self.config, &self.camera_settings);
+        // both eyes when serving in 3D
+        let (width, height) = (source.width(), source.height());
+        let mut captured = false;
+        match self.jpeg_capture.capture(&mut source) {
+            Ok(jpeg) => {
+                captured = true;
+                if http_wants {
+                    if let Some(ref mut server) = self.http_or_none {
+                        server.push_frame(jpeg);
+                    }
+                }
+                if let Some(ref mut server) = self.rtsp_or_none {
+                    server.push_frame(jpeg, (&tables.0, &tables.1), width, height);
+                }
+            }
+            Err(e) => println!("{}", AppError::from(e)),
+        }
+        if captured {
+            self.display.draw_preview(self.jpeg_capture.last_frame(), PixelFormat::Yuv422, width, height, source.flags());
+        }
+    }
+}
+
+fn setup(system: &impl SystemInfo, network: &impl Network, keys: &KeyMap) {
+    println!("ctr-camera-rs v0.1.0 by Lena");
+    println!("https://github.com/adryzz/ctr-camera-rs");
+    match system.model() {
+        Ok(model) => println!("IP: {}, running on {}", network.host_address(), model),
+        Err(_) => println!("IP: {}", network.host_address()),
+    }
+    println!("Press {} to exit or {} to find a server.", keys.label(Action::Exit), keys.label(Action::Confirm));
+    println!("Press {} to serve the camera over HTTP", keys.label(Action::Serve));
+    println!("Press {} to toggle the RTSP server", keys.label(Action::Rtsp));
+    println!("Press {} to go into the settings menu", keys.label(Action::Settings));
+    println!("Press {} to record to the SD card", keys.label(Action::Record));
+    println!("Press {} to take a picture", keys.label(Action::Photo));
+
+    println!("\u{001b}[46;1m                \u{001b}[0m");
+    println!("\u{001b}[45;1m                \u{001b}[0m");
+    println!("\u{001b}[47m                \u{001b}[0m");
+    println!("\u{001b}[45;1m                \u{001b}[0m");
+    println!("\u{001b}[46;1m                \u{001b}[0m");
+}
+
+/// Rewrites the current console line with the time left until the next attempt.
+fn draw_countdown(server: &SavedServer, reconnect: &Reconnect, seconds: u64, keys: &KeyMap) {
+    print!("\r\u{001b}[2KRetrying {} in {}s (#{}), {} stops", server.name, seconds, reconnect.attempt() + 1, keys.label(Action::Back));
+    let _ = std::io::stdout().flush();
+}
+
+/// Seed for the reconnect jitter, different on every console and every time.
+fn seed() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// Overwrites the bottom rows of the console, leaving the rest of the screen and the cursor alone.
+fn draw_stats(snapshot: &Snapshot) {
+    print!("\u{001b}[s");
+    for (i, line) in snapshot.lines().iter().enumerate() {
+        print!("\u{001b}[{};1H\u{001b}[2K{}", STATS_ROW + i, line);
+    }
+    print!("\u{001b}[u");
+    let _ = std::io::stdout().flush();
+}
+
+fn draw_settings(settings: &CameraSettings, cursor: &Cursor, keys: &KeyMap) {
+    println!("Camera settings");
+    println!();
+    for (i, &setting) in Setting::ALL.iter().enumerate() {
+        let marker = if i == cursor.index() { '>' } else { ' ' };
+        println!("{} {:<20}{}", marker, setting.label(), settings.value(setting));
+    }
+    println!();
+    println!("{}/{}: select, {}/{}/{}: change", keys.label(Action::Up), keys.label(Action::Down), keys.label(Action::Left), keys.label(Action::Right), keys.label(Action::Confirm));
+    println!("{}: back", keys.label(Action::Back));
+}
+
+/// Stream settings for HTTP and RTSP, which don't get a say in them.
+fn serving_config(settings: &CameraSettings) -> StreamConfig {
+    StreamConfig {
+        camera: settings.camera,
+        stereo: settings.stereo,
+        ..StreamConfig::default()
+    }
+}
+
+/// Stream settings for recordings, which need JPEG or YUV depending on the file.
+fn recording_config(settings: &CameraSettings, container: Container) -> StreamConfig {
+    StreamConfig {
+        format: container.format(),
+        ..serving_config(settings)
+    }
+}
+
+/// Stream settings for stills: the biggest size there is, uncompressed so it only gets encoded
+/// once, and both eyes side by side for an MPO.
+fn photo_config(settings: &CameraSettings, resolutions: &[Resolution], mpo: bool) -> StreamConfig {
+    let serving = serving_config(settings);
+    StreamConfig {
+        resolution: resolutions
+            .iter()
+            .copied()
+            .max_by_key(|r| r.width as u32 * r.height as u32)
+            .unwrap_or(serving.resolution),
+        format: PixelFormat::Yuv422,
+        stereo: if mpo { StereoLayout::SideBySide } else { settings.stereo },
+        ..serving
+    }
+}
+
+/// Everything we can do, with the camera and 3D layout from `settings` to pick if the server
+/// doesn't mind.
+fn hello(system: &impl SystemInfo, camera: &Mutex<impl Camera>, transport: Transport, settings: &CameraSettings) -> Result<Hello, AppError> {
+    let camera = pipeline::lock(camera);
+
+    let mut cameras = camera.cameras();
+    cameras.retain(|&c| c != settings.camera);
+    cameras.insert(0, settings.camera);
+
+    let mut stereo_layouts = camera.stereo_layouts();
+    stereo_layouts.retain(|&l| l != settings.stereo);
+    stereo_layouts.insert(0, settings.stereo);
+
+    Ok(Hello {
+        model: system.model()?,
+        formats: camera.formats(),
+        frame_rates: FrameRate::ALL.to_vec(),
+        resolutions: camera.resolutions(),
+        cameras,
+        transport,
+        stereo_layouts,
+    })
+}
+
+/// An open connection to a server. Frames go over `udp` when the user picked it, otherwise over
+/// `control`, which is the TCP connection the handshake happened on.
+pub struct Connection {
+    pub(crate) control: TcpStream,
+    pub(crate) udp: Option<UdpFrameWriter>,
+    pub(crate) stats: Stats,
+    acks: AckDecoder,
+}
+
+impl Connection {
+    /// Feeds whatever acks the server sent back since last time into the statistics.
+    pub(crate) fn poll_acks(&mut self) -> Result<(), AppError> {
+        let mut buf = [0u8; 256];
+
+        // frames are written blocking, only the reads must not wait
+        self.control.set_nonblocking(true)?;
+        let result = loop {
+            match self.control.read(&mut buf) {
+                Ok(0) => break Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+                Ok(len) => match self.acks.feed(&buf[..len]) {
+                    Ok(acks) => {
+                        let now = Instant::now();
+                        for sequence in acks {
+                            self.stats.acked(now, sequence);
+                        }
+                    }
+                    Err(e) => break Err(e.into()),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.control.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// Asks for an address on the software keyboard and connects to it.
+fn try_connect(
+    text: &mut impl TextEntry,
+    network: &mut impl Network,
+    system: &impl SystemInfo,
+    camera: &Mutex<impl Camera>,
+    settings: &CameraSettings,
+) -> Result<Option<(SavedServer, Connection, StreamConfig)>, AppError> {
+    let text_or_none = text.text("192.168.1.1:5000 (udp://... for UDP)", 64)?;
+    match text_or_none {
+        Some(text) => {
+            let (transport, address) = match text.strip_prefix("udp://") {
+                Some(address) => (Transport::Udp, address),
+                None => (Transport::Tcp, text.strip_prefix("tcp://").unwrap_or(&text)),
+            };
+
+            let server = SavedServer::from_address(address, transport).ok_or_else(|| AppError::Address(address.to_owned()))?;
+            connect(network, system, camera, server, settings).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Connects and runs the handshake, asking for the server's saved settings first.
+fn connect(
+    network: &mut impl Network,
+    system: &impl SystemInfo,
+    camera: &Mutex<impl Camera>,
+    server: SavedServer,
+    settings: &CameraSettings,
+) -> Result<(SavedServer, Connection, StreamConfig), AppError> {
+    let transport = server.transport;
+    let address = server.address();
+
+    println!("Connecting to {} over {:?}...", server.name, transport);
+    let mut control = network.connect(address.as_str()).map_err(|e| AppError::Connect(address.clone(), e))?;
+
+    let mut hello = hello(system, camera, transport, settings)?;
+    if let Some(mut preferred) = server.preferred {
+        // the camera picked in the settings wins over the one used last time
+        preferred.camera = settings.camera;
+        preferred.stereo = settings.stereo;
+        hello.prefer(&preferred);
+    }
+    // the server could accept and then never answer, streaming sets its own pace afterwards
+    control.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    control.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    let config = handshake::negotiate(&mut control, &hello)?;
+    control.set_read_timeout(None)?;
+    control.set_write_timeout(None)?;
+
+    let udp = match transport {
+        Transport::Udp => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            socket.connect(control.peer_addr()?)?;
+            Some(UdpFrameWriter::new(socket))
+        }
+        Transport::Tcp => None,
+    };
+
+    let connection = Connection {
+        control,
+        udp,
+        stats: Stats::new(Instant::now()),
+        acks: AckDecoder::new(),
+    };
+    Ok((server, connection, config))
+}
+
+fn discover() -> std::io::Result<Browser> {
+    let mut browser = Browser::new()?;
+    browser.probe_broadcast()?;
+    Ok(browser)
+}
+
+/// What gets saved for a receiver found on the network.
+fn discovered(server: &Server) -> SavedServer {
+    SavedServer {
+        name: server.name.clone(),
+        host: server.addr.ip().to_string(),
+        port: server.addr.port(),
+        transport: server.transport(),
+        favourite: false,
+        preferred: None,
+    }
+}
+
+/// Saved servers, then searching and the keyboard as the last two entries.
+fn draw_saved(saved: &Config, cursor: &Cursor, keys: &KeyMap) {
+    println!("Saved servers:");
+    println!();
+    for (i, server) in saved.servers.iter().enumerate() {
+        let marker = if i == cursor.index() { '>' } else { ' ' };
+        let star = if server.favourite { '*' } else { ' ' };
+        println!("{}{} {} ({})", marker, star, server.name, server.address());
+    }
+    let count = saved.servers.len();
+    println!("{}  Search the network", if cursor.index() == count { '>' } else { ' ' });
+    println!("{}  Enter address manually", if cursor.index() == count + 1 { '>' } else { ' ' });
+    println!();
+    println!("{}: connect, {}: back, {}: rename", keys.label(Action::Confirm), keys.label(Action::Back), keys.label(Action::Rename));
+    println!("{}: favourite, {}/{}: move, {}: delete", keys.label(Action::Favourite), keys.label(Action::MoveUp), keys.label(Action::MoveDown), keys.label(Action::Delete));
+}
+
+/// Receivers found so far, with the keyboard as the last entry.
+fn draw_servers(servers: &[Server], cursor: &Cursor, keys: &KeyMap) {
+    println!("Receivers on this network:");
+    println!();
+    if servers.is_empty() {
+        println!("  (searching...)");
+    }
+    for (i, server) in servers.iter().enumerate() {
+        let marker = if i == cursor.index() { '>' } else { ' ' };
+        println!("{} {} ({})", marker, server.name, server.addr);
+    }
+    let marker = if cursor.index() == servers.len() { '>' } else { ' ' };
+    println!("{} Enter address manually", marker);
+    println!();
+    println!("{}: connect, {}: search again, {}: back", keys.label(Action::Confirm), keys.label(Action::Search), keys.label(Action::Back));
+}
@@ -0,0 +1,367 @@
+use std::time::Duration;
+
+use ctr_camera_common::handshake::{self, CameraId, Resolution, StreamConfig};
+use ctr_camera_common::protocol::PixelFormat;
+use ctr_camera_common::settings::{CameraSettings, Contrast, Setting, WhiteBalance};
+use ctr_camera_common::stereo::{StereoLayout, StereoPacker};
+use ctr_camera_common::stream::FrameSource;
+use ctr_camera_common::yuv;
+use ctru::services::cam::{self, Cam, Camera, FrameRate, OutputFormat, ViewSize};
+
+use crate::platform;
+use crate::AppError;
+
+/// Shortest wait for a frame, the first one after starting a capture takes a while even at 30 fps.
+const MIN_CAPTURE_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long to wait for a frame at `rate` before giving up: twice the slowest a frame can be, so
+/// the variable rates don't time out in the dark.
+pub fn capture_timeout(rate: handshake::FrameRate) -> Duration {
+    (rate.slowest_frame() * 2).max(MIN_CAPTURE_TIMEOUT)
+}
+
+pub const FORMATS: [PixelFormat; 3] = [PixelFormat::Jpeg, PixelFormat::Yuv422, PixelFormat::Rgb565];
+
+pub const CAMERAS: [CameraId; 4] = CameraId::ALL;
+
+pub const STEREO_LAYOUTS: [StereoLayout; 6] = StereoLayout::ALL;
+
+/// Resolutions the camera can output directly, along with the matching `ViewSize`.
+const VIEW_SIZES: [(Resolution, ViewSize); 8] = [
+    (Resolution::new(640, 480), ViewSize::Vga),
+    (Resolution::new(512, 384), ViewSize::DSX4),
+    (Resolution::new(400, 240), ViewSize::TopLCD),
+    (Resolution::new(352, 288), ViewSize::Cif),
+    (Resolution::new(320, 240), ViewSize::BottomLCD),
+    (Resolution::new(256, 192), ViewSize::DS),
+    (Resolution::new(176, 144), ViewSize::QCif),
+    (Resolution::new(160, 120), ViewSize::QQVga),
+];
+
+pub fn resolutions() -> Vec<Resolution> {
+    VIEW_SIZES.iter().map(|(res, _)| *res).collect()
+}
+
+pub fn view_size(res: Resolution) -> Option<ViewSize> {
+    VIEW_SIZES
+        .iter()
+        .find(|(r, _)| *r == res)
+        .map(|(_, size)| *size)
+}
+
+pub fn output_format(format: PixelFormat) -> OutputFormat {
+    match format.capture_format() {
+        PixelFormat::Rgb565 => OutputFormat::Rgb565,
+        _ => OutputFormat::Yuv422,
+    }
+}
+
+pub fn frame_rate(rate: handshake::FrameRate) -> FrameRate {
+    use handshake::FrameRate as F;
+
+    match rate {
+        F::Fps15 => FrameRate::Fps15,
+        F::Fps15To5 => FrameRate::Fps15To5,
+        F::Fps15To2 => FrameRate::Fps15To2,
+        F::Fps10 => FrameRate::Fps10,
+        F::Fps8_5 => FrameRate::Fps8_5,
+        F::Fps5 => FrameRate::Fps5,
+        F::Fps20 => FrameRate::Fps20,
+        F::Fps20To5 => FrameRate::Fps20To5,
+        F::Fps30 => FrameRate::Fps30,
+        F::Fps30To5 => FrameRate::Fps30To5,
+        F::Fps15To10 => FrameRate::Fps15To10,
+        F::Fps20To10 => FrameRate::Fps20To10,
+        F::Fps30To10 => FrameRate::Fps30To10,
+    }
+}
+
+fn white_balance(wb: WhiteBalance) -> cam::WhiteBalance {
+    match wb {
+        WhiteBalance::Auto => cam::WhiteBalance::Auto,
+        WhiteBalance::Temp3200K => cam::WhiteBalance::Temp3200K,
+        WhiteBalance::Temp4150K => cam::WhiteBalance::Temp4150K,
+        WhiteBalance::Temp5200K => cam::WhiteBalance::Temp5200K,
+        WhiteBalance::Temp6000K => cam::WhiteBalance::Temp6000K,
+        WhiteBalance::Temp7000K => cam::WhiteBalance::Temp7000K,
+    }
+}
+
+fn contrast(contrast: Contrast) -> cam::Contrast {
+    match contrast {
+        Contrast::Low => cam::Contrast::Low,
+        Contrast::Normal => cam::Contrast::Normal,
+        Contrast::High => cam::Contrast::High,
+    }
+}
+
+/// Pushes one setting to the camera. Brightness is done in software, switching cameras or 3D
+/// layouts is up to [`Cameras`] and the preview isn't the camera's business, so there's nothing to
+/// send for those.
+pub fn apply<C: Camera + ?Sized>(cam: &mut C, settings: &CameraSettings, setting: Setting) -> ctru::Result<()> {
+    match setting {
+        Setting::Camera => Ok(()),
+        Setting::AutoExposure => cam.set_auto_exposure(settings.auto_exposure),
+        Setting::AutoWhiteBalance => cam.set_auto_white_balance(settings.auto_white_balance),
+        Setting::Trimming => cam.set_trimming(settings.trimming),
+        Setting::Exposure => cam.set_exposure(settings.exposure),
+        Setting::WhiteBalance => cam.set_white_balance(white_balance(settings.white_balance)),
+        Setting::Sharpness => cam.set_sharpness(settings.sharpness),
+        Setting::Contrast => cam.set_contrast(contrast(settings.contrast)),
+        Setting::Brightness => Ok(()),
+        Setting::NoiseFilter => cam.set_noise_filter(settings.noise_filter),
+        Setting::Stereo | Setting::Preview => Ok(()),
+    }
+}
+
+/// Pushes everything, for after the camera got reconfigured.
+pub fn apply_all<C: Camera + ?Sized>(cam: &mut C, settings: &CameraSettings) -> ctru::Result<()> {
+    // a manual exposure switches auto exposure off in the camera, so the flags go last
+    let order = [
+        Setting::Exposure,
+        Setting::WhiteBalance,
+        Setting::Sharpness,
+        Setting::Contrast,
+        Setting::NoiseFilter,
+        Setting::Trimming,
+        Setting::AutoExposure,
+        Setting::AutoWhiteBalance,
+    ];
+    for setting in order {
+        apply(cam, settings, setting)?;
+    }
+    Ok(())
+}
+
+/// Every camera behind one handle, everything else only talks to the selected one.
+pub struct Cameras {
+    cam: Cam,
+    selected: CameraId,
+    stereo: Option<StereoPacker>,
+}
+
+impl Cameras {
+    pub fn new(cam: Cam, selected: CameraId) -> Self {
+        Self {
+            cam,
+            selected,
+            stereo: None,
+        }
+    }
+
+    pub fn selected(&self) -> CameraId {
+        self.selected
+    }
+
+    /// Switches cameras. The new one still has to be configured, see [`platform::Camera::configure`].
+    pub fn select(&mut self, id: CameraId) {
+        self.selected = id;
+    }
+
+    /// Captures both outer cameras as one frame from now on, packed as `config.stereo` says.
+    /// Anything but [`CameraId::BothOuter`] with a stereo layout goes back to plain captures.
+    pub fn set_stereo(&mut self, config: &StreamConfig) {
+        self.stereo = match (config.camera, config.stereo) {
+            (_, StereoLayout::Mono) => None,
+            (CameraId::BothOuter, layout) => Some(StereoPacker::new(
+                layout,
+                config.resolution.width,
+                config.resolution.height,
+                config.format,
+            )),
+            _ => None,
+        };
+    }
+
+    pub fn get(&mut self) -> &mut dyn Camera {
+        match self.selected {
+            CameraId::OuterRight => &mut self.cam.outer_right_cam,
+            CameraId::Inner => &mut self.cam.inner_cam,
+            CameraId::OuterLeft => &mut self.cam.outer_left_cam,
+            CameraId::BothOuter => &mut self.cam.both_outer_cams,
+        }
+    }
+}
+
+impl platform::Camera for Cameras {
+    type Source<'a> = CameraSource<'a>;
+
+    fn configure(&mut self, config: &StreamConfig, settings: &CameraSettings) -> Result<(), AppError> {
+        let size = view_size(config.resolution).ok_or(AppError::Resolution(config.resolution.width, config.resolution.height))?;
+
+        self.select(config.camera);
+        let cam = self.get();
+
+        cam.set_view_size(size).map_err(|e| AppError::Camera("set the resolution", e))?;
+
+        cam.set_frame_rate(frame_rate(config.frame_rate)).map_err(|e| AppError::Camera("set the frame rate", e))?;
+
+        cam.set_output_format(output_format(config.format)).map_err(|e| AppError::Camera("set the output format", e))?;
+
+        apply_all(cam, settings).map_err(|e| AppError::Camera("apply the image settings", e))?;
+
+        self.set_stereo(config);
+
+        Ok(())
+    }
+
+    fn apply(&mut self, settings: &CameraSettings, setting: Setting) -> ctru::Result<()> {
+        apply(self.get(), settings, setting)
+    }
+
+    fn source(&mut self, config: StreamConfig, settings: &CameraSettings) -> CameraSource<'_> {
+        CameraSource::new(self, config, settings)
+    }
+
+    fn formats(&self) -> Vec<PixelFormat> {
+        FORMATS.to_vec()
+    }
+
+    fn resolutions(&self) -> Vec<Resolution> {
+        resolutions()
+    }
+
+    fn cameras(&self) -> Vec<CameraId> {
+        CAMERAS.to_vec()
+    }
+
+    fn stereo_layouts(&self) -> Vec<StereoLayout> {
+        STEREO_LAYOUTS.to_vec()
+    }
+}
+
+fn check(result: ctru_sys::Result) -> ctru::Result<()> {
+    if result < 0 {
+        Err(ctru::Error::Os(result))
+    } else {
+        Ok(())
+    }
+}
+
+/// Takes one picture with each outer camera, started on the same vsync so the two eyes match.
+///
+/// `ctru` only knows how to receive from one port at a time, so this talks to `CAMU` directly.
+/// The outer cameras have to be configured (as [`CameraId::BothOuter`]) beforehand.
+fn take_stereo_picture(left: &mut [u8], right: &mut [u8], width: u16, height: u16, timeout: Duration) -> ctru::Result<()> {
+    use ctru_sys::{PORT_BOTH, PORT_CAM1, PORT_CAM2, SELECT_NONE, SELECT_OUT1, SELECT_OUT1_OUT2, SELECT_OUT2};
+
+    let width = width as i16;
+    let height = height as i16;
+    let len = left.len().min(right.len()) as u32;
+
+    unsafe {
+        let mut transfer_unit = 0;
+        check(ctru_sys::CAMU_GetMaxBytes(&mut transfer_unit, width, height))?;
+        check(ctru_sys::CAMU_SetTransferBytes(PORT_BOTH, transfer_unit, width, height))?;
+
+        check(ctru_sys::CAMU_Activate(SELECT_OUT1_OUT2))?;
+        check(ctru_sys::CAMU_ClearBuffer(PORT_BOTH))?;
+        check(ctru_sys::CAMU_SynchronizeVsyncTiming(SELECT_OUT1, SELECT_OUT2))?;
+        check(ctru_sys::CAMU_StartCapture(PORT_BOTH))?;
+
+        // OUT1 is the right camera on port 1, OUT2 the left one on port 2
+        let mut right_event = 0;
+        let mut left_event = 0;
+        let receiving = check(ctru_sys::CAMU_SetReceiving(&mut right_event, right.as_mut_ptr().cast(), PORT_CAM1, len, transfer_unit as i16))
+            .and_then(|_| check(ctru_sys::CAMU_SetReceiving(&mut left_event, left.as_mut_ptr().cast(), PORT_CAM2, len, transfer_unit as i16)));
+
+        let nanos = timeout.as_nanos() as i64;
+        let result = receiving
+            .and_then(|_| check(ctru_sys::svcWaitSynchronization(right_event, nanos)))
+            .and_then(|_| check(ctru_sys::svcWaitSynchronization(left_event, nanos)));
+
+        // tear everything down even if the capture failed, or the next one won't start
+        check(ctru_sys::CAMU_StopCapture(PORT_BOTH))?;
+        if right_event != 0 {
+            ctru_sys::svcCloseHandle(right_event);
+        }
+        if left_event != 0 {
+            ctru_sys::svcCloseHandle(left_event);
+        }
+        check(ctru_sys::CAMU_Activate(SELECT_NONE))?;
+
+        result
+    }
+}
+
+/// Adapts the selected camera to the [`FrameSource`] used by the streaming loop.
+///
+/// The camera has to be configured with the same [`StreamConfig`] beforehand (see
+/// [`platform::Camera::configure`]).
+/// With a stereo layout both eyes are packed into one frame, so the frames are larger than the
+/// configured resolution.
+pub struct CameraSource<'a> {
+    cameras: &'a mut Cameras,
+    config: StreamConfig,
+    luma_offset: i16,
+    timeout: Duration,
+}
+
+impl<'a> CameraSource<'a> {
+    pub fn new(cameras: &'a mut Cameras, config: StreamConfig, settings: &CameraSettings) -> Self {
+        Self {
+            cameras,
+            config,
+            luma_offset: settings.luma_offset(),
+            timeout: capture_timeout(config.frame_rate),
+        }
+    }
+
+    fn frame_dimensions(&self) -> (u16, u16) {
+        match &self.cameras.stereo {
+            Some(packer) => packer.frame_size(),
+            None => (self.config.resolution.width, self.config.resolution.height),
+        }
+    }
+}
+
+impl FrameSource for CameraSource<'_> {
+    type Error = ctru::Error;
+
+    fn width(&self) -> u16 {
+        self.frame_dimensions().0
+    }
+
+    fn height(&self) -> u16 {
+        self.frame_dimensions().1
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.config.format.capture_format()
+    }
+
+    fn capture(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let Resolution { width, height } = self.config.resolution;
+        let yuv = self.format() == PixelFormat::Yuv422;
+
+        match self.cameras.stereo.as_mut() {
+            Some(packer) => {
+                if packer.needs_capture() {
+                    let (left, right) = packer.eyes_mut();
+                    take_stereo_picture(left, right, width, height, self.timeout)?;
+                    if yuv {
+                        yuv::adjust_brightness(left, self.luma_offset);
+                        yuv::adjust_brightness(right, self.luma_offset);
+                    }
+                }
+                packer.pack(buf);
+            }
+            None => {
+                self.cameras
+                    .get()
+                    .take_picture(buf, width, height, self.timeout)?;
+                if yuv {
+                    yuv::adjust_brightness(buf, self.luma_offset);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> u16 {
+        self.cameras
+            .stereo
+            .as_ref()
+            .map_or(0, |packer| packer.last_flags())
+    }
+}
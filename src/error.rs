@@ -0,0 +1,49 @@
+use ctr_camera_common::config::ConfigError;
+use ctr_camera_common::jpeg::JpegError;
+use ctr_camera_common::photo::PhotoError;
+use ctr_camera_common::protocol::ProtocolError;
+use ctr_camera_common::stream::StreamError;
+use thiserror::Error;
+
+use crate::platform::SystemError;
+
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("Couldn't start the {0} service: {1}")]
+    Init(&'static str, SystemError),
+    #[error("Couldn't {0}: {1}")]
+    Camera(&'static str, SystemError),
+    #[error("The camera can't do {0}x{1}")]
+    Resolution(u16, u16),
+    #[error("System error: {0}")]
+    System(#[from] SystemError),
+    #[cfg(feature = "3ds")]
+    #[error("Software keyboard error: {0:?}")]
+    Swkbd(ctru::applets::swkbd::Error),
+    #[error("Couldn't connect to {0}: {1}")]
+    Connect(String, std::io::Error),
+    #[error("Couldn't listen on port {0}: {1}")]
+    Listen(u16, std::io::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("JPEG encoding error: {0}")]
+    Jpeg(#[from] JpegError),
+    #[error("Invalid address {0}")]
+    Address(String),
+    #[error("Config file error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Couldn't save the picture: {0}")]
+    Photo(#[from] PhotoError),
+}
+
+impl From<StreamError<SystemError>> for AppError {
+    fn from(e: StreamError<SystemError>) -> Self {
+        match e {
+            StreamError::Capture(e) => AppError::System(e),
+            StreamError::Encode(e) => AppError::Jpeg(e),
+            StreamError::Io(e) => AppError::Io(e),
+        }
+    }
+}
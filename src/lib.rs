@@ -0,0 +1,14 @@
+//! The app itself, written against the traits in [`platform`] so it runs on the console with the
+//! `3ds` feature and anywhere else (tests, mostly) without it.
+
+pub mod app;
+#[cfg(feature = "3ds")]
+pub mod camera;
+mod error;
+pub mod pipeline;
+pub mod platform;
+#[cfg(feature = "3ds")]
+pub mod preview;
+pub mod state;
+
+pub use error::AppError;
@@ -1,184 +1,78 @@
-use std::io::Write;
-use std::net::Shutdown::Both;
-use std::net::TcpStream;
+use std::path::PathBuf;
 
-use ctru::applets::swkbd::{Swkbd, Button, ValidInput, Filters};
 use ctru::prelude::*;
-use ctru::services::cam::{OutputFormat, BothOutwardCam};
+use ctru::services::cam::Cam;
 use ctru::services::cfgu::Cfgu;
-use ctru::services::cam::{Cam, Camera, FrameRate};
-use thiserror::Error;
+use ctr_camera_common::settings::CameraSettings;
+use ctr_camera_rs::app::{App, Paths};
+use ctr_camera_rs::camera::Cameras;
+use ctr_camera_rs::platform::ctru::{Ctru, CtruDisplay, CtruInput, CtruNetwork, CtruSystem, CtruText};
+use ctr_camera_rs::platform::Devices;
+use ctr_camera_rs::AppError;
+
+const CONFIG_PATH: &str = "sdmc:/3ds/ctr-camera-rs/config.ini";
+const RECORDINGS_DIR: &str = "sdmc:/ctr-camera-rs/recordings";
+const DCIM_DIR: &str = "sdmc:/DCIM";
 
 fn main() {
     ctru::use_panic_handler();
-    
-    let apt = Apt::init().unwrap();
-    let mut hid = Hid::init().unwrap();
-    let gfx = Gfx::init().unwrap();
-    let soc = Soc::init().unwrap();
-    let cfgu = Cfgu::init().unwrap();
-    let console = Console::init(gfx.top_screen.borrow_mut());
-
-    let address = soc.host_address();
-
-    let mut cam = Cam::init().expect("Failed to initialize CAM service.");
-
-    let mut camera = &mut cam.both_outer_cams;
 
-    init_cameras(camera).unwrap();
+    // without these there's nowhere to show an error
+    let (Ok(apt), Ok(mut hid), Ok(gfx)) = (Apt::init(), Hid::init(), Gfx::init()) else {
+        return;
+    };
 
-    let mut status = AppStatus::NotConnected;
-
-    let old_keys = KeyPad::empty();
+    if let Err(e) = run(&apt, &mut hid, &gfx) {
+        fatal(&apt, &mut hid, &gfx, &e);
+    }
+}
 
-    let mut stream_or_none: Option<TcpStream> = None;
+/// Shows an error nothing can be done about until the user leaves.
+fn fatal(apt: &Apt, hid: &mut Hid, gfx: &Gfx, error: &AppError) {
+    let _console = Console::init(gfx.top_screen.borrow_mut());
+    println!("{}", error);
+    println!();
+    println!("Press START to exit.");
 
-    setup(&cfgu, &soc);
     while apt.main_loop() {
-
         hid.scan_input();
-        let keys = hid.keys_held();
-
-        if keys != old_keys
-        {
-
-            if keys.intersects(KeyPad::START) {
-                println!("Exiting...");
-                if let Some(stream) = stream_or_none {
-                    if !stream.peek(&mut [0u8;0][..]).is_err() { // connected
-                        stream.shutdown(Both).unwrap();
-                    }
-                }
-                break;   
-            }
-
-            match status {
-                AppStatus::NotConnected => {
-                    if keys.intersects(KeyPad::X) {
-                        status = AppStatus::Settings;
-                        settings(camera);
-                    }
-
-                    if keys.intersects(KeyPad::A) {
-                        match try_connect() {
-                            Ok(Some(connection)) => {
-                                println!("Connected to {}.", connection.peer_addr().unwrap());
-                                stream_or_none = Some(connection);
-                                status = AppStatus::Connected;
-
-                            }
-                            Ok(None) => {
-                                println!("Cancelled");
-                            }
-                            Err(e) => println!("{}", e),
-                        }
-                    }
-                }
-                AppStatus::Settings => {
-                    if keys.intersects(KeyPad::B) {
-                        status = AppStatus::NotConnected;
-                        console.clear();
-                        setup(&cfgu, &soc);
-                    }
-                }
-                AppStatus::Connected => {
-                    if let Some(ref mut stream) = stream_or_none {
-                        if keys.intersects(KeyPad::B) {
-                            console.clear();
-                            setup(&cfgu, &soc);
-                            println!("Disconnected from {}.", stream.peer_addr().unwrap());
-                            stream.shutdown(Both).unwrap();
-                            status = AppStatus::NotConnected;
-                        }
-                    }
-                    else {
-                        status = AppStatus::NotConnected;
-                    }
-                }
-            }
-        }
-
-        if status == AppStatus::Connected { // send camera data
-            //todo: implement when ctru-rs adds the functionality
+        if hid.keys_down().contains(KeyPad::START) {
+            break;
         }
-        // Flush and swap framebuffers
-        gfx.flush_buffers();
-        gfx.swap_buffers();
         gfx.wait_for_vblank();
     }
 }
 
-fn setup(cfgu: &Cfgu, soc: &Soc) {
-    println!("ctr-camera-rs v0.1.0 by Lena");
-    println!("https://github.com/adryzz/ctr-camera-rs");
-    println!("IP: {}, running on {:?}", soc.host_address(), cfgu.model().unwrap());
-    println!("Press START to exit or A to connect to a server.");
-    println!("Press X to go into the settings menu");
+fn run(apt: &Apt, hid: &mut Hid, gfx: &Gfx) -> Result<(), AppError> {
+    let soc = Soc::init().map_err(|e| AppError::Init("network", e))?;
+    let cfgu = Cfgu::init().map_err(|e| AppError::Init("system settings", e))?;
 
-    println!("\u{001b}[46;1m                \u{001b}[0m");
-    println!("\u{001b}[45;1m                \u{001b}[0m");
-    println!("\u{001b}[47m                \u{001b}[0m");
-    println!("\u{001b}[45;1m                \u{001b}[0m");
-    println!("\u{001b}[46;1m                \u{001b}[0m");
-}
+    let camera_settings = CameraSettings::default();
 
-fn settings(cam: &BothOutwardCam)
-{
-    println!("Selected camera: yes");
-    println!("Auto exposure: {}", cam.is_auto_exposure_enabled().unwrap());
-    println!("Auto white balance: {}", cam.is_auto_white_balance_enabled().unwrap());
-    println!("Trimming: {}", cam.is_trimming_enabled().unwrap());
-}
+    let display = CtruDisplay::new(gfx, camera_settings.preview);
 
-fn init_cameras(cam: &mut BothOutwardCam) -> Result<(), AppError> {
-    cam.set_frame_rate(FrameRate::Fps30)?;
+    let cam = Cam::init().map_err(|e| AppError::Init("camera", e))?;
 
-    cam.set_output_format(OutputFormat::Yuv422)?;
+    let devices = Devices::<Ctru> {
+        camera: Cameras::new(cam, camera_settings.camera),
+        input: CtruInput::new(hid),
+        text: CtruText,
+        network: CtruNetwork::new(soc),
+        system: CtruSystem::new(cfgu),
+        display,
+    };
+    let paths = Paths {
+        config: PathBuf::from(CONFIG_PATH),
+        recordings: PathBuf::from(RECORDINGS_DIR),
+        photos: PathBuf::from(DCIM_DIR),
+    };
+    let mut app = App::new(devices, paths);
 
-    Ok(())
-}
-
-fn try_connect() -> Result<Option<TcpStream>, AppError> {
-    let text_or_none = get_keyboard_text()?;
-    match text_or_none {
-        Some(text) => {
-            println!("Connecting to {}...", &text);
-            return Ok(Some(TcpStream::connect(text)?));
+    while apt.main_loop() {
+        if !app.step() {
+            break;
         }
-        None => return Ok(None),
     }
-}
-
-fn get_keyboard_text() -> Result<Option<String>, AppError> {
-    let mut keyboard = Swkbd::default();
 
-    keyboard.set_hint_text("192.168.1.1:5000");
-    keyboard.set_max_text_len(64);
-    keyboard.set_validation(ValidInput::NotEmptyNotBlank, Filters::BACKSLASH);
-    
-    match keyboard.get_string(64) {
-        Ok((text, Button::Right)) => Ok(Some(text)),
-        Ok((_, Button::Left)) => Ok(None),
-        Ok((_, Button::Middle)) => Ok(None), // ??? unpressable
-        Err(e) => Err(AppError::Swkbd(e)),
-    }
-}
-
-#[derive(Copy, Clone, Debug, PartialEq)]
-enum AppStatus {
-    NotConnected,
-    Connected,
-    Settings
+    Ok(())
 }
-
-#[derive(Debug, Error)]
-pub enum AppError {
-    #[error("Unknown error")]
-    Unknown,
-    #[error("libctru error")]
-    Ctru(#[from] ctru::Error),
-    #[error("Software keyboard error")]
-    Swkbd(ctru::applets::swkbd::Error),
-    #[error("I/O error")]
-    Io(#[from] std::io::Error),
-}
\ No newline at end of file
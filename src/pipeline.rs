@@ -0,0 +1,227 @@
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryIter};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ctr_camera_common::handshake::StreamConfig;
+use ctr_camera_common::protocol::{self, PixelFormat};
+use ctr_camera_common::queue::{DropPolicy, FrameQueue};
+use ctr_camera_common::settings::CameraSettings;
+use ctr_camera_common::stats::Snapshot;
+use ctr_camera_common::stream::{FrameSource, Streamer};
+
+use crate::app::Connection;
+use crate::platform::Camera;
+use crate::AppError;
+
+/// How long the network thread waits for a frame before checking for acks and statistics.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Breather after a failed capture, so a broken camera doesn't starve the other threads.
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Locks `mutex`, carrying on if another thread panicked with it. Everything shared between the
+/// threads stays usable halfway through an update.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// One raw capture, on its way to the network thread and the preview.
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub format: PixelFormat,
+    pub width: u16,
+    pub height: u16,
+    /// Header flags, see [`FrameSource::flags`].
+    pub flags: u16,
+}
+
+/// What the network thread has to tell the UI.
+pub enum Event {
+    Stats(Snapshot),
+    /// The connection is gone, the pipeline has stopped.
+    Lost(AppError),
+}
+
+struct Shared {
+    queue: FrameQueue<Arc<Frame>>,
+    /// Newest capture for the preview, the UI takes it when it gets round to drawing.
+    latest: Mutex<Option<Arc<Frame>>>,
+    stop: AtomicBool,
+    captured: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Streams to a connected server on two threads of its own, so neither the camera nor the
+/// network holds up the UI or each other.
+///
+/// The capture thread keeps the camera busy and hands frames over through a bounded
+/// [`FrameQueue`], the network thread compresses and sends them and keeps the statistics. The UI
+/// loop only draws: it takes the newest frame for the preview and reads [`Event`]s.
+///
+/// Dropping it stops both threads and closes the connection.
+pub struct Pipeline {
+    shared: Arc<Shared>,
+    // a second handle on the connection, to unblock the network thread when stopping
+    control: TcpStream,
+    events: Receiver<Event>,
+    capture: Option<JoinHandle<()>>,
+    network: Option<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    /// Starts streaming `config` from `camera`, which has to be set up for it already (see
+    /// [`Camera::configure`]) and is locked for every capture until the pipeline is dropped.
+    pub fn start<C: Camera>(
+        camera: Arc<Mutex<C>>,
+        config: StreamConfig,
+        settings: &CameraSettings,
+        connection: Connection,
+        queue_length: usize,
+        policy: DropPolicy,
+    ) -> Result<Self, AppError> {
+        let control = connection.control.try_clone()?;
+        let shared = Arc::new(Shared {
+            queue: FrameQueue::new(queue_length, policy),
+            latest: Mutex::new(None),
+            stop: AtomicBool::new(false),
+            captured: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        });
+        let (sender, events) = mpsc::channel();
+
+        let mut pipeline = Self {
+            shared: shared.clone(),
+            control,
+            events,
+            capture: None,
+            network: None,
+        };
+
+        let settings = *settings;
+        let capture_shared = shared.clone();
+        pipeline.capture = Some(
+            thread::Builder::new()
+                .name("capture".to_owned())
+                .spawn(move || capture(&camera, config, &settings, &capture_shared))?,
+        );
+        // on failure, dropping the pipeline takes the capture thread down again
+        pipeline.network = Some(
+            thread::Builder::new()
+                .name("network".to_owned())
+                .spawn(move || network(connection, config, &shared, sender))?,
+        );
+
+        Ok(pipeline)
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.control.peer_addr().ok()
+    }
+
+    /// The newest capture, if there was one since the last call.
+    pub fn take_latest(&self) -> Option<Arc<Frame>> {
+        lock(&self.shared.latest).take()
+    }
+
+    /// Everything the network thread reported since the last call, without waiting.
+    pub fn events(&self) -> TryIter<'_, Event> {
+        self.events.try_iter()
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.queue.close();
+        // a send stuck on a full socket buffer fails right away instead of whenever the server
+        // reads again. The server may have closed it already
+        let _ = self.control.shutdown(Shutdown::Both);
+
+        // a thread that panicked has nothing left to clean up
+        if let Some(capture) = self.capture.take() {
+            let _ = capture.join();
+        }
+        if let Some(network) = self.network.take() {
+            let _ = network.join();
+        }
+    }
+}
+
+fn capture<C: Camera>(camera: &Mutex<C>, config: StreamConfig, settings: &CameraSettings, shared: &Shared) {
+    while !shared.stop.load(Ordering::Relaxed) {
+        let mut camera = lock(camera);
+        let mut source = camera.source(config, settings);
+
+        // every frame gets its own buffer, the last one may still be on its way out
+        let mut data = vec![0; source.frame_size()];
+        match source.capture(&mut data) {
+            Ok(()) => {
+                let frame = Arc::new(Frame {
+                    data,
+                    format: source.format(),
+                    width: source.width(),
+                    height: source.height(),
+                    flags: source.flags(),
+                });
+                drop(source);
+                drop(camera);
+
+                shared.captured.fetch_add(1, Ordering::Relaxed);
+                *lock(&shared.latest) = Some(frame.clone());
+                if !shared.queue.push(frame) {
+                    break;
+                }
+            }
+            // the camera missing a frame isn't worth dropping the connection over
+            Err(_) => {
+                drop(source);
+                drop(camera);
+                shared.failed.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+}
+
+fn network(mut connection: Connection, config: StreamConfig, shared: &Shared, events: Sender<Event>) {
+    let mut streamer = Streamer::for_config(&config);
+    // totals already counted in the statistics
+    let (mut captured, mut dropped) = (0, 0);
+
+    while !shared.stop.load(Ordering::Relaxed) {
+        if let Err(e) = send_next(&mut connection, &mut streamer, shared) {
+            if !shared.stop.load(Ordering::Relaxed) {
+                let _ = events.send(Event::Lost(e));
+            }
+            break;
+        }
+
+        let now_captured = shared.captured.load(Ordering::Relaxed);
+        let now_dropped = shared.failed.load(Ordering::Relaxed) + shared.queue.dropped();
+        connection.stats.captured((now_captured - captured) as u32);
+        connection.stats.dropped(now_dropped - dropped);
+        (captured, dropped) = (now_captured, now_dropped);
+
+        if let Some(snapshot) = connection.stats.tick(Instant::now()) {
+            let _ = events.send(Event::Stats(snapshot));
+        }
+    }
+
+    // nobody left to send frames to
+    shared.queue.close();
+}
+
+/// Sends the next frame if one turns up soon, then collects the acks.
+fn send_next(connection: &mut Connection, streamer: &mut Streamer, shared: &Shared) -> Result<(), AppError> {
+    if let Some(frame) = shared.queue.pop(POLL_INTERVAL) {
+        let header = match connection.udp {
+            Some(ref mut udp) => streamer.send(&frame.data, frame.format, frame.width, frame.height, frame.flags, udp),
+            None => streamer.send(&frame.data, frame.format, frame.width, frame.height, frame.flags, &mut connection.control),
+        }?;
+        connection.stats.sent(Instant::now(), header.sequence, protocol::HEADER_LEN + header.payload_len as usize);
+    }
+    connection.poll_acks()
+}
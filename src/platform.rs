@@ -0,0 +1,125 @@
+use std::io;
+use std::net::{Ipv4Addr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use ctr_camera_common::handshake::{CameraId, Resolution, StreamConfig};
+use ctr_camera_common::input::KeyState;
+use ctr_camera_common::preview::PreviewScreen;
+use ctr_camera_common::protocol::PixelFormat;
+use ctr_camera_common::settings::{CameraSettings, Setting};
+use ctr_camera_common::stereo::StereoLayout;
+use ctr_camera_common::stream::FrameSource;
+
+use crate::AppError;
+
+#[cfg(feature = "3ds")]
+pub mod ctru;
+#[cfg(not(feature = "3ds"))]
+pub mod host;
+
+/// What the console's system calls fail with.
+#[cfg(feature = "3ds")]
+pub type SystemError = ::ctru::Error;
+#[cfg(not(feature = "3ds"))]
+pub type SystemError = host::HostError;
+
+/// Everything the app needs from the machine it runs on: the `ctru` services on the console
+/// (see [`ctru`]) or stand-ins for running it on a PC (see [`host`]).
+pub trait Platform {
+    type Camera: Camera;
+    type Input: Input;
+    type Text: TextEntry;
+    type Network: Network;
+    type System: SystemInfo;
+    type Display: Display;
+}
+
+/// One of each, for [`App::new`](crate::app::App::new).
+pub struct Devices<P: Platform> {
+    pub camera: P::Camera,
+    pub input: P::Input,
+    pub text: P::Text,
+    pub network: P::Network,
+    pub system: P::System,
+    pub display: P::Display,
+}
+
+/// The cameras, handed to the capture thread while streaming.
+pub trait Camera: Send + 'static {
+    type Source<'a>: FrameSource<Error = SystemError>
+    where
+        Self: 'a;
+
+    /// Switches to `config.camera` and sets it up for `config`, including the image settings.
+    fn configure(&mut self, config: &StreamConfig, settings: &CameraSettings) -> Result<(), AppError>;
+
+    /// Pushes one changed setting to the selected camera.
+    fn apply(&mut self, settings: &CameraSettings, setting: Setting) -> Result<(), SystemError>;
+
+    /// Frames from the selected camera, which has to be configured for `config` already.
+    fn source(&mut self, config: StreamConfig, settings: &CameraSettings) -> Self::Source<'_>;
+
+    fn formats(&self) -> Vec<PixelFormat>;
+
+    fn resolutions(&self) -> Vec<Resolution>;
+
+    fn cameras(&self) -> Vec<CameraId> {
+        CameraId::ALL.to_vec()
+    }
+
+    fn stereo_layouts(&self) -> Vec<StereoLayout> {
+        StereoLayout::ALL.to_vec()
+    }
+}
+
+pub trait Input {
+    /// Reads the buttons, once a frame.
+    fn scan(&mut self) -> KeyState;
+}
+
+pub trait TextEntry {
+    /// Asks for a line of text with `hint` greyed out in the empty field. `None` if the user
+    /// cancelled.
+    fn text(&mut self, hint: &str, max_len: usize) -> Result<Option<String>, AppError>;
+}
+
+/// Longest a connection attempt, or the handshake after it, may take. They run on the UI thread,
+/// so a server that doesn't answer mustn't freeze the app for the minute or so the network stack
+/// would wait.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub trait Network {
+    /// Our address on the local network, the one to show and to listen on.
+    fn host_address(&self) -> Ipv4Addr;
+
+    fn connect(&mut self, address: &str) -> io::Result<TcpStream> {
+        let mut error = None;
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")))
+    }
+}
+
+pub trait SystemInfo {
+    /// Console model, sent to the server in the handshake.
+    fn model(&self) -> Result<String, SystemError>;
+}
+
+/// The screens. Text goes to stdout, which is the console on whichever screen the preview isn't
+/// on.
+pub trait Display {
+    fn clear(&mut self);
+
+    /// Moves the preview, and the console out of its way.
+    fn set_preview(&mut self, screen: PreviewScreen);
+
+    /// Draws a raw capture, `flags` being the ones from its frame header.
+    fn draw_preview(&mut self, frame: &[u8], format: PixelFormat, width: u16, height: u16, flags: u16);
+
+    /// Shows what was drawn this frame and waits for the next one.
+    fn present(&mut self);
+}
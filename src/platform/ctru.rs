@@ -0,0 +1,158 @@
+//! The real thing, on top of `ctru`.
+
+use std::cell::RefMut;
+use std::marker::PhantomData;
+use std::net::Ipv4Addr;
+
+use ctr_camera_common::input::{KeyState, Keys};
+use ctr_camera_common::preview::PreviewScreen;
+use ctr_camera_common::protocol::PixelFormat;
+use ctru::applets::swkbd::{Button, Filters, Swkbd, ValidInput};
+use ctru::prelude::*;
+use ctru::services::cfgu::Cfgu;
+use ctru::services::gfx::Screen;
+
+use super::{Display, Input, Network, Platform, SystemInfo, TextEntry};
+use crate::camera::Cameras;
+use crate::preview::Preview;
+use crate::AppError;
+
+/// The console, borrowing the services `main` keeps for itself for as long as `'a`.
+pub struct Ctru<'a>(PhantomData<&'a ()>);
+
+impl<'a> Platform for Ctru<'a> {
+    type Camera = Cameras;
+    type Input = CtruInput<'a>;
+    type Text = CtruText;
+    type Network = CtruNetwork;
+    type System = CtruSystem;
+    type Display = CtruDisplay<'a>;
+}
+
+pub struct CtruInput<'a> {
+    hid: &'a mut Hid,
+}
+
+impl<'a> CtruInput<'a> {
+    pub fn new(hid: &'a mut Hid) -> Self {
+        Self { hid }
+    }
+}
+
+impl Input for CtruInput<'_> {
+    fn scan(&mut self) -> KeyState {
+        self.hid.scan_input();
+        KeyState {
+            held: Keys::from_bits(self.hid.keys_held().bits()),
+            pressed: Keys::from_bits(self.hid.keys_down().bits()),
+            released: Keys::from_bits(self.hid.keys_up().bits()),
+        }
+    }
+}
+
+/// The software keyboard.
+pub struct CtruText;
+
+impl TextEntry for CtruText {
+    fn text(&mut self, hint: &str, max_len: usize) -> Result<Option<String>, AppError> {
+        let mut keyboard = Swkbd::default();
+
+        keyboard.set_hint_text(hint);
+        keyboard.set_max_text_len(max_len as u16);
+        keyboard.set_validation(ValidInput::NotEmptyNotBlank, Filters::BACKSLASH);
+
+        // UTF-8 takes up to four bytes a character
+        match keyboard.get_string(max_len * 4) {
+            Ok((text, Button::Right)) => Ok(Some(text)),
+            Ok((_, Button::Left)) => Ok(None),
+            Ok((_, Button::Middle)) => Ok(None), // ??? unpressable
+            Err(e) => Err(AppError::Swkbd(e)),
+        }
+    }
+}
+
+/// Sockets are plain `std::net` once `Soc` is up.
+pub struct CtruNetwork {
+    soc: Soc,
+}
+
+impl CtruNetwork {
+    pub fn new(soc: Soc) -> Self {
+        Self { soc }
+    }
+}
+
+impl Network for CtruNetwork {
+    fn host_address(&self) -> Ipv4Addr {
+        self.soc.host_address()
+    }
+}
+
+pub struct CtruSystem {
+    cfgu: Cfgu,
+}
+
+impl CtruSystem {
+    pub fn new(cfgu: Cfgu) -> Self {
+        Self { cfgu }
+    }
+}
+
+impl SystemInfo for CtruSystem {
+    fn model(&self) -> Result<String, ctru::Error> {
+        Ok(format!("{:?}", self.cfgu.model()?))
+    }
+}
+
+/// The console on one screen and the preview on the other, see [`Preview`].
+pub struct CtruDisplay<'gfx> {
+    gfx: &'gfx Gfx,
+    // only `None` while moving between screens
+    console: Option<Console<'gfx>>,
+    preview: Preview<'gfx>,
+}
+
+impl<'gfx> CtruDisplay<'gfx> {
+    pub fn new(gfx: &'gfx Gfx, screen: PreviewScreen) -> Self {
+        Self {
+            gfx,
+            console: Some(Console::init(console_screen(gfx, screen))),
+            preview: Preview::new(gfx, screen),
+        }
+    }
+}
+
+impl Display for CtruDisplay<'_> {
+    fn clear(&mut self) {
+        if let Some(console) = &self.console {
+            console.clear();
+        }
+    }
+
+    fn set_preview(&mut self, screen: PreviewScreen) {
+        // the console gets out of the way first
+        self.console = None;
+        self.preview.set_screen(screen);
+        self.console = Some(Console::init(console_screen(self.gfx, screen)));
+    }
+
+    fn draw_preview(&mut self, frame: &[u8], format: PixelFormat, width: u16, height: u16, flags: u16) {
+        self.preview.draw(frame, format, width, height, flags);
+    }
+
+    fn present(&mut self) {
+        self.gfx.flush_buffers();
+        self.gfx.swap_buffers();
+        self.gfx.wait_for_vblank();
+    }
+}
+
+/// The screen the preview doesn't need.
+fn console_screen(gfx: &Gfx, preview: PreviewScreen) -> RefMut<'_, dyn Screen> {
+    if preview.uses_top() {
+        gfx.bottom_screen.borrow_mut()
+    }
+    else {
+        gfx.top_screen.borrow_mut()
+    }
+}
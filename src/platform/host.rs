@@ -0,0 +1,294 @@
+//! Stand-ins for running the app on a PC: synthetic frames, scripted buttons and keyboard input,
+//! and the loopback interface for the network.
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use ctr_camera_common::handshake::{Resolution, StreamConfig};
+use ctr_camera_common::input::{KeyState, Keys};
+use ctr_camera_common::preview::PreviewScreen;
+use ctr_camera_common::protocol::PixelFormat;
+use ctr_camera_common::settings::{CameraSettings, Setting};
+use ctr_camera_common::stream::{FrameSource, TestPattern};
+use ctr_camera_common::yuv::{self, Range, RgbLayout};
+use thiserror::Error;
+
+use super::{Camera, Display, Input, Network, Platform, SystemInfo, TextEntry};
+use crate::AppError;
+
+/// Failures of the stand-in devices, so they can be scripted as well.
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("{0}")]
+pub struct HostError(pub String);
+
+/// The app on a PC, see the module docs.
+pub struct Host;
+
+impl Platform for Host {
+    type Camera = SyntheticCamera;
+    type Input = ScriptedInput;
+    type Text = ScriptedText;
+    type Network = Loopback;
+    type System = HostSystem;
+    type Display = HostDisplay;
+}
+
+// a test poking at a script mustn't take the app down with it
+fn take<T>(queue: &Mutex<VecDeque<T>>) -> Option<T> {
+    queue.lock().unwrap_or_else(PoisonError::into_inner).pop_front()
+}
+
+/// Colour bars at whatever size and format it's configured for, see [`TestPattern`].
+#[derive(Default)]
+pub struct SyntheticCamera {
+    config: Option<StreamConfig>,
+    pattern: Option<TestPattern>,
+    captures: u64,
+}
+
+impl SyntheticCamera {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What it was configured for last.
+    pub fn config(&self) -> Option<StreamConfig> {
+        self.config
+    }
+
+    pub fn captures(&self) -> u64 {
+        self.captures
+    }
+}
+
+/// Resolutions the real cameras can do, so configuring fails in the same places.
+const RESOLUTIONS: [Resolution; 8] = [
+    Resolution::new(640, 480),
+    Resolution::new(512, 384),
+    Resolution::new(400, 240),
+    Resolution::new(352, 288),
+    Resolution::new(320, 240),
+    Resolution::new(256, 192),
+    Resolution::new(176, 144),
+    Resolution::new(160, 120),
+];
+
+impl Camera for SyntheticCamera {
+    type Source<'a> = SyntheticSource<'a>;
+
+    fn configure(&mut self, config: &StreamConfig, _settings: &CameraSettings) -> Result<(), AppError> {
+        let Resolution { width, height } = config.resolution;
+        if !RESOLUTIONS.contains(&config.resolution) {
+            return Err(AppError::Resolution(width, height));
+        }
+        self.config = Some(*config);
+        self.pattern = Some(TestPattern::new(width, height));
+        Ok(())
+    }
+
+    fn apply(&mut self, _settings: &CameraSettings, _setting: Setting) -> Result<(), HostError> {
+        Ok(())
+    }
+
+    fn source(&mut self, config: StreamConfig, _settings: &CameraSettings) -> SyntheticSource<'_> {
+        SyntheticSource {
+            camera: self,
+            config,
+        }
+    }
+
+    fn formats(&self) -> Vec<PixelFormat> {
+        vec![PixelFormat::Jpeg, PixelFormat::Yuv422, PixelFormat::Rgb565]
+    }
+
+    fn resolutions(&self) -> Vec<Resolution> {
+        RESOLUTIONS.to_vec()
+    }
+}
+
+pub struct SyntheticSource<'a> {
+    camera: &'a mut SyntheticCamera,
+    config: StreamConfig,
+}
+
+impl FrameSource for SyntheticSource<'_> {
+    type Error = HostError;
+
+    fn width(&self) -> u16 {
+        self.config.resolution.width
+    }
+
+    fn height(&self) -> u16 {
+        self.config.resolution.height
+    }
+
+    fn format(&self) -> PixelFormat {
+        self.config.format.capture_format()
+    }
+
+    fn capture(&mut self, buf: &mut [u8]) -> Result<(), HostError> {
+        let format = self.format();
+        let pattern = match &mut self.camera.pattern {
+            Some(pattern) if self.camera.config.map(|c| c.resolution) == Some(self.config.resolution) => pattern,
+            _ => return Err(HostError("Camera not configured".to_owned())),
+        };
+
+        match format {
+            PixelFormat::Rgb565 => {
+                // the pattern only comes in YUYV, which is the same size
+                let mut yuyv = vec![0; buf.len()];
+                let Ok(()) = pattern.capture(&mut yuyv);
+                let (width, height) = (self.config.resolution.width as usize, self.config.resolution.height as usize);
+                let mut rgb = Vec::with_capacity(buf.len());
+                yuv::convert(&yuyv, width, height, RgbLayout::Rgb565, Range::default(), &mut rgb)
+                    .map_err(|e| HostError(e.to_string()))?;
+                buf.copy_from_slice(&rgb);
+            }
+            _ => {
+                let Ok(()) = pattern.capture(buf);
+            }
+        }
+        self.camera.captures += 1;
+        Ok(())
+    }
+}
+
+/// Buttons from a script shared with whoever writes it: one entry of held keys per scan, nothing
+/// held once it runs out.
+#[derive(Clone, Default)]
+pub struct ScriptedInput {
+    script: Arc<Mutex<VecDeque<Keys>>>,
+    held: Keys,
+}
+
+impl ScriptedInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Holds `keys` for one scan.
+    pub fn push(&self, keys: Keys) {
+        self.script.lock().unwrap_or_else(PoisonError::into_inner).push_back(keys);
+    }
+
+    /// Presses `keys` and lets go on the next scan.
+    pub fn tap(&self, keys: Keys) {
+        self.push(keys);
+        self.push(Keys::empty());
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.script.lock().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+}
+
+impl Input for ScriptedInput {
+    fn scan(&mut self) -> KeyState {
+        let held = take(&self.script).unwrap_or_default();
+        let state = KeyState::next(self.held, held);
+        self.held = held;
+        state
+    }
+}
+
+/// Keyboard answers from a script, `None` being the cancel button. Cancels once it runs out.
+#[derive(Clone, Default)]
+pub struct ScriptedText {
+    answers: Arc<Mutex<VecDeque<Option<String>>>>,
+}
+
+impl ScriptedText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, answer: Option<&str>) {
+        self.answers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(answer.map(str::to_owned));
+    }
+}
+
+impl TextEntry for ScriptedText {
+    fn text(&mut self, _hint: &str, max_len: usize) -> Result<Option<String>, AppError> {
+        Ok(take(&self.answers)
+            .flatten()
+            .map(|text| text.chars().take(max_len).collect()))
+    }
+}
+
+/// Everything stays on this machine: servers are found and connected to on 127.0.0.1.
+#[derive(Clone, Copy, Default)]
+pub struct Loopback;
+
+impl Network for Loopback {
+    fn host_address(&self) -> Ipv4Addr {
+        Ipv4Addr::LOCALHOST
+    }
+}
+
+pub struct HostSystem {
+    pub model: String,
+}
+
+impl Default for HostSystem {
+    fn default() -> Self {
+        Self {
+            model: "Host".to_owned(),
+        }
+    }
+}
+
+impl SystemInfo for HostSystem {
+    fn model(&self) -> Result<String, HostError> {
+        Ok(self.model.clone())
+    }
+}
+
+/// Counts what would have been drawn, shared like the scripts so it can be checked from outside.
+#[derive(Clone, Default)]
+pub struct HostDisplay {
+    state: Arc<Mutex<DisplayState>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisplayState {
+    pub clears: u64,
+    pub previews: u64,
+    pub presents: u64,
+    pub preview_screen: PreviewScreen,
+}
+
+impl HostDisplay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> DisplayState {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut DisplayState)) {
+        f(&mut self.state.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+}
+
+impl Display for HostDisplay {
+    fn clear(&mut self) {
+        self.update(|s| s.clears += 1);
+    }
+
+    fn set_preview(&mut self, screen: PreviewScreen) {
+        self.update(|s| s.preview_screen = screen);
+    }
+
+    fn draw_preview(&mut self, _frame: &[u8], _format: PixelFormat, _width: u16, _height: u16, _flags: u16) {
+        self.update(|s| s.previews += 1);
+    }
+
+    fn present(&mut self) {
+        self.update(|s| s.presents += 1);
+    }
+}
@@ -0,0 +1,94 @@
+use std::slice;
+
+use ctr_camera_common::preview::{self, PreviewScreen, Rect};
+use ctr_camera_common::protocol::PixelFormat;
+use ctr_camera_common::stereo::Eye;
+use ctru::services::gfx::{Gfx, Screen, TopScreen3D};
+use ctru::services::gspgpu::FramebufferFormat;
+
+/// Shows captured frames on whichever screen the settings say.
+///
+/// The console has to be on the other screen, see `console_screen`, both borrow theirs from `Gfx`.
+pub struct Preview<'gfx> {
+    gfx: &'gfx Gfx,
+    screen: PreviewScreen,
+    // keeps the parallax barrier on while it's alive
+    stereo: Option<TopScreen3D<'gfx>>,
+}
+
+impl<'gfx> Preview<'gfx> {
+    pub fn new(gfx: &'gfx Gfx, screen: PreviewScreen) -> Self {
+        let mut preview = Self {
+            gfx,
+            screen: PreviewScreen::Off,
+            stereo: None,
+        };
+        preview.set_screen(screen);
+        preview
+    }
+
+    /// Moves the preview. The console must have left `screen` already.
+    pub fn set_screen(&mut self, screen: PreviewScreen) {
+        self.stereo = None;
+
+        match screen {
+            PreviewScreen::Off => {}
+            PreviewScreen::Bottom => prepare(&mut *self.gfx.bottom_screen.borrow_mut()),
+            PreviewScreen::Top => prepare(&mut *self.gfx.top_screen.borrow_mut()),
+            PreviewScreen::Top3D => {
+                prepare(&mut *self.gfx.top_screen.borrow_mut());
+                self.stereo = Some(TopScreen3D::from(&self.gfx.top_screen));
+            }
+        }
+        self.screen = screen;
+    }
+
+    /// Draws a raw capture, `flags` being the ones from its frame header.
+    pub fn draw(&mut self, frame: &[u8], format: PixelFormat, width: u16, height: u16, flags: u16) {
+        let width = width as usize;
+        let eyes = preview::eyes(flags, width, height as usize);
+        let left = eyes.iter().find(|(eye, _)| *eye == Eye::Left).map(|(_, region)| *region);
+
+        match self.screen {
+            PreviewScreen::Off => {}
+            PreviewScreen::Bottom => {
+                if let Some(region) = left {
+                    draw_on(&mut *self.gfx.bottom_screen.borrow_mut(), frame, format, width, region);
+                }
+            }
+            PreviewScreen::Top => {
+                if let Some(region) = left {
+                    draw_on(&mut *self.gfx.top_screen.borrow_mut(), frame, format, width, region);
+                }
+            }
+            PreviewScreen::Top3D => {
+                if let Some(stereo) = &self.stereo {
+                    let (mut left, mut right) = stereo.split_mut();
+                    for (eye, region) in eyes {
+                        match eye {
+                            Eye::Left => draw_on(&mut *left, frame, format, width, region),
+                            Eye::Right => draw_on(&mut *right, frame, format, width, region),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn prepare(screen: &mut dyn Screen) {
+    screen.set_framebuffer_format(FramebufferFormat::Rgb565);
+    // frames don't come every vblank, swapping would flicker between the last two
+    screen.set_double_buffering(false);
+}
+
+fn draw_on(screen: &mut dyn Screen, frame: &[u8], format: PixelFormat, width: usize, region: Rect) {
+    let fb = screen.raw_framebuffer();
+    let len = fb.width * fb.height * 2;
+    // SAFETY: the framebuffer is `width` x `height` RGB565 pixels after `prepare`, and nothing
+    // else writes to it while the screen is borrowed
+    let pixels = unsafe { slice::from_raw_parts_mut(fb.ptr, len) };
+
+    // the panel is portrait, `preview` wants the landscape size
+    preview::draw(frame, format, width, region, pixels, (fb.height, fb.width));
+}
@@ -0,0 +1,235 @@
+//! Which screen the app is on and what moves it to the next one.
+//!
+//! [`update`] only decides: given the status and something that happened it returns the new
+//! status and a list of [`Effect`]s, and [`App`](crate::app::App) carries those out. Effects that
+//! can go either way (connecting, searching, ...) report back with another [`Event`], which goes
+//! through [`update`] again. Nothing in here draws, blocks or touches a device.
+
+use ctr_camera_common::config::SavedServer;
+use ctr_camera_common::input::Action;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AppStatus {
+    NotConnected,
+    Connected,
+    Serving,
+    Settings,
+    Discovering,
+    Picking,
+    /// Lost the server, trying it again every now and then.
+    Reconnecting,
+    /// Writing the camera to the SD card.
+    Recording,
+}
+
+impl AppStatus {
+    pub const ALL: [AppStatus; 8] = [
+        AppStatus::NotConnected,
+        AppStatus::Connected,
+        AppStatus::Serving,
+        AppStatus::Settings,
+        AppStatus::Discovering,
+        AppStatus::Picking,
+        AppStatus::Reconnecting,
+        AppStatus::Recording,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Something the buttons asked for, see [`Buttons`](ctr_camera_common::input::Buttons).
+    Action(Action),
+    /// Once a frame, after the buttons.
+    Tick,
+    /// A entry of the saved or discovered list was picked.
+    Chose(Choice),
+    /// There are saved servers to pick from.
+    Listed,
+    SearchStarted,
+    SearchFailed,
+    HttpStarted,
+    /// The handshake went through and frames are on their way.
+    Connected,
+    /// Didn't connect, cancelled included.
+    ConnectFailed,
+    /// The stream died, with what killed it.
+    Lost(String),
+    /// Time for the next reconnect attempt.
+    RetryDue,
+    /// Out of reconnect attempts.
+    GaveUp,
+    RecordingStarted,
+    /// Couldn't write to the SD card, with why.
+    RecordingFailed(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Choice {
+    Server(SavedServer),
+    Search,
+    Keyboard,
+}
+
+/// Who to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Server(SavedServer),
+    /// Whatever gets typed on the software keyboard.
+    Keyboard,
+    /// The server being reconnected to.
+    Retry,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Stops everything and leaves the app.
+    Exit,
+    // clear the console and draw a screen from the top, with a fresh cursor
+    ShowSetup,
+    ShowSettings,
+    ShowSaved,
+    ShowServers,
+    Message(String),
+    /// Moves around the current list or menu. Never changes the status.
+    Navigate(Action),
+    /// Reports [`Event::Chose`] with whatever the cursor is on.
+    Choose,
+    /// Reports [`Event::Listed`] or goes straight to searching if nothing's saved.
+    FindServers,
+    /// Reports [`Event::SearchStarted`] or [`Event::SearchFailed`].
+    Search,
+    PollSearch,
+    StopSearch,
+    /// Reports [`Event::Connected`] or [`Event::ConnectFailed`].
+    Connect(Target),
+    /// Reports [`Event::Lost`] once the stream dies.
+    PollStream,
+    Disconnect,
+    /// Reports [`Event::GaveUp`] if there's nobody to reconnect to.
+    StartReconnect,
+    /// Reports [`Event::RetryDue`] or [`Event::GaveUp`] when it's time.
+    PollReconnect,
+    RetryLater,
+    StopReconnect,
+    /// Reports [`Event::HttpStarted`] if it could listen.
+    StartHttp,
+    PollHttp,
+    StopHttp,
+    ToggleRtsp,
+    PollRtsp,
+    /// Captures a frame for the HTTP and RTSP clients, if they want one.
+    ServeFrames,
+    /// Reports [`Event::RecordingStarted`] if the first file could be opened.
+    StartRecording,
+    /// Reports [`Event::RecordingFailed`] if the frame couldn't be written.
+    RecordFrame,
+    /// Finishes the file being written.
+    StopRecording,
+    /// Saves a still to the SD card.
+    TakePhoto,
+}
+
+/// Where `event` takes the app from `status`, and what has to happen on the way. Events that
+/// mean nothing in `status` leave it alone.
+pub fn update(status: AppStatus, event: Event) -> (AppStatus, Vec<Effect>) {
+    use AppStatus::*;
+
+    match (status, event) {
+        (_, Event::Action(action)) => act(status, action),
+        (_, Event::Tick) => (status, tick(status)),
+
+        (NotConnected, Event::Listed) => (Picking, vec![Effect::ShowSaved]),
+        (NotConnected | Picking, Event::Chose(Choice::Search)) => (status, vec![Effect::Search]),
+        (Picking, Event::Chose(Choice::Server(server))) => (NotConnected, vec![Effect::ShowSetup, Effect::Connect(Target::Server(server))]),
+        (Picking, Event::Chose(Choice::Keyboard)) => (NotConnected, vec![Effect::ShowSetup, Effect::Connect(Target::Keyboard)]),
+        (Discovering, Event::Chose(Choice::Server(server))) => (
+            NotConnected,
+            vec![Effect::StopSearch, Effect::ShowSetup, Effect::Connect(Target::Server(server))],
+        ),
+        (Discovering, Event::Chose(Choice::Keyboard)) => (
+            NotConnected,
+            vec![Effect::StopSearch, Effect::ShowSetup, Effect::Connect(Target::Keyboard)],
+        ),
+
+        (NotConnected | Picking, Event::SearchStarted) => (Discovering, vec![Effect::ShowServers]),
+        // no broadcast, no list
+        (NotConnected, Event::SearchFailed) => (NotConnected, vec![Effect::Connect(Target::Keyboard)]),
+        (Picking, Event::SearchFailed) => (NotConnected, vec![Effect::ShowSetup]),
+
+        (NotConnected, Event::HttpStarted) => (Serving, vec![]),
+
+        (Reconnecting, Event::Connected) => (Connected, vec![Effect::StopReconnect]),
+        // a running stream has to be shown whatever else was going on
+        (_, Event::Connected) => (Connected, vec![]),
+        (Reconnecting, Event::ConnectFailed) => (Reconnecting, vec![Effect::RetryLater]),
+        (Connected, Event::Lost(reason)) => (
+            Reconnecting,
+            vec![Effect::ShowSetup, Effect::Message(format!("Connection lost: {}", reason)), Effect::StartReconnect],
+        ),
+        (Reconnecting, Event::RetryDue) => (Reconnecting, vec![Effect::Connect(Target::Retry)]),
+        (Reconnecting, Event::GaveUp) => (NotConnected, vec![Effect::StopReconnect]),
+
+        (NotConnected, Event::RecordingStarted) => (Recording, vec![]),
+        (Recording, Event::RecordingFailed(reason)) => (
+            NotConnected,
+            vec![Effect::StopRecording, Effect::ShowSetup, Effect::Message(format!("Recording stopped: {}", reason))],
+        ),
+
+        _ => (status, vec![]),
+    }
+}
+
+fn act(status: AppStatus, action: Action) -> (AppStatus, Vec<Effect>) {
+    use AppStatus::*;
+
+    match (status, action) {
+        // the file needs its index written before anything goes away
+        (Recording, Action::Exit) => (NotConnected, vec![Effect::StopRecording, Effect::Exit]),
+        (_, Action::Exit) => (status, vec![Effect::Exit]),
+
+        (NotConnected, Action::Settings) => (Settings, vec![Effect::ShowSettings]),
+        (NotConnected, Action::Confirm) => (status, vec![Effect::FindServers]),
+        (NotConnected, Action::Serve) => (status, vec![Effect::StartHttp]),
+        (NotConnected, Action::Rtsp) => (status, vec![Effect::ToggleRtsp]),
+        (NotConnected, Action::Record) => (status, vec![Effect::StartRecording]),
+        // the camera is borrowed for the still and handed back to whatever was using it
+        (NotConnected | Connected | Serving | Reconnecting | Recording, Action::Photo) => (status, vec![Effect::TakePhoto]),
+
+        (Picking | Discovering, Action::Confirm) => (status, vec![Effect::Choose]),
+        (Picking | Settings, Action::Back) => (NotConnected, vec![Effect::ShowSetup]),
+        (Discovering, Action::Back) => (NotConnected, vec![Effect::StopSearch, Effect::ShowSetup]),
+        (
+            Picking,
+            Action::Up | Action::Down | Action::Rename | Action::Favourite | Action::MoveUp | Action::MoveDown | Action::Delete,
+        )
+        | (Discovering, Action::Up | Action::Down | Action::Search)
+        | (Settings, Action::Up | Action::Down | Action::Left | Action::Right | Action::Confirm) => (status, vec![Effect::Navigate(action)]),
+
+        (Serving, Action::Back) => (NotConnected, vec![Effect::StopHttp, Effect::ShowSetup]),
+        (Connected, Action::Back) => (NotConnected, vec![Effect::ShowSetup, Effect::Disconnect]),
+        (Reconnecting, Action::Back) => (
+            NotConnected,
+            vec![Effect::StopReconnect, Effect::ShowSetup, Effect::Message("Stopped reconnecting.".to_owned())],
+        ),
+        (Recording, Action::Back | Action::Record) => (NotConnected, vec![Effect::StopRecording, Effect::ShowSetup]),
+
+        _ => (status, vec![]),
+    }
+}
+
+fn tick(status: AppStatus) -> Vec<Effect> {
+    let mut effects = match status {
+        AppStatus::Discovering => vec![Effect::PollSearch],
+        AppStatus::Connected => vec![Effect::PollStream],
+        AppStatus::Serving => vec![Effect::PollHttp],
+        AppStatus::Reconnecting => vec![Effect::PollReconnect],
+        AppStatus::Recording => vec![Effect::RecordFrame],
+        AppStatus::NotConnected | AppStatus::Settings | AppStatus::Picking => vec![],
+    };
+    effects.push(Effect::PollRtsp);
+    // the receiver connection and the recording own the camera while they're going
+    if status != AppStatus::Connected && status != AppStatus::Recording {
+        effects.push(Effect::ServeFrames);
+    }
+    effects
+}
@@ -0,0 +1,417 @@
+//! The whole app on the host stand-ins: buttons go in through a script, frames come out at a
+//! receiver on loopback.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ctr_camera_common::config::Config;
+use ctr_camera_common::handshake::{self, CameraId, Resolution, StreamConfig};
+use ctr_camera_common::input::{Action, Keys};
+use ctr_camera_common::protocol::{encode_ack, Frame, FrameDecoder, PixelFormat};
+use ctr_camera_common::stream::{FrameSource, TestPattern};
+use ctr_camera_common::yuv::{self, Range, RgbLayout};
+use ctr_camera_rs::app::{App, Paths};
+use ctr_camera_rs::platform::host::*;
+use ctr_camera_rs::platform::{Devices, CONNECT_TIMEOUT};
+use ctr_camera_rs::state::AppStatus;
+
+/// A fresh directory for one test's files.
+fn paths(name: &str) -> Paths {
+    let dir = std::env::temp_dir().join(format!("ctr-camera-app-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    Paths {
+        config: dir.join("config.ini"),
+        recordings: dir.join("recordings"),
+        photos: dir.join("DCIM"),
+    }
+}
+
+struct Rig {
+    app: App<Host>,
+    input: ScriptedInput,
+    text: ScriptedText,
+    display: HostDisplay,
+}
+
+impl Rig {
+    fn new(paths: Paths) -> Self {
+        let input = ScriptedInput::new();
+        let text = ScriptedText::new();
+        let display = HostDisplay::new();
+        let devices = Devices::<Host> {
+            camera: SyntheticCamera::new(),
+            input: input.clone(),
+            text: text.clone(),
+            network: Loopback,
+            system: HostSystem::default(),
+            display: display.clone(),
+        };
+        Self {
+            app: App::new(devices, paths),
+            input,
+            text,
+            display,
+        }
+    }
+
+    /// Presses and releases `keys`, false if the app wants to exit.
+    fn tap(&mut self, keys: Keys) -> bool {
+        self.input.tap(keys);
+        let pressed = self.app.step();
+        let released = self.app.step();
+        pressed && released
+    }
+
+    fn run_until(&mut self, done: impl Fn(&mut Self) -> bool) {
+        let start = Instant::now();
+        while !done(self) {
+            assert!(start.elapsed() < Duration::from_secs(20), "stuck in {:?}", self.app.status());
+            self.app.step();
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Types `address` on the keyboard, skipping past discovery if it comes up first.
+    fn connect_to(&mut self, address: SocketAddr) {
+        self.text.push(Some(&address.to_string()));
+        self.tap(Keys::A);
+        if self.app.status() == AppStatus::Discovering {
+            self.tap(Keys::A);
+        }
+    }
+}
+
+/// A receiver on loopback asking for `preferred`, acking frames like the real one.
+struct Receiver {
+    address: SocketAddr,
+    /// Frames so far.
+    count: Arc<AtomicUsize>,
+    /// What it agreed to and every frame, once the connection is closed.
+    handle: JoinHandle<(StreamConfig, Vec<Frame>)>,
+}
+
+fn receiver(preferred: StreamConfig) -> Receiver {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let counter = count.clone();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let (_, config) = handshake::accept(&mut stream, &preferred).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode().unwrap() {
+                let _ = stream.write_all(&encode_ack(frame.header.sequence));
+                frames.push(frame);
+                counter.store(frames.len(), Ordering::Relaxed);
+            }
+        }
+        (config, frames)
+    });
+    Receiver {
+        address,
+        count,
+        handle,
+    }
+}
+
+/// Streams until `receiver` got a few frames and stops with B.
+fn stream_a_while(rig: &mut Rig, receiver: &Receiver) {
+    assert_eq!(rig.app.status(), AppStatus::Connected);
+    rig.run_until(|rig| {
+        rig.display.state().previews >= 5 && receiver.count.load(Ordering::Relaxed) >= 3
+    });
+    assert!(rig.tap(Keys::B));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+}
+
+fn check_sequence(frames: &[Frame]) {
+    assert!(!frames.is_empty());
+    for pair in frames.windows(2) {
+        assert_eq!(pair[1].header.sequence, pair[0].header.sequence.wrapping_add(1));
+        assert!(pair[1].header.timestamp_us >= pair[0].header.timestamp_us);
+    }
+}
+
+#[test]
+fn streams_jpeg() {
+    let receiver = receiver(StreamConfig {
+        resolution: Resolution::new(320, 240),
+        camera: CameraId::Inner,
+        ..StreamConfig::default()
+    });
+    let mut rig = Rig::new(paths("jpeg"));
+    rig.connect_to(receiver.address);
+    assert_eq!(rig.app.saved().servers.len(), 1);
+    stream_a_while(&mut rig, &receiver);
+
+    let (config, frames) = receiver.handle.join().unwrap();
+    assert_eq!(config.format, PixelFormat::Jpeg);
+    assert_eq!(rig.app.saved().servers[0].preferred, Some(config));
+    check_sequence(&frames);
+    for frame in &frames {
+        assert_eq!(frame.header.format, PixelFormat::Jpeg);
+        assert_eq!((frame.header.width, frame.header.height), (320, 240));
+        assert_eq!(frame.payload[..2], [0xff, 0xd8]);
+        assert_eq!(frame.payload[frame.payload.len() - 2..], [0xff, 0xd9]);
+    }
+}
+
+#[test]
+fn streams_rgb565() {
+    let receiver = receiver(StreamConfig {
+        format: PixelFormat::Rgb565,
+        resolution: Resolution::new(160, 120),
+        camera: CameraId::Inner,
+        ..StreamConfig::default()
+    });
+    let mut rig = Rig::new(paths("rgb565"));
+    rig.connect_to(receiver.address);
+    stream_a_while(&mut rig, &receiver);
+
+    let (config, frames) = receiver.handle.join().unwrap();
+    assert_eq!(config.format, PixelFormat::Rgb565);
+    check_sequence(&frames);
+
+    for frame in &frames {
+        assert_eq!(frame.header.format, PixelFormat::Rgb565);
+        assert_eq!(frame.payload.len(), 160 * 120 * 2);
+    }
+
+    // every colour bar, converted the way the receiver would
+    let mut yuyv = vec![0; 160 * 2];
+    let Ok(()) = TestPattern::new(160, 1).capture(&mut yuyv);
+    let mut bars = Vec::new();
+    yuv::convert(&yuyv, 160, 1, RgbLayout::Rgb565, Range::default(), &mut bars).unwrap();
+    let expected: HashSet<&[u8]> = bars.chunks_exact(2).collect();
+    let seen: HashSet<&[u8]> = frames[0].payload.chunks_exact(2).collect();
+    assert!(expected.is_subset(&seen), "{:?} not in {:?}", expected, seen);
+}
+
+#[test]
+fn lost_connection_reconnects() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        handshake::accept(&mut stream, &StreamConfig::default()).unwrap();
+        // and gone
+    });
+
+    let mut rig = Rig::new(paths("lost"));
+    rig.connect_to(address);
+    assert_eq!(rig.app.status(), AppStatus::Connected);
+    server.join().unwrap();
+
+    rig.run_until(|rig| rig.app.status() == AppStatus::Reconnecting);
+    assert!(rig.tap(Keys::B));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+}
+
+#[test]
+fn refused_connection() {
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut rig = Rig::new(paths("refused"));
+    rig.connect_to(address);
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+    assert!(rig.app.saved().servers.is_empty());
+}
+
+#[test]
+fn silent_server_times_out() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    // accepts, but never answers the hello
+    let server = thread::spawn(move || listener.accept().unwrap());
+
+    let mut rig = Rig::new(paths("silent"));
+    let start = Instant::now();
+    rig.connect_to(address);
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+    assert!(start.elapsed() < CONNECT_TIMEOUT * 2, "{:?}", start.elapsed());
+    drop(server.join().unwrap());
+}
+
+#[test]
+fn settings_and_back() {
+    let mut rig = Rig::new(paths("settings"));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+    assert!(rig.tap(Keys::X));
+    assert_eq!(rig.app.status(), AppStatus::Settings);
+    assert!(rig.tap(Keys::DPAD_DOWN));
+    assert!(rig.tap(Keys::B));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+    assert!(rig.display.state().clears >= 2);
+}
+
+#[test]
+fn start_exits() {
+    let mut rig = Rig::new(paths("start"));
+    rig.input.push(Keys::START);
+    assert!(!rig.app.step());
+}
+
+#[test]
+fn cancelled_keyboard() {
+    let mut rig = Rig::new(paths("cancel"));
+    rig.text.push(None);
+    rig.tap(Keys::A);
+    if rig.app.status() == AppStatus::Discovering {
+        rig.tap(Keys::A);
+    }
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+}
+
+#[test]
+fn holding_doesnt_repeat() {
+    let mut rig = Rig::new(paths("hold"));
+    for _ in 0..10 {
+        rig.input.push(Keys::X);
+    }
+    while !rig.input.is_done() {
+        rig.app.step();
+    }
+    assert_eq!(rig.app.status(), AppStatus::Settings);
+    assert_eq!(rig.display.state().clears, 1);
+
+    for _ in 0..10 {
+        rig.input.push(Keys::X | Keys::DPAD_DOWN);
+    }
+    while !rig.input.is_done() {
+        rig.app.step();
+    }
+    // the cursor moved once
+    assert_eq!(rig.display.state().clears, 2, "{:?}", rig.display.state());
+}
+
+#[test]
+fn bad_config_line_keeps_the_rest() {
+    let receiver = receiver(StreamConfig::default());
+    let paths = paths("bad-line");
+    std::fs::create_dir_all(paths.config.parent().unwrap()).unwrap();
+    let text = format!(
+        "queue_length = lots\n[keys]\nsettings = L+R\n\
+         [server]\nname = Desk\nhost = 127.0.0.1\nport = {}\nfps = fast\n",
+        receiver.address.port()
+    );
+    std::fs::write(&paths.config, text).unwrap();
+    let mut rig = Rig::new(paths.clone());
+    assert_eq!(rig.app.saved().servers[0].name, "Desk");
+
+    // the saved server is picked straight away
+    rig.tap(Keys::A);
+    assert_eq!(rig.app.status(), AppStatus::Picking);
+    rig.tap(Keys::A);
+    stream_a_while(&mut rig, &receiver);
+    receiver.handle.join().unwrap();
+
+    // saved again after connecting, without losing anything that was fine
+    let (saved, errors) = Config::load(&paths.config).unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(saved.servers.len(), 1);
+    assert_eq!(saved.servers[0].name, "Desk");
+    assert!(saved.servers[0].preferred.is_some());
+    assert_eq!(saved.keys.label(Action::Settings), "L+R");
+}
+
+#[test]
+fn unreadable_config_is_left_alone() {
+    let receiver = receiver(StreamConfig::default());
+    let paths = paths("unreadable");
+    std::fs::create_dir_all(paths.config.parent().unwrap()).unwrap();
+    let text = b"[server]\nname = D\xe9sktop\nhost = 10.0.0.2\n";
+    std::fs::write(&paths.config, text).unwrap();
+    let mut rig = Rig::new(paths.clone());
+    assert!(rig.app.saved().servers.is_empty());
+
+    rig.connect_to(receiver.address);
+    stream_a_while(&mut rig, &receiver);
+    receiver.handle.join().unwrap();
+    assert_eq!(rig.app.saved().servers.len(), 1);
+    assert_eq!(std::fs::read(&paths.config).unwrap(), text);
+}
+
+#[test]
+fn key_map_from_config() {
+    let paths = paths("keys");
+    std::fs::create_dir_all(paths.config.parent().unwrap()).unwrap();
+    std::fs::write(&paths.config, "[keys]\nsettings = L+R\nexit = hold B\n").unwrap();
+    let mut rig = Rig::new(paths);
+
+    // X isn't settings any more
+    rig.tap(Keys::X);
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+    rig.input.push(Keys::R);
+    rig.input.push(Keys::L | Keys::R);
+    rig.app.step();
+    rig.app.step();
+    assert_eq!(rig.app.status(), AppStatus::Settings);
+    rig.input.push(Keys::empty());
+    rig.app.step();
+
+    // B let go quickly is still back
+    assert!(rig.tap(Keys::B));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+
+    let start = Instant::now();
+    loop {
+        rig.input.push(Keys::B);
+        if !rig.app.step() {
+            break;
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn records_until_back() {
+    let paths = paths("record");
+    let recordings = paths.recordings.clone();
+    let mut rig = Rig::new(paths);
+    assert!(rig.tap(Keys::L));
+    assert_eq!(rig.app.status(), AppStatus::Recording);
+    for _ in 0..5 {
+        rig.app.step();
+    }
+    assert!(rig.tap(Keys::B));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+
+    let files: Vec<PathBuf> = std::fs::read_dir(&recordings)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(files, [recordings.join("REC_0001.AVI")]);
+    let avi = std::fs::read(&files[0]).unwrap();
+    assert_eq!(avi[..4], *b"RIFF");
+    // total frames in the main header
+    assert!(u32::from_le_bytes(avi[48..52].try_into().unwrap()) >= 5);
+}
+
+#[test]
+fn photo_on_r() {
+    let paths = paths("photo");
+    let folder = paths.photos.join("100CTRCM");
+    let mut rig = Rig::new(paths);
+    assert!(rig.tap(Keys::R));
+    assert_eq!(rig.app.status(), AppStatus::NotConnected);
+
+    let jpeg = std::fs::read(folder.join("CTR_0001.JPG")).unwrap();
+    assert_eq!(jpeg[..2], [0xff, 0xd8]);
+    assert!(jpeg.windows(6).any(|w| w == b"Exif\0\0"));
+    assert!(rig.tap(Keys::R));
+    assert!(folder.join("CTR_0002.JPG").exists());
+}
@@ -0,0 +1,320 @@
+//! Every status against every event, so a new transition can't sneak in or go missing unnoticed.
+
+use ctr_camera_common::config::SavedServer;
+use ctr_camera_common::handshake::Transport;
+use ctr_camera_common::input::Action;
+use ctr_camera_rs::state::AppStatus::{self, *};
+use ctr_camera_rs::state::{update, Choice, Effect, Event, Target};
+
+fn server() -> SavedServer {
+    SavedServer::from_address("10.0.0.2:5000", Transport::Tcp).unwrap()
+}
+
+/// One of every event, and every action.
+fn events() -> Vec<Event> {
+    let mut events = vec![
+        Event::Tick,
+        Event::Chose(Choice::Server(server())),
+        Event::Chose(Choice::Search),
+        Event::Chose(Choice::Keyboard),
+        Event::Listed,
+        Event::SearchStarted,
+        Event::SearchFailed,
+        Event::HttpStarted,
+        Event::Connected,
+        Event::ConnectFailed,
+        Event::Lost("reset".to_owned()),
+        Event::RetryDue,
+        Event::GaveUp,
+        Event::RecordingStarted,
+        Event::RecordingFailed("card full".to_owned()),
+    ];
+    events.extend(Action::ALL.map(Event::Action));
+
+    // doesn't build once there's a new event, so it gets added above
+    for event in &events {
+        match event {
+            Event::Action(_)
+            | Event::Tick
+            | Event::Chose(_)
+            | Event::Listed
+            | Event::SearchStarted
+            | Event::SearchFailed
+            | Event::HttpStarted
+            | Event::Connected
+            | Event::ConnectFailed
+            | Event::Lost(_)
+            | Event::RetryDue
+            | Event::GaveUp
+            | Event::RecordingStarted
+            | Event::RecordingFailed(_) => {}
+        }
+    }
+    events
+}
+
+fn press(action: Action) -> Event {
+    Event::Action(action)
+}
+
+/// Moving around a list or menu in `status`, staying there.
+fn navigate(status: AppStatus, actions: &[Action]) -> Vec<(Event, AppStatus, Vec<Effect>)> {
+    actions
+        .iter()
+        .map(|&action| (press(action), status, vec![Effect::Navigate(action)]))
+        .collect()
+}
+
+/// Everything `status` reacts to, apart from [`Event::Tick`] and the events every status reacts
+/// to, see [`everywhere`]. The rest has to leave it alone.
+fn transitions(status: AppStatus) -> Vec<(Event, AppStatus, Vec<Effect>)> {
+    let setup = Effect::ShowSetup;
+    let connect = |target| Effect::Connect(target);
+    let mut transitions = match status {
+        NotConnected => vec![
+            (press(Action::Settings), Settings, vec![Effect::ShowSettings]),
+            (press(Action::Confirm), NotConnected, vec![Effect::FindServers]),
+            (press(Action::Serve), NotConnected, vec![Effect::StartHttp]),
+            (press(Action::Rtsp), NotConnected, vec![Effect::ToggleRtsp]),
+            (press(Action::Record), NotConnected, vec![Effect::StartRecording]),
+            (press(Action::Photo), NotConnected, vec![Effect::TakePhoto]),
+            (Event::Listed, Picking, vec![Effect::ShowSaved]),
+            (Event::Chose(Choice::Search), NotConnected, vec![Effect::Search]),
+            (Event::SearchStarted, Discovering, vec![Effect::ShowServers]),
+            // no broadcast, no list
+            (Event::SearchFailed, NotConnected, vec![connect(Target::Keyboard)]),
+            (Event::HttpStarted, Serving, vec![]),
+            (Event::RecordingStarted, Recording, vec![]),
+        ],
+        Connected => vec![
+            (press(Action::Back), NotConnected, vec![setup.clone(), Effect::Disconnect]),
+            (press(Action::Photo), Connected, vec![Effect::TakePhoto]),
+            (
+                Event::Lost("reset".to_owned()),
+                Reconnecting,
+                vec![
+                    setup.clone(),
+                    Effect::Message("Connection lost: reset".to_owned()),
+                    Effect::StartReconnect,
+                ],
+            ),
+        ],
+        Serving => vec![
+            (press(Action::Back), NotConnected, vec![Effect::StopHttp, setup.clone()]),
+            (press(Action::Photo), Serving, vec![Effect::TakePhoto]),
+        ],
+        Settings => {
+            let mut transitions = navigate(Settings, &[
+                Action::Up,
+                Action::Down,
+                Action::Left,
+                Action::Right,
+                Action::Confirm,
+            ]);
+            transitions.push((press(Action::Back), NotConnected, vec![setup.clone()]));
+            transitions
+        }
+        Discovering => {
+            let mut transitions =
+                navigate(Discovering, &[Action::Up, Action::Down, Action::Search]);
+            transitions.extend([
+                (press(Action::Confirm), Discovering, vec![Effect::Choose]),
+                (press(Action::Back), NotConnected, vec![Effect::StopSearch, setup.clone()]),
+                (
+                    Event::Chose(Choice::Server(server())),
+                    NotConnected,
+                    vec![Effect::StopSearch, setup.clone(), connect(Target::Server(server()))],
+                ),
+                (
+                    Event::Chose(Choice::Keyboard),
+                    NotConnected,
+                    vec![Effect::StopSearch, setup.clone(), connect(Target::Keyboard)],
+                ),
+            ]);
+            transitions
+        }
+        Picking => {
+            let mut transitions = navigate(Picking, &[
+                Action::Up,
+                Action::Down,
+                Action::Rename,
+                Action::Favourite,
+                Action::MoveUp,
+                Action::MoveDown,
+                Action::Delete,
+            ]);
+            transitions.extend([
+                (press(Action::Confirm), Picking, vec![Effect::Choose]),
+                (press(Action::Back), NotConnected, vec![setup.clone()]),
+                (
+                    Event::Chose(Choice::Server(server())),
+                    NotConnected,
+                    vec![setup.clone(), connect(Target::Server(server()))],
+                ),
+                (
+                    Event::Chose(Choice::Keyboard),
+                    NotConnected,
+                    vec![setup.clone(), connect(Target::Keyboard)],
+                ),
+                (Event::Chose(Choice::Search), Picking, vec![Effect::Search]),
+                (Event::SearchStarted, Discovering, vec![Effect::ShowServers]),
+                (Event::SearchFailed, NotConnected, vec![setup.clone()]),
+            ]);
+            transitions
+        }
+        Reconnecting => vec![
+            (
+                press(Action::Back),
+                NotConnected,
+                vec![
+                    Effect::StopReconnect,
+                    setup.clone(),
+                    Effect::Message("Stopped reconnecting.".to_owned()),
+                ],
+            ),
+            (Event::Connected, Connected, vec![Effect::StopReconnect]),
+            (Event::ConnectFailed, Reconnecting, vec![Effect::RetryLater]),
+            (Event::RetryDue, Reconnecting, vec![connect(Target::Retry)]),
+            (Event::GaveUp, NotConnected, vec![Effect::StopReconnect]),
+            (press(Action::Photo), Reconnecting, vec![Effect::TakePhoto]),
+        ],
+        Recording => vec![
+            (press(Action::Back), NotConnected, vec![Effect::StopRecording, setup.clone()]),
+            (press(Action::Record), NotConnected, vec![Effect::StopRecording, setup.clone()]),
+            // a still in between frames, the recording carries on
+            (press(Action::Photo), Recording, vec![Effect::TakePhoto]),
+            // the file needs its index written before anything goes away
+            (press(Action::Exit), NotConnected, vec![Effect::StopRecording, Effect::Exit]),
+            (
+                Event::RecordingFailed("card full".to_owned()),
+                NotConnected,
+                vec![
+                    Effect::StopRecording,
+                    setup.clone(),
+                    Effect::Message("Recording stopped: card full".to_owned()),
+                ],
+            ),
+        ],
+    };
+
+    for (event, to, effects) in everywhere(status) {
+        if !transitions.iter().any(|(e, _, _)| *e == event) {
+            transitions.push((event, to, effects));
+        }
+    }
+    transitions
+}
+
+/// Exit works from anywhere, and a running stream is shown whatever else was going on.
+fn everywhere(status: AppStatus) -> [(Event, AppStatus, Vec<Effect>); 2] {
+    [
+        (press(Action::Exit), status, vec![Effect::Exit]),
+        (Event::Connected, Connected, vec![]),
+    ]
+}
+
+/// Polls for whatever is running, the RTSP server always, and frames for the HTTP and RTSP
+/// clients unless something else owns the camera.
+fn tick(status: AppStatus) -> Vec<Effect> {
+    match status {
+        NotConnected | Settings | Picking => vec![Effect::PollRtsp, Effect::ServeFrames],
+        Discovering => vec![Effect::PollSearch, Effect::PollRtsp, Effect::ServeFrames],
+        Serving => vec![Effect::PollHttp, Effect::PollRtsp, Effect::ServeFrames],
+        Reconnecting => vec![Effect::PollReconnect, Effect::PollRtsp, Effect::ServeFrames],
+        Connected => vec![Effect::PollStream, Effect::PollRtsp],
+        Recording => vec![Effect::RecordFrame, Effect::PollRtsp],
+    }
+}
+
+#[test]
+fn every_status_and_event() {
+    let events = events();
+    assert_eq!(events.len(), 15 + Action::ALL.len());
+
+    for status in AppStatus::ALL {
+        let transitions = transitions(status);
+        for event in &events {
+            let expected = match transitions.iter().find(|(e, _, _)| e == event) {
+                Some((_, to, effects)) => (*to, effects.clone()),
+                None if *event == Event::Tick => (status, tick(status)),
+                None => (status, vec![]),
+            };
+            assert_eq!(update(status, event.clone()), expected, "{:?} on {:?}", event, status);
+        }
+    }
+}
+
+#[test]
+fn exit_from_everywhere() {
+    for status in AppStatus::ALL {
+        let (to, effects) = update(status, press(Action::Exit));
+        assert_eq!(effects.last(), Some(&Effect::Exit), "{:?}", status);
+        if status == Recording {
+            assert_eq!((to, effects), (NotConnected, vec![Effect::StopRecording, Effect::Exit]));
+        } else {
+            assert_eq!((to, effects), (status, vec![Effect::Exit]));
+        }
+    }
+}
+
+#[test]
+fn connected_from_everywhere() {
+    for status in AppStatus::ALL {
+        let (to, effects) = update(status, Event::Connected);
+        assert_eq!(to, Connected, "{:?}", status);
+        let expected = if status == Reconnecting { vec![Effect::StopReconnect] } else { vec![] };
+        assert_eq!(effects, expected, "{:?}", status);
+    }
+}
+
+#[test]
+fn lost_only_while_connected() {
+    for status in AppStatus::ALL {
+        let (to, effects) = update(status, Event::Lost("reset".to_owned()));
+        if status == Connected {
+            assert_eq!(to, Reconnecting);
+            assert_eq!(effects.last(), Some(&Effect::StartReconnect));
+        } else {
+            assert_eq!((to, effects), (status, vec![]), "{:?}", status);
+        }
+    }
+}
+
+#[test]
+fn connect_lose_reconnect_give_up() {
+    let (status, effects) = update(NotConnected, press(Action::Confirm));
+    assert_eq!(effects, [Effect::FindServers]);
+    let (status, _) = update(status, Event::Listed);
+    assert_eq!(status, Picking);
+    let (status, effects) = update(status, Event::Chose(Choice::Server(server())));
+    assert_eq!(status, NotConnected);
+    assert_eq!(effects.last(), Some(&Effect::Connect(Target::Server(server()))));
+    let (status, _) = update(status, Event::Connected);
+    assert_eq!(status, Connected);
+
+    let (status, _) = update(status, Event::Lost("reset".to_owned()));
+    assert_eq!(status, Reconnecting);
+    let (status, effects) = update(status, Event::RetryDue);
+    assert_eq!(effects, [Effect::Connect(Target::Retry)]);
+    let (status, effects) = update(status, Event::ConnectFailed);
+    assert_eq!((status, effects), (Reconnecting, vec![Effect::RetryLater]));
+    let (status, _) = update(status, Event::RetryDue);
+    let (status, effects) = update(status, Event::Connected);
+    assert_eq!((status, effects), (Connected, vec![Effect::StopReconnect]));
+
+    let (status, _) = update(status, Event::Lost("again".to_owned()));
+    let (status, effects) = update(status, Event::GaveUp);
+    assert_eq!((status, effects), (NotConnected, vec![Effect::StopReconnect]));
+}
+
+#[test]
+fn record_until_back_or_failure() {
+    let (status, _) = update(NotConnected, press(Action::Record));
+    let (status, _) = update(status, Event::RecordingStarted);
+    assert_eq!(status, Recording);
+    // nothing else gets the camera meanwhile, apart from a still
+    assert_eq!(update(status, press(Action::Serve)), (Recording, vec![]));
+    assert_eq!(update(status, press(Action::Photo)), (Recording, vec![Effect::TakePhoto]));
+    let (status, effects) = update(status, press(Action::Back));
+    assert_eq!((status, effects), (NotConnected, vec![Effect::StopRecording, Effect::ShowSetup]));
+}